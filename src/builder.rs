@@ -1,23 +1,65 @@
-use std::process::Command;
+use crate::config::{ArtifactSpec, BuildConfig, GitConfig};
+use crate::hook;
+use crate::runner;
+use std::collections::HashSet;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
 
-/// Execute build command
-pub fn build(command: &str, repo_path: &str) -> Result<(), Box<dyn std::error::Error>> {
-    log::info!("Starting build with command: {}", command);
+/// Run `build.command`, then each of `build.steps` whose `when_changed`
+/// globs match a file changed in HEAD (steps without globs always run)
+///
+/// An empty `command` is skipped when there are steps; skipped steps are logged.
+pub fn run_build(
+    build_config: &BuildConfig,
+    repo_path: &str,
+    git: &GitConfig,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if !build_config.command.trim().is_empty() || build_config.steps.is_empty() {
+        let working_dir = resolve_working_dir(repo_path, build_config.working_dir.as_deref())?;
+        build(&build_config.command, &working_dir, build_config.use_shell)?;
+    }
 
-    // Parse command into parts
-    let parts: Vec<&str> = command.split_whitespace().collect();
-    if parts.is_empty() {
-        return Err("Build command is empty".into());
+    let mut changed_files = None;
+    for step in &build_config.steps {
+        if !step.when_changed.is_empty() {
+            let files = match &changed_files {
+                Some(files) => files,
+                None => changed_files.insert(hook::changed_files_in_head(repo_path, git)?),
+            };
+            if !hook::matches_watch_paths(files, &step.when_changed)? {
+                log::info!(
+                    "Skipping build step '{}': no file changed in HEAD matches {}",
+                    step.command,
+                    step.when_changed.join(", ")
+                );
+                continue;
+            }
+        }
+
+        let working_dir = resolve_working_dir(repo_path, step.working_dir.as_deref())?;
+        build(&step.command, &working_dir, step.use_shell)?;
     }
 
-    let program = parts[0];
-    let args = &parts[1..];
+    Ok(())
+}
+
+/// Execute build command
+pub fn build(command: &str, repo_path: &str, use_shell: bool) -> Result<(), Box<dyn std::error::Error>> {
+    log::info!("Starting build with command: {}", command);
+    check_make_targets(command, repo_path)?;
+
+    let mut process = runner::shell_command(command, use_shell).ok_or("Build command is empty")?;
 
     // Execute build command
-    let output = Command::new(program)
-        .args(args)
-        .current_dir(repo_path)
-        .output()?;
+    log::debug!("Running {:?} in {}", process, repo_path);
+    let output = runner::run_tracked(process.current_dir(repo_path))?;
+    log::trace!(
+        "Build command exited with {}\nstdout:\n{}\nstderr:\n{}",
+        output.status,
+        String::from_utf8_lossy(&output.stdout),
+        String::from_utf8_lossy(&output.stderr)
+    );
 
     // Check if build succeeded
     if !output.status.success() {
@@ -26,35 +68,712 @@ pub fn build(command: &str, repo_path: &str) -> Result<(), Box<dyn std::error::E
         return Err(format!("Build failed: {}", stderr).into());
     }
 
-    let stdout = String::from_utf8_lossy(&output.stdout);
-    log::info!("Build succeeded: {}", stdout);
+    for (level, message) in success_log_lines(&output) {
+        log::log!(level, "{}", message);
+    }
+
+    Ok(())
+}
+
+/// For `make <target>...` command lines, fail with "make target not found"
+/// when the Makefile has no rule for a named target, instead of leaving the
+/// user with make's own error
+///
+/// Nothing is built: the targets are looked up in GNU make's database
+/// (`make -pq .DEFAULT`), and only a target without an explicit rule is
+/// asked about with `make -q <target>`, to cover implicit rules. (`make -n`
+/// and `make -q` on an explicit target still run its `+` recipe lines.)
+/// Other problems are left for the real build to surface. Non-make commands,
+/// non-GNU makes and command lines using shell syntax are not checked.
+fn check_make_targets(command: &str, working_dir: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let mut words = command.split_whitespace();
+    let Some(program) = words.next() else {
+        return Ok(());
+    };
+    let is_make = Path::new(program)
+        .file_name()
+        .is_some_and(|name| name == "make" || name == "gmake");
+    let args: Vec<&str> = words.collect();
+    if !is_make || command.contains(|c: char| "|&;<>$`()\"'".contains(c)) {
+        return Ok(());
+    }
+
+    // Everything but options, their values and VAR=value assignments is a target
+    let mut targets = Vec::new();
+    let mut options = Vec::new();
+    let mut takes_value = false;
+    for arg in &args {
+        if std::mem::take(&mut takes_value) {
+            options.push(*arg);
+            continue;
+        }
+        if arg.starts_with('-') {
+            takes_value = matches!(*arg, "-C" | "-f" | "-I" | "-o" | "-W" | "--file" | "--directory");
+            options.push(*arg);
+        } else if arg.contains('=') {
+            options.push(*arg);
+        } else {
+            targets.push(*arg);
+        }
+    }
+    if targets.is_empty() {
+        return Ok(());
+    }
 
+    let make = |extra: &[&str]| {
+        let mut process = std::process::Command::new(program);
+        process.args(&options).args(extra).current_dir(working_dir);
+        runner::run_tracked(&mut process)
+    };
+    // `.DEFAULT` has no recipe to run; the goal only keeps make from building the default one
+    let database = make(&["-pq", ".DEFAULT"])?;
+    let Some(explicit) = explicit_make_targets(&String::from_utf8_lossy(&database.stdout)) else {
+        return Ok(());
+    };
+
+    for target in targets.into_iter().filter(|target| !explicit.contains(*target)) {
+        // Exits 1 when the target is merely out of date, 2 on errors
+        let output = make(&["-q", target])?;
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        if output.status.code() == Some(2) && stderr.contains(&format!("No rule to make target '{}'", target)) {
+            return Err(format!("make target not found: {} (no rule for it in {})", target, working_dir).into());
+        }
+    }
     Ok(())
 }
 
-/// Verify that build artifacts exist
-pub fn verify_artifacts(artifacts: &[String], repo_path: &str) -> Result<(), Box<dyn std::error::Error>> {
+/// Targets with an explicit rule in a GNU make database (`make -p`), or
+/// `None` when the output isn't one
+fn explicit_make_targets(database: &str) -> Option<HashSet<String>> {
+    let (_, files) = database.split_once("\n# Files\n")?;
+    let mut targets = HashSet::new();
+    let mut not_a_target = false;
+    for line in files.lines() {
+        if line == "# Not a target:" {
+            not_a_target = true;
+        } else if line.is_empty() || line.starts_with(['#', '\t']) {
+            continue;
+        } else if let Some((name, _)) = line.split_once(':') {
+            if !std::mem::take(&mut not_a_target) {
+                targets.insert(name.to_string());
+            }
+        }
+    }
+    Some(targets)
+}
+
+/// Log lines for a successful build: the exit code, then stdout and stderr
+/// (info), each only when non-empty
+///
+/// Many tools write progress to stderr, so only its warning-like lines are
+/// repeated at warn.
+fn success_log_lines(output: &std::process::Output) -> Vec<(log::Level, String)> {
+    let code = output.status.code().map_or("none".to_string(), |code| code.to_string());
+    let mut lines = vec![(log::Level::Info, format!("Build succeeded (exit code {})", code))];
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    if !stdout.trim().is_empty() {
+        lines.push((log::Level::Info, format!("Build output:\n{}", stdout.trim_end())));
+    }
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if !stderr.trim().is_empty() {
+        lines.push((log::Level::Info, format!("Build stderr:\n{}", stderr.trim_end())));
+        let warnings: Vec<&str> = stderr.lines().filter(|line| is_warning_line(line)).collect();
+        if !warnings.is_empty() {
+            lines.push((log::Level::Warn, format!("Build succeeded with warnings:\n{}", warnings.join("\n"))));
+        }
+    }
+
+    lines
+}
+
+/// Whether a line of build output looks like a warning (`warning: ...`,
+/// `WARN ...`, `npm warn ...`)
+fn is_warning_line(line: &str) -> bool {
+    line.split(|c: char| !c.is_ascii_alphabetic())
+        .any(|word| word.eq_ignore_ascii_case("warning") || word.eq_ignore_ascii_case("warn"))
+}
+
+/// Resolve a command's working directory relative to the repository root
+///
+/// Returns `repo_path` itself when no working directory is configured.
+pub fn resolve_working_dir(
+    repo_path: &str,
+    working_dir: Option<&str>,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let Some(working_dir) = working_dir else {
+        return Ok(repo_path.to_string());
+    };
+
+    let resolved = Path::new(repo_path).join(working_dir);
+    if !resolved.is_dir() {
+        return Err(format!("Working directory does not exist: {}", resolved.display()).into());
+    }
+
+    Ok(resolved.to_str().ok_or("Invalid working directory path")?.to_string())
+}
+
+/// Verify that build artifacts exist and pass their `min_size`/`executable` assertions
+///
+/// Every artifact is checked, and all problems are reported in one error.
+pub fn verify_artifacts(artifacts: &[ArtifactSpec], repo_path: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let mut missing = Vec::new();
+    let mut problems = Vec::new();
+
     for artifact in artifacts {
-        let mut artifact_path = std::path::PathBuf::from(repo_path);
-        artifact_path.push(artifact);
+        let matches = expand_artifact(artifact.path(), Path::new(repo_path))?;
+        if matches.is_empty() && !artifact.is_optional() {
+            missing.push(artifact.path());
+        }
+        for artifact_path in matches {
+            let size = crate::rollback::dir_size(&artifact_path)?;
+            let shown = artifact_path.strip_prefix(repo_path).unwrap_or(&artifact_path).display();
 
-        if !artifact_path.exists() {
-            return Err(format!("Build artifact not found: {}", artifact).into());
+            match artifact.min_size() {
+                // min_size = 0 sets no minimum, so an empty artifact passes
+                None | Some(0) => {}
+                Some(_) if size == 0 => problems.push(format!("artifact {} is 0 bytes", shown)),
+                Some(min_size) if size < min_size => problems.push(format!(
+                    "artifact {} is {} bytes, below min_size {}",
+                    shown, size, min_size
+                )),
+                _ => {}
+            }
+            if artifact.must_be_executable() && !is_executable(&artifact_path)? {
+                problems.push(format!("artifact {} is not executable", shown));
+            }
+
+            log::info!("Verified artifact: {:?} ({} bytes)", artifact_path, size);
         }
+    }
+
+    if !missing.is_empty() {
+        problems.insert(
+            0,
+            format!("Build artifacts not found ({}): {}", missing.len(), missing.join(", ")),
+        );
+    }
+
+    if !problems.is_empty() {
+        return Err(problems.join("; ").into());
+    }
+
+    Ok(())
+}
+
+/// Run a custom verification command (`deploy.verify_command`) against the artifacts
+///
+/// The absolute artifact paths are appended as arguments (when not running
+/// through the shell) and passed newline-separated in `PLOOP_ARTIFACTS`. The
+/// command runs in `repo_path`; a non-zero exit is an error carrying its output.
+pub fn run_verify_command(
+    command_line: &str,
+    use_shell: bool,
+    artifacts: &[ArtifactSpec],
+    artifact_base: &str,
+    repo_path: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let paths = expand_artifacts(artifacts, artifact_base)?;
+    let listed = paths
+        .iter()
+        .map(|path| path.to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("\n");
 
-        log::info!("Verified artifact: {}", artifact);
+    let mut command = runner::shell_command(command_line, use_shell).ok_or("Verify command is empty")?;
+    if !use_shell {
+        command.args(&paths);
     }
+    command.current_dir(repo_path).env("PLOOP_ARTIFACTS", listed);
 
+    log::info!("Running verify command: {}", command_line);
+    let output = runner::run_tracked(&mut command)?;
+    if !output.status.success() {
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let detail = if stderr.trim().is_empty() { stdout } else { stderr };
+        return Err(format!("Verify command failed ({}): {}", output.status, detail.trim()).into());
+    }
     Ok(())
 }
 
+/// Whether `path` is an executable file: exec bit on Unix, ELF or PE header elsewhere
+fn is_executable(path: &Path) -> Result<bool, Box<dyn std::error::Error>> {
+    if !path.is_file() {
+        return Ok(false);
+    }
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        Ok(std::fs::metadata(path)?.permissions().mode() & 0o111 != 0)
+    }
+
+    #[cfg(not(unix))]
+    {
+        use std::io::Read;
+        let mut magic = [0u8; 4];
+        let read = std::fs::File::open(path)?.read(&mut magic)?;
+        Ok(magic[..read].starts_with(b"\x7fELF") || magic[..read].starts_with(b"MZ"))
+    }
+}
+
+/// Untracked artifacts (build outputs) last modified before `since`
+///
+/// A directory counts by its most recently modified file. Files tracked by
+/// git are skipped: their mtime is when they were checked out or edited, not
+/// when they were built.
+pub fn stale_artifacts(
+    artifacts: &[ArtifactSpec],
+    artifact_base: &str,
+    repo_path: &str,
+    git: &GitConfig,
+    since: SystemTime,
+) -> Result<Vec<PathBuf>, Box<dyn std::error::Error>> {
+    let tracked: HashSet<PathBuf> = hook::tracked_files(repo_path, git)?
+        .into_iter()
+        .map(|file| Path::new(repo_path).join(file))
+        .collect();
+
+    let mut stale = Vec::new();
+    for artifact in resolve_artifacts(artifacts, artifact_base)? {
+        if tracked.contains(&artifact.path) {
+            continue;
+        }
+        if newest_mtime(&artifact.path)?.is_some_and(|modified| modified < since) {
+            stale.push(artifact.path);
+        }
+    }
+    Ok(stale)
+}
+
+/// Latest modification time of a file, or of any file below a directory
+fn newest_mtime(path: &Path) -> std::io::Result<Option<SystemTime>> {
+    let metadata = std::fs::metadata(path)?;
+    if !metadata.is_dir() {
+        return Ok(Some(metadata.modified()?));
+    }
+
+    let mut newest = None;
+    for entry in std::fs::read_dir(path)? {
+        newest = newest.max(newest_mtime(&entry?.path())?);
+    }
+    Ok(newest)
+}
+
+/// An artifact path together with the config entry it was expanded from
+#[derive(Debug, Clone)]
+pub struct ResolvedArtifact<'a> {
+    pub path: PathBuf,
+    pub spec: &'a ArtifactSpec,
+}
+
+impl ResolvedArtifact<'_> {
+    /// File name the artifact gets in the target: its `dest` with `{name}`
+    /// replaced by the source file name, or the source file name
+    pub fn dest_name(&self) -> Result<OsString, Box<dyn std::error::Error>> {
+        let file_name = self.path.file_name().ok_or("Invalid artifact path")?;
+        Ok(match self.spec.dest() {
+            Some(dest) => dest.replace("{name}", &file_name.to_string_lossy()).into(),
+            None => file_name.to_os_string(),
+        })
+    }
+}
+
+/// Expand artifact paths and globs relative to `repo_path`
+///
+/// Each glob's matches are sorted so the resulting order is stable. A path or
+/// glob that matches nothing is an error unless the artifact is optional.
+pub fn expand_artifacts(
+    artifacts: &[ArtifactSpec],
+    repo_path: &str,
+) -> Result<Vec<PathBuf>, Box<dyn std::error::Error>> {
+    Ok(resolve_artifacts(artifacts, repo_path)?
+        .into_iter()
+        .map(|artifact| artifact.path)
+        .collect())
+}
+
+/// Whether a build command line runs cargo
+pub fn is_cargo_command(command: &str) -> bool {
+    command
+        .split_whitespace()
+        .next()
+        .and_then(|program| Path::new(program).file_stem())
+        .is_some_and(|stem| stem == "cargo")
+}
+
+/// Directory under the cargo target directory that `command` builds into:
+/// the profile's (`debug` unless `--release` or `--profile` says otherwise),
+/// below the `--target` triple when one is given
+fn cargo_output_dir(command: &str) -> PathBuf {
+    let mut profile = "debug".to_string();
+    let mut triple = None;
+    let mut args = command.split_whitespace().skip(1);
+    while let Some(arg) = args.next() {
+        match arg {
+            "--release" | "-r" => profile = "release".to_string(),
+            "--profile" => profile = args.next().unwrap_or("dev").to_string(),
+            "--target" => triple = args.next(),
+            "--" => break,
+            _ => {
+                if let Some(name) = arg.strip_prefix("--profile=") {
+                    profile = name.to_string();
+                } else if let Some(name) = arg.strip_prefix("--target=") {
+                    triple = Some(name);
+                }
+            }
+        }
+    }
+    // The built-in profiles share their parents' directories
+    let profile = match profile.as_str() {
+        "dev" | "test" => "debug".to_string(),
+        "bench" => "release".to_string(),
+        _ => profile,
+    };
+
+    triple.map(PathBuf::from).unwrap_or_default().join(profile)
+}
+
+/// Binaries a cargo build `command` produces, as absolute paths under the
+/// project's target directory
+///
+/// Uses `cargo metadata`, so workspace members, `[[bin]]` entries,
+/// `src/bin/*.rs` and `CARGO_TARGET_DIR` are all taken into account.
+pub fn discover_cargo_artifacts(repo_path: &str, command: &str) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let output = std::process::Command::new("cargo")
+        .args(["metadata", "--no-deps", "--format-version", "1", "--offline"])
+        .current_dir(repo_path)
+        .output()?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(format!("cargo metadata failed: {}", stderr.trim()).into());
+    }
+
+    let metadata: serde_json::Value = serde_json::from_slice(&output.stdout)?;
+    let output_dir = Path::new(
+        metadata["target_directory"]
+            .as_str()
+            .ok_or("cargo metadata reported no target directory")?,
+    )
+    .join(cargo_output_dir(command));
+
+    let mut paths = Vec::new();
+    for target in metadata["packages"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .flat_map(|package| package["targets"].as_array().into_iter().flatten())
+    {
+        let is_bin = target["kind"]
+            .as_array()
+            .is_some_and(|kinds| kinds.iter().any(|kind| kind == "bin"));
+        if let (true, Some(name)) = (is_bin, target["name"].as_str()) {
+            let file_name = format!("{}{}", name, std::env::consts::EXE_SUFFIX);
+            paths.push(output_dir.join(file_name).to_string_lossy().into_owned());
+        }
+    }
+
+    if paths.is_empty() {
+        return Err(format!("No binary targets found in the cargo project at {}", repo_path).into());
+    }
+    Ok(paths)
+}
+
+/// Like [`expand_artifacts`], keeping track of each path's config entry
+pub fn resolve_artifacts<'a>(
+    artifacts: &'a [ArtifactSpec],
+    repo_path: &str,
+) -> Result<Vec<ResolvedArtifact<'a>>, Box<dyn std::error::Error>> {
+    let mut paths = Vec::new();
+
+    for artifact in artifacts {
+        let matches = expand_artifact(artifact.path(), Path::new(repo_path))?;
+
+        if matches.is_empty() {
+            if artifact.is_optional() {
+                log::info!("Optional artifact not found: {}", artifact.path());
+                continue;
+            }
+            return Err(format!("Build artifact not found: {}", artifact.path()).into());
+        }
+
+        paths.extend(matches.into_iter().map(|path| ResolvedArtifact { path, spec: artifact }));
+    }
+
+    Ok(paths)
+}
+
+fn expand_artifact(pattern: &str, base: &Path) -> Result<Vec<PathBuf>, Box<dyn std::error::Error>> {
+    let is_glob = pattern.contains(['*', '?', '[']);
+    if !is_glob {
+        let path = base.join(pattern);
+        return Ok(if path.exists() { vec![path] } else { Vec::new() });
+    }
+
+    // Absolute patterns are used as-is
+    let full_pattern = if Path::new(pattern).is_absolute() {
+        pattern.to_string()
+    } else {
+        let base = glob::Pattern::escape(base.to_str().ok_or("Invalid artifact base path")?);
+        format!("{}/{}", base, pattern)
+    };
+    let mut matches = glob::glob(&full_pattern)?.collect::<Result<Vec<_>, _>>()?;
+    matches.sort();
+    Ok(matches)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_build_steps_run_only_when_matching_files_changed() {
+        let repo = crate::test_support::init_repo();
+        std::fs::write(repo.join("Cargo.toml"), "[package]").unwrap();
+        crate::test_support::git(&repo, &["add", "."]);
+        crate::test_support::git(&repo, &["commit", "-qm", "rust only"]);
+        let step = |command: &str, when_changed: &[&str]| crate::config::BuildStep {
+            command: command.to_string(),
+            working_dir: None,
+            use_shell: false,
+            when_changed: when_changed.iter().map(|glob| glob.to_string()).collect(),
+        };
+        let build_config = BuildConfig {
+            command: String::new(),
+            working_dir: None,
+            use_shell: false,
+            update_submodules: false,
+            steps: vec![
+                step("touch npm-built", &["package.json", "web/**"]),
+                step("touch cargo-built", &["Cargo.toml", "**/*.rs"]),
+                step("touch always-built", &[]),
+            ],
+        };
+
+        run_build(&build_config, repo.to_str().unwrap(), &Default::default()).unwrap();
+        assert!(!repo.join("npm-built").exists());
+        assert!(repo.join("cargo-built").exists());
+        assert!(repo.join("always-built").exists());
+        std::fs::remove_dir_all(&repo).unwrap();
+    }
+
+    #[test]
+    fn test_discover_cargo_artifacts() {
+        let project = crate::test_support::temp_dir("cargo-fixture");
+        std::fs::create_dir_all(project.join("src/bin")).unwrap();
+        std::fs::write(
+            project.join("Cargo.toml"),
+            "[package]\nname = \"sample-app\"\nversion = \"0.1.0\"\nedition = \"2021\"\n\n[lib]\npath = \"src/lib.rs\"\n",
+        )
+        .unwrap();
+        std::fs::write(project.join("src/main.rs"), "fn main() {}\n").unwrap();
+        std::fs::write(project.join("src/lib.rs"), "").unwrap();
+        std::fs::write(project.join("src/bin/tool.rs"), "fn main() {}\n").unwrap();
+
+        let mut names: Vec<String> = discover_cargo_artifacts(project.to_str().unwrap(), "cargo build --release")
+            .unwrap()
+            .iter()
+            .map(|path| {
+                let path = Path::new(path);
+                assert!(path.is_absolute());
+                assert!(path.parent().unwrap().ends_with("release"));
+                path.file_stem().unwrap().to_string_lossy().into_owned()
+            })
+            .collect();
+        names.sort();
+        assert_eq!(names, ["sample-app", "tool"]);
+
+        let debug = discover_cargo_artifacts(project.to_str().unwrap(), "cargo build").unwrap();
+        assert!(debug.iter().all(|path| Path::new(path).parent().unwrap().ends_with("debug")));
+
+        assert_eq!(cargo_output_dir("cargo build"), Path::new("debug"));
+        assert_eq!(cargo_output_dir("cargo build -r"), Path::new("release"));
+        assert_eq!(cargo_output_dir("cargo build --profile=dist"), Path::new("dist"));
+        assert_eq!(cargo_output_dir("cargo build --profile bench"), Path::new("release"));
+        assert_eq!(
+            cargo_output_dir("cargo build --release --target aarch64-unknown-linux-gnu"),
+            Path::new("aarch64-unknown-linux-gnu/release")
+        );
+
+        assert!(is_cargo_command("cargo build --release"));
+        assert!(is_cargo_command("/usr/local/bin/cargo build"));
+        assert!(!is_cargo_command("make release"));
+        std::fs::remove_dir_all(&project).unwrap();
+    }
+
     #[test]
     fn test_build_with_echo() {
-        let result = build("echo test", ".");
+        let result = build("echo test", ".", false);
         assert!(result.is_ok());
     }
+
+    #[test]
+    fn test_success_log_lines() {
+        let run = |command: &str| std::process::Command::new("sh").args(["-c", command]).output().unwrap();
+
+        let quiet = success_log_lines(&run("true"));
+        assert_eq!(quiet, vec![(log::Level::Info, "Build succeeded (exit code 0)".to_string())]);
+
+        let noisy = success_log_lines(&run("echo compiled; echo 'warning: unused variable' >&2"));
+        assert_eq!(noisy.len(), 4);
+        assert_eq!(noisy[1], (log::Level::Info, "Build output:\ncompiled".to_string()));
+        assert_eq!(noisy[2], (log::Level::Info, "Build stderr:\nwarning: unused variable".to_string()));
+        assert_eq!(noisy[3], (log::Level::Warn, "Build succeeded with warnings:\nwarning: unused variable".to_string()));
+
+        // Progress on stderr is not a warning
+        let progress = success_log_lines(&run("echo '   Compiling app v0.1.0' >&2; echo 'npm WARN deprecated' >&2"));
+        assert_eq!(progress.len(), 3);
+        assert_eq!(progress[1].0, log::Level::Info);
+        assert_eq!(progress[2], (log::Level::Warn, "Build succeeded with warnings:\nnpm WARN deprecated".to_string()));
+        assert!(!is_warning_line("Downloaded warnings-lib v1.0"));
+    }
+
+    #[test]
+    fn test_build_with_shell_pipeline() {
+        let repo = crate::test_support::temp_dir("shell");
+        let repo_str = repo.to_str().unwrap();
+
+        let command = "echo 'hello world' | tr a-z A-Z > out.txt && test -s out.txt";
+        build(command, repo_str, true).unwrap();
+        assert_eq!(std::fs::read_to_string(repo.join("out.txt")).unwrap(), "HELLO WORLD\n");
+
+        // Without the shell, `|` and `&&` are passed to echo as plain arguments
+        build("echo a | false", repo_str, false).unwrap();
+        assert!(build("echo a | false", repo_str, true).is_err());
+        std::fs::remove_dir_all(&repo).unwrap();
+    }
+
+    #[test]
+    fn test_missing_make_target() {
+        if crate::deployer::find_in_path("make").is_none() {
+            return;
+        }
+        let repo = crate::test_support::temp_dir("make");
+        std::fs::write(repo.join("Makefile"), "deploy-build:\n\t+touch built\n%.txt:\n\ttouch $@\n").unwrap();
+        let repo_str = repo.to_str().unwrap();
+
+        let err = build("make deploy-build deploy-biuld", repo_str, false).unwrap_err();
+        assert!(err.to_string().contains("make target not found: deploy-biuld"), "{}", err);
+        // Checking the targets ran none of the recipes
+        assert!(!repo.join("built").exists());
+        // Targets built by a pattern rule exist too
+        build("make notes.txt", repo_str, false).unwrap();
+        assert!(repo.join("notes.txt").exists());
+        build("make deploy-build", repo_str, false).unwrap();
+        assert!(repo.join("built").exists());
+        std::fs::remove_dir_all(&repo).unwrap();
+    }
+
+    #[test]
+    fn test_build_in_working_dir() {
+        let repo = crate::test_support::temp_dir("workdir");
+        std::fs::create_dir_all(repo.join("frontend")).unwrap();
+        std::fs::write(repo.join("frontend/package.json"), "{}").unwrap();
+        let repo_str = repo.to_str().unwrap();
+
+        let cwd = resolve_working_dir(repo_str, Some("frontend")).unwrap();
+        assert!(build("ls package.json", &cwd, false).is_ok());
+        assert!(build("ls package.json", repo_str, false).is_err());
+        assert!(resolve_working_dir(repo_str, Some("missing")).is_err());
+        std::fs::remove_dir_all(&repo).unwrap();
+    }
+
+    #[test]
+    fn test_verify_reports_all_missing_artifacts() {
+        let repo = crate::test_support::temp_dir("verify");
+        std::fs::write(repo.join("app"), "bin").unwrap();
+        let artifacts: Vec<ArtifactSpec> = ["app", "missing-a", "dist/*.js", "missing-b"]
+            .into_iter()
+            .map(ArtifactSpec::from)
+            .collect();
+
+        let err = verify_artifacts(&artifacts, repo.to_str().unwrap()).unwrap_err().to_string();
+        assert!(err.contains("(3)"), "{}", err);
+        for missing in ["missing-a", "dist/*.js", "missing-b"] {
+            assert!(err.contains(missing), "{}", err);
+        }
+        assert!(verify_artifacts(&artifacts[..1], repo.to_str().unwrap()).is_ok());
+        std::fs::remove_dir_all(&repo).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_verify_command_rejects_artifact() {
+        let repo = crate::test_support::temp_dir("verify-command");
+        std::fs::write(repo.join("good"), "signed").unwrap();
+        std::fs::write(repo.join("bad"), "tampered").unwrap();
+        let repo_str = repo.to_str().unwrap();
+        let artifacts = vec![ArtifactSpec::from("good"), ArtifactSpec::from("bad")];
+
+        // Paths are appended as arguments ...
+        assert!(run_verify_command("grep -q signed", false, &artifacts[..1], repo_str, repo_str).is_ok());
+        // ... and listed in PLOOP_ARTIFACTS for shell commands
+        let check = r#"for f in $PLOOP_ARTIFACTS; do grep -q signed "$f" || { echo "unsigned: $f" >&2; exit 1; }; done"#;
+        let err = run_verify_command(check, true, &artifacts, repo_str, repo_str).unwrap_err().to_string();
+        assert!(err.starts_with("Verify command failed"), "{}", err);
+        assert!(err.contains(&format!("unsigned: {}", repo.join("bad").display())), "{}", err);
+        std::fs::remove_dir_all(&repo).unwrap();
+    }
+
+    #[test]
+    fn test_verify_size_and_executable_assertions() {
+        let repo = crate::test_support::temp_dir("assert");
+        std::fs::write(repo.join("empty"), "").unwrap();
+        std::fs::write(repo.join("small"), "abc").unwrap();
+        std::fs::write(repo.join("script"), "#!/bin/sh\n").unwrap();
+        let repo_str = repo.to_str().unwrap();
+        let entry = |path: &str, min_size: Option<u64>, executable: bool| {
+            ArtifactSpec::Detailed(crate::config::ArtifactEntry {
+                path: path.to_string(),
+                optional: false,
+                exclude: Vec::new(),
+                min_size,
+                executable,
+                dest: None,
+            })
+        };
+
+        let err = verify_artifacts(&[entry("empty", Some(1), false)], repo_str).unwrap_err();
+        assert_eq!(err.to_string(), "artifact empty is 0 bytes");
+        let err = verify_artifacts(&[entry("small", Some(10), false)], repo_str).unwrap_err();
+        assert_eq!(err.to_string(), "artifact small is 3 bytes, below min_size 10");
+        assert!(verify_artifacts(&[entry("small", Some(3), false)], repo_str).is_ok());
+        assert!(verify_artifacts(&[entry("empty", Some(0), false)], repo_str).is_ok());
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let err = verify_artifacts(&[entry("script", None, true)], repo_str).unwrap_err();
+            assert_eq!(err.to_string(), "artifact script is not executable");
+            std::fs::set_permissions(repo.join("script"), std::fs::Permissions::from_mode(0o755)).unwrap();
+            assert!(verify_artifacts(&[entry("script", None, true)], repo_str).is_ok());
+        }
+        std::fs::remove_dir_all(&repo).unwrap();
+    }
+
+    #[test]
+    fn test_expand_artifact_globs() {
+        let dir = std::env::temp_dir().join(format!("postloop-build-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("b.so"), "b").unwrap();
+        std::fs::write(dir.join("a.so"), "a").unwrap();
+        let repo = dir.to_str().unwrap();
+
+        let paths = expand_artifacts(&[ArtifactSpec::from("*.so")], repo).unwrap();
+        assert_eq!(paths, vec![dir.join("a.so"), dir.join("b.so")]);
+
+        assert!(verify_artifacts(&[ArtifactSpec::from("*.dll")], repo).is_err());
+
+        let optional = ArtifactSpec::Detailed(crate::config::ArtifactEntry {
+            path: "*.dll".to_string(),
+            optional: true,
+            exclude: Vec::new(),
+            min_size: None,
+            executable: false,
+            dest: None,
+        });
+        assert!(expand_artifacts(&[optional], repo).unwrap().is_empty());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 }
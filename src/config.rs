@@ -4,87 +4,865 @@ use std::path::Path;
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct Config {
+    #[serde(default)]
     pub watch: WatchConfig,
     pub build: BuildConfig,
     pub deploy: DeployConfig,
+    #[serde(default)]
     pub sync: SyncConfig,
+    #[serde(default)]
     pub rollback: RollbackConfig,
+    #[serde(default)]
     pub log: LogConfig,
+    #[serde(default)]
+    pub notify: NotifyConfig,
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct WatchConfig {
+    #[serde(default = "default_repo_path")]
     pub repo_path: String,
+    #[serde(default = "default_branch")]
     pub branch: String,
+    /// Glob patterns; when set, only commits touching a matching path trigger a run
+    #[serde(default)]
+    pub paths: Vec<String>,
+    /// Refuse to run when tracked files have uncommitted changes
+    #[serde(default)]
+    pub require_clean_tree: bool,
+    /// Refuse to run when the branch is behind `sync.remote` (as of the last fetch)
+    #[serde(default)]
+    pub abort_if_behind: bool,
+    /// Wall-clock budget for a whole run; the active command is killed when it runs out
+    #[serde(default)]
+    pub run_timeout_secs: Option<u64>,
+    /// How git is invoked (`git_path`, `git_extra_args` keys of `[watch]`)
+    #[serde(flatten)]
+    pub git: GitConfig,
+}
+
+impl Default for WatchConfig {
+    fn default() -> Self {
+        WatchConfig {
+            repo_path: ".".to_string(),
+            branch: "main".to_string(),
+            paths: Vec::new(),
+            require_clean_tree: false,
+            abort_if_behind: false,
+            run_timeout_secs: None,
+            git: GitConfig::default(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Default, PartialEq, Eq)]
+pub struct GitConfig {
+    /// Git executable to run instead of `git` from PATH
+    #[serde(default)]
+    pub git_path: Option<String>,
+    /// Arguments placed before every git subcommand, e.g. `["-c", "safe.directory=*"]`
+    #[serde(default)]
+    pub git_extra_args: Vec<String>,
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct BuildConfig {
+    /// Runs first, unconditionally; may be empty when `steps` does the building
+    #[serde(default)]
     pub command: String,
+    /// Directory the build command runs in, relative to the repository root
+    #[serde(default)]
+    pub working_dir: Option<String>,
+    /// Run the command through `sh -c` (`cmd /C` on Windows) instead of splitting on whitespace
+    #[serde(default)]
+    pub use_shell: bool,
+    /// Run `git submodule update --init --recursive` before building
+    #[serde(default)]
+    pub update_submodules: bool,
+    /// Further build commands run after `command`, in order
+    #[serde(default)]
+    pub steps: Vec<BuildStep>,
+}
+
+/// `[[build.steps]]`: a build command that can be limited to commits touching certain files
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct BuildStep {
+    pub command: String,
+    /// Directory the command runs in, relative to the repository root
+    #[serde(default)]
+    pub working_dir: Option<String>,
+    #[serde(default)]
+    pub use_shell: bool,
+    /// Glob patterns; the step only runs when a file changed in HEAD matches one
+    #[serde(default)]
+    pub when_changed: Vec<String>,
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct DeployConfig {
     pub command: Option<String>,
+    /// Directory the deploy command runs in, relative to the repository root
+    #[serde(default)]
+    pub working_dir: Option<String>,
+    /// Run the command through `sh -c` (`cmd /C` on Windows) instead of splitting on whitespace
+    #[serde(default)]
+    pub use_shell: bool,
     pub target_dir: Option<String>,
-    pub artifacts: Option<Vec<String>>,
+    pub artifacts: Option<Vec<ArtifactSpec>>,
+    /// Deploy into `{commit}` subdirectories behind a `current` symlink.
+    /// When false, artifacts are copied straight into target_dir and the
+    /// previous contents are kept in a timestamped `.bak` directory, and the
+    /// history in a sibling `.ploop` directory (see [`DeployConfig::state_dir`]).
+    #[serde(default = "default_true")]
+    pub versioned: bool,
+    /// For unversioned deploys, empty target_dir (after backing it up) before
+    /// copying, so files no longer among the artifacts are removed
+    #[serde(default = "default_true")]
+    pub clean_target: bool,
+    /// How versioned directories are named
+    #[serde(default)]
+    pub version_scheme: VersionScheme,
+    /// Globs excluded when copying directory artifacts, relative to each artifact root
+    #[serde(default)]
+    pub exclude: Vec<String>,
+    /// Number of threads copying artifacts concurrently
+    #[serde(default = "default_copy_parallelism")]
+    pub copy_parallelism: usize,
+    /// Hardlink file artifacts that are unchanged (same checksum) from the previous version
+    #[serde(default)]
+    pub dedup: bool,
+    /// Clone artifacts copy-on-write (reflinks) where the filesystem supports it
+    #[serde(default = "default_true")]
+    pub prefer_reflink: bool,
+    /// Track the active version in a 'current.txt' pointer file instead of a 'current' symlink
+    #[serde(default)]
+    pub pointer_file: bool,
+    /// Create the 'current' symlink at all; when false the active version is
+    /// only recorded in the 'current.txt' pointer file
+    #[serde(default = "default_true")]
+    pub create_current_symlink: bool,
+    /// Name of the symlink naming the active version (e.g. `live`, `www`)
+    #[serde(default = "default_current_link_name")]
+    pub current_link_name: String,
+    /// Extra deploy attempts after a transient I/O failure, before rolling
+    /// back; `pre_deploy`/`post_deploy` are not re-run
+    #[serde(default)]
+    pub retries: u32,
+    /// Seconds to wait between deploy attempts
+    #[serde(default = "default_retry_delay_secs")]
+    pub retry_delay_secs: u64,
+    /// Inline shell script run in the repository before deploying; a failure aborts the deploy
+    #[serde(default)]
+    pub pre_deploy: Option<String>,
+    /// Inline shell script run after a successful deploy; a failure fails the deploy
+    #[serde(default)]
+    pub post_deploy: Option<String>,
+    /// Skip the run when every target's current version already holds
+    /// byte-identical artifacts, going by its recorded checksums (`ploop run
+    /// --force` overrides)
+    #[serde(default = "default_true")]
+    pub skip_unchanged: bool,
+    /// Directory artifact paths are resolved against instead of `repo_path`
+    /// (e.g. an out-of-tree `CARGO_TARGET_DIR`); relative to `repo_path` itself
+    #[serde(default)]
+    pub artifact_base: Option<String>,
+    /// Pack the artifacts into a single `release-{version}` archive inside
+    /// the version directory instead of copying them loose
+    #[serde(default)]
+    pub bundle: Option<BundleFormat>,
+    /// Globs (path in the version directory, or file name) of deployed files that also get a
+    /// gzip-compressed `.gz` sibling, e.g. for nginx `gzip_static`
+    #[serde(default)]
+    pub precompress: Vec<String>,
+    /// gzip level (0-9) for `precompress`
+    #[serde(default = "default_precompress_level")]
+    pub precompress_level: u32,
+    /// Hardlink tracked file artifacts not touched since the previously deployed commit
+    #[serde(default)]
+    pub incremental: bool,
+    /// Free space (in MB) that must remain on the target volume after copying artifacts
+    #[serde(default)]
+    pub disk_margin_mb: u64,
+    /// Fail instead of warning when a build output is older than the commit being deployed
+    #[serde(default)]
+    pub strict_freshness: bool,
+    /// How many seconds older than the commit an artifact may be before it counts as stale
+    #[serde(default)]
+    pub freshness_tolerance_secs: u64,
+    /// Command run after the artifact checks with every artifact path as an
+    /// argument (and in `PLOOP_ARTIFACTS`); a non-zero exit fails the run
+    #[serde(default)]
+    pub verify_command: Option<String>,
+    /// Run `verify_command` through the shell (see `build.use_shell`)
+    #[serde(default)]
+    pub verify_use_shell: bool,
+    /// Upload artifacts to a remote host over SFTP instead of copying locally
+    #[serde(default)]
+    pub sftp: Option<SftpConfig>,
+    /// Deploy to a canary target and health-check it before promoting to `target_dir`
+    #[serde(default)]
+    pub canary: Option<CanaryConfig>,
+    /// Further `[[deploy.targets]]`, each with its own artifacts and versions
+    #[serde(default)]
+    pub targets: Vec<DeployTarget>,
+    /// `[[deploy.previews]]`: where commits on matching branches deploy instead
+    #[serde(default)]
+    pub previews: Vec<PreviewConfig>,
+}
+
+/// `[[deploy.targets]]`: a named file target deployed alongside the main one
+///
+/// Targets share the `[deploy]` options (versioning, exclusions, ...) but are
+/// versioned and rolled back independently. Canary promotion only applies to
+/// the main target.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct DeployTarget {
+    pub name: String,
+    pub target_dir: String,
+    pub artifacts: Vec<ArtifactSpec>,
+    /// Versions kept for this target; `rollback.keep_versions` when unset
+    #[serde(default)]
+    pub keep_versions: Option<usize>,
+}
+
+/// `[[deploy.previews]]`: a preview deploy for branches matching a pattern
+///
+/// A commit on a matching branch deploys to the preview's `target_dir`
+/// instead of the main one; `[[deploy.targets]]` and the canary are skipped.
+/// The first matching entry wins.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct PreviewConfig {
+    /// Glob patterns matched against the branch name, e.g. `feature/*`
+    pub branches: Vec<String>,
+    /// Target directory, normally containing `{branch}` as a path component
+    pub target_dir: String,
+    /// Deploy command for previews; `deploy.command` never runs for them
+    #[serde(default)]
+    pub command: Option<String>,
+}
+
+impl DeployConfig {
+    /// Directory artifact paths are resolved against: `artifact_base` (joined
+    /// onto `repo_path` when relative), or `repo_path` when unset
+    pub fn artifact_base_dir(&self, repo_path: &str) -> String {
+        match &self.artifact_base {
+            Some(base) => Path::new(repo_path).join(base).to_string_lossy().into_owned(),
+            None => repo_path.to_string(),
+        }
+    }
+
+    /// Whether the active version is tracked in the pointer file rather than
+    /// a 'current' symlink
+    pub fn uses_pointer_file(&self) -> bool {
+        self.pointer_file || !self.create_current_symlink
+    }
+
+    /// Every local target as `(name, target_dir)`: the main `target_dir`
+    /// (named "default") followed by `targets`
+    pub fn file_targets(&self) -> Vec<(&str, &str)> {
+        self.target_dir
+            .as_deref()
+            .map(|target_dir| ("default", target_dir))
+            .into_iter()
+            .chain(self.targets.iter().map(|target| (target.name.as_str(), target.target_dir.as_str())))
+            .collect()
+    }
+
+    /// Directory holding ploop's own records (`history.log`) for `target_dir`
+    ///
+    /// An unversioned target is served as it is, so its records go to a
+    /// sibling `{target_dir}.ploop` rather than into the target itself.
+    pub fn state_dir(&self, target_dir: &str) -> String {
+        if self.versioned {
+            target_dir.to_string()
+        } else {
+            format!("{}.ploop", target_dir.trim_end_matches('/'))
+        }
+    }
+
+    /// Number of versions to keep for the target called `name`: its own
+    /// `keep_versions`, or `default` (`rollback.keep_versions`)
+    pub fn keep_versions_for(&self, name: &str, default: usize) -> usize {
+        self.targets
+            .iter()
+            .find(|target| target.name == name)
+            .and_then(|target| target.keep_versions)
+            .unwrap_or(default)
+    }
+
+    /// The target directory a rollback or status should act on
+    ///
+    /// Without a name this is the main `target_dir`, or the only entry of
+    /// `targets`; with several targets a name is required.
+    pub fn select_target(&self, name: Option<&str>) -> Result<&str, Box<dyn std::error::Error>> {
+        let targets = self.file_targets();
+        match name {
+            Some(name) => targets
+                .iter()
+                .find(|(target, _)| *target == name)
+                .map(|(_, target_dir)| *target_dir)
+                .ok_or_else(|| format!("No deploy target named '{}'", name).into()),
+            None if targets.len() == 1 => Ok(targets[0].1),
+            None if targets.is_empty() => Err("No deploy target configured".into()),
+            None => {
+                let names: Vec<&str> = targets.iter().map(|(name, _)| *name).collect();
+                Err(format!("Several deploy targets configured, pick one of: {}", names.join(", ")).into())
+            }
+        }
+    }
+}
+
+/// `[deploy.canary]`: a target deployed to and checked before the real one
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct CanaryConfig {
+    pub target_dir: String,
+    /// Command that must succeed before promoting; `PLOOP_CANARY_DIR` names the canary version directory
+    pub health_check: String,
+    /// Run the health check through the shell (see `build.use_shell`)
+    #[serde(default)]
+    pub use_shell: bool,
+    /// Seconds to wait after switching the canary before the health check runs
+    #[serde(default)]
+    pub initial_delay_secs: u64,
+}
+
+/// `[deploy.sftp]`: remote target reached over SFTP (requires the `sftp` feature)
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct SftpConfig {
+    pub host: String,
+    #[serde(default = "default_sftp_port")]
+    pub port: u16,
+    pub user: String,
+    /// Remote directory holding the version directories and 'current'
+    pub remote_dir: String,
+    /// Private key for public key auth; tried before `password`
+    #[serde(default)]
+    pub key_path: Option<String>,
+    /// Password auth, ideally given as `${env:...}`; the SSH agent is tried when neither is set or both fail
+    #[serde(default)]
+    pub password: Option<String>,
+    /// Connect and per-operation timeout
+    #[serde(default = "default_sftp_timeout_secs")]
+    pub timeout_secs: u64,
+    /// Write a 'current.txt' pointer instead of a 'current' symlink
+    #[serde(default)]
+    pub pointer_file: bool,
+}
+
+/// Naming scheme for versioned deploy directories
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum VersionScheme {
+    /// `abc1234` (a rebuild of the same commit replaces the directory)
+    #[default]
+    ShortHash,
+    /// The full 40-character commit hash
+    FullHash,
+    /// `abc1234-20240501120000`, never reused
+    HashTimestamp,
+    /// `7-abc1234`, one higher than the largest existing counter
+    Counter,
+}
+
+/// Archive format for `deploy.bundle`
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+pub enum BundleFormat {
+    #[serde(rename = "tar.gz")]
+    TarGz,
+    #[serde(rename = "zip")]
+    Zip,
+}
+
+impl BundleFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            BundleFormat::TarGz => "tar.gz",
+            BundleFormat::Zip => "zip",
+        }
+    }
+}
+
+/// A build artifact: either a plain path/glob or a table with per-artifact options
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(untagged)]
+pub enum ArtifactSpec {
+    Path(String),
+    Detailed(ArtifactEntry),
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct ArtifactEntry {
+    /// Path or glob pattern, relative to the repository
+    pub path: String,
+    /// Don't fail when nothing matches
+    #[serde(default)]
+    pub optional: bool,
+    /// Globs excluded when copying this directory artifact, relative to its root
+    #[serde(default)]
+    pub exclude: Vec<String>,
+    /// Fail verification when the artifact (or directory total) is smaller than this many bytes;
+    /// 0 sets no minimum
+    #[serde(default)]
+    pub min_size: Option<u64>,
+    /// Fail verification unless the file is executable (exec bit on Unix, PE/ELF header elsewhere)
+    #[serde(default)]
+    pub executable: bool,
+    /// File name in the target instead of the source name; may contain
+    /// `{commit}` (short hash), `{date}` (YYYYMMDD) and `{name}` (source file name)
+    #[serde(default)]
+    pub dest: Option<String>,
+}
+
+impl ArtifactSpec {
+    pub fn path(&self) -> &str {
+        match self {
+            ArtifactSpec::Path(path) => path,
+            ArtifactSpec::Detailed(entry) => &entry.path,
+        }
+    }
+
+    pub fn is_optional(&self) -> bool {
+        match self {
+            ArtifactSpec::Path(_) => false,
+            ArtifactSpec::Detailed(entry) => entry.optional,
+        }
+    }
+
+    pub fn exclude(&self) -> &[String] {
+        match self {
+            ArtifactSpec::Path(_) => &[],
+            ArtifactSpec::Detailed(entry) => &entry.exclude,
+        }
+    }
+
+    pub fn min_size(&self) -> Option<u64> {
+        match self {
+            ArtifactSpec::Path(_) => None,
+            ArtifactSpec::Detailed(entry) => entry.min_size,
+        }
+    }
+
+    pub fn must_be_executable(&self) -> bool {
+        match self {
+            ArtifactSpec::Path(_) => false,
+            ArtifactSpec::Detailed(entry) => entry.executable,
+        }
+    }
+
+    pub fn dest(&self) -> Option<&str> {
+        match self {
+            ArtifactSpec::Path(_) => None,
+            ArtifactSpec::Detailed(entry) => entry.dest.as_deref(),
+        }
+    }
+}
+
+impl From<&str> for ArtifactSpec {
+    fn from(path: &str) -> Self {
+        ArtifactSpec::Path(path.to_string())
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct SyncConfig {
     pub enabled: bool,
+    #[serde(default = "default_remote")]
     pub remote: String,
+    /// Branch to push; empty means the watched branch (filled in by `Config::migrate`)
+    #[serde(default)]
     pub branch: String,
+    /// Push submodule commits referenced by the pushed revisions as well
+    #[serde(default)]
+    pub push_submodules: bool,
+    /// Fail the run (after the deploy) when the push or release fails
+    #[serde(default)]
+    pub required: bool,
+    /// With `required`, also switch targets back to their previous version
+    /// when the sync fails (needs `rollback.enabled`)
+    #[serde(default)]
+    pub rollback_on_failure: bool,
+    /// Hosting service to create a release (GitHub) or tag (GitLab) on after
+    /// the push; `None` only pushes
+    #[serde(default)]
+    pub provider: Option<SyncProvider>,
+    /// API token for `provider`; without one no release is created
+    #[serde(default)]
+    pub token: Option<String>,
+    /// `owner/repo` (GitHub) or project path (GitLab)
+    #[serde(default)]
+    pub repository: Option<String>,
+    /// API base URL for self-hosted instances, e.g. `https://gitlab.example.com/api/v4`
+    #[serde(default)]
+    pub api_url: Option<String>,
+}
+
+/// A config without a `[sync]` section never pushes
+impl Default for SyncConfig {
+    fn default() -> Self {
+        SyncConfig {
+            enabled: false,
+            remote: default_remote(),
+            branch: String::new(),
+            push_submodules: false,
+            required: false,
+            rollback_on_failure: false,
+            provider: None,
+            token: None,
+            repository: None,
+            api_url: None,
+        }
+    }
+}
+
+/// Hosting service behind `sync.remote`, for `sync.provider`
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SyncProvider {
+    GitHub,
+    GitLab,
+}
+
+impl SyncProvider {
+    /// API base URL of the public instance
+    pub fn default_api_url(self) -> &'static str {
+        match self {
+            SyncProvider::GitHub => "https://api.github.com",
+            SyncProvider::GitLab => "https://gitlab.com/api/v4",
+        }
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct RollbackConfig {
     pub enabled: bool,
+    #[serde(default = "default_keep_versions")]
     pub keep_versions: usize,
+    /// Pack versions beyond `keep_versions` into `.tar.gz` instead of deleting them
+    #[serde(default)]
+    pub archive_old_versions: bool,
+    /// What to do when there is no earlier version to roll back to
+    #[serde(default)]
+    pub on_no_previous: NoPreviousAction,
+    /// Script run for `on_no_previous = "run_command"`
+    #[serde(default)]
+    pub on_no_previous_command: Option<String>,
+    /// Cap on the total size of a target's version directories; the oldest
+    /// are deleted after `keep_versions` is applied until they fit
+    #[serde(default)]
+    pub max_total_bytes: Option<u64>,
+}
+
+/// `rollback.on_no_previous`: handling of a rollback with nothing to return to,
+/// e.g. after a failed first deploy
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum NoPreviousAction {
+    /// Fail with "No previous version available for rollback"
+    #[default]
+    Error,
+    /// Remove 'current' (and the pointer file), taking the deploy offline
+    RemoveCurrent,
+    /// Run `rollback.on_no_previous_command`
+    RunCommand,
+}
+
+impl Default for RollbackConfig {
+    fn default() -> Self {
+        RollbackConfig {
+            enabled: true,
+            keep_versions: default_keep_versions(),
+            archive_old_versions: false,
+            on_no_previous: NoPreviousAction::Error,
+            on_no_previous_command: None,
+            max_total_bytes: None,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct NotifyConfig {
+    /// Webhook (e.g. Slack incoming webhook) receiving a JSON payload per deploy
+    pub webhook_url: Option<String>,
+    #[serde(default = "default_true")]
+    pub on_success: bool,
+    #[serde(default = "default_true")]
+    pub on_failure: bool,
+    #[serde(default = "default_notify_timeout_secs")]
+    pub timeout_secs: u64,
+    /// Unix socket receiving every pipeline phase event as a JSON line
+    #[serde(default)]
+    pub event_socket: Option<String>,
+    /// Command run last after a successful run, with `PLOOP_*` variables set
+    #[serde(default)]
+    pub success_command: Option<String>,
+    /// Command run last after a failed run, with `PLOOP_*` variables set
+    #[serde(default)]
+    pub failure_command: Option<String>,
+    /// Run the success/failure commands through the shell (see `build.use_shell`)
+    #[serde(default)]
+    pub use_shell: bool,
+}
+
+impl Default for NotifyConfig {
+    fn default() -> Self {
+        NotifyConfig {
+            webhook_url: None,
+            on_success: true,
+            on_failure: true,
+            timeout_secs: default_notify_timeout_secs(),
+            event_socket: None,
+            success_command: None,
+            failure_command: None,
+            use_shell: false,
+        }
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct LogConfig {
+    #[serde(default = "default_log_file")]
     pub file: String,
+    #[serde(default = "default_log_level")]
     pub level: String,
+    /// Trim the log file to its last `max_lines` lines when the logger starts
+    #[serde(default)]
+    pub max_lines: Option<usize>,
+    /// chrono format for line timestamps (default `%Y-%m-%d %H:%M:%S`)
+    #[serde(default)]
+    pub timestamp_format: Option<String>,
+    /// Stamp lines in UTC instead of local time
+    #[serde(default)]
+    pub utc: bool,
+    /// When false, a run's lines below warn are only written if it fails; successful runs log one summary line
+    #[serde(default = "default_true")]
+    pub log_on_success: bool,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        LogConfig {
+            file: default_log_file(),
+            level: default_log_level(),
+            max_lines: None,
+            timestamp_format: None,
+            utc: false,
+            log_on_success: true,
+        }
+    }
+}
+
+impl LogConfig {
+    /// Apply per-invocation overrides (`--log-file`, `--log-level`), which win over the config file
+    pub fn with_overrides(mut self, file: Option<&str>, level: Option<&str>) -> Self {
+        if let Some(file) = file {
+            self.file = file.to_string();
+        }
+        if let Some(level) = level {
+            self.level = level.to_string();
+        }
+        self
+    }
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_precompress_level() -> u32 {
+    9
+}
+
+fn default_copy_parallelism() -> usize {
+    1
+}
+
+fn default_current_link_name() -> String {
+    crate::rollback::DEFAULT_CURRENT_LINK.to_string()
+}
+
+fn default_retry_delay_secs() -> u64 {
+    5
+}
+
+fn default_sftp_port() -> u16 {
+    22
+}
+
+fn default_sftp_timeout_secs() -> u64 {
+    30
+}
+
+fn default_notify_timeout_secs() -> u64 {
+    10
+}
+
+fn default_repo_path() -> String {
+    ".".to_string()
+}
+
+fn default_branch() -> String {
+    "main".to_string()
+}
+
+fn default_remote() -> String {
+    "origin".to_string()
+}
+
+fn default_keep_versions() -> usize {
+    3
+}
+
+fn default_log_file() -> String {
+    "postloop.log".to_string()
+}
+
+fn default_log_level() -> String {
+    "info".to_string()
 }
 
 impl Config {
     /// Load configuration from a TOML file
+    ///
+    /// String values may reference secrets as `${env:NAME}` or `${file:/path}`;
+    /// these are substituted at load time. A top-level `include` list pulls in
+    /// other config files first (see [`load_with_includes`]).
     pub fn load(path: &str) -> Result<Self, Box<dyn std::error::Error>> {
-        let content = fs::read_to_string(path)?;
-        let config: Config = toml::from_str(&content)?;
-        Ok(config)
+        Ok(Self::load_layered(path, &process_env)?.0)
+    }
+
+    /// Load a config along with its merged TOML before references were
+    /// substituted, looking `${env:...}` references up with `env`
+    fn load_layered(path: &str, env: &EnvLookup) -> Result<(Self, toml::Value), Box<dyn std::error::Error>> {
+        let raw = load_with_includes(Path::new(path), &mut Vec::new())?;
+        let mut value = raw.clone();
+        resolve_references(&mut value, env)?;
+        let mut config: Config = value.try_into()?;
+        config.migrate();
+        config.validate()?;
+        Ok((config, raw))
+    }
+
+    /// The effective configuration loaded from `path`, as TOML, for `ploop config show`
+    ///
+    /// The file is loaded like [`Config::load`] (includes, references,
+    /// defaults, migration) and serialized like [`Config::save`]. Values
+    /// that came from `${env:...}` / `${file:...}` references, and values of
+    /// keys naming a password, token, secret or webhook, are shown as `***`.
+    pub fn show_effective(path: &str) -> Result<String, Box<dyn std::error::Error>> {
+        Self::show_effective_with(path, &process_env)
+    }
+
+    fn show_effective_with(path: &str, env: &EnvLookup) -> Result<String, Box<dyn std::error::Error>> {
+        let (config, raw) = Self::load_layered(path, env)?;
+        let mut effective = toml::Value::try_from(&config)?;
+        mask_secrets(&mut effective, Some(&raw));
+        Ok(toml::to_string_pretty(&effective)?)
+    }
+
+    /// Fill in defaults that serde cannot express, so older config files keep working
+    ///
+    /// Sections added after the first release (`sync`, `rollback`, `log`,
+    /// `notify`) may be omitted entirely; their per-field defaults come from
+    /// their `Default` impls. This pass handles values derived from other
+    /// fields or out of range.
+    pub fn migrate(&mut self) {
+        if self.sync.branch.is_empty() {
+            self.sync.branch = self.watch.branch.clone();
+        }
+        if self.deploy.copy_parallelism == 0 {
+            self.deploy.copy_parallelism = default_copy_parallelism();
+        }
+        if self.notify.timeout_secs == 0 {
+            self.notify.timeout_secs = default_notify_timeout_secs();
+        }
+    }
+
+    /// Reject settings that would make a deploy damage the target
+    pub fn validate(&self) -> Result<(), Box<dyn std::error::Error>> {
+        crate::rollback::validate_current_link_name(&self.deploy.current_link_name)?;
+
+        // Targets are picked by name for rollback and status; the main
+        // target_dir is "default"
+        let mut names = std::collections::HashSet::new();
+        for target in &self.deploy.targets {
+            if target.name == "default" {
+                return Err("deploy.targets name \"default\" is reserved for deploy.target_dir".into());
+            }
+            if !names.insert(target.name.as_str()) {
+                return Err(format!("deploy.targets name {:?} is used more than once", target.name).into());
+            }
+        }
+
+        // Counter names need the existing versions, which SFTP cannot list
+        if self.deploy.sftp.is_some() && self.deploy.version_scheme == VersionScheme::Counter {
+            return Err("deploy.version_scheme = \"counter\" is not supported with deploy.sftp".into());
+        }
+        Ok(())
     }
 
     /// Generate default configuration
+    #[allow(clippy::should_implement_trait)]
     pub fn default() -> Self {
         Config {
-            watch: WatchConfig {
-                repo_path: ".".to_string(),
-                branch: "main".to_string(),
-            },
+            watch: WatchConfig::default(),
             build: BuildConfig {
                 command: "cargo build --release".to_string(),
+                working_dir: None,
+                use_shell: false,
+                update_submodules: false,
+                steps: Vec::new(),
             },
             deploy: DeployConfig {
                 command: None,
+                working_dir: None,
+                use_shell: false,
                 target_dir: Some("/opt/deploy".to_string()),
-                artifacts: Some(vec!["target/release/my-app".to_string()]),
+                artifacts: Some(vec![ArtifactSpec::from("target/release/my-app")]),
+                versioned: true,
+                clean_target: true,
+                version_scheme: VersionScheme::ShortHash,
+                exclude: Vec::new(),
+                copy_parallelism: 1,
+                dedup: false,
+                prefer_reflink: true,
+                pointer_file: false,
+                create_current_symlink: true,
+                current_link_name: default_current_link_name(),
+                retries: 0,
+                retry_delay_secs: default_retry_delay_secs(),
+                pre_deploy: None,
+                post_deploy: None,
+                skip_unchanged: true,
+                artifact_base: None,
+                bundle: None,
+                precompress: Vec::new(),
+                precompress_level: default_precompress_level(),
+                incremental: false,
+                disk_margin_mb: 0,
+                strict_freshness: false,
+                freshness_tolerance_secs: 0,
+                verify_command: None,
+                verify_use_shell: false,
+                sftp: None,
+                canary: None,
+                targets: Vec::new(),
+                previews: Vec::new(),
             },
             sync: SyncConfig {
                 enabled: true,
                 remote: "origin".to_string(),
                 branch: "main".to_string(),
+                push_submodules: false,
+                required: false,
+                rollback_on_failure: false,
+                provider: None,
+                token: None,
+                repository: None,
+                api_url: None,
             },
-            rollback: RollbackConfig {
-                enabled: true,
-                keep_versions: 3,
-            },
-            log: LogConfig {
-                file: "postloop.log".to_string(),
-                level: "info".to_string(),
-            },
+            rollback: RollbackConfig::default(),
+            log: LogConfig::default(),
+            notify: NotifyConfig::default(),
         }
     }
 
@@ -101,6 +879,238 @@ impl Config {
     }
 }
 
+/// Read a config file as TOML, merging in the files its top-level
+/// `include = [...]` list names
+///
+/// Included files are merged in order, then the including file is merged on
+/// top, so later includes override earlier ones and the file itself
+/// overrides them all. Tables merge key by key; any other value (arrays
+/// included) is replaced whole. Relative include paths resolve from the
+/// including file's directory. `stack` holds the files being loaded, to
+/// reject include cycles.
+fn load_with_includes(path: &Path, stack: &mut Vec<std::path::PathBuf>) -> Result<toml::Value, Box<dyn std::error::Error>> {
+    let canonical = fs::canonicalize(path).map_err(|e| format!("Cannot read config {}: {}", path.display(), e))?;
+    if stack.contains(&canonical) {
+        let chain: Vec<String> = stack.iter().chain([&canonical]).map(|p| p.display().to_string()).collect();
+        return Err(format!("Config include cycle: {}", chain.join(" -> ")).into());
+    }
+
+    let content = fs::read_to_string(path)?;
+    let mut value: toml::Value = toml::from_str(&content).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let includes = match value.as_table_mut().and_then(|table| table.remove("include")) {
+        None => return Ok(value),
+        Some(toml::Value::Array(includes)) => includes,
+        Some(_) => return Err(format!("{}: include must be a list of paths", path.display()).into()),
+    };
+
+    stack.push(canonical);
+    let base_dir = path.parent().unwrap_or(Path::new("."));
+    let mut merged = toml::Value::Table(toml::map::Map::new());
+    for include in includes {
+        let include = include
+            .as_str()
+            .ok_or_else(|| format!("{}: include must be a list of paths", path.display()))?;
+        merge_values(&mut merged, load_with_includes(&base_dir.join(include), stack)?);
+    }
+    stack.pop();
+
+    merge_values(&mut merged, value);
+    Ok(merged)
+}
+
+/// Merge `overlay` into `base`: tables recursively, everything else replaced
+fn merge_values(base: &mut toml::Value, overlay: toml::Value) {
+    match (base, overlay) {
+        (toml::Value::Table(base), toml::Value::Table(overlay)) => {
+            for (key, value) in overlay {
+                match base.get_mut(&key) {
+                    Some(existing) => merge_values(existing, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, overlay) => *base = overlay,
+    }
+}
+
+const MASK: &str = "***";
+
+fn is_secret_key(key: &str) -> bool {
+    let key = key.to_lowercase();
+    ["password", "token", "secret", "webhook"].iter().any(|word| key.contains(word))
+}
+
+/// Replace secrets in `value` with [`MASK`]: strings under secret-looking keys,
+/// and strings whose counterpart in `raw` (the file before substitution) was a reference
+fn mask_secrets(value: &mut toml::Value, raw: Option<&toml::Value>) {
+    match value {
+        toml::Value::String(text)
+            if raw
+                .and_then(|raw| raw.as_str())
+                .is_some_and(|raw| raw.contains("${env:") || raw.contains("${file:")) =>
+        {
+            *text = MASK.to_string();
+        }
+        toml::Value::Array(items) => {
+            for (index, item) in items.iter_mut().enumerate() {
+                mask_secrets(item, raw.and_then(|raw| raw.get(index)));
+            }
+        }
+        toml::Value::Table(table) => {
+            for (key, item) in table.iter_mut() {
+                if is_secret_key(key) && item.is_str() {
+                    *item = toml::Value::String(MASK.to_string());
+                } else {
+                    mask_secrets(item, raw.and_then(|raw| raw.get(key.as_str())));
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Where `${env:NAME}` references are looked up; the process environment
+/// outside tests, which must not modify it while other threads read it
+type EnvLookup = dyn Fn(&str) -> Option<String>;
+
+fn process_env(name: &str) -> Option<String> {
+    std::env::var(name).ok()
+}
+
+/// Substitute `${env:...}` / `${file:...}` references in every string value
+fn resolve_references(value: &mut toml::Value, env: &EnvLookup) -> Result<(), Box<dyn std::error::Error>> {
+    match value {
+        toml::Value::String(text) => *text = resolve_string_with(text, env)?,
+        toml::Value::Array(items) => {
+            for item in items {
+                resolve_references(item, env)?;
+            }
+        }
+        toml::Value::Table(table) => {
+            for (_, item) in table.iter_mut() {
+                resolve_references(item, env)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Substitute `${env:NAME}` and `${file:/path}` references in a string
+pub fn resolve_string(text: &str) -> Result<String, Box<dyn std::error::Error>> {
+    resolve_string_with(text, &process_env)
+}
+
+fn resolve_string_with(text: &str, env: &EnvLookup) -> Result<String, Box<dyn std::error::Error>> {
+    let mut resolved = String::new();
+    let mut rest = text;
+
+    while let Some(start) = rest.find("${") {
+        let Some(len) = rest[start..].find('}') else {
+            break;
+        };
+        let reference = &rest[start + 2..start + len];
+
+        let replacement = if let Some(name) = reference.strip_prefix("env:") {
+            env(name)
+                .ok_or_else(|| format!("Config references unset environment variable: {}", name))?
+        } else if let Some(file) = reference.strip_prefix("file:") {
+            fs::read_to_string(file)
+                .map_err(|e| format!("Config references unreadable secret file {}: {}", file, e))?
+                .trim_end_matches(['\r', '\n'])
+                .to_string()
+        } else {
+            // Not a reference we handle; keep it verbatim
+            rest[start..start + len + 1].to_string()
+        };
+
+        resolved.push_str(&rest[..start]);
+        resolved.push_str(&replacement);
+        rest = &rest[start + len + 1..];
+    }
+
+    resolved.push_str(rest);
+    Ok(resolved)
+}
+
+/// Fluent construction of a [`Config`], starting from [`Config::default`]
+///
+/// ```
+/// use intentloop::config::{ArtifactSpec, Config};
+///
+/// let config = Config::builder()
+///     .repo_path("/srv/app")
+///     .build_command("make release")
+///     .target_dir("/opt/app")
+///     .artifacts(vec![ArtifactSpec::from("build/app")])
+///     .sync(false, "origin", "main")
+///     .build();
+///
+/// assert_eq!(config.watch.repo_path, "/srv/app");
+/// assert_eq!(config.deploy.target_dir.as_deref(), Some("/opt/app"));
+/// assert!(!config.sync.enabled);
+/// ```
+#[derive(Debug, Clone)]
+pub struct ConfigBuilder {
+    config: Config,
+}
+
+impl Config {
+    /// Start a [`ConfigBuilder`] from the default configuration
+    ///
+    /// ```
+    /// let config = intentloop::config::Config::builder().build_command("npm run build").build();
+    /// assert_eq!(config.build.command, "npm run build");
+    /// assert_eq!(config.rollback.keep_versions, 3);
+    /// ```
+    pub fn builder() -> ConfigBuilder {
+        ConfigBuilder {
+            config: Config::default(),
+        }
+    }
+}
+
+impl ConfigBuilder {
+    pub fn repo_path(mut self, repo_path: &str) -> Self {
+        self.config.watch.repo_path = repo_path.to_string();
+        self
+    }
+
+    pub fn build_command(mut self, command: &str) -> Self {
+        self.config.build.command = command.to_string();
+        self
+    }
+
+    /// Deploy with a command instead of copying files
+    pub fn deploy_command(mut self, command: &str) -> Self {
+        self.config.deploy.command = Some(command.to_string());
+        self
+    }
+
+    pub fn target_dir(mut self, target_dir: &str) -> Self {
+        self.config.deploy.target_dir = Some(target_dir.to_string());
+        self
+    }
+
+    pub fn artifacts(mut self, artifacts: Vec<ArtifactSpec>) -> Self {
+        self.config.deploy.artifacts = Some(artifacts);
+        self
+    }
+
+    pub fn sync(mut self, enabled: bool, remote: &str, branch: &str) -> Self {
+        self.config.sync.enabled = enabled;
+        self.config.sync.remote = remote.to_string();
+        self.config.sync.branch = branch.to_string();
+        self
+    }
+
+    pub fn build(self) -> Config {
+        self.config
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -113,4 +1123,230 @@ mod tests {
         assert!(config.sync.enabled);
         assert_eq!(config.rollback.keep_versions, 3);
     }
+
+    #[test]
+    fn test_artifact_spec_forms() {
+        let deploy: DeployConfig = toml::from_str(
+            r#"
+            artifacts = ["target/release/app", { path = "target/release/*.so", optional = true }]
+            "#,
+        )
+        .unwrap();
+        let artifacts = deploy.artifacts.unwrap();
+        assert_eq!(artifacts[0].path(), "target/release/app");
+        assert!(!artifacts[0].is_optional());
+        assert_eq!(artifacts[1].path(), "target/release/*.so");
+        assert!(artifacts[1].is_optional());
+    }
+
+    /// An environment holding just `name=value`
+    fn env_with(name: &'static str, value: &'static str) -> impl Fn(&str) -> Option<String> {
+        move |key| (key == name).then(|| value.to_string())
+    }
+
+    #[test]
+    fn test_resolve_env_and_file_references() {
+        let env = env_with("POSTLOOP_TEST_TOKEN", "s3cret");
+        assert_eq!(resolve_string_with("Bearer ${env:POSTLOOP_TEST_TOKEN}", &env).unwrap(), "Bearer s3cret");
+
+        let secret = std::env::temp_dir().join(format!("postloop-secret-{}", uuid::Uuid::new_v4()));
+        fs::write(&secret, "from-file\n").unwrap();
+        let reference = format!("${{file:{}}}", secret.display());
+        assert_eq!(resolve_string(&reference).unwrap(), "from-file");
+        fs::remove_file(&secret).unwrap();
+
+        assert_eq!(resolve_string("${HOME} stays").unwrap(), "${HOME} stays");
+    }
+
+    #[test]
+    fn test_unresolved_references_error() {
+        let err = resolve_string("${env:POSTLOOP_TEST_MISSING_VAR}").unwrap_err();
+        assert!(err.to_string().contains("POSTLOOP_TEST_MISSING_VAR"));
+
+        let err = resolve_string("${file:/nonexistent/postloop/secret}").unwrap_err();
+        assert!(err.to_string().contains("/nonexistent/postloop/secret"));
+    }
+
+    #[test]
+    fn test_load_resolves_references() {
+        let env = env_with("POSTLOOP_TEST_DEPLOY_CMD", "systemctl restart app");
+        let mut config = Config::default();
+        config.deploy.command = Some("${env:POSTLOOP_TEST_DEPLOY_CMD}".to_string());
+        let path = std::env::temp_dir().join(format!("postloop-config-{}.toml", uuid::Uuid::new_v4()));
+        config.save(path.to_str().unwrap()).unwrap();
+
+        let loaded = Config::load_layered(path.to_str().unwrap(), &env).unwrap().0;
+        assert_eq!(loaded.deploy.command.as_deref(), Some("systemctl restart app"));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_include_merge_precedence() {
+        let dir = std::env::temp_dir().join(format!("postloop-include-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(dir.join("shared")).unwrap();
+        fs::write(
+            dir.join("shared/deploy.base.toml"),
+            "include = [\"sync.toml\"]\n\
+             [build]\ncommand = \"make release\"\nuse_shell = true\n\
+             [deploy]\ntarget_dir = \"/opt/base\"\nartifacts = [\"base-app\"]\n",
+        )
+        .unwrap();
+        fs::write(dir.join("shared/sync.toml"), "[sync]\nenabled = true\nremote = \"upstream\"\nbranch = \"main\"\n").unwrap();
+        fs::write(dir.join("extra.toml"), "[sync]\nremote = \"mirror\"\n").unwrap();
+        fs::write(
+            dir.join("deploy.toml"),
+            "include = [\"shared/deploy.base.toml\", \"extra.toml\"]\n\
+             [build]\ncommand = \"cargo build --release\"\n\
+             [deploy]\nartifacts = [\"target/release/app\"]\n",
+        )
+        .unwrap();
+
+        let config = Config::load(dir.join("deploy.toml").to_str().unwrap()).unwrap();
+        // The including file wins, keys it does not set come from the includes
+        assert_eq!(config.build.command, "cargo build --release");
+        assert!(config.build.use_shell);
+        assert_eq!(config.deploy.target_dir.as_deref(), Some("/opt/base"));
+        assert_eq!(config.deploy.artifacts.as_ref().unwrap().len(), 1);
+        assert_eq!(config.deploy.artifacts.as_ref().unwrap()[0].path(), "target/release/app");
+        // Later includes override earlier ones (and their nested includes)
+        assert!(config.sync.enabled);
+        assert_eq!(config.sync.remote, "mirror");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_include_cycle_is_rejected() {
+        let dir = std::env::temp_dir().join(format!("postloop-include-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("a.toml"), "include = [\"b.toml\"]\n[build]\ncommand = \"make\"\n").unwrap();
+        fs::write(dir.join("b.toml"), "include = [\"./a.toml\"]\n").unwrap();
+
+        let err = Config::load(dir.join("a.toml").to_str().unwrap()).unwrap_err().to_string();
+        assert!(err.contains("include cycle"), "{}", err);
+        assert!(err.contains("b.toml"), "{}", err);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_show_effective_masks_secrets() {
+        let env = env_with("POSTLOOP_TEST_SHOW_TOKEN", "hunter2-token");
+        let dir = std::env::temp_dir().join(format!("postloop-show-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("base.toml"), "[notify]\nwebhook_url = \"https://hooks.example.com/T000/B000/abcdef\"\n").unwrap();
+        fs::write(
+            dir.join("deploy.toml"),
+            "include = [\"base.toml\"]\n\
+             [build]\ncommand = \"cargo build --release\"\n\
+             [deploy]\ncommand = \"./deploy.sh --token ${env:POSTLOOP_TEST_SHOW_TOKEN}\"\n\
+             [deploy.sftp]\nhost = \"example.com\"\nuser = \"deploy\"\nremote_dir = \"/srv\"\npassword = \"plain-password\"\n",
+        )
+        .unwrap();
+
+        let shown = Config::show_effective_with(dir.join("deploy.toml").to_str().unwrap(), &env).unwrap();
+        for secret in ["hunter2-token", "abcdef", "plain-password"] {
+            assert!(!shown.contains(secret), "{} leaked:\n{}", secret, shown);
+        }
+        let effective: toml::Value = toml::from_str(&shown).unwrap();
+        assert_eq!(effective["deploy"]["command"].as_str(), Some("***"));
+        assert_eq!(effective["deploy"]["sftp"]["password"].as_str(), Some("***"));
+        assert_eq!(effective["notify"]["webhook_url"].as_str(), Some("***"));
+        // Everything else is shown resolved, defaults included
+        assert_eq!(effective["build"]["command"].as_str(), Some("cargo build --release"));
+        assert_eq!(effective["deploy"]["sftp"]["port"].as_integer(), Some(22));
+        assert_eq!(effective["rollback"]["keep_versions"].as_integer(), Some(3));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_log_overrides_take_precedence() {
+        let log = LogConfig {
+            level: "warn".to_string(),
+            ..LogConfig::default()
+        };
+
+        let overridden = log.clone().with_overrides(Some("/var/log/ci/ploop.log"), Some("debug"));
+        assert_eq!(overridden.file, "/var/log/ci/ploop.log");
+        assert_eq!(overridden.level, "debug");
+
+        let untouched = log.with_overrides(None, None);
+        assert_eq!((untouched.file.as_str(), untouched.level.as_str()), ("postloop.log", "warn"));
+    }
+
+    #[test]
+    fn test_load_minimal_legacy_config() {
+        let path = std::env::temp_dir().join(format!("postloop-legacy-{}.toml", uuid::Uuid::new_v4()));
+        fs::write(
+            &path,
+            r#"
+            [watch]
+            repo_path = "."
+            branch = "release"
+            git_extra_args = ["-c", "safe.directory=*"]
+
+            [build]
+            command = "make"
+
+            [deploy]
+            command = "./deploy.sh"
+            copy_parallelism = 0
+            "#,
+        )
+        .unwrap();
+
+        let config = Config::load(path.to_str().unwrap()).unwrap();
+        assert!(!config.sync.enabled);
+        assert_eq!(config.sync.remote, "origin");
+        assert_eq!(config.sync.branch, "release");
+        assert!(config.rollback.enabled);
+        assert_eq!(config.rollback.keep_versions, 3);
+        assert_eq!(config.log.file, "postloop.log");
+        assert_eq!(config.deploy.copy_parallelism, 1);
+        assert!(config.deploy.versioned);
+        assert_eq!(config.notify.timeout_secs, 10);
+        assert_eq!(config.watch.git.git_path, None);
+        assert_eq!(config.watch.git.git_extra_args, vec!["-c", "safe.directory=*"]);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_validate_rejects_ambiguous_target_names() {
+        let target = |name: &str| DeployTarget {
+            name: name.to_string(),
+            target_dir: format!("/opt/{}", name),
+            artifacts: Vec::new(),
+            keep_versions: None,
+        };
+        let mut config = Config::default();
+        config.deploy.targets = vec![target("docs"), target("api")];
+        assert!(config.validate().is_ok());
+
+        config.deploy.targets.push(target("docs"));
+        let err = config.validate().unwrap_err().to_string();
+        assert!(err.contains("\"docs\" is used more than once"), "{}", err);
+
+        config.deploy.targets = vec![target("default")];
+        let err = config.validate().unwrap_err().to_string();
+        assert!(err.contains("reserved"), "{}", err);
+    }
+
+    #[test]
+    fn test_validate_rejects_counter_versions_over_sftp() {
+        let mut config = Config::default();
+        config.deploy.sftp = Some(SftpConfig {
+            host: "legacy.example.com".to_string(),
+            port: 22,
+            user: "deploy".to_string(),
+            remote_dir: "/home/deploy/app".to_string(),
+            key_path: None,
+            password: None,
+            timeout_secs: 30,
+            pointer_file: false,
+        });
+        config.deploy.version_scheme = VersionScheme::HashTimestamp;
+        assert!(config.validate().is_ok());
+
+        config.deploy.version_scheme = VersionScheme::Counter;
+        let err = config.validate().unwrap_err().to_string();
+        assert!(err.contains("\"counter\""), "{}", err);
+    }
 }
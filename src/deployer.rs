@@ -1,132 +1,2845 @@
+use crate::builder;
+use crate::config::{
+    ArtifactSpec, BundleFormat, CanaryConfig, Config, DeployConfig, GitConfig, PreviewConfig, VersionScheme,
+};
+use crate::hook;
+use crate::rollback::{self, VersionMeta};
+use crate::runner;
+use std::collections::{BTreeMap, HashSet};
 use std::fs;
 use std::path::{Path, PathBuf};
-use std::process::Command;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+
+/// Largest deploy command output kept for the history record
+pub const MAX_CAPTURED_OUTPUT: usize = 64 * 1024;
+
+/// Files at least this large are copied in chunks with progress reporting
+const PROGRESS_THRESHOLD: u64 = 64 * 1024 * 1024;
+
+const COPY_CHUNK_SIZE: usize = 1024 * 1024;
 
 /// Deploy using a custom command (process deployment)
-pub fn deploy_with_command(command: &str, repo_path: &str) -> Result<(), Box<dyn std::error::Error>> {
+///
+/// Returns the command's combined stdout and stderr, truncated to
+/// [`MAX_CAPTURED_OUTPUT`] bytes. A failed command's output is kept in its
+/// [`CommandFailed`] error.
+pub fn deploy_with_command(command: &str, repo_path: &str, use_shell: bool) -> Result<String, Box<dyn std::error::Error>> {
     log::info!("Starting deployment with command: {}", command);
 
-    // Parse command into parts
-    let parts: Vec<&str> = command.split_whitespace().collect();
-    if parts.is_empty() {
-        return Err("Deploy command is empty".into());
-    }
-
-    let program = parts[0];
-    let args = &parts[1..];
+    let mut process = runner::shell_command(command, use_shell).ok_or("Deploy command is empty")?;
 
     // Execute deploy command
-    let output = Command::new(program)
-        .args(args)
-        .current_dir(repo_path)
-        .output()?;
+    log::debug!("Running {:?} in {}", process, repo_path);
+    let output = runner::run_tracked(process.current_dir(repo_path))?;
+    log::trace!(
+        "Deploy command exited with {}\nstdout:\n{}\nstderr:\n{}",
+        output.status,
+        String::from_utf8_lossy(&output.stdout),
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let captured = truncate_output(&format!("{}{}", stdout, stderr), MAX_CAPTURED_OUTPUT);
 
     // Check if deployment succeeded
     if !output.status.success() {
-        let stderr = String::from_utf8_lossy(&output.stderr);
         log::error!("Deployment failed: {}", stderr);
-        return Err(format!("Deployment failed: {}", stderr).into());
+        return Err(Box::new(CommandFailed {
+            message: format!("Deployment failed: {}", stderr),
+            output: captured,
+        }));
     }
 
-    let stdout = String::from_utf8_lossy(&output.stdout);
     log::info!("Deployment succeeded: {}", stdout);
 
-    Ok(())
+    Ok(captured)
+}
+
+/// A deploy command that exited unsuccessfully, with its captured output
+#[derive(Debug)]
+pub struct CommandFailed {
+    message: String,
+    /// Combined stdout and stderr, truncated like a successful command's
+    pub output: String,
+}
+
+impl std::fmt::Display for CommandFailed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for CommandFailed {}
+
+/// Output of the failed deploy command in an error's source chain, if any
+pub fn failed_command_output(error: &(dyn std::error::Error + 'static)) -> Option<String> {
+    let mut current = Some(error);
+    while let Some(error) = current {
+        if let Some(failed) = error.downcast_ref::<CommandFailed>() {
+            return Some(failed.output.clone());
+        }
+        current = error.source();
+    }
+    None
+}
+
+/// Keep the last `max_bytes` of `output`, prefixed with a marker when anything was cut
+pub fn truncate_output(output: &str, max_bytes: usize) -> String {
+    if output.len() <= max_bytes {
+        return output.to_string();
+    }
+
+    let mut start = output.len() - max_bytes;
+    while !output.is_char_boundary(start) {
+        start += 1;
+    }
+    format!("[... {} bytes truncated ...]\n{}", start, &output[start..])
 }
 
 /// Deploy by copying artifacts to target directory (file deployment)
 pub fn deploy_with_files(
-    artifacts: &[String],
+    artifacts: &[ArtifactSpec],
     target_dir: &str,
     repo_path: &str,
+    git: &GitConfig,
     commit_hash: &str,
+    options: &DeployConfig,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    log::info!("Starting file deployment to: {}", target_dir);
+    let artifacts = render_dest_templates(artifacts, commit_hash);
+    let versioned_dir = stage_version(&artifacts, target_dir, repo_path, git, commit_hash, options)?;
+    promote_version(target_dir, &versioned_dir, options)
+}
 
-    // Create versioned target directory
-    let versioned_dir = format!("{}/{}", target_dir, commit_hash);
-    fs::create_dir_all(&versioned_dir)?;
+/// Fill `{commit}` (short hash) and `{date}` (YYYYMMDD) into artifact `dest`
+/// templates; `{name}` is filled per matched file when copying
+pub fn render_dest_templates(artifacts: &[ArtifactSpec], commit_hash: &str) -> Vec<ArtifactSpec> {
+    let short_hash: String = commit_hash.chars().take(7).collect();
+    let date = chrono::Local::now().format("%Y%m%d").to_string();
+    artifacts
+        .iter()
+        .map(|artifact| match artifact {
+            ArtifactSpec::Detailed(entry) if entry.dest.is_some() => {
+                let mut entry = entry.clone();
+                entry.dest = entry
+                    .dest
+                    .map(|dest| dest.replace("{commit}", &short_hash).replace("{date}", &date));
+                ArtifactSpec::Detailed(entry)
+            }
+            _ => artifact.clone(),
+        })
+        .collect()
+}
 
-    // Copy artifacts to versioned directory
+/// Fail when two artifacts would land on the same name in the target, or a
+/// `dest` is not a plain file name
+fn check_dest_names(artifacts: &[builder::ResolvedArtifact]) -> Result<(), Box<dyn std::error::Error>> {
+    let mut seen: BTreeMap<std::ffi::OsString, &Path> = BTreeMap::new();
     for artifact in artifacts {
-        let mut src_path = PathBuf::from(repo_path);
-        src_path.push(artifact);
+        let name = artifact.dest_name()?;
+        let mut components = Path::new(&name).components();
+        let plain = matches!(
+            (components.next(), components.next()),
+            (Some(std::path::Component::Normal(_)), None)
+        );
+        if !plain {
+            return Err(format!("Artifact destination {:?} for {:?} must be a plain file name", name, artifact.path).into());
+        }
+        if let Some(other) = seen.insert(name.clone(), &artifact.path) {
+            return Err(format!("Artifacts {:?} and {:?} would both be deployed as {:?}", other, artifact.path, name).into());
+        }
+    }
+    Ok(())
+}
+
+/// Copy artifacts into a new `{target_dir}/{version}` directory without
+/// touching 'current', returning the version directory
+pub fn stage_version(
+    artifacts: &[ArtifactSpec],
+    target_dir: &str,
+    repo_path: &str,
+    git: &GitConfig,
+    version: &str,
+    options: &DeployConfig,
+) -> Result<PathBuf, Box<dyn std::error::Error>> {
+    log::info!("Starting file deployment to: {}", target_dir);
+    let started = std::time::Instant::now();
+
+    let resolved = builder::resolve_artifacts(artifacts, &options.artifact_base_dir(repo_path))?;
+    copy_into_version(&resolved, target_dir, Some((repo_path, git)), version, options, |staging_dir| {
+        write_version_metadata(staging_dir, target_dir, &options.current_link_name, version, repo_path, git, started.elapsed())
+    })
+}
 
-        if !src_path.exists() {
-            return Err(format!("Artifact not found: {}", artifact).into());
+/// Stage a copy of a version already staged elsewhere (e.g. on the canary
+/// target) into `{target_dir}/{version}`, reusing its files instead of rebuilding
+pub fn restage_version(
+    staged_dir: &Path,
+    target_dir: &str,
+    version: &str,
+    options: &DeployConfig,
+) -> Result<PathBuf, Box<dyn std::error::Error>> {
+    log::info!("Promoting staged version {:?} to: {}", staged_dir, target_dir);
+
+    let spec = ArtifactSpec::from(staged_dir.to_str().ok_or("Invalid staged version path")?);
+    let mut entries = fs::read_dir(staged_dir)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<Vec<_>, _>>()?;
+    entries.sort();
+    let resolved: Vec<_> = entries
+        .into_iter()
+        .map(|path| builder::ResolvedArtifact { path, spec: &spec })
+        .collect();
+
+    // Metadata travels along as one of the staged files; only the sequence is per target
+    copy_into_version(&resolved, target_dir, None, version, options, |staging_dir| {
+        match rollback::read_metadata(staging_dir) {
+            Some(mut meta) => {
+                meta.sequence = Some(next_sequence(target_dir, &options.current_link_name, version)?);
+                rollback::write_version_meta(staging_dir, &meta)
+            }
+            None => Ok(()),
         }
+    })
+}
+
+/// Switch 'current' (or the pointer file) to a staged version directory
+pub fn promote_version(
+    target_dir: &str,
+    versioned_dir: &Path,
+    options: &DeployConfig,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let versioned_dir = versioned_dir.to_str().ok_or("Invalid version path")?;
+    if options.uses_pointer_file() {
+        rollback::write_current_pointer(target_dir, &options.current_link_name, versioned_dir)?;
+        log::info!("Updated {} to: {}", rollback::POINTER_FILE, versioned_dir);
+    } else {
+        rollback::switch_current(target_dir, &options.current_link_name, versioned_dir)?;
+        log::info!("Updated 'current' symlink to: {}", versioned_dir);
+    }
+    rollback::clear_redo(target_dir)?;
+
+    Ok(())
+}
+
+/// Deploy to the canary target, check its health, and only then promote the
+/// same files to the real target; a failing canary leaves the real target untouched
+pub fn deploy_with_canary(
+    artifacts: &[ArtifactSpec],
+    canary: &CanaryConfig,
+    target_dir: &str,
+    repo_path: &str,
+    git: &GitConfig,
+    version: &str,
+    options: &DeployConfig,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let staged = stage_version(artifacts, &canary.target_dir, repo_path, git, version, options)?;
+    promote_version(&canary.target_dir, &staged, options)?;
+    run_health_check(canary, &staged, repo_path)?;
 
-        let file_name = src_path.file_name().ok_or("Invalid artifact path")?;
-        let mut dest_path = PathBuf::from(&versioned_dir);
-        dest_path.push(file_name);
+    let versioned_dir = restage_version(&staged, target_dir, version, options)?;
+    promote_version(target_dir, &versioned_dir, options)
+}
 
-        fs::copy(&src_path, &dest_path)?;
-        log::info!("Copied artifact: {} -> {:?}", artifact, dest_path);
+/// Run the canary health check, with `PLOOP_CANARY_DIR` set to the canary version directory
+fn run_health_check(canary: &CanaryConfig, version_dir: &Path, repo_path: &str) -> Result<(), Box<dyn std::error::Error>> {
+    if canary.initial_delay_secs > 0 {
+        log::info!("Waiting {}s before the canary health check", canary.initial_delay_secs);
+        thread::sleep(std::time::Duration::from_secs(canary.initial_delay_secs));
     }
 
-    // Create or update 'current' symlink to point to the latest version
-    let current_link = format!("{}/current", target_dir);
-    
-    // Remove existing symlink if it exists
-    if Path::new(&current_link).exists() {
-        #[cfg(unix)]
-        fs::remove_file(&current_link)?;
-        #[cfg(windows)]
-        {
-            if Path::new(&current_link).is_dir() {
-                fs::remove_dir(&current_link)?;
-            } else {
-                fs::remove_file(&current_link)?;
+    log::info!("Running canary health check: {}", canary.health_check);
+
+    let version_dir = version_dir.to_str().ok_or("Invalid version path")?;
+    let output = runner::run_command_line(
+        &canary.health_check,
+        canary.use_shell,
+        repo_path,
+        &[("PLOOP_CANARY_DIR", version_dir)],
+    )?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        log::error!("Canary health check failed: {}", stderr);
+        return Err(format!("Canary health check failed ({}): {}", output.status, stderr.trim()).into());
+    }
+
+    log::info!("Canary health check passed");
+    Ok(())
+}
+
+/// Copy resolved artifacts into a new version directory under `target_dir`
+///
+/// The version is built in a hidden `.{version}.staging` directory and only
+/// renamed into place once every artifact is copied and `finish` (which
+/// writes the metadata) has succeeded. On any failure, including an
+/// interrupt, the staging directory is removed, so 'current', rollback and
+/// cleanup never see a partial version. `repo` is the repository the
+/// artifacts come from and its git settings, used by incremental copies.
+fn copy_into_version(
+    resolved: &[builder::ResolvedArtifact],
+    target_dir: &str,
+    repo: Option<(&str, &GitConfig)>,
+    version: &str,
+    options: &DeployConfig,
+    finish: impl FnOnce(&Path) -> Result<(), Box<dyn std::error::Error>>,
+) -> Result<PathBuf, Box<dyn std::error::Error>> {
+    check_dest_names(resolved)?;
+    let versioned_dir = Path::new(target_dir).join(version);
+
+    // The version 'current' points to before this deploy, for dedup and incremental copies
+    let previous_dir = rollback::current_version(target_dir, &options.current_link_name)
+        .map(|version| Path::new(target_dir).join(version))
+        .filter(|dir| dir.is_dir() && *dir != versioned_dir);
+    let previous = match previous_dir.as_deref() {
+        Some(dir) => {
+            let mut unchanged = match repo {
+                Some((repo_path, git)) if options.incremental => unchanged_artifacts(resolved, repo_path, git, dir),
+                _ => HashSet::new(),
+            };
+            if options.dedup {
+                unchanged = identical_artifacts(resolved, dir, unchanged)?;
             }
+            Some(PreviousVersion { dir, unchanged })
         }
+        None => None,
+    };
+    ensure_disk_space(resolved, Path::new(target_dir), previous.as_ref(), options, |path| fs2::available_space(path))?;
+
+    let staging_dir = Path::new(target_dir).join(format!(".{}.staging", version));
+    if staging_dir.exists() {
+        // Left behind by a run that was killed mid-copy
+        fs::remove_dir_all(&staging_dir)?;
     }
+    fs::create_dir_all(&staging_dir)?;
 
-    // Create new symlink
-    #[cfg(unix)]
-    std::os::unix::fs::symlink(&versioned_dir, &current_link)?;
-    
-    #[cfg(windows)]
-    {
-        if Path::new(&versioned_dir).is_dir() {
-            std::os::windows::fs::symlink_dir(&versioned_dir, &current_link)?;
-        } else {
-            std::os::windows::fs::symlink_file(&versioned_dir, &current_link)?;
+    // Copy artifacts to the staging directory. A restaged version already
+    // holds the bundle, so only fresh artifacts from the repo are packed.
+    let staged = match (options.bundle, repo) {
+        (Some(format), Some(_)) => bundle_artifacts(resolved, &staging_dir, version, format, options).map(|_| ()),
+        _ => copy_artifacts(resolved, &staging_dir, previous.as_ref(), options)
+            .and_then(|()| precompress_files(&staging_dir, &staging_dir, options)),
+    }
+    .and_then(|()| {
+        // Never publish a version whose copy was interrupted
+        if runner::is_aborted() {
+            return Err(runner::abort_error());
+        }
+        finish(&staging_dir)
+    })
+    .and_then(|()| publish_staged_version(&staging_dir, &versioned_dir));
+
+    if let Err(e) = staged {
+        if staging_dir.exists() {
+            fs::remove_dir_all(&staging_dir)?;
+        }
+        return Err(e);
+    }
+
+    Ok(versioned_dir)
+}
+
+/// Extensions of already compressed formats, which `precompress` skips
+const COMPRESSED_EXTENSIONS: &[&str] = &[
+    "gz", "br", "zst", "xz", "bz2", "zip", "7z", "png", "jpg", "jpeg", "gif", "webp", "avif", "woff", "woff2",
+];
+
+/// Write a gzip `{name}.gz` next to every file under `dir` matching
+/// `deploy.precompress` (by path relative to the version directory `root`,
+/// or by file name)
+///
+/// A `.gz` the artifacts already ship is kept as it is.
+fn precompress_files(root: &Path, dir: &Path, options: &DeployConfig) -> Result<(), Box<dyn std::error::Error>> {
+    if options.precompress.is_empty() {
+        return Ok(());
+    }
+    let patterns = options
+        .precompress
+        .iter()
+        .map(|pattern| glob::Pattern::new(pattern))
+        .collect::<Result<Vec<_>, _>>()?;
+    let level = flate2::Compression::new(options.precompress_level.min(9));
+    precompress_dir(root, dir, &patterns, level)
+}
+
+fn precompress_dir(
+    root: &Path,
+    dir: &Path,
+    patterns: &[glob::Pattern],
+    level: flate2::Compression,
+) -> Result<(), Box<dyn std::error::Error>> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let file_type = fs::symlink_metadata(&path)?.file_type();
+        if file_type.is_dir() {
+            precompress_dir(root, &path, patterns, level)?;
+            continue;
+        }
+        let compressed = path
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .is_some_and(|extension| COMPRESSED_EXTENSIONS.contains(&extension.to_ascii_lowercase().as_str()));
+        let file_name = path.file_name().unwrap_or_default();
+        if !file_type.is_file() || compressed || !matches_any(patterns, path.strip_prefix(root)?, file_name) {
+            continue;
+        }
+
+        let mut gz_name = file_name.to_os_string();
+        gz_name.push(".gz");
+        let gz_path = path.with_file_name(gz_name);
+        if gz_path.exists() {
+            log::debug!("Keeping the shipped {:?}", gz_path);
+            continue;
         }
+        let mut encoder = flate2::write::GzEncoder::new(fs::File::create(&gz_path)?, level);
+        std::io::copy(&mut fs::File::open(&path)?, &mut encoder)?;
+        encoder.finish()?;
+        log::debug!("Precompressed {:?}", path);
     }
+    Ok(())
+}
 
-    log::info!("Updated 'current' symlink to: {}", versioned_dir);
+/// Rename a finished staging directory to its version name
+///
+/// A directory already holding that version (a redeploy of the same commit)
+/// is moved aside first and only removed once the new one is in place.
+fn publish_staged_version(staging_dir: &Path, versioned_dir: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    if !versioned_dir.exists() {
+        fs::rename(staging_dir, versioned_dir)?;
+        return Ok(());
+    }
 
+    let name = versioned_dir.file_name().and_then(|name| name.to_str()).ok_or("Invalid version path")?;
+    let replaced = versioned_dir.with_file_name(format!(".{}.replaced", name));
+    if replaced.exists() {
+        fs::remove_dir_all(&replaced)?;
+    }
+    fs::rename(versioned_dir, &replaced)?;
+    if let Err(e) = fs::rename(staging_dir, versioned_dir) {
+        fs::rename(&replaced, versioned_dir)?;
+        return Err(e.into());
+    }
+    fs::remove_dir_all(&replaced)?;
     Ok(())
 }
 
-/// Deploy artifacts (choose between command or file deployment)
-pub fn deploy(
-    command: Option<&str>,
-    artifacts: Option<&[String]>,
-    target_dir: Option<&str>,
+/// Path of the archive `deploy.bundle` writes into a version directory
+pub fn bundle_path(versioned_dir: &Path, version: &str, format: BundleFormat) -> PathBuf {
+    versioned_dir.join(format!("release-{}.{}", version, format.extension()))
+}
+
+/// Pack artifacts into `release-{version}.{ext}` inside the version directory
+///
+/// The artifacts are copied into a scratch directory first so the archive has
+/// the same layout (and exclusions) a loose copy would have. The finished
+/// archive is read back before the deploy carries on.
+fn bundle_artifacts(
+    resolved: &[builder::ResolvedArtifact],
+    versioned_dir: &Path,
+    version: &str,
+    format: BundleFormat,
+    options: &DeployConfig,
+) -> Result<PathBuf, Box<dyn std::error::Error>> {
+    let scratch = versioned_dir.join(".bundle");
+    let bundle = bundle_path(versioned_dir, version, format);
+    fs::create_dir_all(&scratch)?;
+    let written = copy_artifacts(resolved, &scratch, None, options).and_then(|()| write_bundle(&scratch, &bundle, format));
+    fs::remove_dir_all(&scratch)?;
+    written?;
+
+    let entries = list_bundle(&bundle, format).map_err(|e| format!("Bundle {:?} is unreadable: {}", bundle, e))?;
+    log::info!("Bundled {} entries into: {:?}", entries.len(), bundle);
+    Ok(bundle)
+}
+
+fn write_bundle(source_dir: &Path, bundle: &Path, format: BundleFormat) -> Result<(), Box<dyn std::error::Error>> {
+    let mut entries = Vec::new();
+    collect_bundle_entries(source_dir, source_dir, &mut entries)?;
+    let file = fs::File::create(bundle)?;
+
+    match format {
+        BundleFormat::TarGz => {
+            let encoder = flate2::write::GzEncoder::new(file, flate2::Compression::default());
+            let mut builder = tar::Builder::new(encoder);
+            for entry in &entries {
+                builder.append_path_with_name(source_dir.join(entry), entry)?;
+            }
+            builder.into_inner()?.finish()?.sync_all()?;
+        }
+        BundleFormat::Zip => {
+            let mut writer = zip::ZipWriter::new(file);
+            for entry in &entries {
+                let path = source_dir.join(entry);
+                let name = entry.to_str().ok_or("Invalid artifact path")?.replace('\\', "/");
+                let options = zip::write::SimpleFileOptions::default();
+                #[cfg(unix)]
+                let options = {
+                    use std::os::unix::fs::PermissionsExt;
+                    options.unix_permissions(fs::metadata(&path)?.permissions().mode())
+                };
+                if path.is_dir() {
+                    writer.add_directory(name, options)?;
+                } else {
+                    writer.start_file(name, options)?;
+                    std::io::copy(&mut fs::File::open(&path)?, &mut writer)?;
+                }
+            }
+            writer.finish()?.sync_all()?;
+        }
+    }
+    Ok(())
+}
+
+/// Paths under `dir` relative to `root`, sorted, each directory before its contents
+fn collect_bundle_entries(root: &Path, dir: &Path, entries: &mut Vec<PathBuf>) -> std::io::Result<()> {
+    let mut children = fs::read_dir(dir)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<Vec<_>, _>>()?;
+    children.sort();
+    for child in children {
+        entries.push(child.strip_prefix(root).unwrap_or(&child).to_path_buf());
+        if child.is_dir() {
+            collect_bundle_entries(root, &child, entries)?;
+        }
+    }
+    Ok(())
+}
+
+/// Names of the entries in a deploy bundle
+///
+/// Every entry's data is read through, so a truncated or corrupt archive is
+/// an error rather than a short listing.
+pub fn list_bundle(bundle: &Path, format: BundleFormat) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let file = fs::File::open(bundle)?;
+    let mut names = Vec::new();
+
+    match format {
+        BundleFormat::TarGz => {
+            let mut archive = tar::Archive::new(flate2::read::GzDecoder::new(file));
+            for entry in archive.entries()? {
+                let mut entry = entry?;
+                names.push(entry.path()?.to_string_lossy().into_owned());
+                std::io::copy(&mut entry, &mut std::io::sink())?;
+            }
+        }
+        BundleFormat::Zip => {
+            let mut archive = zip::ZipArchive::new(file)?;
+            for index in 0..archive.len() {
+                let mut entry = archive.by_index(index)?;
+                names.push(entry.name().to_string());
+                std::io::copy(&mut entry, &mut std::io::sink())?;
+            }
+        }
+    }
+    Ok(names)
+}
+
+/// Deploy by copying artifacts straight into target directory (unversioned deployment)
+///
+/// The previous contents are copied to a sibling `{target_dir}.{timestamp}.bak`
+/// directory, which `rollback::restore_backup` uses in place of the `current`
+/// symlink. With `clean_target` they are then removed, so files dropped from
+/// the artifacts do not linger; the directory itself is kept (it may be a
+/// mount or a web root).
+pub fn deploy_to_bare_target(
+    artifacts: &[ArtifactSpec],
+    target_dir: &str,
     repo_path: &str,
-    commit_hash: &str,
+    options: &DeployConfig,
+) -> Result<(), Box<dyn std::error::Error>> {
+    log::info!("Starting unversioned file deployment to: {}", target_dir);
+
+    let resolved = builder::resolve_artifacts(artifacts, &options.artifact_base_dir(repo_path))?;
+    check_dest_names(&resolved)?;
+
+    let target = Path::new(target_dir);
+    if target.exists() {
+        let timestamp = chrono::Local::now().format("%Y%m%d%H%M%S%3f");
+        let backup_dir = format!("{}.{}.bak", target_dir.trim_end_matches('/'), timestamp);
+        copy_dir_all(target, Path::new(&backup_dir))?;
+        log::info!("Backed up previous contents to: {}", backup_dir);
+        if options.clean_target {
+            for entry in fs::read_dir(target)? {
+                let path = entry?.path();
+                if fs::symlink_metadata(&path)?.is_dir() {
+                    fs::remove_dir_all(&path)?;
+                } else {
+                    fs::remove_file(&path)?;
+                }
+            }
+        }
+    } else {
+        fs::create_dir_all(target)?;
+    }
+
+    copy_artifacts(&resolved, target, None, options)?;
+
+    Ok(())
+}
+
+/// Fail before copying if the target volume cannot hold the artifacts plus
+/// the configured safety margin, rather than leaving a half-copied version
+///
+/// Only what will actually be written counts: excluded files are left out,
+/// and so are files that will be hardlinked from `previous`.
+/// `available_space` reports free bytes for a path (injectable for tests).
+fn ensure_disk_space(
+    artifacts: &[builder::ResolvedArtifact],
+    target_dir: &Path,
+    previous: Option<&PreviousVersion>,
+    options: &DeployConfig,
+    available_space: impl Fn(&Path) -> std::io::Result<u64>,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    // Try command deployment first
-    if let Some(cmd) = command {
-        return deploy_with_command(cmd, repo_path);
+    let mut required = 0;
+    for artifact in artifacts {
+        required += copy_size(artifact, previous, options)?;
     }
+    let margin = options.disk_margin_mb * 1024 * 1024;
 
-    // Fall back to file deployment
-    if let (Some(arts), Some(target)) = (artifacts, target_dir) {
-        return deploy_with_files(arts, target, repo_path, commit_hash);
+    // The target may not exist yet; measure the nearest existing ancestor
+    let volume = target_dir.ancestors().find(|dir| dir.exists()).unwrap_or(Path::new("."));
+    let available = available_space(volume)?;
+
+    if available < required.saturating_add(margin) {
+        return Err(format!(
+            "Insufficient disk space on {}: artifacts need {} bytes plus a {} MB margin, {} bytes available",
+            volume.display(),
+            required,
+            options.disk_margin_mb,
+            available
+        )
+        .into());
     }
 
-    Err("No deployment method configured (neither command nor target_dir/artifacts)".into())
+    Ok(())
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+/// Bytes [`copy_artifact`] will write for `artifact`
+fn copy_size(
+    artifact: &builder::ResolvedArtifact,
+    previous: Option<&PreviousVersion>,
+    options: &DeployConfig,
+) -> Result<u64, Box<dyn std::error::Error>> {
+    if artifact.path.is_dir() {
+        return filtered_size(&artifact.path, &artifact.path, &exclude_patterns(artifact, options)?);
+    }
 
-    #[test]
-    fn test_deploy_with_echo() {
-        let result = deploy_with_command("echo deployed", ".");
-        assert!(result.is_ok());
+    let linked = previous.is_some_and(|previous| {
+        previous.unchanged.contains(&artifact.path)
+            && artifact.dest_name().is_ok_and(|name| previous.dir.join(name).is_file())
+    });
+    if linked {
+        return Ok(0);
+    }
+    Ok(fs::metadata(&artifact.path)?.len())
+}
+
+/// Total size of the files under `src` that [`copy_dir_filtered`] copies
+fn filtered_size(root: &Path, src: &Path, exclude: &[glob::Pattern]) -> Result<u64, Box<dyn std::error::Error>> {
+    let mut size = 0;
+    for entry in fs::read_dir(src)? {
+        let entry = entry?;
+        let path = entry.path();
+        if matches_any(exclude, path.strip_prefix(root)?, &entry.file_name()) {
+            continue;
+        }
+        size += if entry.file_type()?.is_dir() {
+            filtered_size(root, &path, exclude)?
+        } else {
+            entry.metadata()?.len()
+        };
+    }
+    Ok(size)
+}
+
+/// The version being replaced, whose files may be hardlinked instead of copied
+struct PreviousVersion<'a> {
+    dir: &'a Path,
+    /// Artifact paths known to be unchanged since the previous deploy: untouched
+    /// in git (incremental mode) or with the same checksum (dedup)
+    unchanged: HashSet<PathBuf>,
+}
+
+/// Add to `unchanged` the file artifacts whose content matches their copy in
+/// `previous_dir`
+///
+/// Each candidate of the same size is hashed once here, so the disk space
+/// check and the copy both just look the result up.
+fn identical_artifacts(
+    artifacts: &[builder::ResolvedArtifact],
+    previous_dir: &Path,
+    mut unchanged: HashSet<PathBuf>,
+) -> Result<HashSet<PathBuf>, Box<dyn std::error::Error>> {
+    for artifact in artifacts {
+        if unchanged.contains(&artifact.path) || !artifact.path.is_file() {
+            continue;
+        }
+        let previous_path = previous_dir.join(artifact.dest_name()?);
+        let size = fs::metadata(&artifact.path)?.len();
+        let same_size = fs::metadata(&previous_path).is_ok_and(|metadata| metadata.is_file() && metadata.len() == size);
+        if same_size && file_checksum(&artifact.path)? == file_checksum(&previous_path)? {
+            unchanged.insert(artifact.path.clone());
+        }
+    }
+    Ok(unchanged)
+}
+
+/// File artifacts that are tracked by git and untouched, in the working tree,
+/// since the commit the previous version was deployed from
+///
+/// Untracked files (usually build outputs) and directories are never assumed
+/// unchanged. Without a recorded previous commit, nothing is.
+fn unchanged_artifacts(
+    artifacts: &[builder::ResolvedArtifact],
+    repo_path: &str,
+    git: &GitConfig,
+    previous_dir: &Path,
+) -> HashSet<PathBuf> {
+    let Some(previous_commit) = rollback::deployed_commit(previous_dir) else {
+        log::info!("No commit recorded for the previous version, copying all artifacts");
+        return HashSet::new();
+    };
+
+    let (changed, tracked) = match (
+        hook::changed_files_since(repo_path, git, &previous_commit),
+        hook::tracked_files(repo_path, git),
+    ) {
+        (Ok(changed), Ok(tracked)) => (changed, tracked),
+        (Err(e), _) | (_, Err(e)) => {
+            log::warn!("Cannot diff against {}, copying all artifacts: {}", previous_commit, e);
+            return HashSet::new();
+        }
+    };
+    let changed: HashSet<String> = changed.into_iter().collect();
+    let tracked: HashSet<String> = tracked.into_iter().collect();
+
+    artifacts
+        .iter()
+        .filter(|artifact| artifact.path.is_file())
+        .filter_map(|artifact| {
+            let relative = artifact.path.strip_prefix(repo_path).ok()?.to_str()?.replace('\\', "/");
+            (tracked.contains(&relative) && !changed.contains(&relative)).then(|| artifact.path.clone())
+        })
+        .collect()
+}
+
+/// Write the version's metadata file: commit, time, sequence number, copy
+/// duration and file checksums
+///
+/// Deploys from outside a git repository get no commit.
+fn write_version_metadata(
+    version_dir: &Path,
+    target_dir: &str,
+    link_name: &str,
+    version: &str,
+    repo_path: &str,
+    git: &GitConfig,
+    copy_duration: std::time::Duration,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut checksums = BTreeMap::new();
+    collect_checksums(version_dir, version_dir, &mut checksums)?;
+
+    let meta = VersionMeta {
+        commit: hook::get_current_commit_hash(repo_path, git).ok(),
+        branch: hook::get_current_branch(repo_path, git).ok(),
+        deployed_at: Some(chrono::Local::now().to_rfc3339()),
+        sequence: Some(next_sequence(target_dir, link_name, version)?),
+        build_duration_secs: None,
+        copy_duration_secs: Some(copy_duration.as_secs_f64()),
+        user: Some(crate::history::current_user()),
+        host: Some(crate::history::current_host()),
+        run_id: crate::logger::current_run_id(),
+        checksums,
+    };
+    rollback::write_version_meta(version_dir, &meta)
+}
+
+/// Sequence number for `version`: one more than the highest among the target's other versions
+fn next_sequence(target_dir: &str, link_name: &str, version: &str) -> Result<u64, Box<dyn std::error::Error>> {
+    let highest = rollback::get_deployed_versions(target_dir, link_name)?
+        .iter()
+        .filter(|name| name.as_str() != version)
+        .filter_map(|name| rollback::read_version_meta(target_dir, name)?.sequence)
+        .max()
+        .unwrap_or(0);
+    Ok(highest + 1)
+}
+
+/// SHA-256 of every regular file under `dir`, keyed by path relative to `root`
+fn collect_checksums(
+    root: &Path,
+    dir: &Path,
+    checksums: &mut BTreeMap<String, String>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let file_type = fs::symlink_metadata(&path)?.file_type();
+        if file_type.is_dir() {
+            collect_checksums(root, &path, checksums)?;
+        } else if file_type.is_file() && path.file_name() != Some(std::ffi::OsStr::new(rollback::META_FILE)) {
+            let relative = path.strip_prefix(root)?;
+            let key = relative
+                .components()
+                .map(|part| part.as_os_str().to_string_lossy())
+                .collect::<Vec<_>>()
+                .join("/");
+            checksums.insert(key, file_checksum(&path)?);
+        }
+    }
+    Ok(())
+}
+
+/// Copy all artifacts into `dest_dir`, using up to `copy_parallelism` threads
+///
+/// The first failure stops workers from starting further copies and is
+/// returned. Each copy logs a single complete line, so concurrent copies
+/// never interleave within a line.
+fn copy_artifacts(
+    artifacts: &[builder::ResolvedArtifact],
+    dest_dir: &Path,
+    previous: Option<&PreviousVersion>,
+    options: &DeployConfig,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let workers = options.copy_parallelism.clamp(1, artifacts.len().max(1));
+    if workers == 1 {
+        for artifact in artifacts {
+            copy_artifact(artifact, dest_dir, previous, options)?;
+        }
+        return Ok(());
+    }
+
+    let next = AtomicUsize::new(0);
+    let failed = AtomicBool::new(false);
+    // Kept as an io::Error (with the original kind) so retries can classify it
+    let first_error: Mutex<Option<std::io::Error>> = Mutex::new(None);
+
+    let run_id = crate::logger::current_run_id();
+    let deadline = runner::deadline();
+    thread::scope(|scope| {
+        for _ in 0..workers {
+            scope.spawn(|| {
+                crate::logger::set_run_id(run_id.as_deref());
+                runner::set_deadline(deadline);
+                while !failed.load(Ordering::SeqCst) {
+                    let index = next.fetch_add(1, Ordering::SeqCst);
+                    let Some(artifact) = artifacts.get(index) else {
+                        break;
+                    };
+                    if let Err(e) = copy_artifact(artifact, dest_dir, previous, options) {
+                        failed.store(true, Ordering::SeqCst);
+                        let mut first_error = first_error.lock().unwrap_or_else(|p| p.into_inner());
+                        first_error.get_or_insert_with(|| {
+                            let kind = io_error_kind(e.as_ref()).unwrap_or(std::io::ErrorKind::Other);
+                            std::io::Error::new(kind, format!("Failed to copy {:?}: {}", artifact.path, e))
+                        });
+                    }
+                }
+            });
+        }
+    });
+
+    match first_error.into_inner().unwrap_or_else(|p| p.into_inner()) {
+        Some(error) => Err(error.into()),
+        None => Ok(()),
+    }
+}
+
+/// Copy one resolved artifact (file or directory) into `dest_dir`
+fn copy_artifact(
+    artifact: &builder::ResolvedArtifact,
+    dest_dir: &Path,
+    previous: Option<&PreviousVersion>,
+    options: &DeployConfig,
+) -> Result<PathBuf, Box<dyn std::error::Error>> {
+    let file_name = artifact.dest_name()?;
+    let dest_path = dest_dir.join(&file_name);
+
+    if artifact.path.is_dir() {
+        let exclude = exclude_patterns(artifact, options)?;
+        copy_dir_filtered(&artifact.path, &artifact.path, &dest_path, &exclude, options.prefer_reflink)?;
+    } else {
+        if let Some(previous) = previous {
+            let previous_path = previous.dir.join(&file_name);
+            if previous.unchanged.contains(&artifact.path) && link_previous(&previous_path, &dest_path)? {
+                log::info!("Linked unchanged artifact: {:?} -> {:?}", previous_path, dest_path);
+                return Ok(dest_path);
+            }
+        }
+        copy_file(&artifact.path, &dest_path, PROGRESS_THRESHOLD, options.prefer_reflink)?;
+    }
+
+    log::info!("Copied artifact: {:?} -> {:?}", artifact.path, dest_path);
+    Ok(dest_path)
+}
+
+/// Copy a file like `fs::copy`, but report progress for files of at least
+/// `threshold` bytes
+///
+/// With `prefer_reflink`, a copy-on-write clone is tried first (see
+/// [`reflink`]). Otherwise large files are copied in chunks; every further
+/// 10% is printed to stderr and logged, each as one complete line so
+/// parallel copies don't garble each other. An abort (Ctrl-C) stops the copy
+/// between chunks.
+fn copy_file(src: &Path, dest: &Path, threshold: u64, prefer_reflink: bool) -> Result<u64, Box<dyn std::error::Error>> {
+    use std::io::{Read, Write};
+
+    let metadata = fs::metadata(src)?;
+    let total = metadata.len();
+    if prefer_reflink && reflink(src, dest)? {
+        fs::set_permissions(dest, metadata.permissions())?;
+        log::debug!("Cloned {:?} -> {:?}", src, dest);
+        return Ok(total);
+    }
+    if total < threshold {
+        return Ok(fs::copy(src, dest)?);
+    }
+
+    let mut reader = fs::File::open(src)?;
+    let mut writer = fs::File::create(dest)?;
+    let mut buffer = vec![0_u8; COPY_CHUNK_SIZE];
+    let mut copied = 0;
+    let mut reported = 0;
+    loop {
+        if runner::is_aborted() {
+            return Err(runner::abort_error());
+        }
+        let read = reader.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        writer.write_all(&buffer[..read])?;
+        copied += read as u64;
+
+        let percent = copied * 100 / total.max(1);
+        if percent >= reported + 10 {
+            reported = percent - percent % 10;
+            let line = format!("Copying {}: {}% ({} of {} bytes)", src.display(), percent, copied, total);
+            eprintln!("{}", line);
+            log::info!("{}", line);
+        }
+    }
+    fs::set_permissions(dest, metadata.permissions())?;
+
+    Ok(copied)
+}
+
+/// Clone `src` into a new file at `dest` copy-on-write, so no data is copied
+///
+/// Returns false, leaving nothing at `dest`, when the platform or filesystem
+/// can't (including across filesystems); the caller copies instead.
+#[cfg(target_os = "linux")]
+fn reflink(src: &Path, dest: &Path) -> std::io::Result<bool> {
+    use std::os::fd::AsRawFd;
+
+    let source = fs::File::open(src)?;
+    let target = fs::File::create(dest)?;
+    // SAFETY: FICLONE only reads the two descriptors, which stay open for the call
+    if unsafe { libc::ioctl(target.as_raw_fd(), libc::FICLONE, source.as_raw_fd()) } == 0 {
+        return Ok(true);
+    }
+    drop(target);
+    fs::remove_file(dest)?;
+    Ok(false)
+}
+
+#[cfg(target_os = "macos")]
+fn reflink(src: &Path, dest: &Path) -> std::io::Result<bool> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let src = CString::new(src.as_os_str().as_bytes())?;
+    let dest = CString::new(dest.as_os_str().as_bytes())?;
+    // SAFETY: both are valid NUL-terminated paths; clonefile fails if dest exists
+    Ok(unsafe { libc::clonefile(src.as_ptr(), dest.as_ptr(), 0) } == 0)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn reflink(_src: &Path, _dest: &Path) -> std::io::Result<bool> {
+    Ok(false)
+}
+
+/// Hardlink `previous` to `dest`, returning false if it is missing or cannot be linked
+fn link_previous(previous: &Path, dest: &Path) -> Result<bool, Box<dyn std::error::Error>> {
+    if !previous.is_file() {
+        return Ok(false);
+    }
+
+    if dest.exists() {
+        fs::remove_file(dest)?;
+    }
+    match fs::hard_link(previous, dest) {
+        Ok(()) => Ok(true),
+        Err(e) => {
+            log::debug!("Hardlink failed, copying instead: {}", e);
+            Ok(false)
+        }
+    }
+}
+
+/// SHA-256 of a file's contents as lowercase hex
+pub fn file_checksum(path: &Path) -> Result<String, Box<dyn std::error::Error>> {
+    use sha2::{Digest, Sha256};
+    use std::io::Read;
+
+    let mut file = fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buffer = [0_u8; 64 * 1024];
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+
+    Ok(hasher.finalize().iter().map(|byte| format!("{:02x}", byte)).collect())
+}
+
+/// Recursively copy a directory
+pub fn copy_dir_all(src: &Path, dest: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    copy_dir_filtered(src, src, dest, &[], true)
+}
+
+/// Recursively copy a directory, skipping paths matching `exclude`
+///
+/// Patterns are matched against the path relative to `root` and against the
+/// bare file name, so `node_modules` excludes that directory at any depth.
+fn copy_dir_filtered(
+    root: &Path,
+    src: &Path,
+    dest: &Path,
+    exclude: &[glob::Pattern],
+    prefer_reflink: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    fs::create_dir_all(dest)?;
+
+    for entry in fs::read_dir(src)? {
+        let entry = entry?;
+        let path = entry.path();
+        let relative = path.strip_prefix(root)?;
+        if matches_any(exclude, relative, &entry.file_name()) {
+            log::debug!("Excluded from copy: {:?}", relative);
+            continue;
+        }
+
+        let dest_path = dest.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            copy_dir_filtered(root, &path, &dest_path, exclude, prefer_reflink)?;
+        } else {
+            copy_file(&path, &dest_path, PROGRESS_THRESHOLD, prefer_reflink)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// The global and per-artifact exclusion globs for a directory artifact
+fn exclude_patterns(
+    artifact: &builder::ResolvedArtifact,
+    options: &DeployConfig,
+) -> Result<Vec<glob::Pattern>, glob::PatternError> {
+    options
+        .exclude
+        .iter()
+        .chain(artifact.spec.exclude())
+        .map(|pattern| glob::Pattern::new(pattern))
+        .collect()
+}
+
+/// Whether an entry (its path relative to the artifact root, or its bare name)
+/// matches any of `patterns`, e.g. the `exclude` or `precompress` globs
+fn matches_any(patterns: &[glob::Pattern], relative: &Path, file_name: &std::ffi::OsStr) -> bool {
+    patterns
+        .iter()
+        .any(|pattern| pattern.matches_path(relative) || pattern.matches_path(Path::new(file_name)))
+}
+
+/// Checksums a version directory staged from these artifacts would record,
+/// keyed like [`VersionMeta::checksums`] but computed from the sources without copying
+fn source_checksums(
+    artifacts: &[builder::ResolvedArtifact],
+    options: &DeployConfig,
+) -> Result<BTreeMap<String, String>, Box<dyn std::error::Error>> {
+    let mut checksums = BTreeMap::new();
+    for artifact in artifacts {
+        let file_name = artifact.dest_name()?.to_string_lossy().into_owned();
+        if artifact.path.is_dir() {
+            let exclude = exclude_patterns(artifact, options)?;
+            collect_source_checksums(&artifact.path, &artifact.path, &file_name, &exclude, &mut checksums)?;
+        } else {
+            checksums.insert(file_name, file_checksum(&artifact.path)?);
+        }
+    }
+    Ok(checksums)
+}
+
+fn collect_source_checksums(
+    root: &Path,
+    dir: &Path,
+    prefix: &str,
+    exclude: &[glob::Pattern],
+    checksums: &mut BTreeMap<String, String>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        let relative = path.strip_prefix(root)?;
+        if matches_any(exclude, relative, &entry.file_name()) {
+            continue;
+        }
+
+        if entry.file_type()?.is_dir() {
+            collect_source_checksums(root, &path, prefix, exclude, checksums)?;
+        } else {
+            let key = std::iter::once(prefix.into())
+                .chain(relative.components().map(|part| part.as_os_str().to_string_lossy()))
+                .collect::<Vec<_>>()
+                .join("/");
+            checksums.insert(key, file_checksum(&path)?);
+        }
+    }
+    Ok(())
+}
+
+/// Whether deploying `commit_hash` would change nothing: with
+/// `skip_unchanged`, every local file target's current version already holds
+/// identical artifacts
+///
+/// Always `false` for command and SFTP deploys, unversioned targets and
+/// bundles (whose recorded checksum is the archive's, which never matches
+/// the sources).
+pub fn nothing_to_deploy(config: &DeployConfig, repo_path: &str, commit_hash: &str) -> Result<bool, Box<dyn std::error::Error>> {
+    if !config.skip_unchanged || config.command.is_some() || config.sftp.is_some() || !config.versioned || config.bundle.is_some() {
+        return Ok(false);
+    }
+
+    let main = config.target_dir.as_deref().zip(config.artifacts.as_deref());
+    let targets = main
+        .into_iter()
+        .chain(config.targets.iter().map(|target| (target.target_dir.as_str(), target.artifacts.as_slice())))
+        .collect::<Vec<_>>();
+    if targets.is_empty() {
+        return Ok(false);
+    }
+    for (target_dir, artifacts) in targets {
+        let artifacts = render_dest_templates(artifacts, commit_hash);
+        match unchanged_current_version(config, &artifacts, target_dir, repo_path)? {
+            Some(current) => log::debug!("{} already holds identical artifacts in {}", target_dir, current),
+            None => return Ok(false),
+        }
+    }
+    Ok(true)
+}
+
+/// The current version of `target_dir`, if its recorded checksums show it
+/// already holds exactly these artifacts
+fn unchanged_current_version(
+    config: &DeployConfig,
+    artifacts: &[ArtifactSpec],
+    target_dir: &str,
+    repo_path: &str,
+) -> Result<Option<String>, Box<dyn std::error::Error>> {
+    let Some(current) = rollback::current_version(target_dir, &config.current_link_name) else {
+        return Ok(None);
+    };
+    let recorded = match rollback::read_version_meta(target_dir, &current) {
+        Some(meta) if !meta.checksums.is_empty() => meta.checksums,
+        _ => return Ok(None),
+    };
+
+    let resolved = builder::resolve_artifacts(artifacts, &config.artifact_base_dir(repo_path))?;
+    let sources = source_checksums(&resolved, config)?;
+    Ok((sources == without_precompressed(recorded, &sources)).then_some(current))
+}
+
+/// Checksums of the files deploying `artifacts` for `commit_hash` would put
+/// in a version directory, keyed like [`VersionMeta::checksums`]
+pub fn artifact_checksums(
+    config: &DeployConfig,
+    artifacts: &[ArtifactSpec],
+    repo_path: &str,
+    commit_hash: &str,
+) -> Result<BTreeMap<String, String>, Box<dyn std::error::Error>> {
+    let artifacts = render_dest_templates(artifacts, commit_hash);
+    let resolved = builder::resolve_artifacts(&artifacts, &config.artifact_base_dir(repo_path))?;
+    source_checksums(&resolved, config)
+}
+
+/// Checksums of a deployed version: the ones recorded in its metadata, or
+/// hashed from disk for versions deployed before checksums were recorded
+pub fn version_checksums(target_dir: &str, version: &str) -> Result<BTreeMap<String, String>, Box<dyn std::error::Error>> {
+    if let Some(meta) = rollback::read_version_meta(target_dir, version) {
+        if !meta.checksums.is_empty() {
+            return Ok(meta.checksums);
+        }
+    }
+    let version_dir = Path::new(target_dir).join(version);
+    let mut checksums = BTreeMap::new();
+    collect_checksums(&version_dir, &version_dir, &mut checksums)?;
+    Ok(checksums)
+}
+
+/// `recorded` without the `.gz` siblings `precompress` wrote next to files in
+/// `sources`, which have no source of their own
+pub fn without_precompressed(
+    recorded: BTreeMap<String, String>,
+    sources: &BTreeMap<String, String>,
+) -> BTreeMap<String, String> {
+    recorded
+        .into_iter()
+        .filter(|(key, _)| {
+            sources.contains_key(key) || !key.strip_suffix(".gz").is_some_and(|original| sources.contains_key(original))
+        })
+        .collect()
+}
+
+/// Name for a version that doesn't clobber the same commit deployed from
+/// another branch (e.g. after a merge): `{version}-{branch}` when `version`
+/// already exists and its metadata records a different branch
+///
+/// Redeploys from the same branch, detached HEADs and versions without a
+/// recorded branch keep the plain name and replace the existing directory.
+fn disambiguate_by_branch(target_dir: &str, version: String, repo_path: &str, git: &GitConfig) -> String {
+    let Ok(branch) = hook::get_current_branch(repo_path, git) else {
+        return version;
+    };
+    match rollback::read_version_meta(target_dir, &version).and_then(|meta| meta.branch) {
+        Some(deployed_from) if deployed_from != branch => {
+            let renamed = format!("{}-{}", version, path_safe_branch(&branch));
+            log::info!("Version {} was deployed from {}, using {} for {}", version, deployed_from, renamed, branch);
+            renamed
+        }
+        _ => version,
+    }
+}
+
+/// Name of the version directory deploying `commit_hash` to the main target
+/// creates, or `None` for command and unversioned deploys
+///
+/// The pipeline computes it once, before building, so the build sees the same
+/// `PLOOP_DEPLOY_VERSION` the deploy then uses.
+pub fn planned_version(
+    config: &DeployConfig,
+    repo_path: &str,
+    git: &GitConfig,
+    commit_hash: &str,
+) -> Result<Option<String>, Box<dyn std::error::Error>> {
+    if config.command.is_some() {
+        return Ok(None);
+    }
+    if config.sftp.is_some() {
+        return Ok(Some(sftp_version_name(config.version_scheme, commit_hash)));
+    }
+    match config.target_dir.as_deref() {
+        Some(target_dir) if config.versioned => target_version_name(config, target_dir, repo_path, git, commit_hash).map(Some),
+        _ => Ok(None),
+    }
+}
+
+/// Version directory name for `commit_hash` in a local `target_dir`
+fn target_version_name(
+    config: &DeployConfig,
+    target_dir: &str,
+    repo_path: &str,
+    git: &GitConfig,
+    commit_hash: &str,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let version = version_dir_name(config.version_scheme, commit_hash, target_dir)?;
+    Ok(disambiguate_by_branch(target_dir, version, repo_path, git))
+}
+
+/// `{short_hash}-{YYYYmmddHHMMSS}` for the "hash_timestamp" scheme
+fn timestamped_name(short_hash: &str) -> String {
+    format!("{}-{}", short_hash, chrono::Local::now().format("%Y%m%d%H%M%S"))
+}
+
+/// Compute the versioned directory name for a commit under the given scheme
+///
+/// Timestamp and counter names are checked against existing directories in
+/// `target_dir` so they never collide.
+pub fn version_dir_name(
+    scheme: VersionScheme,
+    commit_hash: &str,
+    target_dir: &str,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let short_hash: String = commit_hash.chars().take(7).collect();
+
+    let name = match scheme {
+        VersionScheme::ShortHash => short_hash,
+        VersionScheme::FullHash => commit_hash.to_string(),
+        VersionScheme::HashTimestamp => {
+            let base = timestamped_name(&short_hash);
+            let mut name = base.clone();
+            let mut suffix = 2;
+            while Path::new(target_dir).join(&name).exists() {
+                name = format!("{}-{}", base, suffix);
+                suffix += 1;
+            }
+            name
+        }
+        VersionScheme::Counter => {
+            let mut highest = 0;
+            if Path::new(target_dir).exists() {
+                for entry in fs::read_dir(target_dir)? {
+                    let file_name = entry?.file_name();
+                    let counter = file_name
+                        .to_str()
+                        .and_then(|name| name.split_once('-'))
+                        .and_then(|(counter, _)| counter.parse::<u64>().ok());
+                    highest = highest.max(counter.unwrap_or(0));
+                }
+            }
+            format!("{}-{}", highest + 1, short_hash)
+        }
+    };
+
+    Ok(name)
+}
+
+/// Fill `{branch}` and `{commit}` (short hash) placeholders in a target directory
+///
+/// Branch names are made filesystem-safe: `/`, `\` and any other character
+/// outside `[A-Za-z0-9._-]` become `-`.
+pub fn render_target_template(template: &str, branch: &str, commit: &str) -> String {
+    let short_commit: String = commit.chars().take(7).collect();
+    template
+        .replace("{branch}", &path_safe_branch(branch))
+        .replace("{commit}", &short_commit)
+}
+
+/// `branch` with `/` and other characters unsafe in a path component turned into `-`
+pub fn path_safe_branch(branch: &str) -> String {
+    branch
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-') { c } else { '-' })
+        .collect()
+}
+
+/// The first `[[deploy.previews]]` entry with a pattern matching `branch`
+pub fn preview_for<'a>(config: &'a DeployConfig, branch: &str) -> Option<&'a PreviewConfig> {
+    config.previews.iter().find(|preview| {
+        preview.branches.iter().any(|pattern| match glob::Pattern::new(pattern) {
+            Ok(pattern) => pattern.matches(branch),
+            Err(e) => {
+                log::warn!("Invalid preview branch pattern {}: {}", pattern, e);
+                false
+            }
+        })
+    })
+}
+
+/// `config` as it deploys from `branch` when that is a preview branch: the
+/// preview's target and command replace the main ones, and the other
+/// targets and the canary are dropped
+pub fn preview_deploy_config(config: &DeployConfig, branch: &str) -> Option<DeployConfig> {
+    let preview = preview_for(config, branch)?;
+    let mut deploy = config.clone();
+    deploy.target_dir = Some(preview.target_dir.clone());
+    deploy.command = preview.command.clone();
+    deploy.targets = Vec::new();
+    deploy.canary = None;
+    Some(deploy)
+}
+
+/// Resolve a templated target directory from the repository's current git state
+///
+/// Git is only consulted for placeholders that are actually present.
+pub fn resolve_target_dir(
+    template: &str,
+    repo_path: &str,
+    git: &GitConfig,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let branch = if template.contains("{branch}") {
+        hook::get_current_branch(repo_path, git)?
+    } else {
+        String::new()
+    };
+    let commit = if template.contains("{commit}") {
+        hook::get_current_commit_hash(repo_path, git)?
+    } else {
+        String::new()
+    };
+
+    Ok(render_target_template(template, &branch, &commit))
+}
+
+/// A failure deploying one of `deploy.targets`, naming the target
+#[derive(Debug)]
+struct TargetError {
+    name: String,
+    source: Box<dyn std::error::Error>,
+}
+
+impl std::fmt::Display for TargetError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Target '{}': {}", self.name, self.source)
+    }
+}
+
+impl std::error::Error for TargetError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(self.source.as_ref())
+    }
+}
+
+/// The kind of the first `io::Error` in an error's source chain, if any
+fn io_error_kind(error: &(dyn std::error::Error + 'static)) -> Option<std::io::ErrorKind> {
+    let mut current = Some(error);
+    while let Some(error) = current {
+        if let Some(io_error) = error.downcast_ref::<std::io::Error>() {
+            return Some(io_error.kind());
+        }
+        current = error.source();
+    }
+    None
+}
+
+/// Whether a failed deploy is worth retrying: only transient I/O failures (a
+/// target mount briefly gone or stale, a full disk being cleaned up, a
+/// dropped connection) are; missing artifacts, permission problems,
+/// configuration errors, failed deploy commands and aborts are not
+pub fn is_retryable(error: &(dyn std::error::Error + 'static)) -> bool {
+    use std::io::ErrorKind;
+
+    matches!(
+        io_error_kind(error),
+        Some(
+            ErrorKind::TimedOut
+                | ErrorKind::WouldBlock
+                | ErrorKind::ResourceBusy
+                | ErrorKind::StaleNetworkFileHandle
+                | ErrorKind::StorageFull
+                | ErrorKind::NotConnected
+                | ErrorKind::ConnectionReset
+                | ErrorKind::ConnectionAborted
+                | ErrorKind::ConnectionRefused
+                | ErrorKind::BrokenPipe
+        )
+    )
+}
+
+/// Run `attempt` once, then up to `retries` more times while it fails with a
+/// retryable error, sleeping `delay` between attempts
+pub fn with_retries<T>(
+    retries: u32,
+    delay: std::time::Duration,
+    mut attempt: impl FnMut() -> Result<T, Box<dyn std::error::Error>>,
+) -> Result<T, Box<dyn std::error::Error>> {
+    let attempts = retries + 1;
+    let mut number = 1;
+    loop {
+        match attempt() {
+            Ok(value) => return Ok(value),
+            Err(e) if number < attempts && is_retryable(e.as_ref()) && !runner::is_aborted() => {
+                log::warn!("Deploy attempt {}/{} failed: {}; retrying in {:?}", number, attempts, e, delay);
+                thread::sleep(delay);
+                number += 1;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Deploy artifacts with the backend `config` selects (see [`select_backend`]),
+/// wrapped in the `pre_deploy` / `post_deploy` scripts
+///
+/// `version` names the main target's new version directory, as computed
+/// up front by [`planned_version`]; with `None` the backend names it itself.
+/// Only the backend's deploy is retried (`deploy.retries`), so the scripts
+/// run once. Returns the deploy command's captured output in command mode.
+pub fn deploy(
+    config: &DeployConfig,
+    repo_path: &str,
+    git: &GitConfig,
+    commit_hash: &str,
+    version: Option<&str>,
+) -> Result<Option<String>, Box<dyn std::error::Error>> {
+    deploy_with_backend(select_backend(config)?.as_ref(), config, repo_path, git, commit_hash, version)
+}
+
+/// Like [`deploy`] with a given backend, e.g. one provided by an embedder
+pub fn deploy_with_backend(
+    backend: &dyn DeployBackend,
+    config: &DeployConfig,
+    repo_path: &str,
+    git: &GitConfig,
+    commit_hash: &str,
+    version: Option<&str>,
+) -> Result<Option<String>, Box<dyn std::error::Error>> {
+    rollback::validate_current_link_name(&config.current_link_name)?;
+    let ctx = DeployContext {
+        config,
+        repo_path,
+        git,
+        commit_hash,
+        version,
+    };
+
+    if let Some(script) = &config.pre_deploy {
+        run_deploy_script("pre_deploy", script, repo_path, commit_hash)?;
+    }
+    log::debug!("Deploying with the {} backend", backend.name());
+    let delay = std::time::Duration::from_secs(config.retry_delay_secs);
+    let output = with_retries(config.retries, delay, || backend.deploy(&ctx))?;
+    if let Some(script) = &config.post_deploy {
+        run_deploy_script("post_deploy", script, repo_path, commit_hash)?;
+    }
+    Ok(output)
+}
+
+/// Run a `pre_deploy` / `post_deploy` script with `PLOOP_COMMIT` set
+fn run_deploy_script(name: &str, script: &str, repo_path: &str, commit_hash: &str) -> Result<(), Box<dyn std::error::Error>> {
+    log::info!("Running {} script", name);
+    let output = runner::run_inline_script(script, repo_path, &[("PLOOP_COMMIT", commit_hash)])?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        log::error!("{} script failed: {}", name, stderr);
+        return Err(format!("{} script failed ({}): {}", name, output.status, stderr.trim()).into());
+    }
+    Ok(())
+}
+
+/// What a [`DeployBackend`] deploys: the settings, the repository (and the
+/// git settings to query it with) and the commit
+pub struct DeployContext<'a> {
+    pub config: &'a DeployConfig,
+    pub repo_path: &'a str,
+    pub git: &'a GitConfig,
+    pub commit_hash: &'a str,
+    /// Name for the main target's version directory, when already chosen
+    pub version: Option<&'a str>,
+}
+
+/// One way of deploying a commit (a command, an SFTP upload, local file targets)
+///
+/// [`deploy`] picks the built-in backend the config asks for; an embedder can
+/// pass its own to [`deploy_with_backend`].
+pub trait DeployBackend {
+    /// Short name for logs, e.g. `command`
+    fn name(&self) -> &'static str;
+
+    /// Deploy `ctx.commit_hash`, returning output worth keeping with the
+    /// history record (a deploy command's output)
+    fn deploy(&self, ctx: &DeployContext) -> Result<Option<String>, Box<dyn std::error::Error>>;
+
+    /// Return to the version deployed before the current one, returning the
+    /// restored version(s)
+    fn rollback(&self, ctx: &DeployContext) -> Result<Vec<String>, Box<dyn std::error::Error>>;
+}
+
+/// The backend for `config`: `deploy.command`, then `deploy.sftp`, then the
+/// local file targets
+pub fn select_backend(config: &DeployConfig) -> Result<Box<dyn DeployBackend>, Box<dyn std::error::Error>> {
+    if config.command.is_some() {
+        return Ok(Box::new(CommandBackend));
+    }
+    if config.sftp.is_some() {
+        return Ok(Box::new(SftpBackend));
+    }
+    if !config.file_targets().is_empty() {
+        return Ok(Box::new(FileBackend));
+    }
+    Err("No deployment method configured (neither command nor target_dir/artifacts)".into())
+}
+
+/// Runs `deploy.command` (process deployment)
+pub struct CommandBackend;
+
+impl DeployBackend for CommandBackend {
+    fn name(&self) -> &'static str {
+        "command"
+    }
+
+    fn deploy(&self, ctx: &DeployContext) -> Result<Option<String>, Box<dyn std::error::Error>> {
+        let command = ctx.config.command.as_deref().ok_or("Command deployment needs deploy.command")?;
+        let working_dir = builder::resolve_working_dir(ctx.repo_path, ctx.config.working_dir.as_deref())?;
+        deploy_with_command(command, &working_dir, ctx.config.use_shell).map(Some)
+    }
+
+    fn rollback(&self, _ctx: &DeployContext) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+        Err("Command deploys keep no versions to roll back to".into())
+    }
+}
+
+/// Uploads artifacts into versioned directories on a remote host (`deploy.sftp`)
+pub struct SftpBackend;
+
+impl DeployBackend for SftpBackend {
+    fn name(&self) -> &'static str {
+        "sftp"
+    }
+
+    fn deploy(&self, ctx: &DeployContext) -> Result<Option<String>, Box<dyn std::error::Error>> {
+        let config = ctx.config;
+        let sftp_config = config.sftp.as_ref().ok_or("SFTP deployment needs deploy.sftp")?;
+        let arts = config.artifacts.as_deref().ok_or("SFTP deployment needs deploy.artifacts")?;
+        let arts = &render_dest_templates(arts, ctx.commit_hash);
+        let version = match ctx.version {
+            Some(version) => version.to_string(),
+            None => sftp_version_name(config.version_scheme, ctx.commit_hash),
+        };
+        #[cfg(feature = "sftp")]
+        return crate::sftp::deploy_with_sftp(
+            arts,
+            sftp_config,
+            &config.artifact_base_dir(ctx.repo_path),
+            &version,
+            &config.current_link_name,
+            ctx.commit_hash,
+        )
+        .map(|()| None);
+        #[cfg(not(feature = "sftp"))]
+        {
+            let _ = (arts, sftp_config, version);
+            Err(SFTP_UNAVAILABLE.into())
+        }
+    }
+
+    fn rollback(&self, ctx: &DeployContext) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+        let sftp_config = ctx.config.sftp.as_ref().ok_or("SFTP rollback needs deploy.sftp")?;
+        #[cfg(feature = "sftp")]
+        return crate::sftp::rollback_to_previous(sftp_config, &ctx.config.current_link_name).map(|version| vec![version]);
+        #[cfg(not(feature = "sftp"))]
+        {
+            let _ = sftp_config;
+            Err(SFTP_UNAVAILABLE.into())
+        }
+    }
+}
+
+/// Version directory name for an SFTP deploy
+fn sftp_version_name(scheme: VersionScheme, commit_hash: &str) -> String {
+    let short_hash: String = commit_hash.chars().take(7).collect();
+    // Remote directories are not listed up front, so timestamps are not
+    // suffixed against collisions; Config::validate rejects "counter"
+    match scheme {
+        VersionScheme::FullHash => commit_hash.to_string(),
+        VersionScheme::HashTimestamp => timestamped_name(&short_hash),
+        VersionScheme::ShortHash | VersionScheme::Counter => short_hash,
+    }
+}
+
+#[cfg(not(feature = "sftp"))]
+const SFTP_UNAVAILABLE: &str = "SFTP deployment requires ploop to be built with the `sftp` feature";
+
+/// Copies artifacts into the local main target, then any `[[deploy.targets]]`
+pub struct FileBackend;
+
+impl DeployBackend for FileBackend {
+    fn name(&self) -> &'static str {
+        "files"
+    }
+
+    fn deploy(&self, ctx: &DeployContext) -> Result<Option<String>, Box<dyn std::error::Error>> {
+        let config = ctx.config;
+        let mut deployed = false;
+        if let (Some(arts), Some(target)) = (config.artifacts.as_deref(), config.target_dir.as_deref()) {
+            deploy_file_target(config, arts, target, ctx, ctx.version, config.canary.as_ref())?;
+            deployed = true;
+        }
+        for target in &config.targets {
+            log::info!("Deploying target '{}'", target.name);
+            deploy_file_target(config, &target.artifacts, &target.target_dir, ctx, None, None)
+                .map_err(|e| TargetError {
+                    name: target.name.clone(),
+                    source: e,
+                })?;
+            deployed = true;
+        }
+
+        if deployed {
+            return Ok(None);
+        }
+        Err("No deployment method configured (neither command nor target_dir/artifacts)".into())
+    }
+
+    fn rollback(&self, ctx: &DeployContext) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+        let mut restored = Vec::new();
+        for (name, target_dir) in ctx.config.file_targets() {
+            let result = rollback::rollback_to_previous(target_dir, &ctx.config.current_link_name).map_err(|e| TargetError {
+                name: name.to_string(),
+                source: e,
+            })?;
+            restored.push(result.to);
+        }
+        Ok(restored)
+    }
+}
+
+/// Deploy one local file target, versioned (optionally via a canary) or bare
+///
+/// `version` is the name chosen up front for this target, if any.
+fn deploy_file_target(
+    config: &DeployConfig,
+    artifacts: &[ArtifactSpec],
+    target_dir: &str,
+    ctx: &DeployContext,
+    version: Option<&str>,
+    canary: Option<&CanaryConfig>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (repo_path, git, commit_hash) = (ctx.repo_path, ctx.git, ctx.commit_hash);
+    // The version name may not be the commit (e.g. counter schemes), so
+    // templates are filled in here, where the commit is known
+    let artifacts = &render_dest_templates(artifacts, commit_hash);
+    if !config.versioned {
+        if canary.is_some() {
+            return Err("deploy.canary requires versioned deploys".into());
+        }
+        return deploy_to_bare_target(artifacts, target_dir, repo_path, config);
+    }
+
+    let version = match version {
+        Some(version) => version.to_string(),
+        None => target_version_name(config, target_dir, repo_path, git, commit_hash)?,
+    };
+    if version == config.current_link_name {
+        return Err(format!("Version {} would replace the {} symlink", version, config.current_link_name).into());
+    }
+    runner::set_command_env("PLOOP_DEPLOY_VERSION", &version);
+    match canary {
+        Some(canary) => deploy_with_canary(artifacts, canary, target_dir, repo_path, git, &version, config),
+        None => deploy_with_files(artifacts, target_dir, repo_path, git, &version, config),
+    }
+}
+
+/// Check the deploy configuration before building, returning every problem found
+pub fn preflight(config: &Config) -> Vec<String> {
+    let mut problems = Vec::new();
+
+    for (_, target_dir) in config.deploy.file_targets() {
+        if let Err(e) = check_dir_writable(Path::new(target_dir)) {
+            problems.push(format!("Target directory {} is not writable: {}", target_dir, e));
+        }
+    }
+
+    if let Some(command) = &config.deploy.command {
+        // In shell mode this checks for the shell itself
+        match runner::shell_command(command, config.deploy.use_shell) {
+            Some(process) => {
+                let program = process.get_program().to_string_lossy();
+                if find_in_path(&program).is_none() {
+                    problems.push(format!("Deploy command not found on PATH: {}", program));
+                }
+            }
+            None => problems.push("Deploy command is empty".to_string()),
+        }
+    }
+
+    problems
+}
+
+/// Ensure a directory exists (creating it if needed) and that files can be written to it
+fn check_dir_writable(dir: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    fs::create_dir_all(dir)?;
+    let probe = dir.join(format!(".postloop-write-test-{}", std::process::id()));
+    fs::write(&probe, b"")?;
+    fs::remove_file(&probe)?;
+    Ok(())
+}
+
+/// Resolve a program name against PATH (paths containing a separator are checked as-is)
+pub fn find_in_path(program: &str) -> Option<PathBuf> {
+    let candidate = Path::new(program);
+    if candidate.components().count() > 1 {
+        return candidate.is_file().then(|| candidate.to_path_buf());
+    }
+
+    let path_var = std::env::var_os("PATH")?;
+    std::env::split_paths(&path_var)
+        .map(|dir| dir.join(program))
+        .find(|path| path.is_file())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_deploy_with_echo() {
+        let result = deploy_with_command("echo deployed", ".", false);
+        assert_eq!(result.unwrap(), "deployed\n");
+    }
+
+    #[test]
+    fn test_render_target_template() {
+        assert_eq!(
+            render_target_template("/opt/deploy/preview/{branch}", "feature/login form", "abc"),
+            "/opt/deploy/preview/feature-login-form"
+        );
+        assert_eq!(
+            render_target_template("/srv/{branch}/{commit}", "main", "0123456789abcdef"),
+            "/srv/main/0123456"
+        );
+        assert_eq!(render_target_template("/opt/deploy/prod", "main", "abc"), "/opt/deploy/prod");
+    }
+
+    #[test]
+    fn test_truncate_output_keeps_tail() {
+        assert_eq!(truncate_output("short", 10), "short");
+        let truncated = truncate_output("0123456789abcdef", 6);
+        assert_eq!(truncated, "[... 10 bytes truncated ...]\nabcdef");
+        // Never splits a multi-byte character
+        assert!(truncate_output("ééé", 3).ends_with("é"));
+    }
+
+    #[test]
+    fn test_preflight_missing_command() {
+        let mut config = Config::default();
+        config.deploy.target_dir = None;
+        config.deploy.command = Some("postloop-no-such-binary --now".to_string());
+
+        let problems = preflight(&config);
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].contains("postloop-no-such-binary"));
+
+        config.deploy.command = Some("echo deployed".to_string());
+        assert!(preflight(&config).is_empty());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_preflight_read_only_target() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = std::env::temp_dir().join(format!("postloop-ro-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::set_permissions(&dir, fs::Permissions::from_mode(0o555)).unwrap();
+
+        let mut config = Config::default();
+        config.deploy.target_dir = Some(dir.join("deploy").to_str().unwrap().to_string());
+
+        // Privileged users bypass permission bits, so only assert when the check is meaningful
+        if fs::write(dir.join("probe"), b"").is_err() {
+            let problems = preflight(&config);
+            assert_eq!(problems.len(), 1);
+            assert!(problems[0].contains("not writable"));
+        }
+
+        fs::set_permissions(&dir, fs::Permissions::from_mode(0o755)).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_preflight_uncreatable_target() {
+        let file = std::env::temp_dir().join(format!("postloop-file-{}", uuid::Uuid::new_v4()));
+        fs::write(&file, b"").unwrap();
+
+        let mut config = Config::default();
+        config.deploy.target_dir = Some(file.join("deploy").to_str().unwrap().to_string());
+
+        assert_eq!(preflight(&config).len(), 1);
+        fs::remove_file(&file).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_deploy_with_files_glob() {
+        let root = std::env::temp_dir().join(format!("postloop-deploy-{}", uuid::Uuid::new_v4()));
+        let repo = root.join("repo");
+        let target = root.join("target");
+        fs::create_dir_all(repo.join("out")).unwrap();
+        fs::write(repo.join("out/a.so"), "a").unwrap();
+        fs::write(repo.join("out/b.so"), "b").unwrap();
+
+        deploy_with_files(
+            &[ArtifactSpec::from("out/*.so")],
+            target.to_str().unwrap(),
+            repo.to_str().unwrap(),
+            &Default::default(),
+            "abc1234",
+            &Config::default().deploy,
+        )
+        .unwrap();
+
+        assert!(target.join("current/a.so").exists());
+        assert!(target.join("current/b.so").exists());
+
+        let missing = deploy_with_files(
+            &[ArtifactSpec::from("out/*.dll")],
+            target.to_str().unwrap(),
+            repo.to_str().unwrap(),
+            &Default::default(),
+            "def5678",
+            &Config::default().deploy,
+        );
+        assert!(missing.is_err());
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_deploy_without_current_symlink() {
+        let root = std::env::temp_dir().join(format!("postloop-deploy-{}", uuid::Uuid::new_v4()));
+        let repo = root.join("repo");
+        let target = root.join("www");
+        let target_str = target.to_str().unwrap();
+        fs::create_dir_all(&repo).unwrap();
+
+        let config = DeployConfig {
+            target_dir: Some(target_str.to_string()),
+            artifacts: Some(vec![ArtifactSpec::from("index.html")]),
+            create_current_symlink: false,
+            ..Config::default().deploy
+        };
+
+        fs::write(repo.join("index.html"), "v1").unwrap();
+        deploy(&config, repo.to_str().unwrap(), &Default::default(), "abc1234", None).unwrap();
+        fs::write(repo.join("index.html"), "v2").unwrap();
+        deploy(&config, repo.to_str().unwrap(), &Default::default(), "def5678", None).unwrap();
+
+        assert!(fs::symlink_metadata(target.join("current")).is_err());
+        assert_eq!(crate::rollback::current_version(target_str, rollback::DEFAULT_CURRENT_LINK).as_deref(), Some("def5678"));
+
+        let rolled_back = crate::rollback::rollback_to_previous(target_str, rollback::DEFAULT_CURRENT_LINK).unwrap();
+        assert_eq!(rolled_back.to, "abc1234");
+        assert!(fs::symlink_metadata(target.join("current")).is_err());
+        assert_eq!(
+            fs::read_to_string(target.join(crate::rollback::POINTER_FILE)).unwrap().trim(),
+            "abc1234"
+        );
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_bundle_artifacts() {
+        let root = std::env::temp_dir().join(format!("postloop-bundle-{}", uuid::Uuid::new_v4()));
+        let repo = root.join("repo");
+        let target = root.join("target");
+        fs::create_dir_all(repo.join("dist/assets")).unwrap();
+        fs::write(repo.join("app"), "binary").unwrap();
+        fs::write(repo.join("dist/index.html"), "<html>").unwrap();
+        fs::write(repo.join("dist/assets/app.js"), "js").unwrap();
+        fs::write(repo.join("dist/assets/app.js.map"), "map").unwrap();
+
+        for format in [BundleFormat::TarGz, BundleFormat::Zip] {
+            let options = DeployConfig {
+                bundle: Some(format),
+                exclude: vec!["*.map".to_string()],
+                ..Config::default().deploy
+            };
+            let version = format!("abc1234-{}", format.extension());
+            let versioned_dir = stage_version(
+                &[ArtifactSpec::from("app"), ArtifactSpec::from("dist")],
+                target.to_str().unwrap(),
+                repo.to_str().unwrap(),
+                &Default::default(),
+                &version,
+                &options,
+            )
+            .unwrap();
+
+            let bundle = bundle_path(&versioned_dir, &version, format);
+            assert!(bundle.ends_with(format!("release-{}.{}", version, format.extension())));
+            assert!(!versioned_dir.join("app").exists());
+            assert!(!versioned_dir.join(".bundle").exists());
+
+            let names: Vec<String> = list_bundle(&bundle, format)
+                .unwrap()
+                .into_iter()
+                .map(|name| name.trim_end_matches('/').to_string())
+                .collect();
+            assert_eq!(names, ["app", "dist", "dist/assets", "dist/assets/app.js", "dist/index.html"]);
+
+            fs::write(&bundle, "not an archive").unwrap();
+            assert!(list_bundle(&bundle, format).is_err());
+        }
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_failed_copy_leaves_no_partial_version() {
+        let root = std::env::temp_dir().join(format!("postloop-deploy-{}", uuid::Uuid::new_v4()));
+        let repo = root.join("repo");
+        let target = root.join("target");
+        let target_str = target.to_str().unwrap();
+        fs::create_dir_all(repo.join("dist")).unwrap();
+        fs::write(repo.join("app"), "binary").unwrap();
+        fs::write(repo.join("dist/index.html"), "<html>").unwrap();
+        let artifacts = [ArtifactSpec::from("app"), ArtifactSpec::from("dist")];
+        let options = &Config::default().deploy;
+
+        deploy_with_files(&artifacts, target_str, repo.to_str().unwrap(), &Default::default(), "abc1234", options).unwrap();
+
+        // A dangling symlink makes the directory copy fail after 'app' was copied
+        std::os::unix::fs::symlink(root.join("missing"), repo.join("dist/broken")).unwrap();
+        for version in ["def5678", "abc1234"] {
+            assert!(deploy_with_files(&artifacts, target_str, repo.to_str().unwrap(), &Default::default(), version, options).is_err());
+        }
+
+        assert_eq!(crate::rollback::get_deployed_versions(target_str, rollback::DEFAULT_CURRENT_LINK).unwrap(), ["abc1234"]);
+        assert!(!target.join("def5678").exists());
+        let hidden: Vec<_> = fs::read_dir(&target)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .filter(|name| name.starts_with('.'))
+            .collect();
+        assert!(hidden.is_empty(), "leftover staging dirs: {:?}", hidden);
+        // The failed redeploy of the current version left it intact
+        assert_eq!(fs::read_to_string(target.join("current/dist/index.html")).unwrap(), "<html>");
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_artifacts_resolved_from_base_outside_repo() {
+        let root = std::env::temp_dir().join(format!("postloop-deploy-{}", uuid::Uuid::new_v4()));
+        let repo = root.join("repo");
+        let cargo_target = root.join("cargo-target");
+        let target = root.join("www");
+        fs::create_dir_all(&repo).unwrap();
+        fs::create_dir_all(cargo_target.join("release")).unwrap();
+        fs::write(cargo_target.join("release/app"), "binary").unwrap();
+        fs::write(cargo_target.join("release/libapp.so"), "lib").unwrap();
+
+        let artifacts = vec![
+            ArtifactSpec::from("release/app"),
+            ArtifactSpec::from(format!("{}/release/*.so", cargo_target.display()).as_str()),
+        ];
+        let config = DeployConfig {
+            target_dir: Some(target.to_str().unwrap().to_string()),
+            artifacts: Some(artifacts.clone()),
+            artifact_base: Some(cargo_target.to_str().unwrap().to_string()),
+            ..Config::default().deploy
+        };
+        let base = config.artifact_base_dir(repo.to_str().unwrap());
+        assert_eq!(base, cargo_target.to_str().unwrap());
+
+        builder::verify_artifacts(&artifacts, &base).unwrap();
+        assert!(builder::verify_artifacts(&artifacts, repo.to_str().unwrap()).is_err());
+
+        deploy(&config, repo.to_str().unwrap(), &Default::default(), "abc1234", None).unwrap();
+        assert_eq!(fs::read_to_string(target.join("current/app")).unwrap(), "binary");
+        assert!(target.join("current/libapp.so").exists());
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_same_commit_from_two_branches_gets_two_versions() {
+        let repo = crate::test_support::init_repo();
+        let commit = crate::test_support::commit_file(&repo, "README");
+        let repo_str = repo.to_str().unwrap();
+        let target = repo.join("deploy");
+        let target_str = target.to_str().unwrap();
+        let config = DeployConfig {
+            target_dir: Some(target_str.to_string()),
+            artifacts: Some(vec![ArtifactSpec::from("app")]),
+            ..Config::default().deploy
+        };
+
+        fs::write(repo.join("app"), "built on main").unwrap();
+        deploy(&config, repo_str, &Default::default(), &commit, None).unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        crate::test_support::git(&repo, &["checkout", "-qb", "release/1.0"]);
+        fs::write(repo.join("app"), "built on release").unwrap();
+        deploy(&config, repo_str, &Default::default(), &commit, None).unwrap();
+
+        let short = &commit[..7];
+        let release_version = format!("{}-release-1.0", short);
+        assert_eq!(rollback::get_deployed_versions(target_str, rollback::DEFAULT_CURRENT_LINK).unwrap(), vec![release_version.clone(), short.to_string()]);
+        let main_meta = rollback::read_version_meta(target_str, short).unwrap();
+        let release_meta = rollback::read_version_meta(target_str, &release_version).unwrap();
+        assert_eq!((main_meta.commit.as_deref(), main_meta.branch.as_deref()), (Some(commit.as_str()), Some("main")));
+        assert_eq!((release_meta.commit.as_deref(), release_meta.branch.as_deref()), (Some(commit.as_str()), Some("release/1.0")));
+        assert_eq!(fs::read_to_string(target.join(short).join("app")).unwrap(), "built on main");
+
+        // Redeploying from the same branch still replaces its own version
+        fs::write(repo.join("app"), "rebuilt on release").unwrap();
+        deploy(&config, repo_str, &Default::default(), &commit, None).unwrap();
+        assert_eq!(rollback::get_deployed_versions(target_str, rollback::DEFAULT_CURRENT_LINK).unwrap().len(), 2);
+        fs::remove_dir_all(&repo).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_deploy_invalidates_redo_stack() {
+        let root = std::env::temp_dir().join(format!("postloop-deploy-{}", uuid::Uuid::new_v4()));
+        let repo = root.join("repo");
+        let target = root.join("www");
+        let target_str = target.to_str().unwrap();
+        let repo_str = repo.to_str().unwrap();
+        fs::create_dir_all(&repo).unwrap();
+        fs::write(repo.join("app"), "v1").unwrap();
+        let config = DeployConfig {
+            target_dir: Some(target_str.to_string()),
+            artifacts: Some(vec![ArtifactSpec::from("app")]),
+            ..Config::default().deploy
+        };
+        let link = &config.current_link_name;
+
+        deploy(&config, repo_str, &Default::default(), "aaa1111", None).unwrap();
+        let first = rollback::current_version(target_str, link).unwrap();
+        fs::write(repo.join("app"), "v2").unwrap();
+        deploy(&config, repo_str, &Default::default(), "bbb2222", None).unwrap();
+        let second = rollback::current_version(target_str, link).unwrap();
+
+        rollback::rollback_to_version(target_str, link, &first).unwrap();
+        assert_eq!(rollback::redo_rollback(target_str, link).unwrap(), second);
+
+        // A deploy after a rollback drops the redo stack
+        rollback::rollback_to_version(target_str, link, &first).unwrap();
+        fs::write(repo.join("app"), "v3").unwrap();
+        deploy(&config, repo_str, &Default::default(), "ccc3333", None).unwrap();
+        assert!(rollback::redo_rollback(target_str, link).is_err());
+        assert_eq!(fs::read_to_string(target.join(link).join("app")).unwrap(), "v3");
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_identical_redeploy_is_detected() {
+        let root = std::env::temp_dir().join(format!("postloop-deploy-{}", uuid::Uuid::new_v4()));
+        let repo = root.join("repo");
+        let target = root.join("www");
+        let target_str = target.to_str().unwrap();
+        let repo_str = repo.to_str().unwrap();
+        fs::create_dir_all(repo.join("dist")).unwrap();
+        fs::write(repo.join("app"), "binary").unwrap();
+        fs::write(repo.join("dist/index.html"), "<html>").unwrap();
+        fs::write(repo.join("dist/debug.log"), "noise").unwrap();
+
+        let mut config = DeployConfig {
+            target_dir: Some(target_str.to_string()),
+            artifacts: Some(vec![ArtifactSpec::from("app"), ArtifactSpec::from("dist")]),
+            exclude: vec!["*.log".to_string()],
+            skip_unchanged: true,
+            ..Config::default().deploy
+        };
+        assert!(!nothing_to_deploy(&config, repo_str, "abc1234").unwrap());
+        deploy(&config, repo_str, &Default::default(), "abc1234", None).unwrap();
+
+        // Excluded files do not count as changes
+        fs::write(repo.join("dist/debug.log"), "more noise").unwrap();
+        assert!(nothing_to_deploy(&config, repo_str, "def5678").unwrap());
+
+        config.skip_unchanged = false;
+        assert!(!nothing_to_deploy(&config, repo_str, "def5678").unwrap());
+
+        config.skip_unchanged = true;
+        fs::write(repo.join("dist/index.html"), "<html>v2").unwrap();
+        assert!(!nothing_to_deploy(&config, repo_str, "0123abc").unwrap());
+
+        // Every target has to be unchanged
+        deploy(&config, repo_str, &Default::default(), "0123abc", None).unwrap();
+        assert!(nothing_to_deploy(&config, repo_str, "0123abc").unwrap());
+        config.targets.push(crate::config::DeployTarget {
+            name: "mirror".to_string(),
+            target_dir: root.join("mirror").to_str().unwrap().to_string(),
+            artifacts: vec![ArtifactSpec::from("app")],
+            keep_versions: None,
+        });
+        assert!(!nothing_to_deploy(&config, repo_str, "0123abc").unwrap());
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_precompress_writes_gz_siblings() {
+        use std::io::Read;
+
+        let root = std::env::temp_dir().join(format!("postloop-deploy-{}", uuid::Uuid::new_v4()));
+        let repo = root.join("repo");
+        let target = root.join("www");
+        let target_str = target.to_str().unwrap();
+        fs::create_dir_all(repo.join("dist/assets")).unwrap();
+        let html = "<html><body>hello</body></html>\n".repeat(50);
+        fs::write(repo.join("dist/index.html"), &html).unwrap();
+        fs::write(repo.join("dist/assets/app.css"), "body { margin: 0 }").unwrap();
+        fs::write(repo.join("dist/assets/logo.png"), "not really a png").unwrap();
+        fs::write(repo.join("dist/readme.txt"), "plain").unwrap();
+        fs::write(repo.join("dist/assets/vendor.js"), "let vendor = 1;").unwrap();
+        fs::write(repo.join("dist/assets/vendor.js.gz"), "shipped").unwrap();
+
+        let config = DeployConfig {
+            target_dir: Some(target_str.to_string()),
+            artifacts: Some(vec![ArtifactSpec::from("dist")]),
+            precompress: vec!["*.html".to_string(), "dist/assets/*".to_string()],
+            ..Config::default().deploy
+        };
+        deploy(&config, repo.to_str().unwrap(), &Default::default(), "abc1234", None).unwrap();
+
+        let dist = target.join("abc1234/dist");
+        let mut decoded = String::new();
+        flate2::read::GzDecoder::new(fs::File::open(dist.join("index.html.gz")).unwrap())
+            .read_to_string(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, html);
+        assert!(fs::metadata(dist.join("index.html.gz")).unwrap().len() < html.len() as u64);
+        assert!(dist.join("assets/app.css.gz").exists());
+        // Already compressed formats and non-matching files get no sibling
+        assert!(!dist.join("assets/logo.png.gz").exists());
+        assert!(!dist.join("readme.txt.gz").exists());
+        // A .gz the artifacts ship is not overwritten
+        assert_eq!(fs::read_to_string(dist.join("assets/vendor.js.gz")).unwrap(), "shipped");
+
+        // The siblings don't make an identical redeploy look changed
+        let config = DeployConfig {
+            skip_unchanged: true,
+            ..config
+        };
+        assert!(nothing_to_deploy(&config, repo.to_str().unwrap(), "def5678").unwrap());
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_with_retries_retries_only_transient_errors() {
+        let mut calls = 0;
+        let result = with_retries(2, std::time::Duration::ZERO, || {
+            calls += 1;
+            if calls == 1 {
+                let unavailable = std::io::Error::new(std::io::ErrorKind::StaleNetworkFileHandle, "mount unavailable");
+                return Err(TargetError {
+                    name: "nfs".to_string(),
+                    source: unavailable.into(),
+                }
+                .into());
+            }
+            Ok(calls)
+        });
+        assert_eq!(result.unwrap(), 2);
+
+        let mut calls = 0;
+        let result: Result<(), _> = with_retries(2, std::time::Duration::ZERO, || {
+            calls += 1;
+            Err("No deployment method configured (neither command nor target_dir/artifacts)".into())
+        });
+        assert!(result.is_err());
+        assert_eq!(calls, 1);
+
+        let mut calls = 0;
+        let result: Result<(), _> = with_retries(1, std::time::Duration::ZERO, || {
+            calls += 1;
+            Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "still down").into())
+        });
+        assert_eq!(result.unwrap_err().to_string(), "still down");
+        assert_eq!(calls, 2);
+
+        // A missing artifact or a permission problem won't fix itself
+        for kind in [std::io::ErrorKind::NotFound, std::io::ErrorKind::PermissionDenied] {
+            let mut calls = 0;
+            let result: Result<(), _> = with_retries(2, std::time::Duration::ZERO, || {
+                calls += 1;
+                Err(std::io::Error::from(kind).into())
+            });
+            assert!(result.is_err());
+            assert_eq!(calls, 1, "{:?}", kind);
+        }
+    }
+
+    #[test]
+    fn test_deploy_unversioned_backs_up_previous_contents() {
+        let root = std::env::temp_dir().join(format!("postloop-deploy-{}", uuid::Uuid::new_v4()));
+        let repo = root.join("repo");
+        let target = root.join("www");
+        fs::create_dir_all(&repo).unwrap();
+
+        let mut config = DeployConfig {
+            target_dir: Some(target.to_str().unwrap().to_string()),
+            artifacts: Some(vec![ArtifactSpec::from("index.html")]),
+            versioned: false,
+            ..Config::default().deploy
+        };
+
+        fs::write(repo.join("index.html"), "v1").unwrap();
+        fs::write(repo.join("old.html"), "v1").unwrap();
+        config.artifacts = Some(vec![ArtifactSpec::from("index.html"), ArtifactSpec::from("old.html")]);
+        deploy(&config, repo.to_str().unwrap(), &Default::default(), "abc1234", None).unwrap();
+        fs::write(repo.join("index.html"), "v2").unwrap();
+        config.artifacts = Some(vec![ArtifactSpec::from("index.html")]);
+        deploy(&config, repo.to_str().unwrap(), &Default::default(), "def5678", None).unwrap();
+
+        assert_eq!(fs::read_to_string(target.join("index.html")).unwrap(), "v2");
+        assert!(!target.join("old.html").exists());
+        assert!(!target.join("current").exists());
+
+        crate::rollback::restore_backup(target.to_str().unwrap()).unwrap();
+        assert_eq!(fs::read_to_string(target.join("index.html")).unwrap(), "v1");
+        assert!(target.join("old.html").exists());
+
+        config.versioned = true;
+        deploy(&config, repo.to_str().unwrap(), &Default::default(), "def5678", None).unwrap();
+        assert!(target.join("def5678/index.html").exists());
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_version_dir_name_avoids_collisions() {
+        let target = std::env::temp_dir().join(format!("postloop-names-{}", uuid::Uuid::new_v4()));
+        let target_str = target.to_str().unwrap();
+        let hash = "abc1234def5678abc1234def5678abc1234def56";
+
+        assert_eq!(version_dir_name(VersionScheme::ShortHash, hash, target_str).unwrap(), "abc1234");
+        assert_eq!(version_dir_name(VersionScheme::FullHash, hash, target_str).unwrap(), hash);
+
+        let first = version_dir_name(VersionScheme::HashTimestamp, hash, target_str).unwrap();
+        fs::create_dir_all(target.join(&first)).unwrap();
+        let second = version_dir_name(VersionScheme::HashTimestamp, hash, target_str).unwrap();
+        assert!(first.starts_with("abc1234-"));
+        assert_ne!(first, second);
+
+        assert_eq!(version_dir_name(VersionScheme::Counter, hash, target_str).unwrap(), "1-abc1234");
+        fs::create_dir_all(target.join("1-abc1234")).unwrap();
+        fs::create_dir_all(target.join("2-abc1234")).unwrap();
+        assert_eq!(version_dir_name(VersionScheme::Counter, hash, target_str).unwrap(), "3-abc1234");
+        fs::remove_dir_all(&target).unwrap();
+    }
+
+    #[test]
+    fn test_sftp_version_name_follows_scheme() {
+        let hash = "abc1234def5678abc1234def5678abc1234def56";
+        assert_eq!(sftp_version_name(VersionScheme::ShortHash, hash), "abc1234");
+        assert_eq!(sftp_version_name(VersionScheme::FullHash, hash), hash);
+
+        let timestamped = sftp_version_name(VersionScheme::HashTimestamp, hash);
+        let (short, stamp) = timestamped.split_once('-').unwrap();
+        assert_eq!(short, "abc1234");
+        assert!(stamp.len() == 14 && stamp.chars().all(|c| c.is_ascii_digit()), "{}", timestamped);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_deploy_directory_artifact_with_excludes() {
+        let root = std::env::temp_dir().join(format!("postloop-deploy-{}", uuid::Uuid::new_v4()));
+        let repo = root.join("repo");
+        let target = root.join("target");
+        fs::create_dir_all(repo.join("dist/js")).unwrap();
+        fs::create_dir_all(repo.join("dist/node_modules/dep")).unwrap();
+        fs::write(repo.join("dist/index.html"), "index").unwrap();
+        fs::write(repo.join("dist/js/app.js"), "app").unwrap();
+        fs::write(repo.join("dist/js/app.js.map"), "map").unwrap();
+        fs::write(repo.join("dist/node_modules/dep/index.js"), "dep").unwrap();
+
+        let artifact = ArtifactSpec::Detailed(crate::config::ArtifactEntry {
+            path: "dist".to_string(),
+            optional: false,
+            exclude: vec!["node_modules".to_string()],
+            min_size: None,
+            executable: false,
+            dest: None,
+        });
+        let options = DeployConfig {
+            exclude: vec!["*.map".to_string()],
+            ..Config::default().deploy
+        };
+
+        deploy_with_files(&[artifact], target.to_str().unwrap(), repo.to_str().unwrap(), &Default::default(), "abc1234", &options)
+            .unwrap();
+
+        let deployed = target.join("abc1234/dist");
+        assert!(deployed.join("index.html").exists());
+        assert!(deployed.join("js/app.js").exists());
+        assert!(!deployed.join("js/app.js.map").exists());
+        assert!(!deployed.join("node_modules").exists());
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_parallel_copy() {
+        let root = crate::test_support::temp_dir("parallel");
+        let repo = root.join("repo");
+        let dest = root.join("dest");
+        fs::create_dir_all(&repo).unwrap();
+        fs::create_dir_all(&dest).unwrap();
+        let specs: Vec<ArtifactSpec> = (0..12)
+            .map(|i| {
+                fs::write(repo.join(format!("f{}", i)), i.to_string()).unwrap();
+                ArtifactSpec::Path(format!("f{}", i))
+            })
+            .collect();
+        let resolved = builder::resolve_artifacts(&specs, repo.to_str().unwrap()).unwrap();
+        let options = DeployConfig {
+            copy_parallelism: 4,
+            ..Config::default().deploy
+        };
+
+        copy_artifacts(&resolved, &dest, None, &options).unwrap();
+        for i in 0..12 {
+            assert_eq!(fs::read_to_string(dest.join(format!("f{}", i))).unwrap(), i.to_string());
+        }
+
+        // A directory in the way makes one copy fail, which must surface
+        let failing = root.join("failing");
+        fs::create_dir_all(failing.join("f5")).unwrap();
+        let err = copy_artifacts(&resolved, &failing, None, &options).unwrap_err();
+        assert!(err.to_string().contains("f5"));
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_dedup_hardlinks_unchanged_artifacts() {
+        use std::os::unix::fs::MetadataExt;
+
+        let root = crate::test_support::temp_dir("dedup");
+        let repo = root.join("repo");
+        let target = root.join("target");
+        fs::create_dir_all(&repo).unwrap();
+        fs::write(repo.join("assets.css"), "body {}").unwrap();
+        fs::write(repo.join("app"), "v1").unwrap();
+
+        let artifacts = vec![ArtifactSpec::from("assets.css"), ArtifactSpec::from("app")];
+        let options = DeployConfig {
+            dedup: true,
+            ..Config::default().deploy
+        };
+        let (target_str, repo_str) = (target.to_str().unwrap(), repo.to_str().unwrap());
+
+        deploy_with_files(&artifacts, target_str, repo_str, &Default::default(), "v1", &options).unwrap();
+        fs::write(repo.join("app"), "v2").unwrap();
+        deploy_with_files(&artifacts, target_str, repo_str, &Default::default(), "v2", &options).unwrap();
+
+        let inode = |version: &str, file: &str| fs::metadata(target.join(version).join(file)).unwrap().ino();
+        assert_eq!(inode("v1", "assets.css"), inode("v2", "assets.css"));
+        assert_ne!(inode("v1", "app"), inode("v2", "app"));
+        assert_eq!(fs::read_to_string(target.join("v2/app")).unwrap(), "v2");
+
+        let meta = rollback::read_version_meta(target_str, "v2").unwrap();
+        assert_eq!(meta.sequence, Some(2));
+        assert_eq!(meta.commit, None);
+        assert_eq!(meta.checksums.keys().collect::<Vec<_>>(), vec!["app", "assets.css"]);
+        assert_eq!(meta.checksums["app"], file_checksum(&repo.join("app")).unwrap());
+        assert!(meta.user.is_some_and(|user| !user.is_empty()));
+        assert!(meta.host.is_some_and(|host| !host.is_empty()));
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_incremental_links_artifacts_untouched_since_previous_commit() {
+        use crate::test_support::{commit_file, git, init_repo};
+        use std::os::unix::fs::MetadataExt;
+
+        let repo = init_repo();
+        commit_file(&repo, "static/logo.svg");
+        commit_file(&repo, "config.json");
+        fs::write(repo.join("app"), "build 1").unwrap();
+        let target = repo.join("deploy-target");
+
+        let artifacts: Vec<ArtifactSpec> =
+            ["static/logo.svg", "config.json", "app"].into_iter().map(ArtifactSpec::from).collect();
+        let options = DeployConfig {
+            incremental: true,
+            ..Config::default().deploy
+        };
+        let (target_str, repo_str) = (target.to_str().unwrap(), repo.to_str().unwrap());
+
+        // No previous deploy: everything is copied and the commit recorded
+        deploy_with_files(&artifacts, target_str, repo_str, &Default::default(), "v1", &options).unwrap();
+        let first_commit = git(&repo, &["rev-parse", "HEAD"]);
+        assert_eq!(rollback::deployed_commit(&target.join("v1")), Some(first_commit.trim().to_string()));
+
+        fs::write(repo.join("config.json"), "changed").unwrap();
+        git(&repo, &["commit", "-qam", "change config"]);
+        deploy_with_files(&artifacts, target_str, repo_str, &Default::default(), "v2", &options).unwrap();
+
+        let inode = |version: &str, file: &str| fs::metadata(target.join(version).join(file)).unwrap().ino();
+        assert_eq!(inode("v1", "logo.svg"), inode("v2", "logo.svg"));
+        assert_ne!(inode("v1", "config.json"), inode("v2", "config.json"));
+        // Untracked build outputs are always copied
+        assert_ne!(inode("v1", "app"), inode("v2", "app"));
+        assert_eq!(fs::read_to_string(target.join("current/config.json")).unwrap(), "changed");
+
+        // Uncommitted edits count as changes too
+        fs::write(repo.join("static/logo.svg"), "edited").unwrap();
+        deploy_with_files(&artifacts, target_str, repo_str, &Default::default(), "v3", &options).unwrap();
+        assert_ne!(inode("v2", "logo.svg"), inode("v3", "logo.svg"));
+        assert_eq!(fs::read_to_string(target.join("v3/logo.svg")).unwrap(), "edited");
+        assert_eq!(fs::read_to_string(target.join("v2/logo.svg")).unwrap(), "static/logo.svg");
+        fs::remove_dir_all(&repo).unwrap();
+    }
+
+    #[test]
+    fn test_canary_promotes_after_health_check() {
+        let repo = crate::test_support::temp_dir("canary");
+        fs::create_dir_all(repo.join("static")).unwrap();
+        fs::write(repo.join("app"), "bin").unwrap();
+        fs::write(repo.join("static/index.html"), "<html>").unwrap();
+        let repo_str = repo.to_str().unwrap();
+        let target = repo.join("prod");
+        let canary_target = repo.join("canary");
+
+        let mut config = DeployConfig {
+            target_dir: Some(target.to_str().unwrap().to_string()),
+            artifacts: Some(vec![ArtifactSpec::from("app"), ArtifactSpec::from("static")]),
+            canary: Some(CanaryConfig {
+                target_dir: canary_target.to_str().unwrap().to_string(),
+                health_check: "false".to_string(),
+                use_shell: false,
+                initial_delay_secs: 0,
+            }),
+            ..Config::default().deploy
+        };
+
+        // A failing canary never touches the real target
+        let err = deploy(&config, repo_str, &Default::default(), "abc1234def", None).unwrap_err();
+        assert!(err.to_string().contains("Canary health check failed"), "{}", err);
+        assert!(canary_target.join("current/app").exists());
+        assert!(!target.exists());
+
+        config.canary.as_mut().unwrap().health_check = r#"test -f "$PLOOP_CANARY_DIR/static/index.html""#.to_string();
+        config.canary.as_mut().unwrap().use_shell = true;
+        deploy(&config, repo_str, &Default::default(), "abc1234def", None).unwrap();
+        assert_eq!(fs::read_to_string(target.join("current/app")).unwrap(), "bin");
+        assert_eq!(fs::read_to_string(target.join("current/static/index.html")).unwrap(), "<html>");
+        assert_eq!(rollback::current_version(target.to_str().unwrap(), rollback::DEFAULT_CURRENT_LINK).as_deref(), Some("abc1234"));
+
+        // The health check only runs once the initial delay has passed
+        let canary = CanaryConfig {
+            initial_delay_secs: 1,
+            ..config.canary.clone().unwrap()
+        };
+        let started = std::time::Instant::now();
+        run_health_check(&canary, &canary_target.join("abc1234"), repo_str).unwrap();
+        assert!(started.elapsed() >= std::time::Duration::from_secs(1));
+        fs::remove_dir_all(&repo).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_custom_current_link_name() {
+        let repo = crate::test_support::temp_dir("link-name");
+        fs::write(repo.join("app"), "v1").unwrap();
+        let repo_str = repo.to_str().unwrap();
+        let target = repo.join("www");
+        let target_str = target.to_str().unwrap();
+        let config = DeployConfig {
+            target_dir: Some(target_str.to_string()),
+            artifacts: Some(vec![ArtifactSpec::from("app")]),
+            current_link_name: "live".to_string(),
+            ..Config::default().deploy
+        };
+
+        deploy(&config, repo_str, &Default::default(), "aaaaaaa1", None).unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        fs::write(repo.join("app"), "v2").unwrap();
+        deploy(&config, repo_str, &Default::default(), "bbbbbbb2", None).unwrap();
+        assert_eq!(fs::read_to_string(target.join("live/app")).unwrap(), "v2");
+        assert!(fs::symlink_metadata(target.join("current")).is_err());
+        assert_eq!(rollback::get_deployed_versions(target_str, "live").unwrap(), vec!["bbbbbbb", "aaaaaaa"]);
+
+        assert_eq!(rollback::rollback_to_previous(target_str, "live").unwrap().to, "aaaaaaa");
+        assert_eq!(fs::read_to_string(target.join("live/app")).unwrap(), "v1");
+        assert_eq!(rollback::current_version(target_str, "live").as_deref(), Some("aaaaaaa"));
+        assert!(fs::symlink_metadata(target.join("current")).is_err());
+
+        // A name that would make the link the target itself is refused before anything is touched
+        for name in ["", ".", "..", "a/b", ".hidden", "current.txt"] {
+            let config = DeployConfig {
+                current_link_name: name.to_string(),
+                ..config.clone()
+            };
+            assert!(deploy(&config, repo_str, &Default::default(), "ccccccc3", None).is_err(), "{:?}", name);
+        }
+        assert_eq!(rollback::get_deployed_versions(target_str, "live").unwrap().len(), 2);
+
+        // A version that happens to carry the link's name is never replaced by the link
+        let config = DeployConfig {
+            current_link_name: "aaaaaaa".to_string(),
+            ..config
+        };
+        assert!(deploy(&config, repo_str, &Default::default(), "aaaaaaa1", None).is_err());
+        assert!(target.join("aaaaaaa").join(rollback::META_FILE).is_file());
+        fs::remove_dir_all(&repo).unwrap();
+    }
+
+    #[test]
+    fn test_dest_templates_rename_artifacts() {
+        let repo = crate::test_support::temp_dir("dest-template");
+        fs::create_dir_all(repo.join("lib")).unwrap();
+        for file in ["myapp", "lib/a.so", "lib/b.so"] {
+            fs::write(repo.join(file), file).unwrap();
+        }
+        let repo_str = repo.to_str().unwrap();
+        let renamed = |path: &str, dest: &str| {
+            ArtifactSpec::Detailed(crate::config::ArtifactEntry {
+                path: path.to_string(),
+                optional: false,
+                exclude: Vec::new(),
+                min_size: None,
+                executable: false,
+                dest: Some(dest.to_string()),
+            })
+        };
+
+        // A flat bin directory where every deployed commit keeps its own binary
+        let config = DeployConfig {
+            target_dir: Some(repo.join("bin").to_str().unwrap().to_string()),
+            artifacts: Some(vec![renamed("myapp", "{name}-{commit}"), renamed("lib/*.so", "{date}-{name}")]),
+            versioned: false,
+            clean_target: false,
+            ..Config::default().deploy
+        };
+        deploy(&config, repo_str, &Default::default(), "abc1234def5678", None).unwrap();
+        deploy(&config, repo_str, &Default::default(), "bbbbbbb2", None).unwrap();
+        let date = chrono::Local::now().format("%Y%m%d").to_string();
+        let mut names: Vec<String> = fs::read_dir(repo.join("bin"))
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        assert_eq!(
+            names,
+            vec![format!("{}-a.so", date), format!("{}-b.so", date), "myapp-abc1234".to_string(), "myapp-bbbbbbb".to_string()]
+        );
+
+        // Every matched file renamed to the same name is a collision
+        let target = repo.join("versions");
+        let err = deploy_with_files(&[renamed("lib/*.so", "lib-{commit}.so")], target.to_str().unwrap(), repo_str, &Default::default(), "abc1234", &config)
+            .unwrap_err();
+        assert!(err.to_string().contains("would both be deployed as \"lib-abc1234.so\""), "{}", err);
+        assert!(rollback::get_deployed_versions(target.to_str().unwrap(), rollback::DEFAULT_CURRENT_LINK).unwrap_or_default().is_empty());
+        fs::remove_dir_all(&repo).unwrap();
+    }
+
+    #[test]
+    fn test_multiple_targets_are_versioned_independently() {
+        let repo = crate::test_support::temp_dir("multi-target");
+        fs::write(repo.join("server"), "server").unwrap();
+        fs::write(repo.join("cli"), "cli").unwrap();
+        let repo_str = repo.to_str().unwrap();
+        let target = |name: &str| repo.join(name).to_str().unwrap().to_string();
+
+        let config = DeployConfig {
+            target_dir: None,
+            artifacts: None,
+            targets: vec![
+                crate::config::DeployTarget {
+                    name: "server".to_string(),
+                    target_dir: target("opt-app"),
+                    artifacts: vec![ArtifactSpec::from("server")],
+                    keep_versions: None,
+                },
+                crate::config::DeployTarget {
+                    name: "cli".to_string(),
+                    target_dir: target("bin"),
+                    artifacts: vec![ArtifactSpec::from("cli")],
+                    keep_versions: None,
+                },
+            ],
+            // The same artifacts are deployed twice to get two versions
+            skip_unchanged: false,
+            ..Config::default().deploy
+        };
+
+        deploy(&config, repo_str, &Default::default(), "aaaaaaa1", None).unwrap();
+        deploy(&config, repo_str, &Default::default(), "bbbbbbb2", None).unwrap();
+        assert!(repo.join("opt-app/current/server").exists());
+        assert!(!repo.join("opt-app/current/cli").exists());
+        assert!(repo.join("bin/current/cli").exists());
+
+        // Rolling back one target leaves the other alone
+        let cli_dir = config.select_target(Some("cli")).unwrap();
+        assert_eq!(rollback::rollback_to_previous(cli_dir, rollback::DEFAULT_CURRENT_LINK).unwrap().to, "aaaaaaa");
+        assert_eq!(rollback::current_version(&target("opt-app"), rollback::DEFAULT_CURRENT_LINK).as_deref(), Some("bbbbbbb"));
+        assert!(config.select_target(Some("web")).is_err());
+        assert!(config.select_target(None).is_err());
+        fs::remove_dir_all(&repo).unwrap();
+    }
+
+    #[test]
+    fn test_backend_selection() {
+        let selected = |config: &DeployConfig| select_backend(config).map(|backend| backend.name()).map_err(|e| e.to_string());
+        let files = DeployConfig {
+            target_dir: Some("/srv/app".to_string()),
+            artifacts: Some(vec![ArtifactSpec::from("app")]),
+            ..Config::default().deploy
+        };
+        assert_eq!(selected(&files), Ok("files"));
+
+        let sftp = DeployConfig {
+            sftp: Some(toml::from_str("host = \"h\"\nuser = \"u\"\nremote_dir = \"/srv\"").unwrap()),
+            ..files.clone()
+        };
+        assert_eq!(selected(&sftp), Ok("sftp"));
+
+        // A deploy command wins over every other mode
+        let command = DeployConfig {
+            command: Some("true".to_string()),
+            ..sftp
+        };
+        assert_eq!(selected(&command), Ok("command"));
+
+        let nothing = DeployConfig {
+            target_dir: None,
+            artifacts: None,
+            ..Config::default().deploy
+        };
+        let err = selected(&nothing).unwrap_err();
+        assert!(err.contains("No deployment method configured"), "{}", err);
+        assert!(CommandBackend
+            .rollback(&DeployContext {
+                config: &command,
+                repo_path: ".",
+                git: &Default::default(),
+                commit_hash: "abc1234",
+                version: None,
+            })
+            .is_err());
+    }
+
+    #[test]
+    fn test_deploy_with_custom_backend_runs_scripts() {
+        struct Recording(std::cell::RefCell<Vec<String>>);
+        impl DeployBackend for Recording {
+            fn name(&self) -> &'static str {
+                "recording"
+            }
+            fn deploy(&self, ctx: &DeployContext) -> Result<Option<String>, Box<dyn std::error::Error>> {
+                self.0.borrow_mut().push(ctx.commit_hash.to_string());
+                Ok(Some("recorded".to_string()))
+            }
+            fn rollback(&self, _ctx: &DeployContext) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+                Ok(Vec::new())
+            }
+        }
+
+        let repo = crate::test_support::temp_dir("backend");
+        let config = DeployConfig {
+            pre_deploy: Some("touch pre".to_string()),
+            post_deploy: Some("touch post".to_string()),
+            ..Config::default().deploy
+        };
+        let backend = Recording(std::cell::RefCell::new(Vec::new()));
+        let output = deploy_with_backend(&backend, &config, repo.to_str().unwrap(), &Default::default(), "abc1234", None).unwrap();
+        assert_eq!(output.as_deref(), Some("recorded"));
+        assert_eq!(*backend.0.borrow(), vec!["abc1234"]);
+        assert!(repo.join("pre").exists() && repo.join("post").exists());
+        fs::remove_dir_all(&repo).unwrap();
+    }
+
+    #[test]
+    fn test_retries_rerun_only_the_backend() {
+        struct Flaky(std::cell::Cell<u32>);
+        impl DeployBackend for Flaky {
+            fn name(&self) -> &'static str {
+                "flaky"
+            }
+            fn deploy(&self, _ctx: &DeployContext) -> Result<Option<String>, Box<dyn std::error::Error>> {
+                self.0.set(self.0.get() + 1);
+                if self.0.get() == 1 {
+                    return Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "target busy").into());
+                }
+                Ok(None)
+            }
+            fn rollback(&self, _ctx: &DeployContext) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+                Ok(Vec::new())
+            }
+        }
+
+        let repo = crate::test_support::temp_dir("flaky-backend");
+        let config = DeployConfig {
+            pre_deploy: Some("echo pre >> scripts.log".to_string()),
+            post_deploy: Some("echo post >> scripts.log".to_string()),
+            retries: 2,
+            retry_delay_secs: 0,
+            ..Config::default().deploy
+        };
+        let backend = Flaky(std::cell::Cell::new(0));
+        deploy_with_backend(&backend, &config, repo.to_str().unwrap(), &Default::default(), "abc1234", None).unwrap();
+        assert_eq!(backend.0.get(), 2);
+        assert_eq!(fs::read_to_string(repo.join("scripts.log")).unwrap(), "pre\npost\n");
+        fs::remove_dir_all(&repo).unwrap();
+    }
+
+    #[test]
+    fn test_large_file_copy_reports_progress_and_keeps_content() {
+        let dir = crate::test_support::temp_dir("large-copy");
+        let content: Vec<u8> = (0..5 * COPY_CHUNK_SIZE + 123).map(|i| (i % 251) as u8).collect();
+        fs::write(dir.join("big.bin"), &content).unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(dir.join("big.bin"), fs::Permissions::from_mode(0o750)).unwrap();
+        }
+
+        // A threshold below the size forces the chunked copy
+        let copied = copy_file(&dir.join("big.bin"), &dir.join("copy.bin"), 1024, false).unwrap();
+        assert_eq!(copied, content.len() as u64);
+        assert_eq!(fs::read(dir.join("copy.bin")).unwrap(), content);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(fs::metadata(dir.join("copy.bin")).unwrap().permissions().mode() & 0o777, 0o750);
+        }
+
+        // Small files take the plain fs::copy path
+        fs::write(dir.join("small"), "abc").unwrap();
+        assert_eq!(copy_file(&dir.join("small"), &dir.join("small.copy"), PROGRESS_THRESHOLD, false).unwrap(), 3);
+        assert_eq!(fs::read_to_string(dir.join("small.copy")).unwrap(), "abc");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_reflink_copy_keeps_content_either_way() {
+        let dir = crate::test_support::temp_dir("reflink");
+        let content: Vec<u8> = (0..3 * COPY_CHUNK_SIZE + 7).map(|i| (i % 253) as u8).collect();
+        fs::write(dir.join("big.bin"), &content).unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(dir.join("big.bin"), fs::Permissions::from_mode(0o750)).unwrap();
+        }
+
+        // Whether or not this filesystem supports reflinks, the result is a full copy
+        let cloned = reflink(&dir.join("big.bin"), &dir.join("probe.bin")).unwrap();
+        assert_eq!(dir.join("probe.bin").exists(), cloned);
+        let copied = copy_file(&dir.join("big.bin"), &dir.join("copy.bin"), 1024, true).unwrap();
+        assert_eq!(copied, content.len() as u64);
+        assert_eq!(fs::read(dir.join("copy.bin")).unwrap(), content);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(fs::metadata(dir.join("copy.bin")).unwrap().permissions().mode() & 0o777, 0o750);
+        }
+
+        // The clone is independent of its source
+        fs::write(dir.join("big.bin"), "changed").unwrap();
+        assert_eq!(fs::read(dir.join("copy.bin")).unwrap(), content);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_disk_space_guard() {
+        let repo = crate::test_support::temp_dir("disk-space");
+        fs::write(repo.join("app"), vec![0_u8; 4096]).unwrap();
+        let artifacts = vec![ArtifactSpec::from("app")];
+        let resolved = builder::resolve_artifacts(&artifacts, repo.to_str().unwrap()).unwrap();
+        let options = DeployConfig {
+            disk_margin_mb: 1,
+            ..Config::default().deploy
+        };
+        let target = repo.join("not/yet/created");
+
+        let plenty = |_: &Path| Ok(10 * 1024 * 1024);
+        assert!(ensure_disk_space(&resolved, &target, None, &options, plenty).is_ok());
+
+        // Enough for the artifact, but not for the margin on top of it
+        let tight = |_: &Path| Ok(1024 * 1024 + 1024);
+        let err = ensure_disk_space(&resolved, &target, None, &options, tight).unwrap_err();
+        assert!(err.to_string().contains("Insufficient disk space"));
+
+        // Excluded files and files hardlinked from the previous version need no space
+        fs::create_dir_all(repo.join("dist/maps")).unwrap();
+        fs::write(repo.join("dist/app.js"), vec![0_u8; 100]).unwrap();
+        fs::write(repo.join("dist/maps/app.js.map"), vec![0_u8; 4096]).unwrap();
+        fs::create_dir_all(repo.join("previous")).unwrap();
+        fs::write(repo.join("previous/app"), vec![0_u8; 4096]).unwrap();
+        let artifacts = vec![ArtifactSpec::from("app"), ArtifactSpec::from("dist")];
+        let resolved = builder::resolve_artifacts(&artifacts, repo.to_str().unwrap()).unwrap();
+        let options = DeployConfig {
+            disk_margin_mb: 0,
+            exclude: vec!["*.map".to_string()],
+            dedup: true,
+            ..Config::default().deploy
+        };
+        let previous_dir = repo.join("previous");
+        let previous = PreviousVersion {
+            unchanged: identical_artifacts(&resolved, &previous_dir, HashSet::new()).unwrap(),
+            dir: &previous_dir,
+        };
+        assert_eq!(previous.unchanged, HashSet::from([repo.join("app")]));
+        let just_enough = |_: &Path| Ok(100);
+        assert!(ensure_disk_space(&resolved, &target, Some(&previous), &options, just_enough).is_ok());
+        let err = ensure_disk_space(&resolved, &target, None, &options, just_enough).unwrap_err();
+        assert!(err.to_string().contains("artifacts need 4196 bytes"), "{}", err);
+        fs::remove_dir_all(&repo).unwrap();
     }
 }
@@ -0,0 +1,206 @@
+//! What a deploy would change (`ploop diff`)
+//!
+//! Compares the checksums of the artifacts as they are now (optionally after
+//! a fresh build) with the checksums recorded for each target's current
+//! version, file by file.
+
+use crate::config::Config;
+use crate::deployer;
+use crate::hook;
+use crate::pipeline;
+use crate::rollback;
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Change {
+    /// Produced by the build, not in the current version
+    Added,
+    Changed,
+    /// In the current version, no longer produced by the build
+    Removed,
+    Unchanged,
+}
+
+impl std::fmt::Display for Change {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Change::Added => "added",
+            Change::Changed => "changed",
+            Change::Removed => "removed",
+            Change::Unchanged => "unchanged",
+        };
+        f.write_str(name)
+    }
+}
+
+/// One file, by its `/`-separated path inside a version directory
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct FileDiff {
+    pub path: String,
+    pub change: Change,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TargetDiff {
+    pub name: String,
+    pub target_dir: String,
+    /// The version compared against; `None` when nothing is deployed yet,
+    /// so every file counts as added
+    pub current: Option<String>,
+    pub files: Vec<FileDiff>,
+}
+
+impl TargetDiff {
+    pub fn count(&self, change: Change) -> usize {
+        self.files.iter().filter(|file| file.change == change).count()
+    }
+}
+
+/// Per-target diffs (`ploop diff --json` serializes it as is)
+#[derive(Debug, Clone, Serialize)]
+pub struct Diff {
+    pub targets: Vec<TargetDiff>,
+}
+
+/// Compare the artifacts of every file target with its current version
+///
+/// With `build`, the build runs (and the artifacts are verified) first;
+/// otherwise the artifacts already on disk are used. Targets deployed as a
+/// bundle record the archive's checksum and so always show every file as
+/// changed.
+pub fn diff(config: &Config, build: bool) -> Result<Diff, Box<dyn std::error::Error>> {
+    let config = pipeline::resolve_templates(config)?;
+    if build {
+        pipeline::build_and_verify(&config)?;
+    }
+
+    let repo_path = config.watch.repo_path.as_str();
+    let commit = hook::get_current_commit_hash(repo_path, &config.watch.git).unwrap_or_default();
+    let main = config
+        .deploy
+        .target_dir
+        .as_deref()
+        .zip(config.deploy.artifacts.as_deref())
+        .map(|(target_dir, artifacts)| ("default", target_dir, artifacts));
+    let targets = main.into_iter().chain(
+        config
+            .deploy
+            .targets
+            .iter()
+            .map(|target| (target.name.as_str(), target.target_dir.as_str(), target.artifacts.as_slice())),
+    );
+
+    let mut diffs = Vec::new();
+    for (name, target_dir, artifacts) in targets {
+        let fresh = deployer::artifact_checksums(&config.deploy, artifacts, repo_path, &commit)?;
+        let current = rollback::current_version(target_dir, &config.deploy.current_link_name);
+        let deployed = match &current {
+            Some(version) => deployer::without_precompressed(deployer::version_checksums(target_dir, version)?, &fresh),
+            None => BTreeMap::new(),
+        };
+        diffs.push(TargetDiff {
+            name: name.to_string(),
+            target_dir: target_dir.to_string(),
+            current,
+            files: compare_checksums(&deployed, &fresh),
+        });
+    }
+    Ok(Diff { targets: diffs })
+}
+
+/// File-by-file comparison of two checksum maps, sorted by path
+pub fn compare_checksums(deployed: &BTreeMap<String, String>, fresh: &BTreeMap<String, String>) -> Vec<FileDiff> {
+    let mut paths: Vec<&String> = deployed.keys().chain(fresh.keys()).collect();
+    paths.sort();
+    paths.dedup();
+
+    paths
+        .into_iter()
+        .map(|path| {
+            let change = match (deployed.get(path), fresh.get(path)) {
+                (None, _) => Change::Added,
+                (Some(_), None) => Change::Removed,
+                (Some(old), Some(new)) if old == new => Change::Unchanged,
+                _ => Change::Changed,
+            };
+            FileDiff {
+                path: path.clone(),
+                change,
+            }
+        })
+        .collect()
+}
+
+/// One `<change> <target> <path>` line per file, then a summary line per target
+pub fn format_diff(diff: &Diff) -> Vec<String> {
+    let mut lines = Vec::new();
+    for target in &diff.targets {
+        lines.extend(
+            target
+                .files
+                .iter()
+                .map(|file| format!("{} {} {}", file.change, target.name, file.path)),
+        );
+        lines.push(format!(
+            "{}: {} added, {} changed, {} removed, {} unchanged (against {})",
+            target.name,
+            target.count(Change::Added),
+            target.count(Change::Changed),
+            target.count(Change::Removed),
+            target.count(Change::Unchanged),
+            target.current.as_deref().unwrap_or("nothing deployed"),
+        ));
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ArtifactSpec;
+
+    #[test]
+    fn test_diff_reports_changed_and_unchanged_artifacts() {
+        let repo = crate::test_support::temp_dir("diff");
+        std::fs::create_dir_all(repo.join("static")).unwrap();
+        std::fs::write(repo.join("app"), "v1").unwrap();
+        std::fs::write(repo.join("static/same.js"), "same").unwrap();
+        std::fs::write(repo.join("static/old.css"), "old").unwrap();
+        let mut config = Config::default();
+        config.watch.repo_path = repo.to_str().unwrap().to_string();
+        config.deploy.target_dir = Some(repo.join("deploy").to_str().unwrap().to_string());
+        config.deploy.artifacts = Some(vec![ArtifactSpec::from("app"), ArtifactSpec::from("static")]);
+
+        let before = diff(&config, false).unwrap();
+        assert_eq!(before.targets[0].count(Change::Added), 3);
+        deployer::deploy(&config.deploy, repo.to_str().unwrap(), &config.watch.git, "aaaaaaa1", None).unwrap();
+
+        std::fs::write(repo.join("app"), "v2").unwrap();
+        std::fs::remove_file(repo.join("static/old.css")).unwrap();
+        std::fs::write(repo.join("static/new.css"), "new").unwrap();
+        let after = diff(&config, false).unwrap();
+        let changes: Vec<(&str, Change)> = after.targets[0]
+            .files
+            .iter()
+            .map(|file| (file.path.as_str(), file.change))
+            .collect();
+        assert_eq!(
+            changes,
+            vec![
+                ("app", Change::Changed),
+                ("static/new.css", Change::Added),
+                ("static/old.css", Change::Removed),
+                ("static/same.js", Change::Unchanged),
+            ]
+        );
+
+        let lines = format_diff(&after);
+        assert_eq!(lines[0], "changed default app");
+        assert_eq!(lines[4], "default: 1 added, 1 changed, 1 removed, 1 unchanged (against aaaaaaa)");
+        let json = serde_json::to_value(&after).unwrap();
+        assert_eq!(json["targets"][0]["files"][3]["change"], "unchanged");
+        std::fs::remove_dir_all(&repo).unwrap();
+    }
+}
@@ -0,0 +1,174 @@
+use crate::config::Config;
+use crate::deployer;
+use crate::hook;
+use crate::runner;
+use crate::syncer;
+
+/// One diagnostic check with a suggested fix when it fails
+#[derive(Debug, Clone)]
+pub struct Check {
+    pub name: String,
+    pub passed: bool,
+    /// A failed critical check means runs cannot succeed
+    pub critical: bool,
+    pub fix: Option<String>,
+}
+
+impl Check {
+    fn pass(name: &str) -> Self {
+        Check {
+            name: name.to_string(),
+            passed: true,
+            critical: false,
+            fix: None,
+        }
+    }
+
+    fn fail(name: &str, critical: bool, fix: String) -> Self {
+        Check {
+            name: name.to_string(),
+            passed: false,
+            critical,
+            fix: Some(fix),
+        }
+    }
+}
+
+/// Run every diagnostic for `repo_path` and the config file at `config_path`
+///
+/// Checks that need the configuration are skipped when it does not load.
+pub fn run_checks(repo_path: &str, config_path: &str) -> Vec<Check> {
+    let mut checks = Vec::new();
+    let loaded = Config::load(config_path);
+    // Hook lookups honour watch.git when the configuration loads
+    let git = loaded.as_ref().map(|config| config.watch.git.clone()).unwrap_or_default();
+
+    if hook::is_git_repo(repo_path) {
+        checks.push(Check::pass("Inside a git repository"));
+    } else {
+        checks.push(Check::fail(
+            "Inside a git repository",
+            true,
+            format!("Run ploop from the repository root ({} has no .git)", repo_path),
+        ));
+    }
+
+    checks.push(match hook::hook_executable(repo_path, &git) {
+        Some(exe) if exe.exists() => Check::pass("Post-commit hook installed"),
+        Some(exe) => Check::fail(
+            "Post-commit hook installed",
+            false,
+            format!("Hook runs {} which no longer exists; re-run `ploop init`", exe.display()),
+        ),
+        None if hook::is_hook_installed(repo_path, &git) => Check::fail(
+            "Post-commit hook installed",
+            false,
+            "A post-commit hook exists but was not created by ploop; re-run `ploop init`".to_string(),
+        ),
+        None => Check::fail(
+            "Post-commit hook installed",
+            false,
+            "Run `ploop init` to install the hook (or run `ploop run` manually)".to_string(),
+        ),
+    });
+
+    let config = match loaded {
+        Ok(config) => {
+            checks.push(Check::pass("Configuration loads"));
+            config
+        }
+        Err(e) => {
+            checks.push(Check::fail(
+                "Configuration loads",
+                true,
+                format!("Fix {}: {}", config_path, e),
+            ));
+            return checks;
+        }
+    };
+
+    // Only steps may build; their commands are checked when they run
+    let build_program = runner::shell_command(&config.build.command, config.build.use_shell)
+        .map(|process| process.get_program().to_string_lossy().into_owned());
+    let steps_only = build_program.is_none() && !config.build.steps.is_empty();
+    checks.push(match build_program {
+        None if steps_only => Check::pass("Build command found"),
+        Some(program) if deployer::find_in_path(&program).is_some() => Check::pass("Build command found"),
+        Some(program) => Check::fail(
+            "Build command found",
+            true,
+            format!("Install {} or fix build.command", program),
+        ),
+        None => Check::fail("Build command found", true, "Set build.command".to_string()),
+    });
+
+    let problems = deployer::preflight(&config);
+    if problems.is_empty() {
+        checks.push(Check::pass("Deploy target ready"));
+    }
+    for problem in problems {
+        checks.push(Check::fail("Deploy target ready", true, problem));
+    }
+
+    if config.sync.enabled {
+        checks.push(match syncer::remote_reachable(&config.sync.remote, repo_path, &git) {
+            Ok(()) => Check::pass("Git remote reachable"),
+            Err(e) => Check::fail(
+                "Git remote reachable",
+                false,
+                format!("{} (check network access and credentials)", e),
+            ),
+        });
+    }
+
+    checks
+}
+
+/// Format checks as a ✓/✗ checklist, with the fix indented under each failure
+pub fn format_checklist(checks: &[Check]) -> Vec<String> {
+    let mut lines = Vec::new();
+    for check in checks {
+        let mark = if check.passed { "✓" } else { "✗" };
+        lines.push(format!("{} {}", mark, check.name));
+        if let Some(fix) = &check.fix {
+            lines.push(format!("    → {}", fix));
+        }
+    }
+    lines
+}
+
+/// Whether any critical check failed (the doctor command's non-zero exit)
+pub fn has_critical_failure(checks: &[Check]) -> bool {
+    checks.iter().any(|check| !check.passed && check.critical)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{commit_file, init_repo};
+
+    #[test]
+    fn test_checks_on_repo_without_hook() {
+        let repo = init_repo();
+        commit_file(&repo, "README");
+        let repo_str = repo.to_str().unwrap();
+
+        let mut config = Config::default();
+        config.watch.repo_path = repo_str.to_string();
+        config.build.command = "sh -c true".to_string();
+        config.deploy.target_dir = Some(repo.join("deploy").to_str().unwrap().to_string());
+        config.sync.enabled = false;
+        let config_path = repo.join("deploy.toml");
+        config.save(config_path.to_str().unwrap()).unwrap();
+
+        let checks = run_checks(repo_str, config_path.to_str().unwrap());
+        assert!(!has_critical_failure(&checks));
+        let hook = checks.iter().find(|check| check.name == "Post-commit hook installed").unwrap();
+        assert!(!hook.passed);
+        assert!(format_checklist(&checks).contains(&"✓ Configuration loads".to_string()));
+
+        let checks = run_checks(repo_str, repo.join("missing.toml").to_str().unwrap());
+        assert!(has_critical_failure(&checks));
+        std::fs::remove_dir_all(&repo).unwrap();
+    }
+}
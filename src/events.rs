@@ -0,0 +1,173 @@
+//! Pipeline phase events
+//!
+//! [`pipeline::run`](crate::pipeline::run) reports each phase transition as a
+//! [`PipelineEvent`]. The [`EventEmitter`] logs every event and, when
+//! `notify.event_socket` is set, streams it as a JSON line to that Unix socket
+//! for local monitoring agents. Finished runs and rollbacks are also handed to
+//! the [`notifier`](crate::notifier) for the webhook.
+
+use crate::config::NotifyConfig;
+use crate::history::Outcome;
+use serde::Serialize;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// How long a write to the event socket may block before the connection is
+/// dropped, so a stalled monitoring agent cannot hold up the pipeline
+const SOCKET_WRITE_TIMEOUT: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum PipelineEvent {
+    BuildStarted { commit: String },
+    BuildFinished { commit: String, success: bool },
+    DeployStarted { commit: String },
+    DeployFinished { commit: String, success: bool },
+    SyncStarted { commit: String },
+    SyncFinished { commit: String, success: bool },
+    /// 'current' was switched back, after a failed deploy or on request
+    ///
+    /// `to` is `None` when nothing is live afterwards (`rollback.on_no_previous`).
+    RolledBack {
+        target_dir: String,
+        from: Option<String>,
+        to: Option<String>,
+        reason: String,
+        /// Whether a failed deploy triggered it, rather than a request
+        automatic: bool,
+    },
+    RunFinished {
+        commit: String,
+        outcome: Outcome,
+        duration_secs: f64,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+    },
+}
+
+/// Sends pipeline events to the log, an optional monitoring socket and the
+/// webhook notifier
+///
+/// The socket is connected lazily on the first event. Connection and write
+/// failures are logged and never fail the pipeline; a failed connection is
+/// retried on the next event.
+pub struct EventEmitter {
+    socket_path: Option<String>,
+    notify: Option<NotifyConfig>,
+    #[cfg(unix)]
+    stream: Mutex<Option<std::os::unix::net::UnixStream>>,
+    #[cfg(not(unix))]
+    stream: Mutex<Option<()>>,
+}
+
+impl EventEmitter {
+    pub fn new(socket_path: Option<&str>) -> Self {
+        EventEmitter {
+            socket_path: socket_path.map(|path| path.to_string()),
+            notify: None,
+            stream: Mutex::new(None),
+        }
+    }
+
+    /// Emitter for a run: `notify.event_socket` plus the webhook settings
+    pub fn for_config(config: &NotifyConfig) -> Self {
+        EventEmitter {
+            notify: Some(config.clone()),
+            ..EventEmitter::new(config.event_socket.as_deref())
+        }
+    }
+
+    pub fn emit(&self, event: &PipelineEvent) {
+        // The run ID goes into every event, next to the `event` tag
+        let line = match serde_json::to_value(event) {
+            Ok(mut value) => {
+                if let Some(run_id) = crate::logger::current_run_id() {
+                    value["run_id"] = serde_json::Value::String(run_id);
+                }
+                value.to_string()
+            }
+            Err(e) => {
+                log::warn!("Failed to serialize pipeline event: {}", e);
+                return;
+            }
+        };
+        log::debug!("Pipeline event: {}", line);
+
+        if let Some(socket_path) = &self.socket_path {
+            self.send(socket_path, &line);
+        }
+        if let Some(notify) = &self.notify {
+            crate::notifier::notify_event(notify, event);
+        }
+    }
+
+    #[cfg(unix)]
+    fn send(&self, socket_path: &str, line: &str) {
+        use std::io::Write;
+        use std::os::unix::net::UnixStream;
+
+        let mut stream = self.stream.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if stream.is_none() {
+            match UnixStream::connect(socket_path) {
+                Ok(connected) => {
+                    if let Err(e) = connected.set_write_timeout(Some(SOCKET_WRITE_TIMEOUT)) {
+                        log::warn!("Failed to set a write timeout on event socket {}: {}", socket_path, e);
+                    }
+                    *stream = Some(connected);
+                }
+                Err(e) => {
+                    log::debug!("Event socket {} unavailable: {}", socket_path, e);
+                    return;
+                }
+            }
+        }
+
+        if let Some(connected) = stream.as_mut() {
+            if let Err(e) = connected.write_all(format!("{}\n", line).as_bytes()) {
+                log::warn!("Lost connection to event socket {}: {}", socket_path, e);
+                *stream = None;
+            }
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn send(&self, socket_path: &str, _line: &str) {
+        let mut warned = self.stream.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if warned.replace(()).is_none() {
+            log::warn!("Event socket {} ignored: Unix sockets are not supported on this platform", socket_path);
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader};
+    use std::os::unix::net::UnixListener;
+
+    #[test]
+    fn test_events_stream_as_json_lines() {
+        let dir = crate::test_support::temp_dir("events");
+        let socket = dir.join("events.sock");
+        let listener = UnixListener::bind(&socket).unwrap();
+
+        let emitter = EventEmitter::new(socket.to_str());
+        emitter.emit(&PipelineEvent::BuildStarted { commit: "abc".into() });
+        emitter.emit(&PipelineEvent::BuildFinished {
+            commit: "abc".into(),
+            success: true,
+        });
+
+        let (stream, _) = listener.accept().unwrap();
+        let lines: Vec<String> = BufReader::new(stream).lines().take(2).map(|line| line.unwrap()).collect();
+        let first: serde_json::Value = serde_json::from_str(&lines[0]).unwrap();
+        assert_eq!(first["event"], "build_started");
+        let second: serde_json::Value = serde_json::from_str(&lines[1]).unwrap();
+        assert_eq!(second["success"], true);
+
+        // A missing socket is not an error
+        let absent = EventEmitter::new(dir.join("absent.sock").to_str());
+        absent.emit(&PipelineEvent::DeployStarted { commit: "abc".into() });
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
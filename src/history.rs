@@ -0,0 +1,204 @@
+use chrono::Local;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Name of the JSON lines audit file kept in the deploy target directory
+pub const HISTORY_FILE: &str = "history.log";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Outcome {
+    Success,
+    BuildFailed,
+    DeployFailed,
+    RolledBack,
+    /// The artifacts were already deployed (`deploy.skip_unchanged`)
+    Skipped,
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let label = match self {
+            Outcome::Success => "success",
+            Outcome::BuildFailed => "build-failed",
+            Outcome::DeployFailed => "deploy-failed",
+            Outcome::RolledBack => "rolled-back",
+            Outcome::Skipped => "skipped",
+        };
+        write!(f, "{}", label)
+    }
+}
+
+/// One pipeline run, successful or not
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct HistoryRecord {
+    pub commit: String,
+    pub timestamp: String,
+    pub outcome: Outcome,
+    pub duration_secs: f64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Captured output of the deploy command, failed or not (command mode only)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub output: Option<String>,
+    /// OS user who ran the pipeline
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    /// Host the pipeline ran on
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub host: Option<String>,
+}
+
+impl HistoryRecord {
+    /// Create a record stamped with the current time
+    pub fn new(commit: &str, outcome: Outcome, duration: Duration) -> Self {
+        HistoryRecord {
+            commit: commit.to_string(),
+            timestamp: Local::now().to_rfc3339(),
+            outcome,
+            duration_secs: duration.as_secs_f64(),
+            error: None,
+            output: None,
+            user: Some(current_user()),
+            host: Some(current_host()),
+        }
+    }
+
+    pub fn with_error(mut self, error: &str) -> Self {
+        self.error = Some(error.to_string());
+        self
+    }
+
+    pub fn with_output(mut self, output: &str) -> Self {
+        self.output = Some(output.to_string());
+        self
+    }
+}
+
+/// Name of the OS user running ploop, or "unknown"
+pub fn current_user() -> String {
+    ["USER", "USERNAME", "LOGNAME"]
+        .iter()
+        .find_map(|name| std::env::var(name).ok().filter(|value| !value.trim().is_empty()))
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+/// Name of the host running ploop, or "unknown"
+pub fn current_host() -> String {
+    ["HOSTNAME", "COMPUTERNAME"]
+        .iter()
+        .find_map(|name| std::env::var(name).ok())
+        .or_else(|| fs::read_to_string("/etc/hostname").ok())
+        .map(|host| host.trim().to_string())
+        .filter(|host| !host.is_empty())
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+/// Path of the history file for a target directory
+pub fn history_path(target_dir: &str) -> PathBuf {
+    Path::new(target_dir).join(HISTORY_FILE)
+}
+
+/// Append a record to the target directory's history
+pub fn append_record(target_dir: &str, record: &HistoryRecord) -> Result<(), Box<dyn std::error::Error>> {
+    fs::create_dir_all(target_dir)?;
+
+    let mut line = serde_json::to_string(record)?;
+    line.push('\n');
+
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(history_path(target_dir))?;
+    file.write_all(line.as_bytes())?;
+    Ok(())
+}
+
+/// Destination for the structured record of each pipeline run
+///
+/// The pipeline writes to a [`FileRecorder`] on the deploy target unless it
+/// is handed another recorder (see `pipeline::run_with_recorder`), so an
+/// embedder can route records to its own storage without touching the
+/// global `log` logger.
+pub trait DeploymentRecorder {
+    fn record(&self, record: &HistoryRecord) -> Result<(), Box<dyn std::error::Error>>;
+}
+
+/// Appends records to the `history.log` of a target directory
+pub struct FileRecorder {
+    target_dir: String,
+}
+
+impl FileRecorder {
+    pub fn new(target_dir: &str) -> Self {
+        FileRecorder {
+            target_dir: target_dir.to_string(),
+        }
+    }
+}
+
+impl DeploymentRecorder for FileRecorder {
+    fn record(&self, record: &HistoryRecord) -> Result<(), Box<dyn std::error::Error>> {
+        append_record(&self.target_dir, record)
+    }
+}
+
+/// Read all history records, oldest first (malformed lines are skipped)
+pub fn read_records(target_dir: &str) -> Result<Vec<HistoryRecord>, Box<dyn std::error::Error>> {
+    let path = history_path(target_dir);
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+
+    let content = fs::read_to_string(path)?;
+    let mut records = Vec::new();
+    for line in content.lines().filter(|line| !line.trim().is_empty()) {
+        match serde_json::from_str(line) {
+            Ok(record) => records.push(record),
+            Err(e) => log::warn!("Skipping malformed history entry: {}", e),
+        }
+    }
+
+    Ok(records)
+}
+
+/// Read the most recent `limit` history records, newest first
+pub fn recent_records(
+    target_dir: &str,
+    limit: usize,
+) -> Result<Vec<HistoryRecord>, Box<dyn std::error::Error>> {
+    let mut records = read_records(target_dir)?;
+    records.reverse();
+    records.truncate(limit);
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_history_records_failures() {
+        let target = std::env::temp_dir().join(format!("postloop-history-{}", uuid::Uuid::new_v4()));
+        let target_str = target.to_str().unwrap();
+
+        append_record(target_str, &HistoryRecord::new("abc1234", Outcome::Success, Duration::from_secs(3))).unwrap();
+        append_record(
+            target_str,
+            &HistoryRecord::new("def5678", Outcome::BuildFailed, Duration::from_millis(1500)).with_error("boom"),
+        )
+        .unwrap();
+
+        let recent = recent_records(target_str, 5).unwrap();
+        assert_eq!(recent.len(), 2);
+        assert_eq!(recent[0].commit, "def5678");
+        assert_eq!(recent[0].outcome, Outcome::BuildFailed);
+        assert_eq!(recent[0].error.as_deref(), Some("boom"));
+        assert_eq!(recent[1].outcome.to_string(), "success");
+        fs::remove_dir_all(&target).unwrap();
+    }
+}
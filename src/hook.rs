@@ -1,15 +1,36 @@
+use crate::config::GitConfig;
+use crate::runner;
 use std::fs;
 #[cfg(unix)]
 use std::os::unix::fs::PermissionsExt;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::process::Command;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 /// Install post-commit hook in the Git repository
-pub fn install_hook(repo_path: &str) -> Result<(), Box<dyn std::error::Error>> {
-    let mut hook_path = PathBuf::from(repo_path);
-    hook_path.push(".git");
-    hook_path.push("hooks");
-    hook_path.push("post-commit");
+///
+/// The hook goes into `core.hooksPath` when that is set, warning if the
+/// directory looks managed by another tool or an existing hook is replaced.
+/// With `watch_paths` set, the script exits early for commits touching none
+/// of them; rerun it after changing `watch.paths` to refresh the list.
+pub fn install_hook(repo_path: &str, git: &GitConfig, watch_paths: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    let hook_path = post_commit_hook_path(repo_path, git);
+    if let Some(hooks_path) = configured_hooks_path(repo_path, git) {
+        log::info!("Installing into core.hooksPath: {}", hooks_path);
+        if foreign_hooks_present(hook_path.parent().unwrap_or(Path::new(repo_path))) {
+            log::warn!(
+                "core.hooksPath {} already holds other hooks; another tool (e.g. Husky) may manage \
+                 this directory and overwrite the postloop hook",
+                hooks_path
+            );
+        }
+    }
+    if hook_path.exists() && hook_executable(repo_path, git).is_none() {
+        log::warn!("Replacing existing post-commit hook not created by postloop: {:?}", hook_path);
+    }
+    if let Some(hooks_dir) = hook_path.parent() {
+        fs::create_dir_all(hooks_dir)?;
+    }
 
     // Get the absolute path to the postloop executable
     let postloop_path = std::env::current_exe()?;
@@ -21,7 +42,8 @@ pub fn install_hook(repo_path: &str) -> Result<(), Box<dyn std::error::Error>> {
          # postloop post-commit hook\n\
          # Auto-generated by postloop init\n\
          \n\
-         {} run\n",
+         {}{} run\n",
+        watch_paths_guard(git, watch_paths),
         postloop_path_str
     );
 
@@ -40,23 +62,81 @@ pub fn install_hook(repo_path: &str) -> Result<(), Box<dyn std::error::Error>> {
     Ok(())
 }
 
+/// Shell snippet that exits the hook unless the commit touches a watched path
+///
+/// Patterns become `case` patterns, where `*` also crosses `/` just like it
+/// does for [`matches_watch_paths`]; `run` applies the same filter again.
+fn watch_paths_guard(git: &GitConfig, patterns: &[String]) -> String {
+    if patterns.is_empty() {
+        return String::new();
+    }
+
+    let git_invocation = std::iter::once(git.git_path.as_deref().unwrap_or("git"))
+        .chain(git.git_extra_args.iter().map(String::as_str))
+        .map(shell_quote)
+        .collect::<Vec<_>>()
+        .join(" ");
+    let case_patterns = patterns
+        .iter()
+        .map(|pattern| case_pattern(pattern))
+        .collect::<Vec<_>>()
+        .join("|");
+
+    let lines = [
+        "# Skip commits that touch none of watch.paths".to_string(),
+        format!("{} diff-tree --no-commit-id --name-only -r --root HEAD | {{", git_invocation),
+        "    while IFS= read -r file; do".to_string(),
+        "        case \"$file\" in".to_string(),
+        format!("            {}) exit 0 ;;", case_patterns),
+        "        esac".to_string(),
+        "    done".to_string(),
+        "    exit 1".to_string(),
+        "} || exit 0".to_string(),
+    ];
+    format!("{}\n\n", lines.join("\n"))
+}
+
+/// A glob as an unquoted `case` pattern: wildcards kept, everything else escaped
+fn case_pattern(pattern: &str) -> String {
+    pattern.replace("**", "*").chars().fold(String::new(), |mut out, c| {
+        if !(c.is_ascii_alphanumeric() || "*?[]!-_./".contains(c)) {
+            out.push('\\');
+        }
+        out.push(c);
+        out
+    })
+}
+
+/// `value` in single quotes for a POSIX shell
+fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "'\\''"))
+}
+
 /// Check if post-commit hook is installed
-pub fn is_hook_installed(repo_path: &str) -> bool {
-    let mut hook_path = PathBuf::from(repo_path);
-    hook_path.push(".git");
-    hook_path.push("hooks");
-    hook_path.push("post-commit");
+pub fn is_hook_installed(repo_path: &str, git: &GitConfig) -> bool {
+    let hook_path = post_commit_hook_path(repo_path, git);
 
     hook_path.exists()
 }
 
+/// Executable the installed post-commit hook runs, if the hook was generated by postloop
+pub fn hook_executable(repo_path: &str, git: &GitConfig) -> Option<PathBuf> {
+    let hook_path = post_commit_hook_path(repo_path, git);
+    let content = fs::read_to_string(hook_path).ok()?;
+    if !content.contains("# postloop post-commit hook") {
+        return None;
+    }
+
+    content
+        .lines()
+        .find_map(|line| line.strip_suffix(" run"))
+        .map(PathBuf::from)
+}
+
 /// Remove post-commit hook
 #[allow(dead_code)]
-pub fn remove_hook(repo_path: &str) -> Result<(), Box<dyn std::error::Error>> {
-    let mut hook_path = PathBuf::from(repo_path);
-    hook_path.push(".git");
-    hook_path.push("hooks");
-    hook_path.push("post-commit");
+pub fn remove_hook(repo_path: &str, git: &GitConfig) -> Result<(), Box<dyn std::error::Error>> {
+    let hook_path = post_commit_hook_path(repo_path, git);
 
     if hook_path.exists() {
         fs::remove_file(&hook_path)?;
@@ -66,12 +146,67 @@ pub fn remove_hook(repo_path: &str) -> Result<(), Box<dyn std::error::Error>> {
     Ok(())
 }
 
-/// Get the current commit hash
-pub fn get_current_commit_hash(repo_path: &str) -> Result<String, Box<dyn std::error::Error>> {
-    let output = Command::new("git")
+/// Where git looks for the post-commit hook: `core.hooksPath` if set, else `.git/hooks`
+pub fn post_commit_hook_path(repo_path: &str, git: &GitConfig) -> PathBuf {
+    let hooks_dir = runner::run_tracked(git_command(repo_path, git).args(["rev-parse", "--git-path", "hooks"]))
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|dir| Path::new(repo_path).join(dir.trim()))
+        .unwrap_or_else(|| Path::new(repo_path).join(".git").join("hooks"));
+
+    hooks_dir.join("post-commit")
+}
+
+/// The repository's `core.hooksPath` setting, if any
+fn configured_hooks_path(repo_path: &str, git: &GitConfig) -> Option<String> {
+    let output = runner::run_tracked(git_command(repo_path, git).args(["config", "--get", "core.hooksPath"])).ok()?;
+    let value = String::from_utf8(output.stdout).ok()?.trim().to_string();
+    (output.status.success() && !value.is_empty()).then_some(value)
+}
+
+/// Whether a hooks directory holds anything besides a postloop post-commit hook
+fn foreign_hooks_present(hooks_dir: &Path) -> bool {
+    let Ok(entries) = fs::read_dir(hooks_dir) else {
+        return false;
+    };
+    entries.flatten().any(|entry| {
+        let path = entry.path();
+        path.file_name() != Some(std::ffi::OsStr::new("post-commit"))
+            || !fs::read_to_string(&path).is_ok_and(|content| content.contains("# postloop post-commit hook"))
+    })
+}
+
+/// Build a git command for `repo_path` from explicit settings
+///
+/// Extra arguments come before the subcommand the caller adds, so global
+/// options such as `-c safe.directory=*` apply to it. Git never prompts for
+/// credentials: commands run through [`runner::run_tracked`] are detached
+/// from the terminal, so a prompt would hang until the run's deadline.
+pub fn git_command(repo_path: &str, git: &GitConfig) -> Command {
+    let mut command = Command::new(git.git_path.as_deref().unwrap_or("git"));
+    command
         .current_dir(repo_path)
-        .args(&["rev-parse", "HEAD"])
-        .output()?;
+        .args(&git.git_extra_args)
+        .env("GIT_TERMINAL_PROMPT", "0");
+    command
+}
+
+/// Committer time of `commit`
+pub fn get_commit_time(repo_path: &str, git: &GitConfig, commit: &str) -> Result<SystemTime, Box<dyn std::error::Error>> {
+    let output = runner::run_tracked(git_command(repo_path, git).args(["log", "-1", "--format=%ct", commit, "--"]))?;
+
+    if !output.status.success() {
+        return Err(format!("Unknown commit: {}", commit).into());
+    }
+
+    let seconds: u64 = String::from_utf8(output.stdout)?.trim().parse()?;
+    Ok(UNIX_EPOCH + Duration::from_secs(seconds))
+}
+
+/// Get the current commit hash
+pub fn get_current_commit_hash(repo_path: &str, git: &GitConfig) -> Result<String, Box<dyn std::error::Error>> {
+    let output = runner::run_tracked(git_command(repo_path, git).args(["rev-parse", "HEAD"]))?;
 
     if !output.status.success() {
         return Err("Failed to get commit hash".into());
@@ -81,15 +216,546 @@ pub fn get_current_commit_hash(repo_path: &str) -> Result<String, Box<dyn std::e
     Ok(hash.trim().to_string())
 }
 
+/// Resolve a revision (hash, tag, branch) to a full commit hash, failing if it doesn't exist
+pub fn resolve_commit(repo_path: &str, git: &GitConfig, rev: &str) -> Result<String, Box<dyn std::error::Error>> {
+    let output = runner::run_tracked(
+        git_command(repo_path, git)
+            .args(["rev-parse", "--verify", "--quiet", "--end-of-options", &format!("{}^{{commit}}", rev)]),
+    )?;
+
+    if !output.status.success() {
+        return Err(format!("Commit not found: {}", rev).into());
+    }
+
+    Ok(String::from_utf8(output.stdout)?.trim().to_string())
+}
+
+/// Whether `ancestor` is `commit` itself or one of its ancestors
+pub fn is_ancestor(repo_path: &str, git: &GitConfig, ancestor: &str, commit: &str) -> Result<bool, Box<dyn std::error::Error>> {
+    let output = runner::run_tracked(git_command(repo_path, git).args(["merge-base", "--is-ancestor", ancestor, commit]))?;
+
+    match output.status.code() {
+        Some(0) => Ok(true),
+        Some(1) => Ok(false),
+        _ => {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            Err(format!("Cannot compare {} with {}: {}", ancestor, commit, stderr.trim()).into())
+        }
+    }
+}
+
+/// Commits reachable from `to` but not from `from`, oldest first, following
+/// first parents only (a merged branch shows up as its merge commit)
+pub fn commits_between(repo_path: &str, git: &GitConfig, from: &str, to: &str) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let output = runner::run_tracked(
+        git_command(repo_path, git)
+            .args(["rev-list", "--reverse", "--first-parent", &format!("{}..{}", from, to), "--"]),
+    )?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(format!("Cannot list commits {}..{}: {}", from, to, stderr.trim()).into());
+    }
+
+    Ok(String::from_utf8(output.stdout)?.lines().map(|line| line.to_string()).collect())
+}
+
+/// A temporary detached worktree, removed again when dropped
+pub struct Worktree {
+    repo_path: String,
+    git: GitConfig,
+    path: PathBuf,
+}
+
+impl Worktree {
+    /// Check out `commit` into a new worktree under the system temp directory
+    pub fn add(repo_path: &str, git: &GitConfig, commit: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let path = std::env::temp_dir().join(format!("postloop-worktree-{}", uuid::Uuid::new_v4()));
+        let output = runner::run_tracked(
+            git_command(repo_path, git)
+                .args(["worktree", "add", "--detach", "--quiet"])
+                .arg(&path)
+                .arg(commit),
+        )?;
+
+        if !output.status.success() {
+            return Err(format!(
+                "git worktree add failed: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            )
+            .into());
+        }
+
+        log::info!("Checked out {} into worktree {:?}", commit, path);
+        Ok(Worktree {
+            repo_path: repo_path.to_string(),
+            git: git.clone(),
+            path,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for Worktree {
+    fn drop(&mut self) {
+        let removed = git_command(&self.repo_path, &self.git)
+            .args(["worktree", "remove", "--force"])
+            .arg(&self.path)
+            .output()
+            .map(|output| output.status.success())
+            .unwrap_or(false);
+
+        // Fall back to deleting the directory and pruning git's bookkeeping
+        if !removed {
+            if let Err(e) = fs::remove_dir_all(&self.path) {
+                log::warn!("Failed to remove worktree {:?}: {}", self.path, e);
+            }
+            let _ = git_command(&self.repo_path, &self.git).args(["worktree", "prune"]).output();
+        }
+    }
+}
+
+/// Fail unless `commit` is HEAD and no other revert is in progress, so
+/// [`revert_head`] reverts exactly that commit
+pub fn ensure_revertable(repo_path: &str, git: &GitConfig, commit: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let in_progress = runner::run_tracked(git_command(repo_path, git).args(["rev-parse", "-q", "--verify", "REVERT_HEAD"]))?;
+    if in_progress.status.success() {
+        return Err("A git revert is already in progress; finish or abort it first".into());
+    }
+
+    let head = get_current_commit_hash(repo_path, git)?;
+    if head != commit {
+        return Err(format!("Deployed commit {} is not HEAD ({}); revert it by hand", commit, head).into());
+    }
+    Ok(())
+}
+
+/// Commit a revert of `commit`, which must be HEAD, returning the new commit's hash
+///
+/// If the revert cannot be made (conflicts, local changes in the way) it is
+/// aborted, leaving the repository as it was.
+pub fn revert_head(repo_path: &str, git: &GitConfig, commit: &str) -> Result<String, Box<dyn std::error::Error>> {
+    ensure_revertable(repo_path, git, commit)?;
+    let output = runner::run_tracked(git_command(repo_path, git).args(["revert", "--no-edit", commit]))?;
+
+    if !output.status.success() {
+        // Fails harmlessly when the revert never started
+        let _ = git_command(repo_path, git).args(["revert", "--abort"]).output();
+        return Err(format!(
+            "git revert failed, repository left unchanged: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        )
+        .into());
+    }
+
+    get_current_commit_hash(repo_path, git)
+}
+
+/// Move the checked-out branch back to `commit`, keeping local changes
+/// (`git reset --keep`)
+pub fn reset_keeping_changes(repo_path: &str, git: &GitConfig, commit: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let output = runner::run_tracked(git_command(repo_path, git).args(["reset", "-q", "--keep", commit]))?;
+
+    if !output.status.success() {
+        return Err(format!("git reset to {} failed: {}", commit, String::from_utf8_lossy(&output.stderr).trim()).into());
+    }
+    Ok(())
+}
+
+/// Get the name of the checked-out branch (errors on a detached HEAD)
+pub fn get_current_branch(repo_path: &str, git: &GitConfig) -> Result<String, Box<dyn std::error::Error>> {
+    let output = runner::run_tracked(git_command(repo_path, git).args(["symbolic-ref", "--short", "-q", "HEAD"]))?;
+
+    if !output.status.success() {
+        return Err("Failed to get current branch (HEAD is detached?)".into());
+    }
+
+    Ok(String::from_utf8(output.stdout)?.trim().to_string())
+}
+
+/// Names of all local branches and remote-tracking branches (without the
+/// remote prefix), sorted and deduplicated
+pub fn list_branches(repo_path: &str, git: &GitConfig) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let output = runner::run_tracked(
+        git_command(repo_path, git)
+            .args(["for-each-ref", "--format=%(refname)", "refs/heads", "refs/remotes"]),
+    )?;
+
+    if !output.status.success() {
+        return Err(format!(
+            "Failed to list branches: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        )
+        .into());
+    }
+
+    Ok(branch_names(&String::from_utf8(output.stdout)?))
+}
+
+/// Names of the local and remote-tracking branches whose tip contains
+/// `commit`, as [`list_branches`] names them
+pub fn branches_containing(repo_path: &str, git: &GitConfig, commit: &str) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let output = runner::run_tracked(
+        git_command(repo_path, git)
+            .args(["for-each-ref", "--format=%(refname)", "--contains", commit, "refs/heads", "refs/remotes"]),
+    )?;
+
+    if !output.status.success() {
+        return Err(format!(
+            "Failed to list branches containing {}: {}",
+            commit,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )
+        .into());
+    }
+
+    Ok(branch_names(&String::from_utf8(output.stdout)?))
+}
+
+/// Branch names from `for-each-ref` refnames, sorted and deduplicated
+fn branch_names(refnames: &str) -> Vec<String> {
+    let mut branches: Vec<String> = refnames
+        .lines()
+        .filter_map(|refname| match refname.strip_prefix("refs/heads/") {
+            Some(branch) => Some(branch),
+            None => refname.strip_prefix("refs/remotes/")?.split_once('/').map(|(_, branch)| branch),
+        })
+        .filter(|branch| *branch != "HEAD")
+        .map(|branch| branch.to_string())
+        .collect();
+    branches.sort();
+    branches.dedup();
+    branches
+}
+
+/// Whether the repository declares any submodules
+pub fn has_submodules(repo_path: &str) -> bool {
+    Path::new(repo_path).join(".gitmodules").is_file()
+}
+
+/// Check out every submodule (recursively) at the commit the repository references
+///
+/// A no-op for repositories without submodules.
+pub fn update_submodules(repo_path: &str, git: &GitConfig) -> Result<(), Box<dyn std::error::Error>> {
+    if !has_submodules(repo_path) {
+        return Ok(());
+    }
+
+    let output = runner::run_tracked(
+        git_command(repo_path, git)
+            .args(["submodule", "update", "--init", "--recursive"]),
+    )?;
+
+    if !output.status.success() {
+        return Err(format!(
+            "git submodule update failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        )
+        .into());
+    }
+
+    log::info!("Updated submodules in {}", repo_path);
+    Ok(())
+}
+
 /// Get the short commit hash (first 7 characters)
-pub fn get_short_commit_hash(repo_path: &str) -> Result<String, Box<dyn std::error::Error>> {
-    let hash = get_current_commit_hash(repo_path)?;
+pub fn get_short_commit_hash(repo_path: &str, git: &GitConfig) -> Result<String, Box<dyn std::error::Error>> {
+    let hash = get_current_commit_hash(repo_path, git)?;
     Ok(hash.chars().take(7).collect())
 }
 
+/// Get the subject line of a commit
+pub fn get_commit_subject(repo_path: &str, git: &GitConfig, commit: &str) -> Result<String, Box<dyn std::error::Error>> {
+    let output = runner::run_tracked(git_command(repo_path, git).args(["log", "-1", "--format=%s", commit, "--"]))?;
+
+    if !output.status.success() {
+        return Err(format!("Unknown commit: {}", commit).into());
+    }
+
+    Ok(String::from_utf8(output.stdout)?.trim().to_string())
+}
+
+/// Get the list of files changed by the latest commit
+pub fn changed_files_in_head(repo_path: &str, git: &GitConfig) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let output = runner::run_tracked(
+        git_command(repo_path, git)
+            .args(["diff-tree", "--no-commit-id", "--name-only", "-r", "--root", "HEAD"]),
+    )?;
+
+    if !output.status.success() {
+        return Err("Failed to list files changed in HEAD".into());
+    }
+
+    let files = String::from_utf8(output.stdout)?;
+    Ok(files
+        .lines()
+        .map(|line| line.trim().to_string())
+        .filter(|line| !line.is_empty())
+        .collect())
+}
+
+/// Get the tracked files that differ between `commit` and the working tree
+/// (committed, staged or not), relative to `repo_path`
+pub fn changed_files_since(repo_path: &str, git: &GitConfig, commit: &str) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let output = runner::run_tracked(
+        git_command(repo_path, git)
+            .args(["diff", "--name-only", "--relative", commit, "--"]),
+    )?;
+
+    if !output.status.success() {
+        return Err(format!("Failed to diff {} against the working tree", commit).into());
+    }
+
+    Ok(String::from_utf8(output.stdout)?.lines().map(|line| line.to_string()).collect())
+}
+
+/// Get the files tracked by git, relative to `repo_path`
+pub fn tracked_files(repo_path: &str, git: &GitConfig) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let output = runner::run_tracked(git_command(repo_path, git).args(["ls-files"]))?;
+
+    if !output.status.success() {
+        return Err("Failed to list tracked files".into());
+    }
+
+    Ok(String::from_utf8(output.stdout)?.lines().map(|line| line.to_string()).collect())
+}
+
+/// Check if any of the given files match the watched path globs
+pub fn matches_watch_paths(
+    files: &[String],
+    patterns: &[String],
+) -> Result<bool, Box<dyn std::error::Error>> {
+    if patterns.is_empty() {
+        return Ok(true);
+    }
+
+    let patterns = patterns
+        .iter()
+        .map(|pattern| glob::Pattern::new(pattern))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(files
+        .iter()
+        .any(|file| patterns.iter().any(|pattern| pattern.matches(file))))
+}
+
+/// Check if the latest commit should trigger the pipeline (always true without path globs)
+pub fn head_touches_watch_paths(
+    repo_path: &str,
+    git: &GitConfig,
+    patterns: &[String],
+) -> Result<bool, Box<dyn std::error::Error>> {
+    if patterns.is_empty() {
+        return Ok(true);
+    }
+
+    let files = changed_files_in_head(repo_path, git)?;
+    let triggered = matches_watch_paths(&files, patterns)?;
+    if !triggered {
+        log::info!("Latest commit touches no watched paths {:?}, skipping", patterns);
+    }
+
+    Ok(triggered)
+}
+
+/// Check that tracked files have no uncommitted changes (untracked files are ignored)
+pub fn is_working_tree_clean(repo_path: &str, git: &GitConfig) -> Result<bool, Box<dyn std::error::Error>> {
+    let output = runner::run_tracked(
+        git_command(repo_path, git)
+            .args(["status", "--porcelain", "--untracked-files=no"]),
+    )?;
+
+    if !output.status.success() {
+        return Err("Failed to get working tree status".into());
+    }
+
+    Ok(output.stdout.iter().all(u8::is_ascii_whitespace))
+}
+
 /// Check if we're in a Git repository
 pub fn is_git_repo(repo_path: &str) -> bool {
     let mut git_path = PathBuf::from(repo_path);
     git_path.push(".git");
     git_path.exists()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{commit_file, init_repo};
+
+    #[test]
+    fn test_head_touches_matching_path() {
+        let repo = init_repo();
+        commit_file(&repo, "service/main.rs");
+        let repo_str = repo.to_str().unwrap();
+        let git = GitConfig::default();
+
+        assert_eq!(changed_files_in_head(repo_str, &git).unwrap(), vec!["service/main.rs"]);
+        assert!(head_touches_watch_paths(repo_str, &git, &["service/**".to_string()]).unwrap());
+        fs::remove_dir_all(&repo).unwrap();
+    }
+
+    #[test]
+    fn test_head_touches_no_matching_path() {
+        let repo = init_repo();
+        commit_file(&repo, "service/main.rs");
+        commit_file(&repo, "docs/readme.md");
+        let repo_str = repo.to_str().unwrap();
+        let git = GitConfig::default();
+
+        assert!(!head_touches_watch_paths(repo_str, &git, &["service/**".to_string()]).unwrap());
+        // Without configured paths every commit triggers
+        assert!(head_touches_watch_paths(repo_str, &git, &[]).unwrap());
+        fs::remove_dir_all(&repo).unwrap();
+    }
+
+    #[test]
+    fn test_installed_hook_skips_unwatched_commits() {
+        let repo = init_repo();
+        let repo_str = repo.to_str().unwrap();
+        let git = GitConfig::default();
+        let paths = vec!["service/**".to_string(), "Cargo.lock".to_string()];
+        install_hook(repo_str, &git, &paths).unwrap();
+        let exe = hook_executable(repo_str, &git).unwrap();
+        assert_eq!(exe, std::env::current_exe().unwrap());
+
+        // Run the installed script with the postloop call swapped for a marker
+        let script = fs::read_to_string(post_commit_hook_path(repo_str, &git))
+            .unwrap()
+            .replace(&format!("{} run", exe.display()), "echo triggered");
+        let run_hook = || {
+            let output = Command::new("sh").arg("-c").arg(&script).current_dir(&repo).output().unwrap();
+            assert!(output.status.success());
+            String::from_utf8(output.stdout).unwrap().contains("triggered")
+        };
+
+        commit_file(&repo, "service/api/main.rs");
+        assert!(run_hook());
+        commit_file(&repo, "docs/readme.md");
+        assert!(!run_hook());
+        commit_file(&repo, "Cargo.lock");
+        assert!(run_hook());
+        fs::remove_dir_all(&repo).unwrap();
+    }
+
+    #[test]
+    fn test_git_command_passes_extra_args() {
+        let repo = init_repo();
+        commit_file(&repo, "README");
+        let repo_str = repo.to_str().unwrap();
+
+        let settings = GitConfig {
+            git_path: None,
+            git_extra_args: vec!["-c".to_string(), "core.abbrev=12".to_string()],
+        };
+        let output = git_command(repo_str, &settings)
+            .args(["rev-parse", "--short", "HEAD"])
+            .output()
+            .unwrap();
+        assert_eq!(String::from_utf8(output.stdout).unwrap().trim().len(), 12);
+
+        let missing = GitConfig {
+            git_path: Some("/nonexistent/git".to_string()),
+            git_extra_args: Vec::new(),
+        };
+        assert!(git_command(repo_str, &missing).arg("status").output().is_err());
+        fs::remove_dir_all(&repo).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_git_calls_stop_at_the_deadline() {
+        let dir = crate::test_support::temp_dir("git-deadline");
+        let fake_git = dir.join("git");
+        fs::write(&fake_git, "#!/bin/sh\nsleep 30\n").unwrap();
+        fs::set_permissions(&fake_git, fs::Permissions::from_mode(0o755)).unwrap();
+        let git = GitConfig {
+            git_path: Some(fake_git.to_str().unwrap().to_string()),
+            git_extra_args: Vec::new(),
+        };
+
+        let started = std::time::Instant::now();
+        runner::set_deadline(Some(started + Duration::from_millis(200)));
+        let err = get_current_commit_hash(dir.to_str().unwrap(), &git).unwrap_err();
+        runner::set_deadline(None);
+        assert_eq!(err.to_string(), runner::TIMED_OUT);
+        assert!(started.elapsed() < Duration::from_secs(5), "{:?}", started.elapsed());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_worktree_is_removed_on_drop() {
+        let repo = init_repo();
+        let first = commit_file(&repo, "first.txt");
+        commit_file(&repo, "second.txt");
+        let repo_str = repo.to_str().unwrap();
+        let git = GitConfig::default();
+
+        assert_eq!(resolve_commit(repo_str, &git, &first[..7]).unwrap(), first);
+        assert!(resolve_commit(repo_str, &git, "0000000").is_err());
+
+        let worktree = Worktree::add(repo_str, &git, &first).unwrap();
+        let path = worktree.path().to_path_buf();
+        assert!(path.join("first.txt").exists());
+        assert!(!path.join("second.txt").exists());
+        assert_eq!(get_current_commit_hash(path.to_str().unwrap(), &git).unwrap(), first);
+
+        drop(worktree);
+        assert!(!path.exists());
+        let list = crate::test_support::git(&repo, &["worktree", "list"]);
+        assert_eq!(list.lines().count(), 1);
+        fs::remove_dir_all(&repo).unwrap();
+    }
+
+    #[test]
+    fn test_install_respects_core_hooks_path() {
+        let repo = init_repo();
+        commit_file(&repo, "README");
+        let repo_str = repo.to_str().unwrap();
+        let git = GitConfig::default();
+        assert_eq!(post_commit_hook_path(repo_str, &git), repo.join(".git/hooks/post-commit"));
+
+        crate::test_support::git(&repo, &["config", "core.hooksPath", ".githooks"]);
+        install_hook(repo_str, &git, &[]).unwrap();
+        assert!(repo.join(".githooks/post-commit").is_file());
+        assert!(!repo.join(".git/hooks/post-commit").exists());
+        assert!(is_hook_installed(repo_str, &git));
+        assert!(hook_executable(repo_str, &git).is_some());
+
+        remove_hook(repo_str, &git).unwrap();
+        assert!(!is_hook_installed(repo_str, &git));
+        fs::remove_dir_all(&repo).unwrap();
+    }
+
+    #[test]
+    fn test_get_current_branch() {
+        let repo = init_repo();
+        let hash = commit_file(&repo, "README");
+        let repo_str = repo.to_str().unwrap();
+        let git = GitConfig::default();
+
+        assert_eq!(get_current_branch(repo_str, &git).unwrap(), "main");
+        crate::test_support::git(&repo, &["checkout", "-q", &hash]);
+        assert!(get_current_branch(repo_str, &git).is_err());
+        fs::remove_dir_all(&repo).unwrap();
+    }
+
+    #[test]
+    fn test_working_tree_clean_and_dirty() {
+        let repo = init_repo();
+        commit_file(&repo, "src/lib.rs");
+        let repo_str = repo.to_str().unwrap();
+        let git = GitConfig::default();
+
+        assert!(is_working_tree_clean(repo_str, &git).unwrap());
+        fs::write(repo.join("untracked.txt"), "ignored").unwrap();
+        assert!(is_working_tree_clean(repo_str, &git).unwrap());
+        fs::write(repo.join("src/lib.rs"), "edited").unwrap();
+        assert!(!is_working_tree_clean(repo_str, &git).unwrap());
+        fs::remove_dir_all(&repo).unwrap();
+    }
+}
@@ -4,7 +4,20 @@ pub mod logger;
 pub mod hook;
 pub mod builder;
 pub mod deployer;
+#[cfg(feature = "sftp")]
+pub mod sftp;
 pub mod syncer;
 pub mod rollback;
+pub mod history;
+pub mod runner;
+pub mod notifier;
+pub mod events;
+pub mod pipeline;
+pub mod doctor;
+pub mod status;
+pub mod diff;
 pub mod intent;
 pub mod registry;
+
+#[cfg(test)]
+mod test_support;
@@ -1,45 +1,154 @@
-use chrono::Local;
+use crate::config::LogConfig;
+use chrono::format::{Item, StrftimeItems};
+use chrono::{Duration, Local, NaiveDate, NaiveDateTime, Utc};
 use log::{Level, Log, Metadata, Record};
-use std::fs::{File, OpenOptions};
+use std::cell::RefCell;
+use std::fs::{self, File, OpenOptions};
 use std::io::Write;
-use std::sync::Mutex;
+use std::sync::{Mutex, OnceLock};
+
+const ALREADY_INSTALLED: &str =
+    "A global logger is already installed; initialize only one of the file or console loggers";
+
+/// Parse a log level name, falling back to info
+pub fn parse_level(level: &str) -> Level {
+    match level.to_lowercase().as_str() {
+        "trace" => Level::Trace,
+        "debug" => Level::Debug,
+        "info" => Level::Info,
+        "warn" => Level::Warn,
+        "error" => Level::Error,
+        _ => Level::Info,
+    }
+}
+
+/// Timestamp format used when `log.timestamp_format` is not set
+pub const DEFAULT_TIMESTAMP_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
 
 pub struct PloopLogger {
     file: Mutex<File>,
     level: Level,
+    timestamp_format: String,
+    utc: bool,
+    log_on_success: bool,
+    /// Lines below warn held back during a run when `log_on_success` is off
+    buffer: Mutex<Option<Vec<String>>>,
+}
+
+/// The logger installed by [`PloopLogger::init`], reachable for run buffering
+static INSTALLED: OnceLock<PloopLogger> = OnceLock::new();
+
+thread_local! {
+    /// ID of the run on this thread, added to its log lines
+    static RUN_ID: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+/// A short random ID for one run (8 hex digits)
+pub fn new_run_id() -> String {
+    uuid::Uuid::new_v4().simple().to_string()[..8].to_string()
+}
+
+/// Tag log lines from the current thread with `run_id`, or stop with `None`
+///
+/// Like the runner's deadline this is per thread, so concurrent runs keep
+/// their own IDs; worker threads of a run have to set it themselves.
+pub fn set_run_id(run_id: Option<&str>) {
+    RUN_ID.with(|current| *current.borrow_mut() = run_id.map(|id| id.to_string()));
+}
+
+/// ID of the run on the current thread, if any
+pub fn current_run_id() -> Option<String> {
+    RUN_ID.with(|current| current.borrow().clone())
+}
+
+/// A log line: `[timestamp] LEVEL - message`, with ` run=<id>` after the
+/// level while a run ID is set
+fn format_line(timestamp: &str, level: Level, message: &std::fmt::Arguments) -> String {
+    match current_run_id() {
+        Some(run_id) => format!("[{}] {} run={} - {}\n", timestamp, level, run_id, message),
+        None => format!("[{}] {} - {}\n", timestamp, level, message),
+    }
 }
 
 impl PloopLogger {
     /// Create a new logger instance
-    pub fn new(log_file: &str, level: &str) -> Result<Self, Box<dyn std::error::Error>> {
+    ///
+    /// With `max_lines`, the existing file is first trimmed to its last
+    /// `max_lines` lines; this only happens here, not on every write. An
+    /// invalid `timestamp_format` is rejected here rather than on each line.
+    /// Missing parent directories of the log file are created.
+    pub fn new(config: &LogConfig) -> Result<Self, Box<dyn std::error::Error>> {
+        let timestamp_format = config
+            .timestamp_format
+            .clone()
+            .unwrap_or_else(|| DEFAULT_TIMESTAMP_FORMAT.to_string());
+        validate_timestamp_format(&timestamp_format)?;
+
+        if let Some(parent) = std::path::Path::new(&config.file).parent() {
+            if !parent.as_os_str().is_empty() {
+                fs::create_dir_all(parent)?;
+            }
+        }
+
+        if let Some(max_lines) = config.max_lines {
+            trim_to_last_lines(&config.file, max_lines)?;
+        }
+
         let file = OpenOptions::new()
             .create(true)
             .append(true)
-            .open(log_file)?;
-
-        let level = match level.to_lowercase().as_str() {
-            "trace" => Level::Trace,
-            "debug" => Level::Debug,
-            "info" => Level::Info,
-            "warn" => Level::Warn,
-            "error" => Level::Error,
-            _ => Level::Info,
-        };
+            .open(&config.file)?;
 
         Ok(PloopLogger {
             file: Mutex::new(file),
-            level,
+            level: parse_level(&config.level),
+            timestamp_format,
+            utc: config.utc,
+            log_on_success: config.log_on_success,
+            buffer: Mutex::new(None),
         })
     }
 
     /// Initialize the logger as the global logger
-    pub fn init(log_file: &str, level: &str) -> Result<(), Box<dyn std::error::Error>> {
-        let logger = PloopLogger::new(log_file, level)?;
-        log::set_boxed_logger(Box::new(logger))?;
-        log::set_max_level(log::LevelFilter::Trace);
+    ///
+    /// The global max level is bounded by the configured level so filtered
+    /// records are discarded before formatting.
+    pub fn init(config: &LogConfig) -> Result<(), Box<dyn std::error::Error>> {
+        let logger = PloopLogger::new(config)?;
+        let max_level = logger.level.to_level_filter();
+        INSTALLED.set(logger).map_err(|_| ALREADY_INSTALLED)?;
+        log::set_logger(INSTALLED.get().ok_or(ALREADY_INSTALLED)?).map_err(|_| ALREADY_INSTALLED)?;
+        log::set_max_level(max_level);
         Ok(())
     }
 
+    /// Start holding lines back in memory, if `log_on_success` is off
+    ///
+    /// Warnings and errors are still written as they happen, so a run that
+    /// succeeds with warnings (or never ends) does not lose them.
+    pub fn begin_run(&self) {
+        if !self.log_on_success {
+            *self.buffer.lock().unwrap_or_else(|poisoned| poisoned.into_inner()) = Some(Vec::new());
+        }
+    }
+
+    /// End a run started with [`begin_run`](Self::begin_run)
+    ///
+    /// A failed run flushes the held-back lines; a successful one discards
+    /// them and writes only `summary`. Without buffering this does nothing.
+    pub fn end_run(&self, success: bool, summary: &str) -> std::io::Result<()> {
+        let buffered = self.buffer.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).take();
+        let Some(lines) = buffered else {
+            return Ok(());
+        };
+
+        if success {
+            self.write_message(&format_line(&self.timestamp(), Level::Info, &format_args!("{}", summary)))
+        } else {
+            self.write_message(&lines.concat())
+        }
+    }
+
     /// Log a message with timestamp and commit hash
     #[allow(dead_code)]
     pub fn log_deployment(
@@ -48,17 +157,33 @@ impl PloopLogger {
         operation: &str,
         result: &str,
     ) -> Result<(), Box<dyn std::error::Error>> {
-        let timestamp = Local::now().format("%Y-%m-%d %H:%M:%S");
+        let timestamp = self.timestamp();
         let message = format!(
             "[{}] Commit: {} | Operation: {} | Result: {}\n",
             timestamp, commit_hash, operation, result
         );
 
-        let mut file = self.file.lock().unwrap();
-        file.write_all(message.as_bytes())?;
-        file.flush()?;
+        self.write_message(&message)?;
         Ok(())
     }
+
+    fn timestamp(&self) -> String {
+        if self.utc {
+            Utc::now().format(&self.timestamp_format).to_string()
+        } else {
+            Local::now().format(&self.timestamp_format).to_string()
+        }
+    }
+
+    /// Write one complete message while holding both the in-process mutex and
+    /// an exclusive file lock, so lines from concurrent processes never interleave
+    fn write_message(&self, message: &str) -> std::io::Result<()> {
+        let mut file = self.file.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        fs2::FileExt::lock_exclusive(&*file)?;
+        let result = file.write_all(message.as_bytes()).and_then(|()| file.flush());
+        fs2::FileExt::unlock(&*file)?;
+        result
+    }
 }
 
 impl Log for PloopLogger {
@@ -68,17 +193,15 @@ impl Log for PloopLogger {
 
     fn log(&self, record: &Record) {
         if self.enabled(record.metadata()) {
-            let timestamp = Local::now().format("%Y-%m-%d %H:%M:%S");
-            let message = format!(
-                "[{}] {} - {}\n",
-                timestamp,
-                record.level(),
-                record.args()
-            );
-
-            if let Ok(mut file) = self.file.lock() {
-                let _ = file.write_all(message.as_bytes());
-                let _ = file.flush();
+            let message = format_line(&self.timestamp(), record.level(), record.args());
+
+            let mut buffer = self.buffer.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+            match buffer.as_mut() {
+                Some(lines) if record.level() > Level::Warn => lines.push(message),
+                _ => {
+                    drop(buffer);
+                    let _ = self.write_message(&message);
+                }
             }
         }
     }
@@ -90,10 +213,369 @@ impl Log for PloopLogger {
     }
 }
 
+/// Start buffering the installed file logger for one run (see [`PloopLogger::begin_run`])
+pub fn begin_run() {
+    if let Some(logger) = INSTALLED.get() {
+        logger.begin_run();
+    }
+}
+
+/// Finish a run on the installed file logger (see [`PloopLogger::end_run`])
+pub fn end_run(success: bool, summary: &str) {
+    if let Some(logger) = INSTALLED.get() {
+        let _ = logger.end_run(success, summary);
+    }
+}
+
+/// Write the lines the installed file logger is holding back, as if the run
+/// failed; for exiting in the middle of a run (e.g. on a second signal)
+pub fn flush_run() {
+    if let Some(logger) = INSTALLED.get() {
+        let _ = logger.end_run(false, "");
+    }
+}
+
+/// Check a chrono format string, so a bad pattern fails at startup instead of on every line
+pub fn validate_timestamp_format(format: &str) -> Result<(), Box<dyn std::error::Error>> {
+    if StrftimeItems::new(format).any(|item| matches!(item, Item::Error)) {
+        return Err(format!("Invalid log timestamp format: {}", format).into());
+    }
+    Ok(())
+}
+
+/// Keep only the last `max_lines` lines of a log file
+///
+/// The file is rewritten in place under the same exclusive lock writers take
+/// (see [`PloopLogger::write_message`]), so a line written concurrently is
+/// never lost, and other processes' handles to the log stay valid.
+pub fn trim_to_last_lines(log_file: &str, max_lines: usize) -> Result<(), Box<dyn std::error::Error>> {
+    use std::io::{Read, Seek};
+
+    let mut file = match OpenOptions::new().read(true).write(true).open(log_file) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    fs2::FileExt::lock_exclusive(&file)?;
+    let trimmed = (|| -> std::io::Result<()> {
+        let mut content = String::new();
+        file.read_to_string(&mut content)?;
+
+        let lines: Vec<&str> = content.lines().collect();
+        if lines.len() <= max_lines {
+            return Ok(());
+        }
+        let mut kept = lines[lines.len() - max_lines..].join("\n");
+        if !kept.is_empty() {
+            kept.push('\n');
+        }
+
+        file.seek(std::io::SeekFrom::Start(0))?;
+        file.write_all(kept.as_bytes())?;
+        file.set_len(kept.len() as u64)?;
+        file.sync_all()
+    })();
+    fs2::FileExt::unlock(&file)?;
+    Ok(trimmed?)
+}
+
+/// Parse a `--since` value: a relative duration (`30s`, `15m`, `1h`, `2d`) or an
+/// absolute local timestamp (`YYYY-MM-DD HH:MM:SS` or `YYYY-MM-DD`)
+pub fn parse_since(spec: &str, now: NaiveDateTime) -> Result<NaiveDateTime, Box<dyn std::error::Error>> {
+    let spec = spec.trim();
+
+    if let Ok(timestamp) = NaiveDateTime::parse_from_str(spec, "%Y-%m-%d %H:%M:%S") {
+        return Ok(timestamp);
+    }
+    if let Ok(date) = NaiveDate::parse_from_str(spec, "%Y-%m-%d") {
+        return Ok(date.and_hms_opt(0, 0, 0).ok_or("Invalid date")?);
+    }
+
+    let split = spec.char_indices().last().map(|(index, _)| index).unwrap_or(0);
+    let (amount, unit) = spec.split_at(split);
+    let amount: i64 = amount
+        .parse()
+        .map_err(|_| format!("Invalid --since value: {}", spec))?;
+    let duration = match unit {
+        "s" => Duration::seconds(amount),
+        "m" => Duration::minutes(amount),
+        "h" => Duration::hours(amount),
+        "d" => Duration::days(amount),
+        _ => return Err(format!("Invalid --since value: {}", spec).into()),
+    };
+
+    Ok(now - duration)
+}
+
+/// Parse the `[YYYY-MM-DD HH:MM:SS]` prefix of a log line
+fn line_timestamp(line: &str) -> Option<NaiveDateTime> {
+    let rest = line.strip_prefix('[')?;
+    let (timestamp, _) = rest.split_once(']')?;
+    NaiveDateTime::parse_from_str(timestamp, DEFAULT_TIMESTAMP_FORMAT).ok()
+}
+
+/// Keep log lines at or after `since`
+///
+/// Lines without a timestamp inherit the visibility of the previous line so
+/// multi-line messages stay intact.
+pub fn filter_since(content: &str, since: NaiveDateTime) -> Vec<&str> {
+    let mut visible = false;
+    content
+        .lines()
+        .filter(|line| {
+            if let Some(timestamp) = line_timestamp(line) {
+                visible = timestamp >= since;
+            }
+            visible
+        })
+        .collect()
+}
+
+/// Console level for a repeated `-v` count: info, then debug, then trace
+pub fn verbosity_filter(verbosity: u8) -> log::LevelFilter {
+    match verbosity {
+        0 => log::LevelFilter::Info,
+        1 => log::LevelFilter::Debug,
+        _ => log::LevelFilter::Trace,
+    }
+}
+
 /// Initialize a simple console logger for development
+///
+/// `verbosity` is the number of `-v` flags given.
 #[allow(dead_code)]
-pub fn init_simple_logger() {
+pub fn init_simple_logger(verbosity: u8) -> Result<(), Box<dyn std::error::Error>> {
     env_logger::Builder::from_default_env()
-        .filter_level(log::LevelFilter::Info)
-        .init();
+        .filter_level(verbosity_filter(verbosity))
+        .try_init()
+        .map_err(|_| ALREADY_INSTALLED)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn log_config(file: &str, level: &str) -> LogConfig {
+        LogConfig {
+            file: file.to_string(),
+            level: level.to_string(),
+            ..LogConfig::default()
+        }
+    }
+
+    #[test]
+    fn test_parse_level() {
+        assert_eq!(parse_level("DEBUG"), Level::Debug);
+        assert_eq!(parse_level("bogus"), Level::Info);
+    }
+
+    #[test]
+    fn test_verbosity_filter() {
+        assert_eq!(verbosity_filter(0), log::LevelFilter::Info);
+        assert_eq!(verbosity_filter(1), log::LevelFilter::Debug);
+        assert_eq!(verbosity_filter(5), log::LevelFilter::Trace);
+    }
+
+    #[test]
+    fn test_parse_since() {
+        let now = NaiveDateTime::parse_from_str("2024-05-01 12:00:00", "%Y-%m-%d %H:%M:%S").unwrap();
+        assert_eq!(parse_since("1h", now).unwrap().to_string(), "2024-05-01 11:00:00");
+        assert_eq!(parse_since("30m", now).unwrap().to_string(), "2024-05-01 11:30:00");
+        assert_eq!(
+            parse_since("2024-04-30 08:15:00", now).unwrap().to_string(),
+            "2024-04-30 08:15:00"
+        );
+        assert!(parse_since("soon", now).is_err());
+    }
+
+    #[test]
+    fn test_max_lines_trims_on_new() {
+        let dir = crate::test_support::temp_dir("log-trim");
+        let log_file = dir.join("ploop.log");
+        let lines: Vec<String> = (1..=10).map(|n| format!("line {}", n)).collect();
+        fs::write(&log_file, lines.join("\n") + "\n").unwrap();
+
+        let mut config = log_config(log_file.to_str().unwrap(), "info");
+        config.max_lines = Some(3);
+        PloopLogger::new(&config).unwrap();
+        assert_eq!(fs::read_to_string(&log_file).unwrap(), "line 8\nline 9\nline 10\n");
+        assert!(!dir.join("ploop.log.tmp").exists());
+
+        config.max_lines = Some(5);
+        PloopLogger::new(&config).unwrap();
+        assert_eq!(fs::read_to_string(&log_file).unwrap().lines().count(), 3);
+
+        // A logger that already had the file open keeps writing to it
+        let running = PloopLogger::new(&log_config(log_file.to_str().unwrap(), "info")).unwrap();
+        config.max_lines = Some(1);
+        PloopLogger::new(&config).unwrap();
+        running.log(&Record::builder().level(Level::Info).args(format_args!("still here")).build());
+        let content = fs::read_to_string(&log_file).unwrap();
+        assert!(content.starts_with("line 10\n") && content.ends_with("INFO - still here\n"), "{}", content);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_new_creates_log_directory() {
+        let dir = crate::test_support::temp_dir("log-dir");
+        let log_file = dir.join("mounted/volume/ploop.log");
+
+        let logger = PloopLogger::new(&log_config(log_file.to_str().unwrap(), "info")).unwrap();
+        logger.log_deployment("abc1234", "deploy", "ok").unwrap();
+        assert!(fs::read_to_string(&log_file).unwrap().contains("abc1234"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_custom_timestamp_format() {
+        let dir = crate::test_support::temp_dir("log-format");
+        let log_file = dir.join("ploop.log");
+        let mut config = log_config(log_file.to_str().unwrap(), "info");
+        config.timestamp_format = Some("%Y-%m-%dT%H:%M:%S%.3fZ".to_string());
+        config.utc = true;
+
+        let logger = PloopLogger::new(&config).unwrap();
+        logger.log_deployment("abc1234", "deploy", "ok").unwrap();
+        let content = fs::read_to_string(&log_file).unwrap();
+        let timestamp = content.strip_prefix('[').unwrap().split(']').next().unwrap();
+        assert!(chrono::DateTime::parse_from_rfc3339(timestamp).is_ok(), "{}", timestamp);
+
+        config.timestamp_format = Some("%Y-%Q".to_string());
+        let err = PloopLogger::new(&config).err().unwrap();
+        assert!(err.to_string().contains("%Y-%Q"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_log_lines_carry_run_id() {
+        let dir = crate::test_support::temp_dir("log-run-id");
+        let log_file = dir.join("ploop.log");
+        let logger = PloopLogger::new(&log_config(log_file.to_str().unwrap(), "info")).unwrap();
+        let info = |message: &str| {
+            logger.log(&Record::builder().level(Level::Info).args(format_args!("{}", message)).build());
+        };
+
+        let run_id = new_run_id();
+        assert_eq!(run_id.len(), 8);
+        set_run_id(Some(&run_id));
+        info("building");
+        // Another thread has no run ID of its own
+        std::thread::scope(|scope| {
+            scope.spawn(|| info("elsewhere"));
+        });
+        set_run_id(None);
+        info("idle");
+
+        let content = fs::read_to_string(&log_file).unwrap();
+        let lines: Vec<&str> = content.lines().collect();
+        assert!(lines[0].ends_with(&format!("INFO run={} - building", run_id)), "{}", content);
+        assert!(lines[1].ends_with("INFO - elsewhere"), "{}", content);
+        assert!(lines[2].ends_with("INFO - idle"), "{}", content);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_buffered_run_flushes_only_on_failure() {
+        let dir = crate::test_support::temp_dir("log-buffer");
+        let log_file = dir.join("ploop.log");
+        let mut config = log_config(log_file.to_str().unwrap(), "info");
+        config.log_on_success = false;
+        let logger = PloopLogger::new(&config).unwrap();
+        let info = |message: &str| {
+            logger.log(&Record::builder().level(Level::Info).args(format_args!("{}", message)).build());
+        };
+
+        logger.begin_run();
+        info("building");
+        info("deploying");
+        assert_eq!(fs::read_to_string(&log_file).unwrap(), "");
+        // Warnings are never held back
+        logger.log(&Record::builder().level(Level::Warn).args(format_args!("disk almost full")).build());
+        assert!(fs::read_to_string(&log_file).unwrap().ends_with("WARN - disk almost full\n"));
+        logger.end_run(true, "status=success exit=0").unwrap();
+        let content = fs::read_to_string(&log_file).unwrap();
+        assert_eq!(content.lines().count(), 2);
+        assert!(content.ends_with("INFO - status=success exit=0\n"), "{}", content);
+
+        logger.begin_run();
+        info("building");
+        info("build failed");
+        logger.end_run(false, "status=build_failed exit=2").unwrap();
+        let content = fs::read_to_string(&log_file).unwrap();
+        let lines: Vec<&str> = content.lines().skip(2).collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].ends_with("INFO - building") && lines[1].ends_with("INFO - build failed"));
+
+        // Outside a run, lines go straight to the file
+        info("idle");
+        assert!(fs::read_to_string(&log_file).unwrap().ends_with("INFO - idle\n"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_concurrent_writes_keep_lines_intact() {
+        let dir = crate::test_support::temp_dir("log-stress");
+        let log_file = dir.join("ploop.log");
+        let log_file = log_file.to_str().unwrap();
+        let payload = "x".repeat(8 * 1024);
+
+        // Separate loggers on the same file stand in for separate processes
+        std::thread::scope(|scope| {
+            for worker in 0..8 {
+                let payload = &payload;
+                scope.spawn(move || {
+                    let logger = PloopLogger::new(&log_config(log_file, "info")).unwrap();
+                    for n in 0..50 {
+                        logger
+                            .log_deployment(&format!("w{}-{}", worker, n), "deploy", payload)
+                            .unwrap();
+                    }
+                });
+            }
+        });
+
+        let content = fs::read_to_string(log_file).unwrap();
+        let lines: Vec<&str> = content.lines().collect();
+        assert_eq!(lines.len(), 8 * 50);
+        for line in lines {
+            assert!(line.starts_with('['), "interleaved line: {:.60}", line);
+            assert!(line.ends_with(payload.as_str()), "truncated line: {:.60}", line);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_filter_since_keeps_continuation_lines() {
+        let content = "[2024-05-01 10:00:00] INFO - old\n\
+                       continued old\n\
+                       [2024-05-01 11:30:00] ERROR - Build failed: boom\n\
+                       error[E0425]: cannot find value\n\
+                       [2024-05-01 11:45:00] INFO - retry";
+        let since = NaiveDateTime::parse_from_str("2024-05-01 11:00:00", "%Y-%m-%d %H:%M:%S").unwrap();
+
+        let lines = filter_since(content, since);
+        assert_eq!(
+            lines,
+            vec![
+                "[2024-05-01 11:30:00] ERROR - Build failed: boom",
+                "error[E0425]: cannot find value",
+                "[2024-05-01 11:45:00] INFO - retry",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_init_bounds_max_level_and_rejects_second_logger() {
+        let log_file = std::env::temp_dir().join(format!("postloop-{}.log", uuid::Uuid::new_v4()));
+        let log_file = log_file.to_str().unwrap();
+
+        PloopLogger::init(&log_config(log_file, "warn")).unwrap();
+        assert_eq!(log::max_level(), log::LevelFilter::Warn);
+
+        let err = PloopLogger::init(&log_config(log_file, "info")).unwrap_err();
+        assert!(err.to_string().contains("already installed"));
+        assert_eq!(log::max_level(), log::LevelFilter::Warn);
+        std::fs::remove_file(log_file).unwrap();
+    }
 }
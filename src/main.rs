@@ -94,6 +94,7 @@ fn main() {
     }
 }
 
+#[allow(clippy::needless_return)]
 fn cmd_zene(
     _prompt: Option<String>,
     _zene_session_id: Option<String>,
@@ -603,10 +604,7 @@ fn execute_with_pty(
     thread::spawn(move || {
         let mut stdin = std::io::stdin();
         let mut buffer = [0_u8; 1024];
-        loop {
-            let Ok(read_bytes) = stdin.read(&mut buffer) else {
-                break;
-            };
+        while let Ok(read_bytes) = stdin.read(&mut buffer) {
             if read_bytes == 0 {
                 break;
             }
@@ -625,10 +623,8 @@ fn execute_with_pty(
     let status = child.wait()?;
     drop(pair.master);
 
-    if let Ok(result) = output_thread.join() {
-        if let Err(error) = result {
-            eprintln!("Warning: failed to capture PTY output: {}", error);
-        }
+    if let Ok(Err(error)) = output_thread.join() {
+        eprintln!("Warning: failed to capture PTY output: {}", error);
     }
 
     let stdout = String::from_utf8_lossy(&output_capture.lock().expect("capture lock poisoned")).to_string();
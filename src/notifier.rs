@@ -0,0 +1,205 @@
+use crate::config::NotifyConfig;
+use crate::events::PipelineEvent;
+use crate::history::Outcome;
+use serde::Serialize;
+use std::time::Duration;
+
+/// Result of a pipeline run (or rollback) sent to the webhook
+#[derive(Debug, Clone, Serialize)]
+pub struct DeployEvent {
+    pub commit: String,
+    pub outcome: Outcome,
+    pub duration_secs: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// ID of the run, as in its log lines
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub run_id: Option<String>,
+}
+
+impl DeployEvent {
+    pub fn new(commit: &str, outcome: Outcome, duration: Duration) -> Self {
+        DeployEvent {
+            commit: commit.to_string(),
+            outcome,
+            duration_secs: duration.as_secs_f64(),
+            error: None,
+            run_id: crate::logger::current_run_id(),
+        }
+    }
+
+    pub fn with_error(mut self, error: &str) -> Self {
+        self.error = Some(error.to_string());
+        self
+    }
+
+    /// One-line human summary, used as the Slack message text
+    pub fn summary(&self) -> String {
+        let mut text = format!(
+            "postloop: {} for commit {} ({:.1}s)",
+            self.outcome, self.commit, self.duration_secs
+        );
+        if let Some(error) = &self.error {
+            text.push_str(&format!(": {}", error));
+        }
+        text
+    }
+}
+
+/// A rollback sent to the webhook, automatic (after a failed deploy) or manual
+#[derive(Debug, Clone, Serialize)]
+pub struct RollbackEvent {
+    pub target_dir: String,
+    /// Version 'current' left, if any
+    pub from: Option<String>,
+    /// Version live afterwards; `None` when there was no previous version
+    /// and `rollback.on_no_previous` took the target offline
+    pub to: Option<String>,
+    /// The deploy error for automatic rollbacks, e.g. "manual rollback" otherwise
+    pub reason: String,
+    pub automatic: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub run_id: Option<String>,
+}
+
+impl RollbackEvent {
+    /// One-line human summary, used as the Slack message text
+    pub fn summary(&self) -> String {
+        format!(
+            "postloop: {} rolled back from {} to {}: {}",
+            self.target_dir,
+            self.from.as_deref().unwrap_or("(none)"),
+            self.to.as_deref().unwrap_or("(none)"),
+            self.reason
+        )
+    }
+}
+
+/// Send the webhook notification for a pipeline event, if it warrants one
+///
+/// Only a finished run and a rollback are reported; phase events are not.
+pub fn notify_event(config: &NotifyConfig, event: &PipelineEvent) {
+    match event {
+        PipelineEvent::RunFinished {
+            commit,
+            outcome,
+            duration_secs,
+            error,
+        } => {
+            let mut event = DeployEvent::new(commit, *outcome, Duration::from_secs_f64(*duration_secs));
+            event.error = error.clone();
+            notify(config, &event);
+        }
+        PipelineEvent::RolledBack {
+            target_dir,
+            from,
+            to,
+            reason,
+            automatic,
+        } => notify_rollback(
+            config,
+            &RollbackEvent {
+                target_dir: target_dir.clone(),
+                from: from.clone(),
+                to: to.clone(),
+                reason: reason.clone(),
+                automatic: *automatic,
+                run_id: crate::logger::current_run_id(),
+            },
+        ),
+        _ => {}
+    }
+}
+
+/// Send a rollback event to the configured webhook (when `on_failure` is set)
+///
+/// Like deploy notifications, failures are only logged.
+pub fn notify_rollback(config: &NotifyConfig, event: &RollbackEvent) {
+    let Some(url) = config.webhook_url.as_deref() else {
+        return;
+    };
+    if !config.on_failure {
+        return;
+    }
+
+    let payload = serde_json::to_value(event).map_err(|e| e.into());
+    match payload.and_then(|payload| send_webhook(url, Duration::from_secs(config.timeout_secs), payload, event.summary())) {
+        Ok(()) => log::info!("Sent rollback notification for {}", event.target_dir),
+        Err(e) => log::warn!("Failed to send rollback notification: {}", e),
+    }
+}
+
+/// Send a deploy event to the configured webhook
+///
+/// Notification failures are logged as warnings and never fail the deploy.
+pub fn notify(config: &NotifyConfig, event: &DeployEvent) {
+    let Some(url) = config.webhook_url.as_deref() else {
+        return;
+    };
+
+    let wanted = match event.outcome {
+        Outcome::Success | Outcome::Skipped => config.on_success,
+        _ => config.on_failure,
+    };
+    if !wanted {
+        return;
+    }
+
+    let payload = serde_json::to_value(event).map_err(|e| e.into());
+    match payload.and_then(|payload| send_webhook(url, Duration::from_secs(config.timeout_secs), payload, event.summary())) {
+        Ok(()) => log::info!("Sent {} notification for {}", event.outcome, event.commit),
+        Err(e) => log::warn!("Failed to send deploy notification: {}", e),
+    }
+}
+
+/// POST `payload` with the summary added as `text`
+fn send_webhook(
+    url: &str,
+    timeout: Duration,
+    mut payload: serde_json::Value,
+    summary: String,
+) -> Result<(), Box<dyn std::error::Error>> {
+    payload["text"] = serde_json::Value::String(summary);
+
+    ureq::AgentBuilder::new()
+        .timeout(timeout)
+        .build()
+        .post(url)
+        .set("Content-Type", "application/json")
+        .send_string(&payload.to_string())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::serve_http_once;
+    use std::net::TcpListener;
+
+    #[test]
+    fn test_notify_posts_event() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let server = serve_http_once(listener, "200 OK");
+
+        let event = DeployEvent::new("abc1234", Outcome::DeployFailed, Duration::from_secs(2)).with_error("disk full");
+        send_webhook(&url, Duration::from_secs(5), serde_json::to_value(&event).unwrap(), event.summary()).unwrap();
+
+        let request = server.join().unwrap();
+        let body: serde_json::Value = serde_json::from_str(request.split_once("\r\n\r\n").unwrap().1).unwrap();
+        assert_eq!(body["commit"], "abc1234");
+        assert_eq!(body["outcome"], "deploy-failed");
+        assert_eq!(body["error"], "disk full");
+        assert!(body["text"].as_str().unwrap().contains("deploy-failed"));
+    }
+
+    #[test]
+    fn test_notify_failure_only_warns() {
+        let config = NotifyConfig {
+            webhook_url: Some("http://127.0.0.1:9/unreachable".to_string()),
+            timeout_secs: 1,
+            ..NotifyConfig::default()
+        };
+        notify(&config, &DeployEvent::new("abc1234", Outcome::Success, Duration::from_secs(1)));
+    }
+}
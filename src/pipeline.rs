@@ -0,0 +1,2569 @@
+use crate::builder;
+use crate::config::{ArtifactSpec, Config, DeployConfig, NoPreviousAction};
+use crate::deployer;
+use crate::events::{EventEmitter, PipelineEvent};
+use crate::history::{self, DeploymentRecorder, FileRecorder, HistoryRecord, Outcome};
+use crate::hook;
+use crate::logger;
+use crate::rollback;
+use crate::runner;
+use crate::syncer;
+use serde::Serialize;
+use std::collections::HashSet;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// Process exit codes for `ploop run`, documented for CI scripts
+pub const EXIT_SUCCESS: i32 = 0;
+pub const EXIT_BUILD_FAILED: i32 = 1;
+pub const EXIT_DEPLOY_FAILED: i32 = 2;
+pub const EXIT_ROLLED_BACK: i32 = 3;
+pub const EXIT_CONFIG_ERROR: i32 = 4;
+pub const EXIT_SYNC_FAILED: i32 = 5;
+
+/// Why a pipeline run failed, mapped onto distinct exit codes
+#[derive(Debug)]
+pub enum PipelineError {
+    /// Invalid configuration or a failed preflight check; nothing was built
+    Config(String),
+    Build(String),
+    /// Deploy failed and no previous version could be restored
+    Deploy(String),
+    /// Deploy failed and 'current' was switched back to `restored`
+    RolledBack { error: String, restored: String },
+    /// Deploy succeeded but the required sync (`sync.required`) failed
+    Sync(String),
+}
+
+impl PipelineError {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            PipelineError::Config(_) => EXIT_CONFIG_ERROR,
+            PipelineError::Build(_) => EXIT_BUILD_FAILED,
+            PipelineError::Deploy(_) => EXIT_DEPLOY_FAILED,
+            PipelineError::RolledBack { .. } => EXIT_ROLLED_BACK,
+            PipelineError::Sync(_) => EXIT_SYNC_FAILED,
+        }
+    }
+
+    /// Short machine-readable label, matching the history outcome names
+    pub fn status(&self) -> &'static str {
+        match self {
+            PipelineError::Config(_) => "config-error",
+            PipelineError::Build(_) => "build-failed",
+            PipelineError::Deploy(_) => "deploy-failed",
+            PipelineError::RolledBack { .. } => "rolled-back",
+            PipelineError::Sync(_) => "sync-failed",
+        }
+    }
+}
+
+impl fmt::Display for PipelineError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PipelineError::Config(e) => write!(f, "Configuration error: {}", e),
+            PipelineError::Build(e) => write!(f, "Build failed: {}", e),
+            PipelineError::Deploy(e) => write!(f, "Deploy failed: {}", e),
+            PipelineError::RolledBack { error, restored } => {
+                write!(f, "Deploy failed: {} (rolled back to {})", error, restored)
+            }
+            PipelineError::Sync(e) => write!(f, "Sync failed: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for PipelineError {}
+
+/// What a successful run did
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RunStatus {
+    Deployed { commit: String },
+    /// The latest commit touched none of the watched paths, or (with
+    /// `deploy.skip_unchanged`) its artifacts are already deployed
+    Skipped,
+}
+
+/// Per-invocation overrides for a run (the `ploop run` flags)
+#[derive(Debug, Clone, Default)]
+pub struct RunOptions {
+    /// Deploy even when `watch.require_clean_tree` is set and the tree is dirty
+    pub allow_dirty: bool,
+    /// Build and deploy this revision from a temporary worktree instead of HEAD
+    pub commit: Option<String>,
+    /// Skip the push even when `sync.enabled` is set
+    pub no_sync: bool,
+    /// Leave a failed deploy in place even when `rollback.enabled` is set
+    pub no_rollback: bool,
+    /// Deploy artifacts that already exist; artifact verification still runs
+    pub no_build: bool,
+    /// Deploy even when the current version already holds identical artifacts,
+    /// or when `watch.abort_if_behind` is set and the branch is behind
+    pub force: bool,
+    /// Wall-clock budget for the run, overriding `watch.run_timeout_secs`
+    pub timeout: Option<Duration>,
+    /// Print a [`RunSummary`] as a JSON line to stdout once the run is over
+    pub summary: bool,
+    /// Resolve artifacts from this directory (e.g. a CI build directory)
+    /// instead of `deploy.artifact_base`; it must exist
+    pub build_dir: Option<String>,
+}
+
+impl RunOptions {
+    /// `config` with the `--no-sync` / `--no-rollback` / `--force` toggles
+    /// and `--build-dir` applied
+    pub fn apply_overrides(&self, config: &Config) -> Config {
+        let mut config = config.clone();
+        if self.no_sync {
+            config.sync.enabled = false;
+        }
+        if self.no_rollback {
+            config.rollback.enabled = false;
+        }
+        if self.force {
+            config.deploy.skip_unchanged = false;
+        }
+        if let Some(build_dir) = &self.build_dir {
+            config.deploy.artifact_base = Some(build_dir.clone());
+        }
+        config
+    }
+}
+
+/// A phase of a run, in execution order
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Phase {
+    Build,
+    Verify,
+    Deploy,
+    /// The canary health check; it runs inside the deploy, so its line reports the result
+    HealthCheck,
+    Sync,
+}
+
+impl Phase {
+    fn description(self) -> &'static str {
+        match self {
+            Phase::Build => "🔨 Building...",
+            Phase::Verify => "🔍 Verifying artifacts...",
+            Phase::Deploy => "🚀 Deploying...",
+            Phase::HealthCheck => "🩺 Canary health check passed",
+            Phase::Sync => "🔄 Syncing...",
+        }
+    }
+}
+
+/// The phases a run will go through with a given config, enumerated up front
+/// so progress can be shown as `[2/4]` (and a dry run can list them)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RunPlan {
+    pub phases: Vec<Phase>,
+}
+
+impl RunPlan {
+    pub fn for_config(config: &Config) -> Self {
+        let deploy = &config.deploy;
+        let file_deploy = deploy.command.is_none() && deploy.sftp.is_none();
+
+        let mut phases = vec![Phase::Build];
+        if target_artifacts(deploy).is_some() {
+            phases.push(Phase::Verify);
+        }
+        phases.push(Phase::Deploy);
+        if file_deploy && deploy.versioned && deploy.canary.is_some() {
+            phases.push(Phase::HealthCheck);
+        }
+        if config.sync.enabled {
+            phases.push(Phase::Sync);
+        }
+        RunPlan { phases }
+    }
+
+    /// Progress line for `phase`, e.g. `[2/4] 🔍 Verifying artifacts...`
+    ///
+    /// Phases not in the plan get the description alone.
+    pub fn label(&self, phase: Phase) -> String {
+        match self.phases.iter().position(|planned| *planned == phase) {
+            Some(index) => format!("[{}/{}] {}", index + 1, self.phases.len(), phase.description()),
+            None => phase.description().to_string(),
+        }
+    }
+}
+
+/// How long one executed phase took, for [`RunSummary`]
+#[derive(Debug, Clone, Serialize)]
+pub struct PhaseTiming {
+    pub phase: Phase,
+    pub duration_secs: f64,
+    pub success: bool,
+}
+
+/// Machine-readable result of one run (`ploop run --summary=json`), printed
+/// as the very last stdout line so CI can pick it up with `tail -1`
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct RunSummary {
+    /// ID tagging the run's log lines, version metadata and notifications
+    pub run_id: String,
+    /// Commit the run built, unset when it stopped before resolving one
+    pub commit: Option<String>,
+    /// Version the main target's 'current' names after the run
+    pub version: Option<String>,
+    /// Phases that ran, in order
+    pub phases: Vec<PhaseTiming>,
+    /// `success`, `skipped`, or the failure status of [`status_line`]
+    pub outcome: String,
+    pub exit_code: i32,
+    pub duration_secs: f64,
+}
+
+impl RunSummary {
+    fn finish(&mut self, result: &Result<RunStatus, PipelineError>, started: Instant) {
+        (self.outcome, self.exit_code) = match result {
+            Ok(RunStatus::Deployed { .. }) => ("success".to_string(), EXIT_SUCCESS),
+            Ok(RunStatus::Skipped) => ("skipped".to_string(), EXIT_SUCCESS),
+            Err(e) => (e.status().to_string(), e.exit_code()),
+        };
+        self.duration_secs = started.elapsed().as_secs_f64();
+    }
+
+    /// The summary as a single JSON line
+    pub fn to_json_line(&self) -> String {
+        serde_json::to_string(self).unwrap_or_default()
+    }
+}
+
+/// Run `phase`, appending its duration and result to `timings`
+fn timed<T, E>(timings: &mut Vec<PhaseTiming>, phase: Phase, run: impl FnOnce() -> Result<T, E>) -> Result<T, E> {
+    let started = Instant::now();
+    let result = run();
+    timings.push(PhaseTiming {
+        phase,
+        duration_secs: started.elapsed().as_secs_f64(),
+        success: result.is_ok(),
+    });
+    result
+}
+
+/// Final status line printed by `ploop run --quiet`, e.g. `status=success exit=0 commit=abc1234`
+pub fn status_line(result: &Result<RunStatus, PipelineError>) -> String {
+    match result {
+        Ok(RunStatus::Deployed { commit }) => {
+            format!("status=success exit={} commit={}", EXIT_SUCCESS, commit)
+        }
+        Ok(RunStatus::Skipped) => format!("status=skipped exit={}", EXIT_SUCCESS),
+        Err(e) => format!("status={} exit={}", e.status(), e.exit_code()),
+    }
+}
+
+/// Run the full pipeline: preflight, build, deploy (rolling back on failure) and sync
+///
+/// Each finished run is appended to the target's history and sent to the
+/// notification webhook, if configured. Phase transitions are reported as
+/// [`PipelineEvent`]s.
+///
+/// With `options.commit` set, that commit is checked out into a temporary git
+/// worktree (removed afterwards, also on failure) and run from there, leaving
+/// the working directory untouched; watch paths and the clean-tree check do
+/// not apply to such explicit redeploys.
+///
+/// With `log.log_on_success` off, the run's log lines below warn only reach
+/// the log file if it fails; a successful run logs just its warnings and
+/// [`status_line`].
+pub fn run(config: &Config, options: &RunOptions) -> Result<RunStatus, PipelineError> {
+    run_recorded(config, options, None)
+}
+
+/// Deploy every commit since the last successful deploy in order, each from
+/// its own worktree (`ploop run --catch-up`), stopping at the first failure
+///
+/// The last deployed commit is the newest successful record in the main
+/// target's `history.log`, or else the commit recorded in the current
+/// version's metadata. Without either, or when that commit is not an
+/// ancestor of HEAD (history was rewritten, or a different branch was
+/// deployed), only HEAD is run. Returns the status of each run;
+/// `options.commit` is ignored.
+pub fn run_catch_up(config: &Config, options: &RunOptions) -> Result<Vec<RunStatus>, PipelineError> {
+    let resolved = resolve_templates(config)?;
+    let repo_path = config.watch.repo_path.as_str();
+    let head_only = || {
+        let options = RunOptions {
+            commit: None,
+            ..options.clone()
+        };
+        run(config, &options).map(|status| vec![status])
+    };
+
+    let Some(last) = last_deployed_commit(&resolved) else {
+        log::info!("No deployed commit recorded, running HEAD only");
+        return head_only();
+    };
+    match hook::is_ancestor(repo_path, &config.watch.git, &last, "HEAD") {
+        Ok(true) => {}
+        Ok(false) => {
+            log::warn!("Last deployed commit {} is not an ancestor of HEAD, running HEAD only", last);
+            return head_only();
+        }
+        Err(e) => {
+            log::warn!("{}, running HEAD only", e);
+            return head_only();
+        }
+    }
+
+    let pending = hook::commits_between(repo_path, &config.watch.git, &last, "HEAD").map_err(|e| PipelineError::Config(e.to_string()))?;
+    log::info!("Catching up {} commit(s) since {}", pending.len(), &last[..last.len().min(7)]);
+
+    let mut statuses = Vec::new();
+    for (index, commit) in pending.iter().enumerate() {
+        log::info!("Catch-up {}/{}: {}", index + 1, pending.len(), commit);
+        let options = RunOptions {
+            commit: Some(commit.clone()),
+            ..options.clone()
+        };
+        statuses.push(run(config, &options)?);
+    }
+    Ok(statuses)
+}
+
+/// Commit of the last successful deploy to the main target, see [`run_catch_up`]
+fn last_deployed_commit(config: &Config) -> Option<String> {
+    let target_dir = config.deploy.target_dir.as_deref()?;
+    let from_history = history::read_records(&config.deploy.state_dir(target_dir))
+        .ok()
+        .and_then(|records| records.into_iter().rev().find(|record| matches!(record.outcome, Outcome::Success | Outcome::Skipped)))
+        .map(|record| record.commit);
+
+    from_history.or_else(|| {
+        let version = rollback::current_version(target_dir, &config.deploy.current_link_name)?;
+        rollback::deployed_commit(&Path::new(target_dir).join(version))
+    })
+}
+
+/// Like [`run`], handing the run's [`HistoryRecord`] to `recorder` instead
+/// of appending it to `history.log` in the target directory
+pub fn run_with_recorder(
+    config: &Config,
+    options: &RunOptions,
+    recorder: &dyn DeploymentRecorder,
+) -> Result<RunStatus, PipelineError> {
+    run_recorded(config, options, Some(recorder))
+}
+
+fn run_recorded(
+    config: &Config,
+    options: &RunOptions,
+    recorder: Option<&dyn DeploymentRecorder>,
+) -> Result<RunStatus, PipelineError> {
+    // Past the deadline the active command is killed and the run unwinds as a
+    // failure of whichever phase it was in; a deploy failure still rolls back
+    let timeout = options.timeout.or(config.watch.run_timeout_secs.map(Duration::from_secs));
+    runner::set_deadline(timeout.map(|timeout| Instant::now() + timeout));
+
+    let (result, summary) = run_summarized(config, options, recorder);
+    runner::set_deadline(None);
+    // PLOOP_* variables describe this run only; later commands on the thread
+    // (a manual rollback, an embedder's own calls) must not inherit them
+    runner::clear_command_env();
+    if options.summary {
+        println!("{}", summary.to_json_line());
+    }
+    result
+}
+
+fn run_summarized(
+    config: &Config,
+    options: &RunOptions,
+    recorder: Option<&dyn DeploymentRecorder>,
+) -> (Result<RunStatus, PipelineError>, RunSummary) {
+    let started = Instant::now();
+    let mut summary = RunSummary {
+        run_id: logger::new_run_id(),
+        ..RunSummary::default()
+    };
+    logger::set_run_id(Some(&summary.run_id));
+    logger::begin_run();
+    let result = run_pinned_or_head(&options.apply_overrides(config), options, recorder, &mut summary);
+    logger::end_run(result.is_ok(), &status_line(&result));
+    logger::set_run_id(None);
+    summary.finish(&result, started);
+    (result, summary)
+}
+
+fn run_pinned_or_head(
+    config: &Config,
+    options: &RunOptions,
+    recorder: Option<&dyn DeploymentRecorder>,
+    summary: &mut RunSummary,
+) -> Result<RunStatus, PipelineError> {
+    runner::clear_command_env();
+
+    let repo_path = config.watch.repo_path.as_str();
+    let Some(rev) = &options.commit else {
+        let branch = hook::get_current_branch(repo_path, &config.watch.git).ok();
+        return run_checked_out(&resolve_templates(config)?, options, branch.as_deref(), recorder, summary);
+    };
+
+    let commit = hook::resolve_commit(repo_path, &config.watch.git, rev).map_err(|e| PipelineError::Config(e.to_string()))?;
+    let deploy_branch = branch_of_commit(config, &commit);
+    let mut pinned = with_preview(config, deploy_branch.as_deref());
+
+    // The worktree is detached, so placeholders are filled in from here
+    let templates = pinned.deploy.target_dir.iter().chain(pinned.deploy.targets.iter().map(|t| &t.target_dir));
+    let branch = if templates.into_iter().any(|template| template.contains("{branch}")) {
+        deploy_branch.clone().ok_or_else(|| {
+            PipelineError::Config(format!("Cannot resolve target_dir: no single branch contains {}", commit))
+        })?
+    } else {
+        String::new()
+    };
+    if let Some(template) = &pinned.deploy.target_dir {
+        pinned.deploy.target_dir = Some(deployer::render_target_template(template, &branch, &commit));
+    }
+    for target in &mut pinned.deploy.targets {
+        target.target_dir = deployer::render_target_template(&target.target_dir, &branch, &commit);
+    }
+
+    let worktree = hook::Worktree::add(repo_path, &config.watch.git, &commit).map_err(|e| PipelineError::Config(e.to_string()))?;
+    pinned.watch.repo_path = worktree.path().to_string_lossy().into_owned();
+    run_checked_out(&pinned, options, deploy_branch.as_deref(), recorder, summary)
+}
+
+/// The branch a pinned `commit` deploys from: the checked-out branch when it
+/// contains the commit, otherwise the only branch that does
+///
+/// `None` when no branch or several other branches contain it.
+fn branch_of_commit(config: &Config, commit: &str) -> Option<String> {
+    let (repo_path, git) = (config.watch.repo_path.as_str(), &config.watch.git);
+    let containing = match hook::branches_containing(repo_path, git, commit) {
+        Ok(containing) => containing,
+        Err(e) => {
+            log::warn!("Cannot find the branch of {}: {}", commit, e);
+            return None;
+        }
+    };
+    if let Ok(current) = hook::get_current_branch(repo_path, git) {
+        if containing.contains(&current) {
+            return Some(current);
+        }
+    }
+    match containing.as_slice() {
+        [only] => Some(only.clone()),
+        _ => None,
+    }
+}
+
+/// Fail when `watch.branch` is behind `sync.remote`, so a commit already
+/// superseded there isn't deployed
+///
+/// Compares against the remote-tracking branch as of the last fetch; a
+/// branch that was never pushed passes.
+fn check_not_behind(config: &Config) -> Result<(), PipelineError> {
+    let remote = &config.sync.remote;
+    let branch = &config.watch.branch;
+    let counts = syncer::ahead_behind(remote, branch, &config.watch.repo_path, &config.watch.git)
+        .map_err(|e| PipelineError::Config(format!("Cannot compare {} with {}/{}: {}", branch, remote, branch, e)))?;
+    match counts {
+        Some((_, behind)) if behind > 0 => Err(PipelineError::Config(format!(
+            "{} is {} commit(s) behind {}/{}; pull first (or pass --force)",
+            branch, behind, remote, branch
+        ))),
+        _ => Ok(()),
+    }
+}
+
+/// Expose the commit being deployed to build and deploy commands as
+/// `PLOOP_COMMIT`, `PLOOP_COMMIT_SHORT`, `PLOOP_BRANCH` (empty when the
+/// commit has no branch) and `PLOOP_DEPLOY_VERSION`
+///
+/// The main target's version name is chosen here, once, and returned so the
+/// deploy uses the very name the build saw. `PLOOP_DEPLOY_VERSION` is the
+/// short hash for unversioned and command deploys.
+fn set_commit_env(config: &Config, commit: &str, branch: Option<&str>) -> Option<String> {
+    let short: String = commit.chars().take(7).collect();
+    let version = deployer::planned_version(&config.deploy, &config.watch.repo_path, &config.watch.git, commit).unwrap_or_else(|e| {
+        log::warn!("Cannot name the version directory yet, the deploy will: {}", e);
+        None
+    });
+
+    runner::set_command_env("PLOOP_COMMIT", commit);
+    runner::set_command_env("PLOOP_COMMIT_SHORT", &short);
+    runner::set_command_env("PLOOP_BRANCH", branch.unwrap_or_default());
+    runner::set_command_env("PLOOP_DEPLOY_VERSION", version.as_deref().unwrap_or(&short));
+    version
+}
+
+/// `recorder` defaults to the `history.log` of the (resolved) main target
+/// directory; `branch` is the branch the commit deploys from
+fn run_checked_out(
+    config: &Config,
+    options: &RunOptions,
+    branch: Option<&str>,
+    recorder: Option<&dyn DeploymentRecorder>,
+    summary: &mut RunSummary,
+) -> Result<RunStatus, PipelineError> {
+    let discovered;
+    let config = match discover_artifacts(config)? {
+        Some(with_artifacts) => {
+            discovered = with_artifacts;
+            &discovered
+        }
+        None => config,
+    };
+    let repo_path = config.watch.repo_path.as_str();
+    let pinned = options.commit.is_some();
+
+    if config.watch.require_clean_tree && !options.allow_dirty && !pinned {
+        let clean = hook::is_working_tree_clean(repo_path, &config.watch.git).map_err(|e| PipelineError::Config(e.to_string()))?;
+        if !clean {
+            return Err(PipelineError::Config(
+                "Working tree has uncommitted changes; commit or stash them, or pass --allow-dirty".to_string(),
+            ));
+        }
+    }
+
+    if config.watch.abort_if_behind && !options.force && !pinned {
+        check_not_behind(config)?;
+    }
+
+    let problems = deployer::preflight(config);
+    if !problems.is_empty() {
+        return Err(PipelineError::Config(problems.join("; ")));
+    }
+    if options.build_dir.is_some() {
+        let build_dir = config.deploy.artifact_base_dir(repo_path);
+        if !Path::new(&build_dir).is_dir() {
+            return Err(PipelineError::Config(format!("Build directory does not exist: {}", build_dir)));
+        }
+    }
+
+    let triggered = pinned
+        || hook::head_touches_watch_paths(repo_path, &config.watch.git, &config.watch.paths)
+            .map_err(|e| PipelineError::Config(e.to_string()))?;
+    if !triggered {
+        return Ok(RunStatus::Skipped);
+    }
+
+    let commit = hook::get_current_commit_hash(repo_path, &config.watch.git).map_err(|e| PipelineError::Config(e.to_string()))?;
+    summary.commit = Some(commit.clone());
+    let version = set_commit_env(config, &commit, branch);
+    let started = Instant::now();
+    let events = EventEmitter::for_config(&config.notify);
+    let mut plan = RunPlan::for_config(config);
+    if options.no_build {
+        plan.phases.retain(|phase| *phase != Phase::Build);
+    }
+
+    events.emit(&PipelineEvent::BuildStarted { commit: commit.clone() });
+    let built = build_and_verify_planned(config, &plan, &mut summary.phases).map_err(|e| PipelineError::Build(e.to_string()));
+    let build_duration = started.elapsed();
+    events.emit(&PipelineEvent::BuildFinished {
+        commit: commit.clone(),
+        success: built.is_ok(),
+    });
+
+    let unchanged = built.is_ok()
+        && deployer::nothing_to_deploy(&config.deploy, repo_path, &commit).unwrap_or_else(|e| {
+            log::warn!("Cannot compare the artifacts with the deployed versions, deploying anyway: {}", e);
+            false
+        });
+    let before = active_versions(config);
+    let mut failed_output = None;
+    let result = if unchanged {
+        // Nothing is deployed, but the run is still synced, recorded and reported
+        log::info!("No changes to deploy: every target already holds identical artifacts");
+        Ok(None)
+    } else {
+        built.and_then(|()| {
+            events.emit(&PipelineEvent::DeployStarted { commit: commit.clone() });
+            log::info!("{}", plan.label(Phase::Deploy));
+            let deployed = timed(&mut summary.phases, Phase::Deploy, || deploy_or_rollback(config, &commit, version.as_deref(), &mut failed_output));
+            if deployed.is_ok() {
+                if plan.phases.contains(&Phase::HealthCheck) {
+                    log::info!("{}", plan.label(Phase::HealthCheck));
+                }
+                record_build_details(config, build_duration, branch);
+            }
+            events.emit(&PipelineEvent::DeployFinished {
+                commit: commit.clone(),
+                success: deployed.is_ok(),
+            });
+            deployed
+        })
+    };
+
+    // A required sync decides the outcome, so it runs before the run is recorded
+    let result = result.and_then(|output| {
+        if !(config.sync.enabled && config.sync.required) {
+            return Ok(output);
+        }
+        match sync_phase(config, &commit, &plan, &events, &mut summary.phases) {
+            Ok(()) => Ok(output),
+            // Nothing was deployed, so there is nothing to roll back
+            Err(e) if unchanged => {
+                log::warn!("Sync failed: {}", e);
+                Err(PipelineError::Sync(e.to_string()))
+            }
+            Err(e) => Err(required_sync_failed(config, before, &e.to_string())),
+        }
+    });
+
+    summary.version = config.deploy.target_dir.as_deref().and_then(|target_dir| rollback::current_version(target_dir, &config.deploy.current_link_name));
+
+    let (outcome, error) = match &result {
+        Ok(_) if unchanged => (Outcome::Skipped, None),
+        Ok(_) => (Outcome::Success, None),
+        Err(PipelineError::Build(e)) => (Outcome::BuildFailed, Some(e.clone())),
+        Err(PipelineError::RolledBack { error, .. }) => (Outcome::RolledBack, Some(error.clone())),
+        Err(e) => (Outcome::DeployFailed, Some(e.to_string())),
+    };
+    let output = match &result {
+        Ok(output) => output.clone(),
+        Err(_) => failed_output,
+    };
+    record_run(config, recorder, &commit, outcome, started, error.as_deref(), output.as_deref());
+
+    if result.is_ok() && config.sync.enabled && !config.sync.required {
+        // The deploy already succeeded (or was not needed), so a failed push is only reported
+        if let Err(e) = sync_phase(config, &commit, &plan, &events, &mut summary.phases) {
+            log::warn!("Sync failed after successful deploy: {}", e);
+        }
+    }
+
+    // The last event, after every phase; it also sends the webhook notification
+    events.emit(&PipelineEvent::RunFinished {
+        commit: commit.clone(),
+        outcome,
+        duration_secs: started.elapsed().as_secs_f64(),
+        error,
+    });
+    run_outcome_command(config, &commit, outcome, started);
+    result.map(|_| if unchanged { RunStatus::Skipped } else { RunStatus::Deployed { commit } })
+}
+
+/// The sync phase of a run: push `commit`, reporting it as pipeline events
+fn sync_phase(
+    config: &Config,
+    commit: &str,
+    plan: &RunPlan,
+    events: &EventEmitter,
+    phases: &mut Vec<PhaseTiming>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    events.emit(&PipelineEvent::SyncStarted { commit: commit.to_string() });
+    log::info!("{}", plan.label(Phase::Sync));
+    let synced = timed(phases, Phase::Sync, || sync_commit(config, commit));
+    events.emit(&PipelineEvent::SyncFinished {
+        commit: commit.to_string(),
+        success: synced.is_ok(),
+    });
+    synced
+}
+
+/// Run `notify.success_command` or `notify.failure_command`, the very last step of a run
+///
+/// The command sees `PLOOP_COMMIT`, `PLOOP_VERSION` (the version 'current'
+/// names afterwards, empty for command deploys), `PLOOP_OUTCOME` and
+/// `PLOOP_DURATION` (seconds). Its failure is only logged; a skipped run,
+/// which changed nothing, runs neither.
+fn run_outcome_command(config: &Config, commit: &str, outcome: Outcome, started: Instant) {
+    let command = match outcome {
+        Outcome::Success => config.notify.success_command.as_deref(),
+        Outcome::Skipped => None,
+        _ => config.notify.failure_command.as_deref(),
+    };
+    let Some(command) = command else {
+        return;
+    };
+
+    let version = config
+        .deploy
+        .target_dir
+        .as_deref()
+        .and_then(|target_dir| rollback::current_version(target_dir, &config.deploy.current_link_name))
+        .unwrap_or_default();
+    let outcome = outcome.to_string();
+    let duration = format!("{:.3}", started.elapsed().as_secs_f64());
+    let env = [
+        ("PLOOP_COMMIT", commit),
+        ("PLOOP_VERSION", version.as_str()),
+        ("PLOOP_OUTCOME", outcome.as_str()),
+        ("PLOOP_DURATION", duration.as_str()),
+    ];
+
+    log::info!("Running {} command: {}", outcome, command);
+    match runner::run_command_line(command, config.notify.use_shell, &config.watch.repo_path, &env) {
+        Ok(output) if output.status.success() => {}
+        Ok(output) => log::warn!(
+            "Outcome command exited with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ),
+        Err(e) => log::warn!("Outcome command failed to run: {}", e),
+    }
+}
+
+/// Roll a deploy target back to the previous version
+///
+/// `target` names one of `[[deploy.targets]]` (see `DeployConfig::select_target`).
+/// With `with_git_revert`, the commit of the version rolled back from is then
+/// reverted in a new commit which is pushed to `sync.remote`/`sync.branch`,
+/// so the repository matches what is deployed. Nothing is rolled back unless
+/// that commit is HEAD and no other revert is in progress. A revert that
+/// doesn't apply cleanly is aborted, and one that can't be pushed is undone;
+/// either way the error is reported and the file rollback stays in place.
+pub fn rollback(
+    config: &Config,
+    target: Option<&str>,
+    with_git_revert: bool,
+) -> Result<RollbackOutcome, Box<dyn std::error::Error>> {
+    let config = resolve_templates(config)?;
+    let target_dir = config.deploy.select_target(target)?;
+    let repo_path = config.watch.repo_path.as_str();
+
+    let to_revert = if with_git_revert {
+        let version = rollback::current_version(target_dir, &config.deploy.current_link_name)
+            .ok_or_else(|| format!("Nothing is deployed to {}, so there is no commit to revert", target_dir))?;
+        let commit = rollback::deployed_commit(&Path::new(target_dir).join(&version))
+            .ok_or_else(|| format!("Version {} has no recorded commit to revert", version))?;
+        hook::ensure_revertable(repo_path, &config.watch.git, &commit)?;
+        Some(commit)
+    } else {
+        None
+    };
+
+    let has_previous = rollback::get_deployed_versions(target_dir, &config.deploy.current_link_name)?.len() >= 2;
+    let outcome = if !config.deploy.versioned {
+        let result = restore_bare_target(target_dir)?;
+        log::info!("Rolled back {} to backup {}", target_dir, result.to);
+        announce_rollback(&config, target_dir, None, Some(&result.to), "manual rollback", false);
+        RollbackOutcome::Restored(result)
+    } else if has_previous || config.rollback.on_no_previous == NoPreviousAction::Error {
+        let result = rollback::rollback_to_previous(target_dir, &config.deploy.current_link_name)?;
+        log::info!(
+            "Rolled back {} from {} to {}",
+            target_dir,
+            result.from.as_deref().unwrap_or("(none)"),
+            result.to
+        );
+        announce_rollback(&config, target_dir, result.from.as_deref(), Some(&result.to), "manual rollback", false);
+        RollbackOutcome::Restored(result)
+    } else {
+        RollbackOutcome::NoPrevious {
+            target_dir: target_dir.to_string(),
+            active: handle_no_previous(&config, target_dir, "manual rollback", false)?,
+        }
+    };
+
+    if let Some(reverted) = to_revert {
+        let revert = hook::revert_head(repo_path, &config.watch.git, &reverted)?;
+        log::info!("Created revert commit {} for {}", revert, reverted);
+        let pushed = syncer::sync_to_remote(&config.sync.remote, &config.sync.branch, repo_path, &config.watch.git, config.sync.push_submodules);
+        if let Err(e) = pushed {
+            hook::reset_keeping_changes(repo_path, &config.watch.git, &reverted)
+                .map_err(|undo| format!("Pushing revert commit {} failed ({}) and undoing it failed: {}", revert, e, undo))?;
+            return Err(format!("Pushing revert commit {} failed, so it was undone: {}", revert, e).into());
+        }
+        log::info!("Pushed revert commit {} to {}", revert, config.sync.remote);
+    }
+
+    Ok(outcome)
+}
+
+/// Resolve the version [`rollback`] (or, with `version`, a rollback to that
+/// version) would switch a deploy target to, without changing anything
+///
+/// `rollback.on_no_previous` is not applied: a target with no previous
+/// version is reported as an error, as it would be by default.
+pub fn plan_rollback(
+    config: &Config,
+    target: Option<&str>,
+    version: Option<&str>,
+) -> Result<rollback::RollbackResult, Box<dyn std::error::Error>> {
+    let config = resolve_templates(config)?;
+    let target_dir = config.deploy.select_target(target)?;
+    rollback::plan_rollback(target_dir, &config.deploy.current_link_name, version)
+}
+
+/// Describe a rollback plan as `from`/`to` lines with each version's commit subject
+pub fn format_rollback_plan(config: &Config, plan: &rollback::RollbackResult) -> Vec<String> {
+    let versions = rollback::get_deployed_versions_detailed(&plan.target_dir, &config.deploy.current_link_name).unwrap_or_default();
+    let describe = |name: &str| {
+        let subject = versions
+            .iter()
+            .find(|version| version.name == name)
+            .and_then(|version| version.commit())
+            .and_then(|commit| hook::get_commit_subject(&config.watch.repo_path, &config.watch.git, &commit).ok());
+        match subject {
+            Some(subject) => format!("{}  {}", name, subject),
+            None => name.to_string(),
+        }
+    };
+
+    vec![
+        format!("Target: {}", plan.target_dir),
+        format!("From:   {}", plan.from.as_deref().map(describe).unwrap_or_else(|| "(none)".to_string())),
+        format!("To:     {}", describe(&plan.to)),
+    ]
+}
+
+/// What [`rollback`] did
+#[derive(Debug, Clone)]
+pub enum RollbackOutcome {
+    /// 'current' points at the previous version again
+    Restored(rollback::RollbackResult),
+    /// There was no previous version, so `rollback.on_no_previous` was
+    /// applied; `active` is the version that was live at the time
+    NoPrevious { target_dir: String, active: Option<String> },
+}
+
+/// Apply `rollback.on_no_previous` (other than `error`) to a target with no
+/// version to roll back to, announcing it like a rollback and returning the
+/// version that was active
+fn handle_no_previous(config: &Config, target_dir: &str, reason: &str, automatic: bool) -> Result<Option<String>, Box<dyn std::error::Error>> {
+    let active = rollback::current_version(target_dir, &config.deploy.current_link_name);
+    match config.rollback.on_no_previous {
+        NoPreviousAction::Error => return Err("No previous version available for rollback".into()),
+        NoPreviousAction::RemoveCurrent => {
+            rollback::remove_current(target_dir, &config.deploy.current_link_name)?;
+            log::warn!(
+                "No previous version in {}: removed 'current' ({}), nothing is deployed now",
+                target_dir,
+                active.as_deref().unwrap_or("none")
+            );
+        }
+        NoPreviousAction::RunCommand => {
+            let command = config
+                .rollback
+                .on_no_previous_command
+                .as_deref()
+                .ok_or("rollback.on_no_previous = \"run_command\" needs rollback.on_no_previous_command")?;
+            log::warn!("No previous version in {}: running on_no_previous_command", target_dir);
+            let env = [
+                ("PLOOP_TARGET_DIR", target_dir),
+                ("PLOOP_VERSION", active.as_deref().unwrap_or_default()),
+            ];
+            let output = runner::run_inline_script(command, &config.watch.repo_path, &env)?;
+            if !output.status.success() {
+                let stderr = String::from_utf8_lossy(&output.stderr);
+                return Err(format!("on_no_previous_command failed ({}): {}", output.status, stderr.trim()).into());
+            }
+        }
+    }
+
+    let live = rollback::current_version(target_dir, &config.deploy.current_link_name);
+    announce_rollback(config, target_dir, active.as_deref(), live.as_deref(), reason, automatic);
+    Ok(active)
+}
+
+/// Outcome of pushing to one remote, as reported by [`sync`]
+#[derive(Debug, Clone)]
+pub struct RemoteSyncResult {
+    pub remote: String,
+    /// Why the push (or release creation) failed; `None` when it succeeded
+    pub error: Option<String>,
+}
+
+/// Run only the sync step for the checked-out commit (`ploop sync`), e.g. to
+/// retry a push that failed after a successful deploy
+///
+/// Nothing is built or deployed, and pushing again is harmless. Failed
+/// pushes are reported per remote rather than as an error; only an unusable
+/// configuration fails the call.
+pub fn sync(config: &Config) -> Result<Vec<RemoteSyncResult>, PipelineError> {
+    if !config.sync.enabled {
+        return Err(PipelineError::Config("Sync is disabled ([sync] enabled = false)".to_string()));
+    }
+    let commit =
+        hook::get_current_commit_hash(&config.watch.repo_path, &config.watch.git).map_err(|e| PipelineError::Config(e.to_string()))?;
+
+    let events = EventEmitter::for_config(&config.notify);
+    events.emit(&PipelineEvent::SyncStarted { commit: commit.clone() });
+    let synced = sync_commit(config, &commit);
+    events.emit(&PipelineEvent::SyncFinished {
+        commit,
+        success: synced.is_ok(),
+    });
+
+    let error = synced.err().map(|e| e.to_string());
+    match &error {
+        Some(e) => log::warn!("Sync to {} failed: {}", config.sync.remote, e),
+        None => log::info!("Synced to {}", config.sync.remote),
+    }
+    Ok(vec![RemoteSyncResult {
+        remote: config.sync.remote.clone(),
+        error,
+    }])
+}
+
+/// Push to `sync.remote`, then create the provider release for `commit` if configured
+fn sync_commit(config: &Config, commit: &str) -> Result<(), Box<dyn std::error::Error>> {
+    syncer::sync_to_remote(
+        &config.sync.remote,
+        &config.sync.branch,
+        &config.watch.repo_path,
+        &config.watch.git,
+        config.sync.push_submodules,
+    )?;
+    syncer::sync_release(&config.sync, commit)?;
+    Ok(())
+}
+
+/// Remove (or archive) old versions in every file target and the canary
+///
+/// Each target keeps its own `keep_versions` when set, otherwise
+/// `rollback.keep_versions`; the current version is never removed. For
+/// unversioned deploys that many `.bak` backups are kept instead.
+pub fn cleanup_old_versions(config: &Config) -> Result<(), Box<dyn std::error::Error>> {
+    let config = resolve_templates(config)?;
+    let canary = config.deploy.canary.iter().map(|canary| ("canary", canary.target_dir.as_str()));
+    for (name, target_dir) in config.deploy.file_targets().into_iter().chain(canary) {
+        if !Path::new(target_dir).is_dir() {
+            continue;
+        }
+        let keep = config.deploy.keep_versions_for(name, config.rollback.keep_versions);
+        if !config.deploy.versioned {
+            // Directories in a bare target are content, not versions
+            log::info!("Cleaning up {} ({}), keeping {} backups", name, target_dir, keep);
+            rollback::prune_backups(target_dir, keep)?;
+            continue;
+        }
+        log::info!("Cleaning up {} ({}), keeping {} versions", name, target_dir, keep);
+        rollback::cleanup_old_versions(target_dir, &config.deploy.current_link_name, keep, config.rollback.archive_old_versions)?;
+        if let Some(max_total_bytes) = config.rollback.max_total_bytes {
+            rollback::enforce_size_quota(target_dir, &config.deploy.current_link_name, max_total_bytes)?;
+        }
+    }
+    Ok(())
+}
+
+/// Resolve `{branch}`/`{commit}` placeholders in `deploy.target_dir`
+///
+/// Anything operating on the deploy target (run, rollback, status) should use
+/// the resolved config so it sees the same per-branch directory.
+pub fn resolve_templates(config: &Config) -> Result<Config, PipelineError> {
+    let branch = hook::get_current_branch(&config.watch.repo_path, &config.watch.git).ok();
+    let mut resolved = with_preview(config, branch.as_deref());
+    let resolve = |template: &str| {
+        deployer::resolve_target_dir(template, &config.watch.repo_path, &config.watch.git)
+            .map_err(|e| PipelineError::Config(format!("Cannot resolve target_dir {}: {}", template, e)))
+    };
+
+    resolved.deploy.target_dir = resolved.deploy.target_dir.as_deref().map(resolve).transpose()?;
+    for target in &mut resolved.deploy.targets {
+        target.target_dir = resolve(&target.target_dir)?;
+    }
+    Ok(resolved)
+}
+
+/// `config` with the preview deploy for `branch` (the branch being deployed
+/// from) applied, when one of `[[deploy.previews]]` matches it
+fn with_preview(config: &Config, branch: Option<&str>) -> Config {
+    let mut config = config.clone();
+    let Some(branch) = branch else {
+        return config;
+    };
+    if let Some(preview) = deployer::preview_deploy_config(&config.deploy, branch) {
+        log::info!("Branch {} deploys as a preview", branch);
+        config.deploy = preview;
+    }
+    config
+}
+
+/// Remove preview deploys whose branch no longer exists, locally or on any
+/// remote, returning the directories removed (or that would be, with `dry_run`)
+///
+/// Each preview's `target_dir` must have `{branch}` as a whole path
+/// component; the directories next to it are candidates. A directory is only
+/// removed when its current version was deployed from a branch this preview
+/// matches and that branch is gone, so production and unrelated directories
+/// alongside the previews survive. Directories holding a configured
+/// `deploy.target_dir`, `deploy.targets` or canary target are never removed.
+pub fn prune_previews(config: &Config, dry_run: bool) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let live: HashSet<String> = hook::list_branches(&config.watch.repo_path, &config.watch.git)?.into_iter().collect();
+    let protected = configured_target_dirs(config);
+
+    let mut removed = Vec::new();
+    for preview in &config.deploy.previews {
+        let template = &preview.target_dir;
+        let whole_component = template.split_once("{branch}").filter(|(parent, rest)| {
+            parent.ends_with(['/', '\\'])
+                && (rest.is_empty() || rest.starts_with(['/', '\\']))
+                && !parent.contains('{')
+                && !rest.contains('{')
+        });
+        let Some((parent, rest)) = whole_component else {
+            return Err(format!("Cannot prune previews of {}: {{branch}} must be a whole path component", template).into());
+        };
+
+        let entries = match std::fs::read_dir(parent) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e.into()),
+        };
+        for entry in entries {
+            let entry = entry?;
+            if !entry.file_type()?.is_dir() {
+                continue;
+            }
+            let entry_path = entry.path().canonicalize()?;
+            if protected.iter().any(|dir| dir.starts_with(&entry_path)) {
+                continue;
+            }
+            let target_dir = entry.path().join(rest.trim_start_matches(['/', '\\']));
+            let target_dir = target_dir.to_string_lossy();
+            let deployed_branch = rollback::current_version(&target_dir, &config.deploy.current_link_name)
+                .and_then(|version| rollback::read_version_meta(&target_dir, &version)?.branch);
+            let Some(branch) = deployed_branch else {
+                continue;
+            };
+            let ours = deployer::preview_for(&config.deploy, &branch).is_some_and(|matched| std::ptr::eq(matched, preview))
+                && deployer::path_safe_branch(&branch) == entry.file_name().to_string_lossy();
+            if !ours || live.contains(&branch) {
+                continue;
+            }
+
+            let path = entry.path().to_string_lossy().into_owned();
+            if dry_run {
+                log::info!("Would remove preview {} (branch gone)", path);
+            } else {
+                log::info!("Removing preview {} (branch gone)", path);
+                std::fs::remove_dir_all(entry.path())?;
+            }
+            removed.push(path);
+        }
+    }
+    removed.sort();
+    Ok(removed)
+}
+
+/// The main, extra and canary target directories, as far as they resolve
+/// from the checked-out branch, canonicalized where they exist
+fn configured_target_dirs(config: &Config) -> Vec<PathBuf> {
+    let deploy = &config.deploy;
+    let templates = deploy
+        .target_dir
+        .iter()
+        .chain(deploy.targets.iter().map(|target| &target.target_dir))
+        .chain(deploy.canary.iter().map(|canary| &canary.target_dir));
+    templates
+        .filter_map(|template| deployer::resolve_target_dir(template, &config.watch.repo_path, &config.watch.git).ok())
+        .map(|dir| Path::new(&dir).canonicalize().unwrap_or_else(|_| PathBuf::from(dir)))
+        .collect()
+}
+
+/// Deploy, switching 'current' back to the previously active version on failure
+///
+/// `version` is the main target's version name from [`set_commit_env`].
+/// Returns the deploy command's output, if there was a command; a failed
+/// command's output is put in `failed_output`.
+fn deploy_or_rollback(
+    config: &Config,
+    commit: &str,
+    version: Option<&str>,
+    failed_output: &mut Option<String>,
+) -> Result<Option<String>, PipelineError> {
+    let previous = active_versions(config);
+
+    let deployed = deployer::deploy(&config.deploy, &config.watch.repo_path, &config.watch.git, commit, version);
+    let error = match deployed {
+        Ok(output) => return Ok(output),
+        Err(e) => {
+            *failed_output = deployer::failed_command_output(e.as_ref());
+            e.to_string()
+        }
+    };
+
+    if !config.rollback.enabled {
+        return Err(PipelineError::Deploy(error));
+    }
+
+    let restored = restore_versions(config, previous, &error);
+    if restored.is_empty() {
+        return Err(PipelineError::Deploy(error));
+    }
+    Err(PipelineError::RolledBack {
+        error,
+        restored: restored.join(", "),
+    })
+}
+
+/// The version each file target's 'current' names, to restore after a failure
+///
+/// Unversioned targets have no 'current'; for them this is the newest `.bak`
+/// backup, so a restore can tell whether the deploy made a new one.
+fn active_versions(config: &Config) -> Vec<(String, Option<String>)> {
+    config
+        .deploy
+        .file_targets()
+        .into_iter()
+        .map(|(_, target_dir)| {
+            let active = if config.deploy.versioned {
+                rollback::current_version(target_dir, &config.deploy.current_link_name)
+            } else {
+                newest_backup(target_dir)
+            };
+            (target_dir.to_string(), active)
+        })
+        .collect()
+}
+
+/// File name of the newest `.bak` backup of an unversioned target
+fn newest_backup(target_dir: &str) -> Option<String> {
+    let backups = rollback::list_backups(target_dir).ok()?;
+    let newest = backups.first()?.file_name()?.to_str()?;
+    Some(newest.to_string())
+}
+
+/// Restore an unversioned target from its newest `.bak` backup
+fn restore_bare_target(target_dir: &str) -> Result<rollback::RollbackResult, Box<dyn std::error::Error>> {
+    let backup = rollback::restore_backup(target_dir)?;
+    Ok(rollback::RollbackResult {
+        from: None,
+        to: backup.file_name().and_then(|name| name.to_str()).unwrap_or_default().to_string(),
+        target_dir: target_dir.to_string(),
+    })
+}
+
+/// Log a failed required sync and build the run's error, first rolling the
+/// targets back to `previous` when `sync.rollback_on_failure` asks for it
+fn required_sync_failed(config: &Config, previous: Vec<(String, Option<String>)>, error: &str) -> PipelineError {
+    log::warn!("Sync failed after successful deploy: {}", error);
+    if !(config.sync.rollback_on_failure && config.rollback.enabled) {
+        return PipelineError::Sync(error.to_string());
+    }
+
+    let error = format!("Sync failed: {}", error);
+    let restored = restore_versions(config, previous, &error);
+    if restored.is_empty() {
+        // The deploy itself succeeded and is still live
+        return PipelineError::Sync(error);
+    }
+    PipelineError::RolledBack {
+        error,
+        restored: restored.join(", "),
+    }
+}
+
+/// Point every target back at the version it had before this deploy,
+/// returning the versions restored
+fn restore_versions(config: &Config, previous: Vec<(String, Option<String>)>, reason: &str) -> Vec<String> {
+    let mut restored = Vec::new();
+    for (target_dir, previous) in previous {
+        let target_dir = target_dir.as_str();
+        if !config.deploy.versioned {
+            // Only a backup this deploy made holds the contents it replaced
+            if newest_backup(target_dir) == previous {
+                continue;
+            }
+            match restore_bare_target(target_dir) {
+                Ok(result) => {
+                    announce_rollback(config, target_dir, None, Some(&result.to), reason, true);
+                    restored.push(result.to);
+                }
+                Err(e) => log::error!("Restoring the backup of {} failed: {}", target_dir, e),
+            }
+            continue;
+        }
+        let Some(previous) = previous else {
+            // A failed first deploy may have gone live anyway (e.g. a failing post_deploy)
+            if config.rollback.on_no_previous != NoPreviousAction::Error && rollback::current_version(target_dir, &config.deploy.current_link_name).is_some() {
+                if let Err(e) = handle_no_previous(config, target_dir, reason, true) {
+                    log::error!("Handling failed first deploy of {} failed: {}", target_dir, e);
+                }
+            }
+            continue;
+        };
+        match rollback::rollback_to_version(target_dir, &config.deploy.current_link_name, &previous) {
+            Ok(result) => {
+                announce_rollback(config, target_dir, result.from.as_deref(), Some(&result.to), reason, true);
+                restored.push(result.to);
+            }
+            Err(e) => log::error!("Rollback of {} to {} failed: {}", target_dir, previous, e),
+        }
+    }
+    restored
+}
+
+/// Report a rollback of `target_dir` from `from` to `to` (`None`: nothing
+/// live) as a pipeline event, which also reaches the webhook
+fn announce_rollback(config: &Config, target_dir: &str, from: Option<&str>, to: Option<&str>, reason: &str, automatic: bool) {
+    EventEmitter::for_config(&config.notify).emit(&PipelineEvent::RolledBack {
+        target_dir: target_dir.to_string(),
+        from: from.map(str::to_string),
+        to: to.map(str::to_string),
+        reason: reason.to_string(),
+        automatic,
+    });
+}
+
+/// Add the build duration, and the branch when staging couldn't tell it, to
+/// the metadata of the version just deployed
+fn record_build_details(config: &Config, duration: std::time::Duration, branch: Option<&str>) {
+    let deploy = &config.deploy;
+    if deploy.command.is_some() || deploy.sftp.is_some() || !deploy.versioned {
+        return;
+    }
+    let Some(target_dir) = deploy.target_dir.as_deref() else {
+        return;
+    };
+    let Some(version) = rollback::current_version(target_dir, &config.deploy.current_link_name) else {
+        return;
+    };
+    let Some(mut meta) = rollback::read_version_meta(target_dir, &version) else {
+        return;
+    };
+
+    meta.build_duration_secs = Some(duration.as_secs_f64());
+    // A pinned commit is staged from a detached worktree
+    if meta.branch.is_none() {
+        meta.branch = branch.map(str::to_string);
+    }
+    let version_dir = std::path::Path::new(target_dir).join(&version);
+    if let Err(e) = rollback::write_version_meta(&version_dir, &meta) {
+        log::warn!("Failed to record build duration for {}: {}", version, e);
+    }
+}
+
+fn record_run(
+    config: &Config,
+    recorder: Option<&dyn DeploymentRecorder>,
+    commit: &str,
+    outcome: Outcome,
+    started: Instant,
+    error: Option<&str>,
+    output: Option<&str>,
+) {
+    let duration = started.elapsed();
+
+    let state_dir = config.deploy.target_dir.as_deref().map(|target_dir| config.deploy.state_dir(target_dir));
+    let file_recorder = state_dir.as_deref().map(FileRecorder::new);
+    let recorder = recorder.or(file_recorder.as_ref().map(|recorder| recorder as &dyn DeploymentRecorder));
+    if let Some(recorder) = recorder {
+        let mut record = HistoryRecord::new(commit, outcome, duration);
+        if let Some(error) = error {
+            record = record.with_error(error);
+        }
+        if let Some(output) = output {
+            record = record.with_output(output);
+        }
+        if let Err(e) = recorder.record(&record) {
+            log::warn!("Failed to write deploy history: {}", e);
+        }
+    }
+}
+
+/// `config` with `deploy.artifacts` filled in from `cargo metadata`, when
+/// they are unset, the build runs cargo and the deploy copies artifacts
+fn discover_artifacts(config: &Config) -> Result<Option<Config>, PipelineError> {
+    let deploy = &config.deploy;
+    let copies_artifacts = deploy.command.is_none() && (deploy.target_dir.is_some() || deploy.sftp.is_some());
+    if deploy.artifacts.is_some() || !copies_artifacts || !builder::is_cargo_command(&config.build.command) {
+        return Ok(None);
+    }
+
+    let paths = builder::discover_cargo_artifacts(&config.watch.repo_path, &config.build.command)
+        .map_err(|e| PipelineError::Config(format!("Cannot discover cargo artifacts: {}", e)))?;
+    log::info!("Discovered cargo artifacts: {}", paths.join(", "));
+
+    let mut config = config.clone();
+    config.deploy.artifacts = Some(paths.iter().map(|path| ArtifactSpec::from(path.as_str())).collect());
+    Ok(Some(config))
+}
+
+/// Build and verify artifacts: the first half of a run, without deploying or syncing
+pub fn build_and_verify(config: &Config) -> Result<(), Box<dyn std::error::Error>> {
+    build_and_verify_planned(config, &RunPlan::for_config(config), &mut Vec::new())
+}
+
+/// Like [`build_and_verify`], skipping the build when the plan has no build phase (`--no-build`)
+fn build_and_verify_planned(
+    config: &Config,
+    plan: &RunPlan,
+    timings: &mut Vec<PhaseTiming>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let repo_path = &config.watch.repo_path;
+
+    if plan.phases.contains(&Phase::Build) {
+        if config.build.update_submodules {
+            hook::update_submodules(repo_path, &config.watch.git)?;
+        }
+
+        log::info!("{}", plan.label(Phase::Build));
+        timed(timings, Phase::Build, || builder::run_build(&config.build, repo_path, &config.watch.git))?;
+    }
+
+    if let Some(all_artifacts) = target_artifacts(&config.deploy) {
+        log::info!("{}", plan.label(Phase::Verify));
+        timed(timings, Phase::Verify, || {
+            let artifact_base = config.deploy.artifact_base_dir(repo_path);
+            builder::verify_artifacts(&all_artifacts, &artifact_base)?;
+            if let Some(verify_command) = &config.deploy.verify_command {
+                builder::run_verify_command(
+                    verify_command,
+                    config.deploy.verify_use_shell,
+                    &all_artifacts,
+                    &artifact_base,
+                    repo_path,
+                )?;
+            }
+            check_freshness(config, &all_artifacts)
+        })?;
+    }
+
+    Ok(())
+}
+
+/// Artifacts of the main target and every `[[deploy.targets]]` entry, or
+/// `None` when no target lists any
+fn target_artifacts(deploy: &DeployConfig) -> Option<Vec<ArtifactSpec>> {
+    let targets = deploy.targets.iter().map(|target| &target.artifacts).filter(|artifacts| !artifacts.is_empty());
+    if deploy.artifacts.is_none() && targets.clone().next().is_none() {
+        return None;
+    }
+    Some(deploy.artifacts.iter().chain(targets).flatten().cloned().collect())
+}
+
+/// Warn about (or, with `deploy.strict_freshness`, reject) build outputs older
+/// than the commit being deployed, which usually means the build was skipped
+/// or wrote somewhere else
+///
+/// Skipped outside a git repository (or before its first commit).
+fn check_freshness(config: &Config, artifacts: &[ArtifactSpec]) -> Result<(), Box<dyn std::error::Error>> {
+    let repo_path = &config.watch.repo_path;
+    let commit_time = match hook::get_commit_time(repo_path, &config.watch.git, "HEAD") {
+        Ok(time) => time,
+        Err(e) => {
+            log::debug!("Skipping artifact freshness check, no commit time: {}", e);
+            return Ok(());
+        }
+    };
+    let since = commit_time - Duration::from_secs(config.deploy.freshness_tolerance_secs);
+    let stale = builder::stale_artifacts(artifacts, &config.deploy.artifact_base_dir(repo_path), repo_path, &config.watch.git, since)?;
+    if stale.is_empty() {
+        return Ok(());
+    }
+
+    let message = format!(
+        "Artifacts older than the commit being deployed, possibly stale: {}",
+        stale.iter().map(|path| path.display().to_string()).collect::<Vec<_>>().join(", ")
+    );
+    if config.deploy.strict_freshness {
+        return Err(message.into());
+    }
+    log::warn!("{}", message);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::temp_dir;
+
+    #[test]
+    fn test_freshness_check_flags_artifacts_older_than_commit() {
+        let repo = crate::test_support::init_repo();
+        std::fs::write(repo.join("app"), "old build").unwrap();
+        std::fs::write(repo.join("config.json"), "{}").unwrap();
+        let an_hour_ago = std::time::SystemTime::now() - Duration::from_secs(3600);
+        for file in ["app", "config.json"] {
+            let file = std::fs::File::options().write(true).open(repo.join(file)).unwrap();
+            file.set_modified(an_hour_ago).unwrap();
+        }
+        // Tracked files are never stale, however old their mtime
+        crate::test_support::git(&repo, &["add", "config.json"]);
+        crate::test_support::git(&repo, &["commit", "-qm", "config"]);
+
+        let mut config = Config::default();
+        config.watch.repo_path = repo.to_str().unwrap().to_string();
+        let artifacts = vec![ArtifactSpec::from("app"), ArtifactSpec::from("config.json")];
+        let stale = builder::stale_artifacts(&artifacts, &config.watch.repo_path, &config.watch.repo_path, &config.watch.git, std::time::SystemTime::now() - Duration::from_secs(60))
+            .unwrap();
+        assert_eq!(stale, vec![repo.join("app")]);
+
+        // Only a warning by default
+        check_freshness(&config, &artifacts).unwrap();
+
+        config.deploy.strict_freshness = true;
+        let err = check_freshness(&config, &artifacts).unwrap_err();
+        assert!(err.to_string().contains("possibly stale"), "{}", err);
+
+        config.deploy.freshness_tolerance_secs = 2 * 3600;
+        check_freshness(&config, &artifacts).unwrap();
+
+        // A rebuild makes it fresh again
+        config.deploy.freshness_tolerance_secs = 0;
+        std::fs::write(repo.join("app"), "new build").unwrap();
+        check_freshness(&config, &artifacts).unwrap();
+        std::fs::remove_dir_all(&repo).unwrap();
+    }
+
+    #[test]
+    fn test_cleanup_uses_per_target_retention() {
+        let root = temp_dir("retention");
+        let target = |name: &str| root.join(name).to_str().unwrap().to_string();
+        for dir in ["prod", "staging", "canary"] {
+            for version in ["v1", "v2", "v3", "v4"] {
+                std::fs::create_dir_all(root.join(dir).join(version)).unwrap();
+                std::thread::sleep(Duration::from_millis(20));
+            }
+        }
+
+        let mut config = Config::default();
+        config.deploy.target_dir = Some(target("prod"));
+        config.deploy.targets = vec![crate::config::DeployTarget {
+            name: "staging".to_string(),
+            target_dir: target("staging"),
+            artifacts: Vec::new(),
+            keep_versions: Some(2),
+        }];
+        config.deploy.canary = Some(crate::config::CanaryConfig {
+            target_dir: target("canary"),
+            health_check: "true".to_string(),
+            use_shell: false,
+            initial_delay_secs: 0,
+        });
+        config.rollback.keep_versions = 3;
+        cleanup_old_versions(&config).unwrap();
+
+        assert_eq!(rollback::get_deployed_versions(&target("prod"), rollback::DEFAULT_CURRENT_LINK).unwrap(), vec!["v4", "v3", "v2"]);
+        assert_eq!(rollback::get_deployed_versions(&target("staging"), rollback::DEFAULT_CURRENT_LINK).unwrap(), vec!["v4", "v3"]);
+        assert_eq!(rollback::get_deployed_versions(&target("canary"), rollback::DEFAULT_CURRENT_LINK).unwrap(), vec!["v4", "v3", "v2"]);
+
+        // Unversioned targets keep their subdirectories and lose old backups
+        for backup in ["www.20260101000000000.bak", "www.20260102000000000.bak", "www.20260103000000000.bak"] {
+            std::fs::create_dir_all(root.join(backup)).unwrap();
+        }
+        std::fs::create_dir_all(root.join("www/assets")).unwrap();
+        config.deploy.target_dir = Some(target("www"));
+        config.deploy.targets.clear();
+        config.deploy.canary = None;
+        config.deploy.versioned = false;
+        config.rollback.keep_versions = 1;
+        cleanup_old_versions(&config).unwrap();
+        assert!(root.join("www/assets").is_dir());
+        assert!(root.join("www.20260103000000000.bak").is_dir());
+        assert!(!root.join("www.20260102000000000.bak").exists() && !root.join("www.20260101000000000.bak").exists());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_run_plan_counts_enabled_phases() {
+        let mut config = Config::default();
+        config.sync.enabled = false;
+        let plan = RunPlan::for_config(&config);
+        assert_eq!(plan.phases, vec![Phase::Build, Phase::Verify, Phase::Deploy]);
+        assert_eq!(plan.label(Phase::Deploy), "[3/3] 🚀 Deploying...");
+
+        config.sync.enabled = true;
+        config.deploy.canary = Some(crate::config::CanaryConfig {
+            target_dir: "/opt/canary".to_string(),
+            health_check: "true".to_string(),
+            use_shell: false,
+            initial_delay_secs: 0,
+        });
+        let plan = RunPlan::for_config(&config);
+        assert_eq!(plan.phases.len(), 5);
+        assert_eq!(plan.label(Phase::Verify), "[2/5] 🔍 Verifying artifacts...");
+        assert_eq!(plan.label(Phase::Sync), "[5/5] 🔄 Syncing...");
+
+        config.deploy.command = Some("./deploy.sh".to_string());
+        config.deploy.artifacts = None;
+        let plan = RunPlan::for_config(&config);
+        assert_eq!(plan.phases, vec![Phase::Build, Phase::Deploy, Phase::Sync]);
+        assert_eq!(plan.label(Phase::Verify), "🔍 Verifying artifacts...");
+    }
+
+    #[test]
+    fn test_build_and_verify() {
+        let repo = temp_dir("pipeline");
+        let mut config = Config::default();
+        config.watch.repo_path = repo.to_str().unwrap().to_string();
+        config.build.command = "touch app".to_string();
+        config.deploy.artifacts = Some(vec![ArtifactSpec::from("app")]);
+
+        assert!(build_and_verify(&config).is_ok());
+
+        config.build.command = "false".to_string();
+        assert!(build_and_verify(&config).is_err());
+        std::fs::remove_dir_all(&repo).unwrap();
+    }
+
+    #[test]
+    fn test_verify_command_covers_every_target() {
+        let repo = temp_dir("verify-targets");
+        let mut config = Config::default();
+        config.watch.repo_path = repo.to_str().unwrap().to_string();
+        config.build.command = "true".to_string();
+        std::fs::write(repo.join("app"), "signed").unwrap();
+        std::fs::write(repo.join("docs"), "tampered").unwrap();
+        config.deploy.targets = vec![crate::config::DeployTarget {
+            name: "docs".to_string(),
+            target_dir: repo.join("docs-target").to_str().unwrap().to_string(),
+            artifacts: vec![ArtifactSpec::from("docs")],
+            keep_versions: None,
+        }];
+        config.deploy.artifacts = None;
+        config.deploy.verify_command = Some("grep -q signed".to_string());
+
+        // Verified even without deploy.artifacts
+        let err = build_and_verify(&config).unwrap_err().to_string();
+        assert!(err.starts_with("Verify command failed"), "{}", err);
+        config.deploy.targets[0].artifacts.push(ArtifactSpec::from("missing"));
+        let err = build_and_verify(&config).unwrap_err().to_string();
+        assert!(err.contains("missing"), "{}", err);
+        config.deploy.targets[0].artifacts.pop();
+
+        // A shell command only sees the paths through PLOOP_ARTIFACTS; the
+        // deploy command's use_shell has no say
+        config.deploy.artifacts = Some(vec![ArtifactSpec::from("app")]);
+        config.deploy.use_shell = true;
+        config.deploy.verify_command = Some(r#"test "$(echo "$PLOOP_ARTIFACTS" | wc -l)" -eq 2"#.to_string());
+        assert!(build_and_verify(&config).is_err());
+        config.deploy.verify_use_shell = true;
+        build_and_verify(&config).unwrap();
+        std::fs::remove_dir_all(&repo).unwrap();
+    }
+
+    #[test]
+    fn test_exit_codes() {
+        let err = |e: PipelineError| (e.exit_code(), status_line(&Err(e)));
+
+        assert_eq!(err(PipelineError::Build("x".into())), (1, "status=build-failed exit=1".to_string()));
+        assert_eq!(err(PipelineError::Deploy("x".into())).0, 2);
+        let rolled_back = PipelineError::RolledBack {
+            error: "x".into(),
+            restored: "v1".into(),
+        };
+        assert_eq!(err(rolled_back).0, 3);
+        assert_eq!(err(PipelineError::Config("x".into())).0, 4);
+        let deployed = Ok(RunStatus::Deployed { commit: "abc".into() });
+        assert_eq!(status_line(&deployed), "status=success exit=0 commit=abc");
+    }
+
+    #[test]
+    fn test_run_specific_commit_in_worktree() {
+        let repo = crate::test_support::init_repo();
+        let git = |args: &[&str]| crate::test_support::git(&repo, args);
+        std::fs::write(repo.join("VERSION"), "1").unwrap();
+        git(&["add", "."]);
+        git(&["commit", "-qm", "v1"]);
+        let first = git(&["rev-parse", "HEAD"]);
+        std::fs::write(repo.join("VERSION"), "2").unwrap();
+        git(&["commit", "-qam", "v2"]);
+
+        let mut config = Config::default();
+        config.watch.repo_path = repo.to_str().unwrap().to_string();
+        config.watch.paths = vec!["nothing/**".to_string()];
+        config.build.command = "cp VERSION app".to_string();
+        config.deploy.target_dir = Some(format!("{}/deploy", repo.display()));
+        config.deploy.artifacts = Some(vec![ArtifactSpec::from("app")]);
+        config.sync.enabled = false;
+
+        let options = RunOptions {
+            commit: Some(first[..7].to_string()),
+            ..RunOptions::default()
+        };
+        let status = run(&config, &options).unwrap();
+        assert!(matches!(status, RunStatus::Deployed { commit } if commit == first));
+        let deployed = repo.join("deploy").join(&first[..7]).join("app");
+        assert_eq!(std::fs::read_to_string(deployed).unwrap(), "1");
+        let meta = rollback::read_version_meta(&format!("{}/deploy", repo.display()), &first[..7]).unwrap();
+        assert_eq!(meta.commit.as_deref(), Some(first.as_str()));
+        assert_eq!(meta.sequence, Some(1));
+        assert!(meta.build_duration_secs.is_some());
+        assert_eq!(meta.run_id.map(|id| id.len()), Some(8));
+
+        // The working directory is untouched and the worktree is gone
+        assert!(!repo.join("app").exists());
+        assert_eq!(std::fs::read_to_string(repo.join("VERSION")).unwrap(), "2");
+        assert_eq!(git(&["worktree", "list"]).lines().count(), 1);
+
+        let missing = RunOptions {
+            commit: Some("0000000".to_string()),
+            ..RunOptions::default()
+        };
+        assert!(matches!(run(&config, &missing), Err(PipelineError::Config(_))));
+        std::fs::remove_dir_all(&repo).unwrap();
+    }
+
+    #[test]
+    fn test_outcome_commands_see_run_variables() {
+        let repo = crate::test_support::init_repo();
+        let commit = crate::test_support::commit_file(&repo, "README");
+        let marker = repo.join("marker");
+
+        let mut config = Config::default();
+        config.watch.repo_path = repo.to_str().unwrap().to_string();
+        config.build.command = "touch app".to_string();
+        config.deploy.target_dir = Some(format!("{}/deploy", repo.display()));
+        config.deploy.artifacts = Some(vec![ArtifactSpec::from("app")]);
+        config.sync.enabled = false;
+        config.notify.use_shell = true;
+        config.notify.success_command = Some(format!(
+            r#"echo "$PLOOP_COMMIT $PLOOP_VERSION $PLOOP_OUTCOME $PLOOP_DURATION" > {}"#,
+            marker.display()
+        ));
+        config.notify.failure_command = Some(format!(r#"echo "$PLOOP_OUTCOME" > {}.failed"#, marker.display()));
+
+        run(&config, &RunOptions::default()).unwrap();
+        let content = std::fs::read_to_string(&marker).unwrap();
+        let fields: Vec<&str> = content.split_whitespace().collect();
+        assert_eq!(fields[..3], [commit.as_str(), &commit[..7], "success"]);
+        assert!(fields[3].parse::<f64>().is_ok(), "{}", content);
+
+        // A failing outcome command only warns
+        config.build.command = "false".to_string();
+        assert!(matches!(run(&config, &RunOptions::default()), Err(PipelineError::Build(_))));
+        let failed = std::fs::read_to_string(repo.join("marker.failed")).unwrap();
+        assert_eq!(failed.trim(), "build-failed");
+        config.notify.success_command = Some("false".to_string());
+        config.build.command = "touch app".to_string();
+        assert!(run(&config, &RunOptions::default()).is_ok());
+        std::fs::remove_dir_all(&repo).unwrap();
+    }
+
+    #[test]
+    fn test_run_flag_overrides() {
+        let options = RunOptions {
+            no_sync: true,
+            no_rollback: true,
+            force: true,
+            ..RunOptions::default()
+        };
+        let mut skipping = Config::default();
+        skipping.sync.enabled = true;
+        skipping.deploy.skip_unchanged = true;
+        let config = options.apply_overrides(&skipping);
+        assert!(!config.sync.enabled);
+        assert!(!config.rollback.enabled);
+        assert!(!config.deploy.skip_unchanged);
+        let config = RunOptions::default().apply_overrides(&skipping);
+        assert!(config.sync.enabled);
+        assert!(config.rollback.enabled);
+        assert!(config.deploy.skip_unchanged);
+
+        let repo = crate::test_support::init_repo();
+        let commit = crate::test_support::commit_file(&repo, "README");
+        let mut config = Config::default();
+        config.watch.repo_path = repo.to_str().unwrap().to_string();
+        config.build.command = "false".to_string();
+        config.deploy.target_dir = Some(format!("{}/deploy", repo.display()));
+        config.deploy.artifacts = Some(vec![ArtifactSpec::from("app")]);
+        config.sync.enabled = false;
+        let no_build = RunOptions {
+            no_build: true,
+            ..RunOptions::default()
+        };
+
+        // The failing build command is never run, but artifacts are still verified
+        assert!(matches!(run(&config, &no_build), Err(PipelineError::Build(_))));
+        std::fs::write(repo.join("app"), "prebuilt").unwrap();
+        assert!(matches!(run(&config, &no_build), Ok(RunStatus::Deployed { commit: deployed }) if deployed == commit));
+        assert_eq!(std::fs::read_to_string(repo.join("deploy/current/app")).unwrap(), "prebuilt");
+        std::fs::remove_dir_all(&repo).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_run_timeout_kills_slow_build() {
+        let repo = crate::test_support::init_repo();
+        crate::test_support::commit_file(&repo, "README");
+        let mut config = Config::default();
+        config.watch.repo_path = repo.to_str().unwrap().to_string();
+        config.watch.run_timeout_secs = Some(30);
+        config.build.command = "sleep 10".to_string();
+        config.deploy.target_dir = Some(format!("{}/deploy", repo.display()));
+        config.sync.enabled = false;
+        let options = RunOptions {
+            timeout: Some(Duration::from_millis(300)),
+            ..RunOptions::default()
+        };
+
+        let started = Instant::now();
+        let result = run(&config, &options);
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert!(matches!(&result, Err(PipelineError::Build(e)) if e.contains(runner::TIMED_OUT)), "{:?}", result);
+        // The deadline ends with the run
+        assert!(!runner::is_aborted());
+        std::fs::remove_dir_all(&repo).unwrap();
+    }
+
+    #[test]
+    fn test_run_summary_json() {
+        let repo = crate::test_support::init_repo();
+        let commit = crate::test_support::commit_file(&repo, "README");
+        let mut config = Config::default();
+        config.watch.repo_path = repo.to_str().unwrap().to_string();
+        config.build.command = "touch app".to_string();
+        config.deploy.target_dir = Some(format!("{}/deploy", repo.display()));
+        config.deploy.artifacts = Some(vec![ArtifactSpec::from("app")]);
+        config.sync.enabled = false;
+
+        let (result, summary) = run_summarized(&config, &RunOptions::default(), None);
+        result.unwrap();
+        let json: serde_json::Value = serde_json::from_str(&summary.to_json_line()).unwrap();
+        assert_eq!(json["commit"], commit.as_str());
+        assert_eq!(json["version"], &commit[..7]);
+        assert_eq!(json["outcome"], "success");
+        assert_eq!(json["exit_code"], 0);
+        let phases: Vec<&str> = json["phases"].as_array().unwrap().iter().map(|p| p["phase"].as_str().unwrap()).collect();
+        assert_eq!(phases, vec!["build", "verify", "deploy"]);
+        assert!(json["phases"][0]["duration_secs"].as_f64().unwrap() >= 0.0);
+
+        // A failed build ends the summary at the failing phase
+        config.build.command = "false".to_string();
+        let (result, summary) = run_summarized(&config, &RunOptions::default(), None);
+        assert!(result.is_err());
+        assert_eq!((summary.outcome.as_str(), summary.exit_code), ("build-failed", 1));
+        assert_eq!(summary.phases.len(), 1);
+        assert!(!summary.phases[0].success);
+        std::fs::remove_dir_all(&repo).unwrap();
+    }
+
+    #[test]
+    fn test_run_with_custom_recorder() {
+        struct Collect(std::sync::Mutex<Vec<HistoryRecord>>);
+        impl DeploymentRecorder for Collect {
+            fn record(&self, record: &HistoryRecord) -> Result<(), Box<dyn std::error::Error>> {
+                self.0.lock().unwrap().push(record.clone());
+                Ok(())
+            }
+        }
+
+        let repo = crate::test_support::init_repo();
+        let commit = crate::test_support::commit_file(&repo, "README");
+        let mut config = Config::default();
+        config.watch.repo_path = repo.to_str().unwrap().to_string();
+        config.build.command = "touch app".to_string();
+        config.deploy.target_dir = Some(format!("{}/deploy", repo.display()));
+        config.deploy.artifacts = Some(vec![ArtifactSpec::from("app")]);
+        config.sync.enabled = false;
+
+        let recorder = Collect(std::sync::Mutex::new(Vec::new()));
+        run_with_recorder(&config, &RunOptions::default(), &recorder).unwrap();
+        let records = recorder.0.into_inner().unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].commit, commit);
+        assert_eq!(records[0].outcome, Outcome::Success);
+        assert!(!history::history_path(config.deploy.target_dir.as_deref().unwrap()).exists());
+        std::fs::remove_dir_all(&repo).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_no_previous_version_removes_current() {
+        let repo = temp_dir("no-previous");
+        std::fs::write(repo.join("app"), "broken").unwrap();
+        let target = repo.join("deploy");
+        let target_str = target.to_str().unwrap();
+        let mut config = Config::default();
+        config.watch.repo_path = repo.to_str().unwrap().to_string();
+        config.deploy.target_dir = Some(target_str.to_string());
+        config.deploy.artifacts = Some(vec![ArtifactSpec::from("app")]);
+        config.deploy.post_deploy = Some("exit 1".to_string());
+
+        // By default a failed first deploy stays live and rollback refuses
+        assert!(matches!(deploy_or_rollback(&config, "aaaaaaa1", None, &mut None), Err(PipelineError::Deploy(_))));
+        assert_eq!(rollback::current_version(target_str, rollback::DEFAULT_CURRENT_LINK).as_deref(), Some("aaaaaaa"));
+        let err = rollback(&config, None, false).unwrap_err();
+        assert!(err.to_string().contains("No previous version"), "{}", err);
+
+        // Explicit rollback takes it offline, keeping the version directory
+        config.rollback.on_no_previous = NoPreviousAction::RemoveCurrent;
+        let outcome = rollback(&config, None, false).unwrap();
+        assert!(matches!(outcome, RollbackOutcome::NoPrevious { active: Some(ref v), .. } if v == "aaaaaaa"));
+        assert_eq!(rollback::current_version(target_str, rollback::DEFAULT_CURRENT_LINK), None);
+        assert!(std::fs::symlink_metadata(target.join("current")).is_err());
+        assert!(target.join("aaaaaaa/app").exists());
+
+        // ... and so does the automatic rollback of a failed first deploy
+        std::fs::remove_dir_all(&target).unwrap();
+        assert!(deploy_or_rollback(&config, "bbbbbbb2", None, &mut None).is_err());
+        assert_eq!(rollback::current_version(target_str, rollback::DEFAULT_CURRENT_LINK), None);
+        std::fs::remove_dir_all(&repo).unwrap();
+    }
+
+    #[test]
+    fn test_automatic_rollback_is_notified() {
+        let repo = temp_dir("rollback-notify");
+        std::fs::write(repo.join("app"), "v1").unwrap();
+        let target = repo.join("deploy");
+        let mut config = Config::default();
+        config.watch.repo_path = repo.to_str().unwrap().to_string();
+        config.deploy.target_dir = Some(target.to_str().unwrap().to_string());
+        config.deploy.artifacts = Some(vec![ArtifactSpec::from("app")]);
+        deploy_or_rollback(&config, "aaaaaaa1", None, &mut None).unwrap();
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        config.notify.webhook_url = Some(format!("http://{}/hook", listener.local_addr().unwrap()));
+        let server = crate::test_support::serve_http_once(listener, "200 OK");
+        std::fs::write(repo.join("app"), "v2").unwrap();
+        config.deploy.post_deploy = Some("exit 1".to_string());
+        assert!(matches!(deploy_or_rollback(&config, "bbbbbbb2", None, &mut None), Err(PipelineError::RolledBack { .. })));
+
+        let request = server.join().unwrap();
+        let body: serde_json::Value = serde_json::from_str(request.split_once("\r\n\r\n").unwrap().1).unwrap();
+        assert_eq!((body["from"].as_str(), body["to"].as_str()), (Some("bbbbbbb"), Some("aaaaaaa")));
+        assert_eq!(body["automatic"], true);
+        assert!(body["reason"].as_str().unwrap().contains("post_deploy"), "{}", body);
+        std::fs::remove_dir_all(&repo).unwrap();
+    }
+
+    #[test]
+    fn test_bare_target_rollback_restores_backup() {
+        let repo = temp_dir("bare-rollback");
+        std::fs::write(repo.join("app"), "v1").unwrap();
+        let target = repo.join("www");
+        let mut config = Config::default();
+        config.watch.repo_path = repo.to_str().unwrap().to_string();
+        config.deploy.target_dir = Some(target.to_str().unwrap().to_string());
+        config.deploy.artifacts = Some(vec![ArtifactSpec::from("app")]);
+        config.deploy.versioned = false;
+        deploy_or_rollback(&config, "aaaaaaa1", None, &mut None).unwrap();
+
+        // A failing post_deploy puts the replaced contents back
+        std::fs::write(repo.join("app"), "v2").unwrap();
+        config.deploy.post_deploy = Some("exit 1".to_string());
+        assert!(matches!(deploy_or_rollback(&config, "bbbbbbb2", None, &mut None), Err(PipelineError::RolledBack { .. })));
+        assert_eq!(std::fs::read_to_string(target.join("app")).unwrap(), "v1");
+
+        // A failure before anything was replaced restores nothing
+        config.deploy.post_deploy = None;
+        config.deploy.artifacts = Some(vec![ArtifactSpec::from("missing")]);
+        assert!(matches!(deploy_or_rollback(&config, "ccccccc3", None, &mut None), Err(PipelineError::Deploy(_))));
+        assert_eq!(std::fs::read_to_string(target.join("app")).unwrap(), "v1");
+
+        // So does a manual rollback
+        config.deploy.artifacts = Some(vec![ArtifactSpec::from("app")]);
+        deploy_or_rollback(&config, "bbbbbbb2", None, &mut None).unwrap();
+        assert_eq!(std::fs::read_to_string(target.join("app")).unwrap(), "v2");
+        assert!(matches!(rollback(&config, None, false).unwrap(), RollbackOutcome::Restored(_)));
+        assert_eq!(std::fs::read_to_string(target.join("app")).unwrap(), "v1");
+        std::fs::remove_dir_all(&repo).unwrap();
+    }
+
+    #[test]
+    fn test_bare_target_history_is_kept_beside_it() {
+        let repo = crate::test_support::init_repo();
+        crate::test_support::commit_file(&repo, "README");
+        let target = repo.join("www");
+        let mut config = Config::default();
+        config.watch.repo_path = repo.to_str().unwrap().to_string();
+        config.build.command = "touch app".to_string();
+        config.deploy.target_dir = Some(target.to_str().unwrap().to_string());
+        config.deploy.artifacts = Some(vec![ArtifactSpec::from("app")]);
+        config.deploy.versioned = false;
+        let options = RunOptions {
+            force: true,
+            ..RunOptions::default()
+        };
+
+        run(&config, &options).unwrap();
+        run(&config, &options).unwrap();
+        let state_dir = config.deploy.state_dir(target.to_str().unwrap());
+        assert_eq!(state_dir, format!("{}.ploop", target.display()));
+        assert_eq!(history::read_records(&state_dir).unwrap().len(), 2);
+        assert!(!history::history_path(target.to_str().unwrap()).exists());
+        std::fs::remove_dir_all(&repo).unwrap();
+    }
+
+    #[test]
+    fn test_manual_rollback_is_notified() {
+        let repo = temp_dir("manual-rollback-notify");
+        std::fs::write(repo.join("app"), "v1").unwrap();
+        let target = repo.join("deploy");
+        let mut config = Config::default();
+        config.watch.repo_path = repo.to_str().unwrap().to_string();
+        config.deploy.target_dir = Some(target.to_str().unwrap().to_string());
+        config.deploy.artifacts = Some(vec![ArtifactSpec::from("app")]);
+        deploy_or_rollback(&config, "aaaaaaa1", None, &mut None).unwrap();
+        std::fs::write(repo.join("app"), "v2").unwrap();
+        deploy_or_rollback(&config, "bbbbbbb2", None, &mut None).unwrap();
+
+        let notified = |config: &mut Config| {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            config.notify.webhook_url = Some(format!("http://{}/hook", listener.local_addr().unwrap()));
+            let server = crate::test_support::serve_http_once(listener, "200 OK");
+            rollback(config, None, false).unwrap();
+            let request = server.join().unwrap();
+            serde_json::from_str::<serde_json::Value>(request.split_once("\r\n\r\n").unwrap().1).unwrap()
+        };
+        let body = notified(&mut config);
+        assert_eq!((body["from"].as_str(), body["to"].as_str()), (Some("bbbbbbb"), Some("aaaaaaa")));
+        assert_eq!((body["automatic"].as_bool(), body["reason"].as_str()), (Some(false), Some("manual rollback")));
+
+        // Taking the only version offline is announced too
+        std::fs::remove_dir_all(target.join("bbbbbbb")).unwrap();
+        config.rollback.on_no_previous = NoPreviousAction::RemoveCurrent;
+        let body = notified(&mut config);
+        assert_eq!((body["from"].as_str(), body["to"].as_str()), (Some("aaaaaaa"), None));
+        assert_eq!(body["automatic"], false);
+        std::fs::remove_dir_all(&repo).unwrap();
+    }
+
+    #[test]
+    fn test_preview_target_selection() {
+        let repo = crate::test_support::init_repo();
+        crate::test_support::commit_file(&repo, "a.txt");
+        let git = |args: &[&str]| crate::test_support::git(&repo, args);
+        let mut config = Config::default();
+        config.watch.repo_path = repo.to_str().unwrap().to_string();
+        config.deploy.command = Some("systemctl restart app".to_string());
+        config.deploy.targets = vec![crate::config::DeployTarget {
+            name: "docs".to_string(),
+            target_dir: "/opt/docs".to_string(),
+            artifacts: Vec::new(),
+            keep_versions: None,
+        }];
+        config.deploy.previews = vec![crate::config::PreviewConfig {
+            branches: vec!["feature/*".to_string(), "fix-*".to_string()],
+            target_dir: "/opt/preview/{branch}".to_string(),
+            command: None,
+        }];
+
+        // init_repo starts out on main
+        let resolved = resolve_templates(&config).unwrap();
+        assert_eq!(resolved.deploy.target_dir.as_deref(), Some("/opt/deploy"));
+        assert_eq!(resolved.deploy.targets.len(), 1);
+
+        git(&["checkout", "-qb", "feature/login"]);
+        let resolved = resolve_templates(&config).unwrap();
+        assert_eq!(resolved.deploy.target_dir.as_deref(), Some("/opt/preview/feature-login"));
+        assert!(resolved.deploy.targets.is_empty());
+        assert_eq!(resolved.deploy.command, None);
+
+        git(&["checkout", "-qb", "fixup"]);
+        assert_eq!(resolve_templates(&config).unwrap().deploy.target_dir.as_deref(), Some("/opt/deploy"));
+        std::fs::remove_dir_all(&repo).unwrap();
+    }
+
+    #[test]
+    fn test_pinned_commit_deploys_as_preview_of_its_branch() {
+        let repo = crate::test_support::init_repo();
+        crate::test_support::commit_file(&repo, "a.txt");
+        let git = |args: &[&str]| crate::test_support::git(&repo, args);
+        git(&["checkout", "-qb", "feature/login"]);
+        crate::test_support::commit_file(&repo, "b.txt");
+        let feature_commit = hook::get_current_commit_hash(repo.to_str().unwrap(), &crate::config::GitConfig::default()).unwrap();
+        git(&["checkout", "-q", "main"]);
+
+        let out = temp_dir("pinned-preview");
+        let mut config = Config::default();
+        config.watch.repo_path = repo.to_str().unwrap().to_string();
+        config.deploy.target_dir = Some(out.join("prod").to_string_lossy().into_owned());
+        config.build.command = "true".to_string();
+        config.deploy.artifacts = Some(vec![ArtifactSpec::from("a.txt")]);
+        config.deploy.previews = vec![crate::config::PreviewConfig {
+            branches: vec!["feature/*".to_string()],
+            target_dir: format!("{}/preview/{{branch}}", out.display()),
+            command: None,
+        }];
+        config.sync.enabled = false;
+        let options = RunOptions {
+            commit: Some(feature_commit.clone()),
+            ..RunOptions::default()
+        };
+        run(&config, &options).unwrap();
+
+        let preview = out.join("preview/feature-login");
+        let preview_dir = preview.to_str().unwrap();
+        let version = rollback::current_version(preview_dir, rollback::DEFAULT_CURRENT_LINK).unwrap();
+        let meta = rollback::read_version_meta(preview_dir, &version).unwrap();
+        assert_eq!(meta.branch.as_deref(), Some("feature/login"));
+        assert_eq!(meta.commit.as_deref(), Some(feature_commit.as_str()));
+        assert!(!out.join("prod").exists());
+        std::fs::remove_dir_all(&repo).unwrap();
+        std::fs::remove_dir_all(&out).unwrap();
+    }
+
+    #[test]
+    fn test_prune_previews_removes_gone_branches() {
+        let repo = crate::test_support::init_repo();
+        crate::test_support::commit_file(&repo, "a.txt");
+        crate::test_support::git(&repo, &["branch", "feature/live"]);
+        let previews = temp_dir("previews");
+        let mut config = Config::default();
+        config.watch.repo_path = repo.to_str().unwrap().to_string();
+        config.deploy.previews = vec![crate::config::PreviewConfig {
+            branches: vec!["feature/*".to_string()],
+            target_dir: format!("{}/{{branch}}/site", previews.display()),
+            command: None,
+        }];
+        let deploy_from = |name: &str, branch: Option<&str>| {
+            let target = previews.join(name).join("site");
+            std::fs::create_dir_all(target.join("v1")).unwrap();
+            let meta = rollback::VersionMeta {
+                branch: branch.map(str::to_string),
+                ..Default::default()
+            };
+            rollback::write_version_meta(&target.join("v1"), &meta).unwrap();
+            rollback::switch_current(target.to_str().unwrap(), rollback::DEFAULT_CURRENT_LINK, target.join("v1").to_str().unwrap()).unwrap();
+        };
+        deploy_from("feature-live", Some("feature/live"));
+        deploy_from("feature-gone", Some("feature/gone"));
+        // Deployed from branches the preview doesn't match, or from no branch
+        deploy_from("release", Some("release"));
+        deploy_from("feature-detached", None);
+        // Production shares the directory, and its branch is gone too
+        deploy_from("feature-prod", Some("feature/prod"));
+        config.deploy.target_dir = Some(previews.join("feature-prod").join("site").to_string_lossy().into_owned());
+        // Not a deploy target, so left alone
+        std::fs::create_dir_all(previews.join("notes")).unwrap();
+
+        let gone = previews.join("feature-gone").to_string_lossy().into_owned();
+        assert_eq!(prune_previews(&config, true).unwrap(), vec![gone.clone()]);
+        assert!(previews.join("feature-gone").exists());
+        assert_eq!(prune_previews(&config, false).unwrap(), vec![gone]);
+        assert!(!previews.join("feature-gone").exists());
+        for kept in ["feature-live", "release", "feature-detached", "feature-prod", "notes"] {
+            assert!(previews.join(kept).exists(), "{}", kept);
+        }
+
+        config.deploy.previews[0].target_dir = format!("{}/pr-{{branch}}", previews.display());
+        assert!(prune_previews(&config, false).is_err());
+        std::fs::remove_dir_all(&repo).unwrap();
+        std::fs::remove_dir_all(&previews).unwrap();
+    }
+
+    #[test]
+    fn test_required_sync_failure_fails_the_run() {
+        let repo = crate::test_support::init_repo();
+        let first = crate::test_support::commit_file(&repo, "a.txt");
+        let target = temp_dir("required-sync");
+        let mut config = Config::default();
+        config.watch.repo_path = repo.to_str().unwrap().to_string();
+        config.build.command = "git rev-parse HEAD > app".to_string();
+        config.build.use_shell = true;
+        config.deploy.target_dir = Some(target.to_str().unwrap().to_string());
+        config.deploy.artifacts = Some(vec![ArtifactSpec::from("app")]);
+        config.sync.enabled = true;
+        config.sync.remote = "missing".to_string();
+        let pinned = |commit: &str| RunOptions {
+            commit: Some(commit.to_string()),
+            force: true,
+            ..RunOptions::default()
+        };
+
+        // An optional sync only warns
+        assert!(run(&config, &pinned(&first)).is_ok());
+
+        config.sync.required = true;
+        let err = run(&config, &pinned(&first)).unwrap_err();
+        assert!(matches!(err, PipelineError::Sync(_)), "{}", err);
+        assert_eq!(err.exit_code(), EXIT_SYNC_FAILED);
+
+        // With rollback_on_failure the new version is switched out again
+        config.sync.rollback_on_failure = true;
+        let second = crate::test_support::commit_file(&repo, "b.txt");
+        let err = run(&config, &pinned(&second)).unwrap_err();
+        assert!(matches!(&err, PipelineError::RolledBack { restored, .. } if restored == &first[..7]), "{}", err);
+        assert_eq!(rollback::current_version(target.to_str().unwrap(), rollback::DEFAULT_CURRENT_LINK).as_deref(), Some(&first[..7]));
+
+        // Nothing to restore on a first deploy: still a sync failure
+        let fresh = temp_dir("required-sync-fresh");
+        config.deploy.target_dir = Some(fresh.to_str().unwrap().to_string());
+        let err = run(&config, &pinned(&second)).unwrap_err();
+        assert!(matches!(err, PipelineError::Sync(_)), "{}", err);
+        assert_eq!(rollback::current_version(fresh.to_str().unwrap(), rollback::DEFAULT_CURRENT_LINK).as_deref(), Some(&second[..7]));
+        std::fs::remove_dir_all(&repo).unwrap();
+        std::fs::remove_dir_all(&target).unwrap();
+        std::fs::remove_dir_all(&fresh).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_run_finished_is_the_last_event() {
+        use std::io::{BufRead, BufReader};
+
+        let repo = crate::test_support::init_repo();
+        let commit = crate::test_support::commit_file(&repo, "a.txt");
+        let target = temp_dir("event-order");
+        let socket = target.join("events.sock");
+        let listener = std::os::unix::net::UnixListener::bind(&socket).unwrap();
+        let mut config = Config::default();
+        config.watch.repo_path = repo.to_str().unwrap().to_string();
+        config.build.command = "touch app".to_string();
+        config.deploy.target_dir = Some(target.join("www").to_str().unwrap().to_string());
+        config.deploy.artifacts = Some(vec![ArtifactSpec::from("app")]);
+        config.notify.event_socket = Some(socket.to_str().unwrap().to_string());
+        config.sync.enabled = true;
+        config.sync.remote = "missing".to_string();
+        let options = RunOptions {
+            commit: Some(commit),
+            ..RunOptions::default()
+        };
+
+        assert!(run(&config, &options).is_ok());
+        let (stream, _) = listener.accept().unwrap();
+        let events: Vec<String> = BufReader::new(stream)
+            .lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(&line.unwrap()).unwrap()["event"].as_str().unwrap().to_string())
+            .collect();
+        assert_eq!(
+            events,
+            ["build_started", "build_finished", "deploy_started", "deploy_finished", "sync_started", "sync_finished", "run_finished"]
+        );
+        std::fs::remove_dir_all(&repo).unwrap();
+        std::fs::remove_dir_all(&target).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_rollback_with_git_revert_pushes() {
+        let repo = crate::test_support::init_repo();
+        let remote = crate::test_support::init_bare_repo();
+        let git = |args: &[&str]| crate::test_support::git(&repo, args);
+        git(&["config", "user.name", "postloop"]);
+        git(&["config", "user.email", "postloop@example.com"]);
+        git(&["remote", "add", "origin", remote.to_str().unwrap()]);
+        std::fs::write(repo.join("app.txt"), "good").unwrap();
+        git(&["add", "."]);
+        git(&["commit", "-qm", "good"]);
+        std::fs::write(repo.join("app.txt"), "bad").unwrap();
+        git(&["commit", "-qam", "bad"]);
+        let bad = git(&["rev-parse", "HEAD"]);
+
+        let target = repo.join("deploy");
+        let deployed = |version: &str, commit: &str| {
+            let meta = rollback::VersionMeta {
+                commit: Some(commit.to_string()),
+                ..Default::default()
+            };
+            std::fs::create_dir_all(target.join(version)).unwrap();
+            rollback::write_version_meta(&target.join(version), &meta).unwrap();
+            std::thread::sleep(std::time::Duration::from_millis(20));
+        };
+        deployed("v1", &git(&["rev-parse", "HEAD~1"]));
+        deployed("v2", &bad);
+        let target_str = target.to_str().unwrap();
+        let current = || rollback::current_version(target_str, rollback::DEFAULT_CURRENT_LINK).unwrap();
+        rollback::switch_current(target_str, rollback::DEFAULT_CURRENT_LINK, &format!("{}/v2", target_str)).unwrap();
+
+        let mut config = Config::default();
+        config.watch.repo_path = repo.to_str().unwrap().to_string();
+        config.deploy.target_dir = Some(target_str.to_string());
+
+        // Nothing is rolled back while the deployed commit isn't HEAD, or
+        // another revert is in progress
+        deployed("v3", &git(&["rev-parse", "HEAD~1"]));
+        rollback::switch_current(target_str, rollback::DEFAULT_CURRENT_LINK, &format!("{}/v3", target_str)).unwrap();
+        assert!(rollback(&config, None, true).unwrap_err().to_string().contains("not HEAD"));
+        assert_eq!(current(), "v3");
+        std::fs::remove_dir_all(target.join("v3")).unwrap();
+        rollback::switch_current(target_str, rollback::DEFAULT_CURRENT_LINK, &format!("{}/v2", target_str)).unwrap();
+        std::fs::write(repo.join(".git/REVERT_HEAD"), format!("{}\n", bad)).unwrap();
+        assert!(rollback(&config, None, true).unwrap_err().to_string().contains("in progress"));
+        assert_eq!(current(), "v2");
+        std::fs::remove_file(repo.join(".git/REVERT_HEAD")).unwrap();
+
+        // A revert blocked by local changes leaves the repository as it was
+        std::fs::write(repo.join("app.txt"), "local edit").unwrap();
+        assert!(rollback(&config, None, true).is_err());
+        assert_eq!(git(&["rev-parse", "HEAD"]), bad);
+        assert_eq!(std::fs::read_to_string(repo.join("app.txt")).unwrap(), "local edit");
+
+        git(&["checkout", "--", "app.txt"]);
+
+        // A revert that can't be pushed is undone
+        rollback::switch_current(target_str, rollback::DEFAULT_CURRENT_LINK, &format!("{}/v2", target_str)).unwrap();
+        git(&["remote", "set-url", "origin", repo.join("missing").to_str().unwrap()]);
+        assert!(rollback(&config, None, true).unwrap_err().to_string().contains("undone"));
+        assert_eq!(git(&["rev-parse", "HEAD"]), bad);
+        assert_eq!(std::fs::read_to_string(repo.join("app.txt")).unwrap(), "bad");
+        assert_eq!(current(), "v1");
+        git(&["remote", "set-url", "origin", remote.to_str().unwrap()]);
+
+        rollback::switch_current(target_str, rollback::DEFAULT_CURRENT_LINK, &format!("{}/v2", target_str)).unwrap();
+        let RollbackOutcome::Restored(result) = rollback(&config, None, true).unwrap() else {
+            panic!("expected a restored version");
+        };
+        assert_eq!((result.from.as_deref(), result.to.as_str()), (Some("v2"), "v1"));
+        assert_eq!(std::fs::read_to_string(repo.join("app.txt")).unwrap(), "good");
+        let head = git(&["rev-parse", "HEAD"]);
+        assert_ne!(head, bad);
+        assert_eq!(crate::test_support::git(&remote, &["rev-parse", "main"]), head);
+
+        std::fs::remove_dir_all(&repo).unwrap();
+        std::fs::remove_dir_all(&remote).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_rollback_dry_run_keeps_current() {
+        let repo = crate::test_support::init_repo();
+        let repo_str = repo.to_str().unwrap();
+        let target = repo.join("deploy");
+        let mut config = Config::default();
+        config.watch.repo_path = repo_str.to_string();
+        config.deploy.target_dir = Some(target.to_str().unwrap().to_string());
+        config.deploy.artifacts = Some(vec![ArtifactSpec::from("app")]);
+
+        let mut commits = Vec::new();
+        for name in ["first", "second"] {
+            commits.push(crate::test_support::commit_file(&repo, name));
+            std::fs::write(repo.join("app"), name).unwrap();
+            deployer::deploy(&config.deploy, repo_str, &config.watch.git, commits.last().unwrap(), None).unwrap();
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        let link_before = std::fs::read_link(target.join("current")).unwrap();
+
+        let plan = plan_rollback(&config, None, None).unwrap();
+        assert_eq!(plan.from.as_deref(), Some(&commits[1][..7]));
+        assert_eq!(plan.to, &commits[0][..7]);
+        let lines = format_rollback_plan(&config, &plan);
+        let subject = hook::get_commit_subject(repo_str, &config.watch.git, &commits[0]).unwrap();
+        assert!(lines[2].ends_with(&subject), "{:?}", lines);
+        assert!(plan_rollback(&config, None, Some("0000000")).is_err());
+
+        assert_eq!(std::fs::read_link(target.join("current")).unwrap(), link_before);
+        std::fs::remove_dir_all(&repo).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_build_sees_the_version_the_deploy_creates() {
+        let repo = crate::test_support::init_repo();
+        crate::test_support::commit_file(&repo, "README");
+        let target = repo.join("deploy");
+        let mut config = Config::default();
+        config.watch.repo_path = repo.to_str().unwrap().to_string();
+        // The timestamp in the name moves on while the build runs
+        config.build.command = r#"echo "$PLOOP_DEPLOY_VERSION" > app && sleep 1"#.to_string();
+        config.build.use_shell = true;
+        config.deploy.target_dir = Some(target.to_str().unwrap().to_string());
+        config.deploy.artifacts = Some(vec![ArtifactSpec::from("app")]);
+        config.deploy.version_scheme = crate::config::VersionScheme::HashTimestamp;
+        config.sync.enabled = false;
+
+        run(&config, &RunOptions::default()).unwrap();
+        let version = rollback::current_version(target.to_str().unwrap(), rollback::DEFAULT_CURRENT_LINK).unwrap();
+        assert_eq!(std::fs::read_to_string(target.join(&version).join("app")).unwrap().trim(), version);
+        std::fs::remove_dir_all(&repo).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_build_and_deploy_commands_see_commit_env() {
+        let repo = crate::test_support::init_repo();
+        let commit = crate::test_support::commit_file(&repo, "README");
+
+        let mut config = Config::default();
+        config.watch.repo_path = repo.to_str().unwrap().to_string();
+        config.build.command = r#"echo "$PLOOP_COMMIT $PLOOP_COMMIT_SHORT $PLOOP_BRANCH $PLOOP_DEPLOY_VERSION" > app"#.to_string();
+        config.build.use_shell = true;
+        config.deploy.target_dir = Some(repo.join("deploy").to_str().unwrap().to_string());
+        config.deploy.artifacts = Some(vec![ArtifactSpec::from("app")]);
+        config.deploy.post_deploy = Some(r#"echo "$PLOOP_DEPLOY_VERSION" > deployed"#.to_string());
+        config.sync.enabled = false;
+
+        run(&config, &RunOptions::default()).unwrap();
+        let short = &commit[..7];
+        assert_eq!(
+            std::fs::read_to_string(repo.join("app")).unwrap().trim(),
+            format!("{} {} main {}", commit, short, short)
+        );
+        assert_eq!(std::fs::read_to_string(repo.join("deployed")).unwrap().trim(), short);
+
+        // Nothing leaks into commands run on the thread after the run
+        let output = runner::run_command_line(r#"echo "[$PLOOP_COMMIT$PLOOP_DEPLOY_VERSION]""#, true, ".", &[]).unwrap();
+        assert_eq!(String::from_utf8_lossy(&output.stdout).trim(), "[]");
+        std::fs::remove_dir_all(&repo).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_unchanged_artifacts_skip_the_run() {
+        let repo = crate::test_support::init_repo();
+        crate::test_support::commit_file(&repo, "README");
+        let target = repo.join("deploy");
+        let target_str = target.to_str().unwrap();
+
+        let mut config = Config::default();
+        config.watch.repo_path = repo.to_str().unwrap().to_string();
+        config.build.command = "true".to_string();
+        config.deploy.target_dir = Some(target_str.to_string());
+        config.deploy.artifacts = Some(vec![ArtifactSpec::from("README")]);
+        config.deploy.post_deploy = Some("echo run >> post-deploy.log".to_string());
+        config.sync.enabled = false;
+        assert!(config.deploy.skip_unchanged);
+
+        run(&config, &RunOptions::default()).unwrap();
+        let version = rollback::current_version(target_str, rollback::DEFAULT_CURRENT_LINK).unwrap();
+        let meta = std::fs::read_to_string(target.join(&version).join(rollback::META_FILE)).unwrap();
+
+        // The skipped run is still recorded and reported, but deploys nothing
+        let socket = repo.join("events.sock");
+        let listener = std::os::unix::net::UnixListener::bind(&socket).unwrap();
+        let mut reported = config.clone();
+        reported.notify.event_socket = Some(socket.to_str().unwrap().to_string());
+        crate::test_support::commit_file(&repo, "CHANGELOG");
+        assert_eq!(run(&reported, &RunOptions::default()).unwrap(), RunStatus::Skipped);
+        assert_eq!(rollback::current_version(target_str, rollback::DEFAULT_CURRENT_LINK).unwrap(), version);
+        assert_eq!(std::fs::read_to_string(target.join(&version).join(rollback::META_FILE)).unwrap(), meta);
+        let records = history::read_records(target_str).unwrap();
+        assert_eq!(records.iter().map(|record| record.outcome).collect::<Vec<_>>(), [Outcome::Success, Outcome::Skipped]);
+        assert_eq!(std::fs::read_to_string(repo.join("post-deploy.log")).unwrap(), "run\n");
+        let (stream, _) = listener.accept().unwrap();
+        let events: Vec<serde_json::Value> = std::io::BufRead::lines(std::io::BufReader::new(stream))
+            .map(|line| serde_json::from_str(&line.unwrap()).unwrap())
+            .collect();
+        let last = events.last().unwrap();
+        assert_eq!((last["event"].as_str(), last["outcome"].as_str()), (Some("run_finished"), Some("skipped")));
+
+        let forced = RunOptions {
+            force: true,
+            ..RunOptions::default()
+        };
+        assert!(matches!(run(&config, &forced).unwrap(), RunStatus::Deployed { .. }));
+        assert_eq!(history::read_records(target_str).unwrap().len(), 3);
+        std::fs::remove_dir_all(&repo).unwrap();
+    }
+
+    #[test]
+    fn test_run_deploys_artifacts_from_separate_build_dir() {
+        let repo = crate::test_support::init_repo();
+        crate::test_support::commit_file(&repo, "README");
+        let build_dir = temp_dir("build-dir");
+        std::fs::create_dir_all(build_dir.join("bin")).unwrap();
+        std::fs::write(build_dir.join("bin/app"), "built in CI").unwrap();
+
+        let mut config = Config::default();
+        config.watch.repo_path = repo.to_str().unwrap().to_string();
+        config.deploy.target_dir = Some(repo.join("deploy").to_str().unwrap().to_string());
+        config.deploy.artifacts = Some(vec![ArtifactSpec::from("bin/app")]);
+        config.sync.enabled = false;
+        let options = RunOptions {
+            no_build: true,
+            build_dir: Some(build_dir.to_str().unwrap().to_string()),
+            ..RunOptions::default()
+        };
+
+        // Without the build directory the artifact is looked for in the repository
+        assert!(matches!(
+            run(&config, &RunOptions { no_build: true, ..RunOptions::default() }),
+            Err(PipelineError::Build(_))
+        ));
+        run(&config, &options).unwrap();
+        assert_eq!(std::fs::read_to_string(repo.join("deploy/current/app")).unwrap(), "built in CI");
+
+        let missing = RunOptions {
+            build_dir: Some(build_dir.join("missing").to_str().unwrap().to_string()),
+            ..options
+        };
+        let err = run(&config, &missing).unwrap_err();
+        assert!(err.to_string().contains("Build directory does not exist"), "{}", err);
+        std::fs::remove_dir_all(&repo).unwrap();
+        std::fs::remove_dir_all(&build_dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_abort_if_behind_remote() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let repo = crate::test_support::init_repo();
+        crate::test_support::commit_file(&repo, "README");
+        // Reports origin/main as existing and 3 commits ahead of main
+        let fake_git = repo.join("fake-git");
+        std::fs::write(
+            &fake_git,
+            "#!/bin/sh\ncase \"$*\" in\n  *\"--verify --quiet origin/main\"*) exit 0 ;;\n  \
+             *\"rev-list --left-right --count\"*) printf '0\\t3\\n' ;;\n  *) exec git \"$@\" ;;\nesac\n",
+        )
+        .unwrap();
+        std::fs::set_permissions(&fake_git, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let mut config = Config::default();
+        config.watch.repo_path = repo.to_str().unwrap().to_string();
+        config.watch.git.git_path = Some(fake_git.to_str().unwrap().to_string());
+        config.build.command = "touch app".to_string();
+        config.deploy.target_dir = Some(repo.join("deploy").to_str().unwrap().to_string());
+        config.deploy.artifacts = Some(vec![ArtifactSpec::from("app")]);
+        config.sync.enabled = false;
+
+        config.watch.abort_if_behind = true;
+        let err = run(&config, &RunOptions::default()).unwrap_err();
+        assert!(matches!(err, PipelineError::Config(_)));
+        assert!(err.to_string().contains("3 commit(s) behind origin/main"), "{}", err);
+        assert!(!repo.join("app").exists());
+
+        let force = RunOptions {
+            force: true,
+            ..RunOptions::default()
+        };
+        run(&config, &force).unwrap();
+        std::fs::remove_dir_all(&repo).unwrap();
+    }
+
+    #[test]
+    fn test_catch_up_deploys_pending_commits_in_order() {
+        let repo = crate::test_support::init_repo();
+        std::fs::write(repo.join(".git/info/exclude"), "app\ndeploy/\n").unwrap();
+        let first = crate::test_support::commit_file(&repo, "first");
+        let target = repo.join("deploy");
+
+        let mut config = Config::default();
+        config.watch.repo_path = repo.to_str().unwrap().to_string();
+        config.build.command = "test ! -e broken && git rev-parse --short HEAD > app".to_string();
+        config.build.use_shell = true;
+        config.deploy.target_dir = Some(target.to_str().unwrap().to_string());
+        config.deploy.artifacts = Some(vec![ArtifactSpec::from("app")]);
+        config.sync.enabled = false;
+
+        // Nothing deployed yet: only HEAD runs
+        let statuses = run_catch_up(&config, &RunOptions::default()).unwrap();
+        assert!(matches!(&statuses[..], [RunStatus::Deployed { commit }] if *commit == first));
+
+        let second = crate::test_support::commit_file(&repo, "second");
+        let third = crate::test_support::commit_file(&repo, "third");
+        let statuses = run_catch_up(&config, &RunOptions::default()).unwrap();
+        let deployed: Vec<&str> = statuses
+            .iter()
+            .map(|status| match status {
+                RunStatus::Deployed { commit } => commit.as_str(),
+                RunStatus::Skipped => "skipped",
+            })
+            .collect();
+        assert_eq!(deployed, vec![second.as_str(), third.as_str()]);
+        assert!(run_catch_up(&config, &RunOptions::default()).unwrap().is_empty());
+
+        // A failing commit stops the catch-up before later ones
+        crate::test_support::commit_file(&repo, "broken");
+        crate::test_support::commit_file(&repo, "fifth");
+        assert!(matches!(run_catch_up(&config, &RunOptions::default()), Err(PipelineError::Build(_))));
+        let commits: Vec<String> = history::read_records(target.to_str().unwrap())
+            .unwrap()
+            .into_iter()
+            .map(|record| record.commit)
+            .collect();
+        assert_eq!(commits.len(), 4);
+        assert_eq!(commits[..3], [first, second, third.clone()]);
+
+        // After a history rewrite the last deployed commit is no longer an
+        // ancestor, so only the new HEAD is deployed
+        crate::test_support::git(&repo, &["reset", "-q", "--hard", &third]);
+        crate::test_support::git(&repo, &["commit", "-q", "--amend", "-m", "rewritten"]);
+        let rewritten = crate::test_support::git(&repo, &["rev-parse", "HEAD"]);
+        let statuses = run_catch_up(&config, &RunOptions::default()).unwrap();
+        assert!(matches!(&statuses[..], [RunStatus::Deployed { commit }] if *commit == rewritten));
+        std::fs::remove_dir_all(&repo).unwrap();
+    }
+
+    #[test]
+    fn test_sync_pushes_without_building_or_deploying() {
+        let repo = crate::test_support::init_repo();
+        let remote = crate::test_support::init_bare_repo();
+        let head = crate::test_support::commit_file(&repo, "README");
+        crate::test_support::git(&repo, &["remote", "add", "origin", remote.to_str().unwrap()]);
+
+        let mut config = Config::default();
+        config.watch.repo_path = repo.to_str().unwrap().to_string();
+        config.build.command = "touch built".to_string();
+        config.deploy.target_dir = Some(repo.join("deploy").to_str().unwrap().to_string());
+        config.sync.enabled = true;
+
+        let results = sync(&config).unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!((results[0].remote.as_str(), results[0].error.as_deref()), ("origin", None));
+        assert_eq!(crate::test_support::git(&remote, &["rev-parse", "main"]), head);
+        assert!(!repo.join("built").exists());
+        assert!(!repo.join("deploy").exists());
+
+        // A failing push is reported for its remote, not raised
+        config.sync.remote = "missing".to_string();
+        let results = sync(&config).unwrap();
+        assert!(results[0].error.is_some());
+
+        std::fs::remove_dir_all(&repo).unwrap();
+        std::fs::remove_dir_all(&remote).unwrap();
+    }
+
+    #[test]
+    fn test_run_deploys_into_branch_directory() {
+        let repo = crate::test_support::init_repo();
+        crate::test_support::commit_file(&repo, "README");
+        crate::test_support::git(&repo, &["checkout", "-qb", "feature/login"]);
+
+        let mut config = Config::default();
+        config.watch.repo_path = repo.to_str().unwrap().to_string();
+        config.build.command = "touch app".to_string();
+        config.deploy.target_dir = Some(format!("{}/preview/{{branch}}", repo.display()));
+        config.deploy.artifacts = Some(vec![ArtifactSpec::from("app")]);
+        config.sync.enabled = false;
+
+        let resolved = resolve_templates(&config).unwrap();
+        let branch_dir = repo.join("preview/feature-login");
+        assert_eq!(resolved.deploy.target_dir.as_deref(), branch_dir.to_str());
+
+        run(&config, &RunOptions::default()).unwrap();
+        assert!(branch_dir.join("current/app").exists());
+        std::fs::remove_dir_all(&repo).unwrap();
+    }
+
+    #[test]
+    fn test_run_outcomes() {
+        let repo = crate::test_support::init_repo();
+        crate::test_support::commit_file(&repo, "README");
+        let target = repo.join("deploy");
+
+        let mut config = Config::default();
+        config.watch.repo_path = repo.to_str().unwrap().to_string();
+        config.build.command = "touch app".to_string();
+        config.deploy.command = None;
+        config.deploy.target_dir = Some(target.to_str().unwrap().to_string());
+        config.deploy.artifacts = Some(vec![ArtifactSpec::from("app")]);
+        config.sync.enabled = false;
+        config.rollback.enabled = true;
+
+        assert!(matches!(run(&config, &RunOptions::default()), Ok(RunStatus::Deployed { .. })));
+
+        // A failing deploy restores the version that was current before it
+        config.deploy.command = Some("false".to_string());
+        assert_eq!(run(&config, &RunOptions::default()).unwrap_err().exit_code(), EXIT_ROLLED_BACK);
+        config.rollback.enabled = false;
+        assert_eq!(run(&config, &RunOptions::default()).unwrap_err().exit_code(), EXIT_DEPLOY_FAILED);
+
+        config.build.command = "false".to_string();
+        assert_eq!(run(&config, &RunOptions::default()).unwrap_err().exit_code(), EXIT_BUILD_FAILED);
+
+        // Uncommitted changes block the run only when a clean tree is required
+        config.build.command = "touch app".to_string();
+        config.deploy.command = None;
+        config.watch.require_clean_tree = true;
+        std::fs::write(repo.join("README"), "edited").unwrap();
+        assert_eq!(run(&config, &RunOptions::default()).unwrap_err().exit_code(), EXIT_CONFIG_ERROR);
+        let allow_dirty = RunOptions {
+            allow_dirty: true,
+            ..RunOptions::default()
+        };
+        assert!(run(&config, &allow_dirty).is_ok());
+
+        // A deploy command's output is kept with its history record
+        config.deploy.command = Some("echo released".to_string());
+        assert!(run(&config, &allow_dirty).is_ok());
+
+        let records = history::read_records(target.to_str().unwrap()).unwrap();
+        assert_eq!(records.last().unwrap().output.as_deref(), Some("released\n"));
+        let outcomes: Vec<Outcome> = records.iter().map(|record| record.outcome).collect();
+        assert_eq!(
+            outcomes,
+            vec![
+                Outcome::Success,
+                Outcome::RolledBack,
+                Outcome::DeployFailed,
+                Outcome::BuildFailed,
+                // Same artifacts as the first run, so the deploy is skipped
+                Outcome::Skipped,
+                Outcome::Success
+            ]
+        );
+
+        // ... and so is a failed one's
+        config.deploy.command = Some("echo half-done; exit 1".to_string());
+        config.deploy.use_shell = true;
+        assert_eq!(run(&config, &allow_dirty).unwrap_err().exit_code(), EXIT_DEPLOY_FAILED);
+        let records = history::read_records(target.to_str().unwrap()).unwrap();
+        assert_eq!(records.last().unwrap().outcome, Outcome::DeployFailed);
+        assert_eq!(records.last().unwrap().output.as_deref(), Some("half-done\n"));
+        std::fs::remove_dir_all(&repo).unwrap();
+    }
+}
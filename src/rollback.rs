@@ -1,21 +1,132 @@
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
 use std::fs;
 use std::path::{Path, PathBuf};
 
+/// Name of the per-version metadata file written at deploy time
+pub const META_FILE: &str = ".ploop-meta.json";
+
+/// Pointer file naming the active version, used instead of the 'current'
+/// symlink where symlinks cannot be created (e.g. Windows without privileges)
+pub const POINTER_FILE: &str = "current.txt";
+
+/// Stack of versions that rollbacks moved away from, newest last
+pub const REDO_FILE: &str = ".ploop-redo";
+
+/// How many rollbacks can be redone
+const REDO_LIMIT: usize = 10;
+
+/// Name of the symlink naming the active version unless `deploy.current_link_name` says otherwise
+pub const DEFAULT_CURRENT_LINK: &str = "current";
+
+/// Check that `name` can serve as the active-version symlink inside a target
+///
+/// The name must be a single plain path component that cannot be mistaken
+/// for anything else in the target: not hidden (staging directories and
+/// ploop's own files start with a dot), not the pointer file, and not an
+/// archive name. An empty name would make the link the target directory
+/// itself, which replacing the link would delete.
+pub fn validate_current_link_name(name: &str) -> Result<(), String> {
+    let mut components = Path::new(name).components();
+    let plain = matches!(
+        (components.next(), components.next()),
+        (Some(std::path::Component::Normal(_)), None)
+    );
+    if !plain || name.contains(['/', '\\']) {
+        return Err(format!("deploy.current_link_name {:?} must be a plain file name", name));
+    }
+    if name.starts_with('.')
+        || [POINTER_FILE, META_FILE, REDO_FILE].contains(&name)
+        || name.ends_with(".tar.gz")
+        || name.ends_with(".tmp")
+    {
+        return Err(format!("deploy.current_link_name {:?} collides with a name ploop uses in the target", name));
+    }
+    Ok(())
+}
+
+fn current_link_path(target_dir: &str, link_name: &str) -> PathBuf {
+    Path::new(target_dir).join(link_name)
+}
+
+/// A deployed version directory with its metadata
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct DeployedVersion {
+    pub name: String,
+    /// Modification time of the version directory (RFC 3339)
+    pub modified: String,
+    /// Whether the 'current' symlink (or pointer file) points at this version
+    pub is_current: bool,
+    /// Parsed deploy metadata, if the version has any
+    pub metadata: Option<VersionMeta>,
+}
+
+/// Contents of a version's [`META_FILE`], written at deploy time
+///
+/// Every field is optional so files written by older releases (which only
+/// had `commit` and `deployed_at`) still load.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+pub struct VersionMeta {
+    #[serde(default)]
+    pub commit: Option<String>,
+    /// Branch checked out when the version was deployed (unset for detached HEADs)
+    #[serde(default)]
+    pub branch: Option<String>,
+    /// RFC 3339 time the version was staged
+    #[serde(default)]
+    pub deployed_at: Option<String>,
+    /// 1 for the first version deployed to a target, increasing by one per deploy
+    #[serde(default)]
+    pub sequence: Option<u64>,
+    #[serde(default)]
+    pub build_duration_secs: Option<f64>,
+    /// Time spent copying artifacts into the version directory
+    #[serde(default)]
+    pub copy_duration_secs: Option<f64>,
+    /// OS user who deployed the version
+    #[serde(default)]
+    pub user: Option<String>,
+    /// Host the version was deployed from
+    #[serde(default)]
+    pub host: Option<String>,
+    /// ID of the run that deployed the version, as in its log lines
+    #[serde(default)]
+    pub run_id: Option<String>,
+    /// SHA-256 of every file in the version, keyed by `/`-separated relative path
+    #[serde(default)]
+    pub checksums: BTreeMap<String, String>,
+}
+
 /// Get list of deployed versions sorted by modification time (newest first)
-pub fn get_deployed_versions(target_dir: &str) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+pub fn get_deployed_versions(target_dir: &str, link_name: &str) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    Ok(get_deployed_versions_detailed(target_dir, link_name)?
+        .into_iter()
+        .map(|version| version.name)
+        .collect())
+}
+
+/// Get deployed versions with metadata, sorted by modification time (newest first)
+pub fn get_deployed_versions_detailed(
+    target_dir: &str,
+    link_name: &str,
+) -> Result<Vec<DeployedVersion>, Box<dyn std::error::Error>> {
     let path = Path::new(target_dir);
     if !path.exists() {
         return Ok(Vec::new());
     }
 
+    let current = current_version(target_dir, link_name);
     let mut versions = Vec::new();
 
     for entry in fs::read_dir(path)? {
         let entry = entry?;
         let path = entry.path();
 
-        // Skip the 'current' symlink
-        if path.file_name() == Some(std::ffi::OsStr::new("current")) {
+        // Skip the 'current' symlink and hidden staging directories
+        if path.file_name() == Some(std::ffi::OsStr::new(link_name))
+            || path.file_name().is_some_and(|name| name.to_string_lossy().starts_with('.'))
+        {
             continue;
         }
 
@@ -30,17 +141,143 @@ pub fn get_deployed_versions(target_dir: &str) -> Result<Vec<String>, Box<dyn st
     }
 
     // Sort by modification time (newest first)
-    versions.sort_by(|a, b| b.1.cmp(&a.1));
+    versions.sort_by_key(|v| std::cmp::Reverse(v.1));
+
+    Ok(versions
+        .into_iter()
+        .map(|(name, modified)| DeployedVersion {
+            is_current: current.as_deref() == Some(name.as_str()),
+            metadata: read_metadata(&path.join(&name)),
+            modified: DateTime::<Local>::from(modified).to_rfc3339(),
+            name,
+        })
+        .collect())
+}
+
+impl DeployedVersion {
+    /// Commit this version was deployed from: the metadata `commit` field, or
+    /// the hash embedded in the directory name
+    pub fn commit(&self) -> Option<String> {
+        if let Some(commit) = self.metadata.as_ref().and_then(|meta| meta.commit.clone()) {
+            return Some(commit);
+        }
+
+        self.name
+            .split('-')
+            .find(|part| part.len() >= 7 && part.chars().all(|c| c.is_ascii_hexdigit()))
+            .map(|part| part.to_string())
+    }
+}
+
+/// Format rollback candidates, newest first, one numbered line each
+///
+/// The current version is marked with `*`; `subject` looks up a commit's
+/// subject line (e.g. `hook::get_commit_subject`).
+pub fn format_version_list(
+    versions: &[DeployedVersion],
+    subject: impl Fn(&str) -> Option<String>,
+) -> Vec<String> {
+    versions
+        .iter()
+        .enumerate()
+        .map(|(index, version)| {
+            let marker = if version.is_current { "*" } else { " " };
+            let subject = version.commit().and_then(|commit| subject(&commit)).unwrap_or_default();
+            format!("{} {:>2}) {}  {}  {}", marker, index + 1, version.name, version.modified, subject)
+                .trim_end()
+                .to_string()
+        })
+        .collect()
+}
+
+/// Load the metadata of `version` under `target_dir`
+///
+/// Versions without a metadata file (or with an unreadable one) give `None`.
+pub fn read_version_meta(target_dir: &str, version: &str) -> Option<VersionMeta> {
+    read_metadata(&Path::new(target_dir).join(version))
+}
+
+/// Write a version directory's metadata file
+pub fn write_version_meta(version_dir: &Path, meta: &VersionMeta) -> Result<(), Box<dyn std::error::Error>> {
+    fs::write(version_dir.join(META_FILE), serde_json::to_string_pretty(meta)?)?;
+    Ok(())
+}
+
+pub(crate) fn read_metadata(version_dir: &Path) -> Option<VersionMeta> {
+    let path = version_dir.join(META_FILE);
+    let content = fs::read_to_string(&path).ok()?;
+    serde_json::from_str(&content)
+        .map_err(|e| log::warn!("Ignoring malformed version metadata {:?}: {}", path, e))
+        .ok()
+}
+
+/// Commit recorded in a version directory's metadata at deploy time
+pub fn deployed_commit(version_dir: &Path) -> Option<String> {
+    read_metadata(version_dir)?.commit
+}
 
-    Ok(versions.into_iter().map(|(name, _)| name).collect())
+/// Summary of a cleanup pass
+#[derive(Debug, Clone, Default)]
+pub struct CleanupReport {
+    pub removed: Vec<String>,
+    pub freed_bytes: u64,
+}
+
+/// Get the version the 'current' symlink (or the pointer file) points to, if any
+pub fn current_version(target_dir: &str, link_name: &str) -> Option<String> {
+    if let Ok(link_target) = fs::read_link(current_link_path(target_dir, link_name)) {
+        return link_target
+            .file_name()
+            .and_then(|name| name.to_str())
+            .map(|name| name.to_string());
+    }
+
+    let pointer = fs::read_to_string(Path::new(target_dir).join(POINTER_FILE)).ok()?;
+    let version = pointer.trim();
+    (!version.is_empty()).then(|| version.to_string())
+}
+
+/// Whether the target tracks its active version with the pointer file
+pub fn uses_pointer_file(target_dir: &str) -> bool {
+    Path::new(target_dir).join(POINTER_FILE).is_file()
 }
 
 /// Clean up old versions, keeping only the specified number
+///
+/// With `archive` set, old versions are packed into `{version}.tar.gz` next
+/// to the version directories instead of being deleted.
 pub fn cleanup_old_versions(
     target_dir: &str,
+    link_name: &str,
     keep_versions: usize,
+    archive: bool,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let versions = get_deployed_versions(target_dir)?;
+    retire_versions(target_dir, link_name, keep_versions, false, archive)?;
+    Ok(())
+}
+
+/// Remove all but the newest `keep_versions` versions, never removing the current one
+///
+/// With `dry_run` set, nothing is deleted and the report lists what would be removed.
+pub fn prune_versions(
+    target_dir: &str,
+    link_name: &str,
+    keep_versions: usize,
+    dry_run: bool,
+) -> Result<CleanupReport, Box<dyn std::error::Error>> {
+    retire_versions(target_dir, link_name, keep_versions, dry_run, false)
+}
+
+fn retire_versions(
+    target_dir: &str,
+    link_name: &str,
+    keep_versions: usize,
+    dry_run: bool,
+    archive: bool,
+) -> Result<CleanupReport, Box<dyn std::error::Error>> {
+    let versions = get_deployed_versions(target_dir, link_name)?;
+    let current = current_version(target_dir, link_name);
+    let mut report = CleanupReport::default();
 
     if versions.len() <= keep_versions {
         log::info!(
@@ -48,122 +285,873 @@ pub fn cleanup_old_versions(
             versions.len(),
             keep_versions
         );
-        return Ok(());
+        return Ok(report);
     }
 
     // Remove old versions
     for version in versions.iter().skip(keep_versions) {
+        if current.as_deref() == Some(version.as_str()) {
+            log::info!("Keeping current version: {}", version);
+            continue;
+        }
+
         let mut version_path = PathBuf::from(target_dir);
         version_path.push(version);
 
-        log::info!("Removing old version: {:?}", version_path);
-        fs::remove_dir_all(&version_path)?;
+        let size = dir_size(&version_path)?;
+        if dry_run {
+            log::info!("Would remove old version: {:?}", version_path);
+        } else if archive {
+            let archive_path = archive_version(target_dir, version)?;
+            log::info!("Archived old version: {:?} -> {:?}", version_path, archive_path);
+        } else {
+            log::info!("Removing old version: {:?}", version_path);
+            fs::remove_dir_all(&version_path)?;
+        }
+
+        report.removed.push(version.clone());
+        report.freed_bytes += size;
+    }
+
+    Ok(report)
+}
+
+/// Delete the oldest versions, never the current one, until all version
+/// directories together take at most `max_total_bytes`
+///
+/// Files hardlinked between versions (dedup, incremental deploys) count
+/// once, and removing a version only frees the files no remaining version
+/// shares. Archives are neither counted nor created here: quota pruning
+/// deletes.
+pub fn enforce_size_quota(target_dir: &str, link_name: &str, max_total_bytes: u64) -> Result<CleanupReport, Box<dyn std::error::Error>> {
+    let current = current_version(target_dir, link_name);
+    let mut versions = Vec::new();
+    let mut links: HashMap<FileKey, (u64, usize)> = HashMap::new();
+    for version in get_deployed_versions(target_dir, link_name)? {
+        let mut files = HashMap::new();
+        collect_file_sizes(&Path::new(target_dir).join(&version), &mut files)?;
+        for (key, size) in &files {
+            links.entry(key.to_owned()).or_insert((*size, 0)).1 += 1;
+        }
+        versions.push((version, files));
+    }
+    let mut total: u64 = links.values().map(|(size, _)| size).sum();
+    let mut report = CleanupReport::default();
+
+    // Oldest first
+    for (version, files) in versions.iter().rev() {
+        if total <= max_total_bytes {
+            break;
+        }
+        if current.as_deref() == Some(version.as_str()) {
+            continue;
+        }
+
+        fs::remove_dir_all(Path::new(target_dir).join(version))?;
+        let mut freed = 0;
+        for key in files.keys() {
+            if let Some((size, count)) = links.get_mut(key) {
+                *count -= 1;
+                if *count == 0 {
+                    freed += *size;
+                }
+            }
+        }
+        log::info!("Removed version {} to stay within the disk quota, reclaiming {} bytes", version, freed);
+        total -= freed;
+        report.removed.push(version.clone());
+        report.freed_bytes += freed;
+    }
+
+    if total > max_total_bytes {
+        log::warn!(
+            "Versions in {} still take {} bytes, over the {} byte quota (the current version is kept)",
+            target_dir,
+            total,
+            max_total_bytes
+        );
+    }
+    Ok(report)
+}
+
+/// Path of the archive an old version is packed into
+pub fn archive_path(target_dir: &str, version: &str) -> PathBuf {
+    Path::new(target_dir).join(format!("{}.tar.gz", version))
+}
+
+/// Pack a version directory into `{version}.tar.gz` and remove the directory
+///
+/// The archive is written under a temporary name and renamed into place, so
+/// an interrupted run never leaves a truncated archive in place of a version.
+pub fn archive_version(target_dir: &str, version: &str) -> Result<PathBuf, Box<dyn std::error::Error>> {
+    let version_path = Path::new(target_dir).join(version);
+    let archive = archive_path(target_dir, version);
+    let partial = archive.with_extension("gz.partial");
+
+    let encoder = flate2::write::GzEncoder::new(fs::File::create(&partial)?, flate2::Compression::default());
+    let mut builder = tar::Builder::new(encoder);
+    builder.follow_symlinks(false);
+    builder.append_dir_all(version, &version_path)?;
+    builder.into_inner()?.finish()?.sync_all()?;
+
+    fs::rename(&partial, &archive)?;
+    fs::remove_dir_all(&version_path)?;
+    Ok(archive)
+}
+
+/// Unpack `{version}.tar.gz` back into its version directory and remove the archive
+///
+/// The archive is unpacked into a hidden `.{version}.staging` directory and
+/// the version renamed into place from there, so a corrupt archive or an
+/// interrupted run never leaves a partial version behind.
+pub fn extract_archived_version(target_dir: &str, version: &str) -> Result<PathBuf, Box<dyn std::error::Error>> {
+    let archive = archive_path(target_dir, version);
+    let version_path = Path::new(target_dir).join(version);
+    let staging_dir = Path::new(target_dir).join(format!(".{}.staging", version));
+    if staging_dir.exists() {
+        fs::remove_dir_all(&staging_dir)?;
+    }
+
+    let unpacked = (|| -> Result<(), Box<dyn std::error::Error>> {
+        let decoder = flate2::read::GzDecoder::new(fs::File::open(&archive)?);
+        tar::Archive::new(decoder).unpack(&staging_dir)?;
+        if !staging_dir.join(version).is_dir() {
+            return Err(format!("Archive {:?} does not contain version {}", archive, version).into());
+        }
+        fs::rename(staging_dir.join(version), &version_path)?;
+        Ok(())
+    })();
+    if staging_dir.exists() {
+        fs::remove_dir_all(&staging_dir)?;
+    }
+    unpacked?;
+
+    fs::remove_file(&archive)?;
+    log::info!("Restored archived version: {:?}", version_path);
+    Ok(version_path)
+}
+
+/// Total size in bytes of all files under a directory (symlinks are not followed)
+pub fn dir_size(path: &Path) -> Result<u64, Box<dyn std::error::Error>> {
+    let metadata = fs::symlink_metadata(path)?;
+    if !metadata.is_dir() {
+        return Ok(metadata.len());
     }
 
+    let mut total = 0;
+    for entry in fs::read_dir(path)? {
+        total += dir_size(&entry?.path())?;
+    }
+
+    Ok(total)
+}
+
+/// Identity of a file on disk, shared by its hardlinks: device and inode on
+/// Unix, the path elsewhere
+#[cfg(unix)]
+type FileKey = (u64, u64);
+#[cfg(not(unix))]
+type FileKey = PathBuf;
+
+#[cfg(unix)]
+fn file_key(_path: &Path, metadata: &fs::Metadata) -> FileKey {
+    use std::os::unix::fs::MetadataExt;
+    (metadata.dev(), metadata.ino())
+}
+
+#[cfg(not(unix))]
+fn file_key(path: &Path, _metadata: &fs::Metadata) -> FileKey {
+    path.to_path_buf()
+}
+
+/// Size of every file (and symlink) under `path`, keyed by [`FileKey`]
+fn collect_file_sizes(path: &Path, files: &mut HashMap<FileKey, u64>) -> Result<(), Box<dyn std::error::Error>> {
+    let metadata = fs::symlink_metadata(path)?;
+    if !metadata.is_dir() {
+        files.insert(file_key(path, &metadata), metadata.len());
+        return Ok(());
+    }
+
+    for entry in fs::read_dir(path)? {
+        collect_file_sizes(&entry?.path(), files)?;
+    }
     Ok(())
 }
 
-/// Rollback to previous version
-pub fn rollback_to_previous(target_dir: &str) -> Result<String, Box<dyn std::error::Error>> {
-    let versions = get_deployed_versions(target_dir)?;
+/// What a rollback changed: the version 'current' left and the one it now names
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct RollbackResult {
+    /// Version 'current' pointed to before the rollback, if any
+    pub from: Option<String>,
+    pub to: String,
+    pub target_dir: String,
+}
+
+/// The version a rollback without an explicit version returns to
+fn previous_version(target_dir: &str, link_name: &str) -> Result<String, Box<dyn std::error::Error>> {
+    let mut versions = get_deployed_versions(target_dir, link_name)?;
 
     if versions.len() < 2 {
+        if !list_backups(target_dir)?.is_empty() {
+            return Err("No previous version available for rollback: target uses unversioned deploys, \
+                        which have no version directories; restore the .bak backup instead"
+                .into());
+        }
         return Err("No previous version available for rollback".into());
     }
 
     // The first version is the current one, so we want the second one
-    let previous_version = &versions[1];
+    Ok(versions.swap_remove(1))
+}
+
+/// Rollback to previous version
+pub fn rollback_to_previous(target_dir: &str, link_name: &str) -> Result<RollbackResult, Box<dyn std::error::Error>> {
+    let previous_version = previous_version(target_dir, link_name)?;
 
     // Update 'current' symlink to point to previous version
-    let current_link = format!("{}/current", target_dir);
     let previous_path = format!("{}/{}", target_dir, previous_version);
+    let from = switch_current_recording_redo(target_dir, link_name, &previous_path)?;
 
-    // Remove existing symlink
-    if Path::new(&current_link).exists() {
-        #[cfg(unix)]
-        fs::remove_file(&current_link)?;
-        #[cfg(windows)]
-        {
-            if Path::new(&current_link).is_dir() {
-                fs::remove_dir(&current_link)?;
-            } else {
-                fs::remove_file(&current_link)?;
+    log::info!("Rolled back to version: {}", previous_version);
+
+    Ok(RollbackResult {
+        from,
+        to: previous_version,
+        target_dir: target_dir.to_string(),
+    })
+}
+
+/// Resolve what a rollback to `version` (or the previous version) would do
+/// without switching 'current' or restoring archived versions
+pub fn plan_rollback(target_dir: &str, link_name: &str, version: Option<&str>) -> Result<RollbackResult, Box<dyn std::error::Error>> {
+    let to = match version {
+        Some(version) => {
+            if !Path::new(target_dir).join(version).is_dir() && !archive_path(target_dir, version).is_file() {
+                return Err(format!("Version not found: {}", version).into());
             }
+            version.to_string()
         }
-    }
+        None => previous_version(target_dir, link_name)?,
+    };
 
-    // Create new symlink to previous version
-    #[cfg(unix)]
-    std::os::unix::fs::symlink(&previous_path, &current_link)?;
-    
-    #[cfg(windows)]
-    {
-        if Path::new(&previous_path).is_dir() {
-            std::os::windows::fs::symlink_dir(&previous_path, &current_link)?;
-        } else {
-            std::os::windows::fs::symlink_file(&previous_path, &current_link)?;
-        }
-    }
+    Ok(RollbackResult {
+        from: current_version(target_dir, link_name),
+        to,
+        target_dir: target_dir.to_string(),
+    })
+}
 
-    log::info!("Rolled back to version: {}", previous_version);
+/// Remove the 'current' symlink and the pointer file, leaving no version active
+///
+/// Returns the version that was active, if any. The version directory itself is kept.
+pub fn remove_current(target_dir: &str, link_name: &str) -> Result<Option<String>, Box<dyn std::error::Error>> {
+    let active = current_version(target_dir, link_name);
+
+    let current_link = current_link_path(target_dir, link_name);
+    if fs::symlink_metadata(&current_link).is_ok_and(|metadata| metadata.file_type().is_symlink()) {
+        remove_link(&current_link)?;
+    }
+    let pointer = Path::new(target_dir).join(POINTER_FILE);
+    if pointer.exists() {
+        fs::remove_file(pointer)?;
+    }
 
-    Ok(previous_version.clone())
+    Ok(active)
 }
 
 /// Rollback to a specific version
 pub fn rollback_to_version(
     target_dir: &str,
+    link_name: &str,
     version: &str,
-) -> Result<(), Box<dyn std::error::Error>> {
+) -> Result<RollbackResult, Box<dyn std::error::Error>> {
     let version_path = format!("{}/{}", target_dir, version);
 
     if !Path::new(&version_path).exists() {
-        return Err(format!("Version not found: {}", version).into());
+        if !archive_path(target_dir, version).is_file() {
+            return Err(format!("Version not found: {}", version).into());
+        }
+        extract_archived_version(target_dir, version)?;
     }
 
     // Update 'current' symlink
-    let current_link = format!("{}/current", target_dir);
+    let from = switch_current_recording_redo(target_dir, link_name, &version_path)?;
 
-    // Remove existing symlink
-    if Path::new(&current_link).exists() {
-        #[cfg(unix)]
-        fs::remove_file(&current_link)?;
-        #[cfg(windows)]
-        {
-            if Path::new(&current_link).is_dir() {
-                fs::remove_dir(&current_link)?;
-            } else {
-                fs::remove_file(&current_link)?;
-            }
+    log::info!("Rolled back to version: {}", version);
+
+    Ok(RollbackResult {
+        from,
+        to: version.to_string(),
+        target_dir: target_dir.to_string(),
+    })
+}
+
+/// Switch 'current', pushing the version it pointed to onto the redo stack
+///
+/// Returns the version 'current' pointed to before the switch.
+fn switch_current_recording_redo(
+    target_dir: &str,
+    link_name: &str,
+    version_path: &str,
+) -> Result<Option<String>, Box<dyn std::error::Error>> {
+    let prior = current_version(target_dir, link_name);
+    switch_current(target_dir, link_name, version_path)?;
+
+    let target = Path::new(version_path).file_name().and_then(|name| name.to_str());
+    if let Some(prior) = prior.as_ref().filter(|prior| Some(prior.as_str()) != target) {
+        let mut stack = read_redo_stack(target_dir);
+        stack.push(prior.clone());
+        let excess = stack.len().saturating_sub(REDO_LIMIT);
+        stack.drain(..excess);
+        write_redo_stack(target_dir, &stack)?;
+    }
+
+    Ok(prior)
+}
+
+/// Undo the most recent rollback, returning the version 'current' points to again
+///
+/// Redoing repeatedly walks forward through chained rollbacks.
+pub fn redo_rollback(target_dir: &str, link_name: &str) -> Result<String, Box<dyn std::error::Error>> {
+    let mut stack = read_redo_stack(target_dir);
+    let version = stack.pop().ok_or("Nothing to redo: no rollback since the last deploy")?;
+
+    let version_path = format!("{}/{}", target_dir, version);
+    if !Path::new(&version_path).is_dir() {
+        write_redo_stack(target_dir, &stack)?;
+        return Err(format!("Cannot redo: version {} no longer exists", version).into());
+    }
+
+    switch_current(target_dir, link_name, &version_path)?;
+    write_redo_stack(target_dir, &stack)?;
+    log::info!("Redid rollback, current is again: {}", version);
+
+    Ok(version)
+}
+
+/// Forget all redo state; called when a fresh deploy moves 'current'
+pub fn clear_redo(target_dir: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let path = Path::new(target_dir).join(REDO_FILE);
+    if path.exists() {
+        fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+fn read_redo_stack(target_dir: &str) -> Vec<String> {
+    fs::read_to_string(Path::new(target_dir).join(REDO_FILE))
+        .map(|content| content.lines().map(|line| line.to_string()).collect())
+        .unwrap_or_default()
+}
+
+fn write_redo_stack(target_dir: &str, stack: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    if stack.is_empty() {
+        return clear_redo(target_dir);
+    }
+    let mut content = stack.join("\n");
+    content.push('\n');
+    fs::write(Path::new(target_dir).join(REDO_FILE), content)?;
+    Ok(())
+}
+
+/// Point the 'current' symlink at `version_path`, replacing any existing link
+///
+/// A dangling link (its version was deleted) is replaced like any other.
+/// Targets already using the pointer file keep using it, and a failure to
+/// create the symlink falls back to the pointer file.
+pub fn switch_current(target_dir: &str, link_name: &str, version_path: &str) -> Result<(), Box<dyn std::error::Error>> {
+    if uses_pointer_file(target_dir) {
+        return write_current_pointer(target_dir, link_name, version_path);
+    }
+
+    if let Err(e) = replace_current_link(target_dir, link_name, version_path) {
+        log::warn!("Cannot create current symlink ({}), using {} instead", e, POINTER_FILE);
+        return write_current_pointer(target_dir, link_name, version_path);
+    }
+
+    Ok(())
+}
+
+/// Record `version_path` as the active version in the pointer file
+///
+/// Any 'current' symlink is removed so the two never disagree. The file is
+/// written to a temp file and renamed into place.
+pub fn write_current_pointer(target_dir: &str, link_name: &str, version_path: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let version = Path::new(version_path)
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or_else(|| format!("Invalid version path: {}", version_path))?;
+
+    let current_link = current_link_path(target_dir, link_name);
+    if fs::symlink_metadata(&current_link).is_ok_and(|metadata| metadata.file_type().is_symlink()) {
+        remove_link(&current_link)?;
+    }
+
+    let pointer = Path::new(target_dir).join(POINTER_FILE);
+    let temp = Path::new(target_dir).join(format!("{}.tmp", POINTER_FILE));
+    fs::write(&temp, format!("{}\n", version))?;
+    fs::rename(&temp, &pointer)?;
+    Ok(())
+}
+
+fn remove_link(link: &Path) -> std::io::Result<()> {
+    #[cfg(windows)]
+    {
+        use std::os::windows::fs::FileTypeExt;
+        if fs::symlink_metadata(link)?.file_type().is_symlink_dir() {
+            return fs::remove_dir(link);
+        }
+    }
+    fs::remove_file(link)
+}
+
+fn replace_current_link(target_dir: &str, link_name: &str, version_path: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let current_link = current_link_path(target_dir, link_name);
+
+    // Remove whatever is there: normally a symlink (symlink_metadata also
+    // sees dangling ones), but a manual deploy may have left a copied
+    // directory or a plain file behind
+    if let Ok(metadata) = fs::symlink_metadata(&current_link) {
+        let file_type = metadata.file_type();
+        if file_type.is_symlink() {
+            log::debug!("Replacing {:?} symlink", current_link);
+            remove_link(&current_link)?;
+        } else if file_type.is_dir() && current_link.join(META_FILE).exists() {
+            return Err(format!("{:?} is a deployed version, not a symlink; refusing to replace it", current_link).into());
+        } else if file_type.is_dir() {
+            log::warn!("Replacing {:?}, which was a plain directory, not a symlink", current_link);
+            fs::remove_dir_all(&current_link)?;
+        } else {
+            log::warn!("Replacing {:?}, which was a plain file, not a symlink", current_link);
+            fs::remove_file(&current_link)?;
         }
     }
 
     // Create new symlink
     #[cfg(unix)]
-    std::os::unix::fs::symlink(&version_path, &current_link)?;
-    
+    std::os::unix::fs::symlink(version_path, &current_link)?;
+
     #[cfg(windows)]
     {
-        if Path::new(&version_path).is_dir() {
-            std::os::windows::fs::symlink_dir(&version_path, &current_link)?;
+        if Path::new(version_path).is_dir() {
+            std::os::windows::fs::symlink_dir(version_path, &current_link)?;
         } else {
-            std::os::windows::fs::symlink_file(&version_path, &current_link)?;
+            std::os::windows::fs::symlink_file(version_path, &current_link)?;
         }
     }
 
-    log::info!("Rolled back to version: {}", version);
-
     Ok(())
 }
 
+/// State of the 'current' symlink (or pointer file)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CurrentLink {
+    Missing,
+    Valid(String),
+    /// Points at a version directory that no longer exists
+    Broken(String),
+}
+
+impl std::fmt::Display for CurrentLink {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CurrentLink::Missing => write!(f, "no current symlink"),
+            CurrentLink::Valid(version) => write!(f, "{}", version),
+            CurrentLink::Broken(version) => {
+                write!(f, "current symlink is broken (points to missing {})", version)
+            }
+        }
+    }
+}
+
+/// Inspect the 'current' symlink (or pointer file)
+pub fn current_link_state(target_dir: &str, link_name: &str) -> CurrentLink {
+    let Some(version) = current_version(target_dir, link_name) else {
+        return CurrentLink::Missing;
+    };
+
+    if Path::new(target_dir).join(&version).is_dir() {
+        CurrentLink::Valid(version)
+    } else {
+        CurrentLink::Broken(version)
+    }
+}
+
+/// Re-point a broken 'current' symlink at the newest valid version
+///
+/// Returns the version it now points to, or `None` if nothing needed repair.
+pub fn repair_current(target_dir: &str, link_name: &str) -> Result<Option<String>, Box<dyn std::error::Error>> {
+    let CurrentLink::Broken(missing) = current_link_state(target_dir, link_name) else {
+        return Ok(None);
+    };
+
+    let versions = get_deployed_versions(target_dir, link_name)?;
+    let newest = versions
+        .first()
+        .ok_or_else(|| format!("Cannot repair current symlink: {} is missing and no other version exists", missing))?;
+
+    switch_current(target_dir, link_name, &format!("{}/{}", target_dir, newest))?;
+    log::warn!("Repaired current symlink: {} was missing, now points to {}", missing, newest);
+
+    Ok(Some(newest.clone()))
+}
+
+/// List `.bak` backups made by unversioned deploys, newest first
+pub fn list_backups(target_dir: &str) -> Result<Vec<PathBuf>, Box<dyn std::error::Error>> {
+    let target = Path::new(target_dir.trim_end_matches('/'));
+    let (Some(parent), Some(name)) = (target.parent(), target.file_name().and_then(|n| n.to_str())) else {
+        return Ok(Vec::new());
+    };
+    let parent = if parent.as_os_str().is_empty() { Path::new(".") } else { parent };
+    if !parent.exists() {
+        return Ok(Vec::new());
+    }
+
+    let prefix = format!("{}.", name);
+    let mut backups = Vec::new();
+    for entry in fs::read_dir(parent)? {
+        let path = entry?.path();
+        let Some(file_name) = path.file_name().and_then(|n| n.to_str()) else {
+            continue;
+        };
+        if file_name.starts_with(&prefix) && file_name.ends_with(".bak") && path.is_dir() {
+            backups.push(path);
+        }
+    }
+
+    // Timestamps are fixed-width, so name order is chronological
+    backups.sort();
+    backups.reverse();
+    Ok(backups)
+}
+
+/// Remove all but the newest `keep` `.bak` backups of an unversioned target,
+/// returning the ones removed
+pub fn prune_backups(target_dir: &str, keep: usize) -> Result<Vec<PathBuf>, Box<dyn std::error::Error>> {
+    let stale: Vec<PathBuf> = list_backups(target_dir)?.into_iter().skip(keep).collect();
+    for backup in &stale {
+        fs::remove_dir_all(backup)?;
+        log::info!("Removed old backup: {:?}", backup);
+    }
+    Ok(stale)
+}
+
+/// Roll an unversioned target back by restoring its newest `.bak` backup
+///
+/// Only the state before the last deploy can be restored; each restore consumes
+/// its backup.
+pub fn restore_backup(target_dir: &str) -> Result<PathBuf, Box<dyn std::error::Error>> {
+    let Some(backup) = list_backups(target_dir)?.into_iter().next() else {
+        return Err("No backup available for rollback: unversioned deploys only keep .bak copies \
+                    of the contents replaced by each deploy"
+            .into());
+    };
+
+    let target = Path::new(target_dir);
+    if target.exists() {
+        fs::remove_dir_all(target)?;
+    }
+    fs::rename(&backup, target)?;
+
+    log::info!("Restored backup: {:?}", backup);
+
+    Ok(backup)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    fn setup_target(versions: &[&str], current: &str) -> PathBuf {
+        let target = std::env::temp_dir().join(format!("postloop-rollback-{}", uuid::Uuid::new_v4()));
+        for version in versions {
+            fs::create_dir_all(target.join(version)).unwrap();
+            fs::write(target.join(version).join("app"), "0123456789").unwrap();
+        }
+        #[cfg(unix)]
+        std::os::unix::fs::symlink(target.join(current), target.join("current")).unwrap();
+        target
+    }
+
     #[test]
     fn test_get_deployed_versions() {
         // Just test that the function doesn't panic
-        let result = get_deployed_versions("/tmp/nonexistent");
+        let result = get_deployed_versions("/tmp/nonexistent", DEFAULT_CURRENT_LINK);
         assert!(result.is_ok());
     }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_detailed_versions_mark_current() {
+        let target = setup_target(&["v1", "v2"], "v1");
+        fs::write(target.join("v1").join(META_FILE), r#"{"commit":"v1"}"#).unwrap();
+
+        let versions = get_deployed_versions_detailed(target.to_str().unwrap(), DEFAULT_CURRENT_LINK).unwrap();
+        assert_eq!(versions.len(), 2);
+        for version in &versions {
+            assert_eq!(version.is_current, version.name == "v1");
+            assert_eq!(version.metadata.is_some(), version.name == "v1");
+        }
+
+        // Files from older releases carry only some fields
+        let meta = read_version_meta(target.to_str().unwrap(), "v1").unwrap();
+        assert_eq!(meta.commit.as_deref(), Some("v1"));
+        assert_eq!(meta.sequence, None);
+        assert!(meta.checksums.is_empty());
+        assert_eq!(read_version_meta(target.to_str().unwrap(), "v2"), None);
+        fs::remove_dir_all(&target).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_format_version_list() {
+        let repo = crate::test_support::init_repo();
+        let commit = crate::test_support::commit_file(&repo, "app.rs");
+        let short: String = commit.chars().take(7).collect();
+        let target = setup_target(&[short.as_str()], &short);
+        let repo_str = repo.to_str().unwrap();
+
+        let versions = get_deployed_versions_detailed(target.to_str().unwrap(), DEFAULT_CURRENT_LINK).unwrap();
+        let lines = format_version_list(&versions, |commit| crate::hook::get_commit_subject(repo_str, &Default::default(), commit).ok());
+        assert_eq!(lines.len(), 1);
+        assert!(lines[0].starts_with("*  1) "));
+        assert!(lines[0].ends_with("app.rs"));
+        fs::remove_dir_all(&target).unwrap();
+        fs::remove_dir_all(&repo).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_dangling_current_symlink() {
+        let target = setup_target(&["v1", "v2", "v3"], "v3");
+        let target_str = target.to_str().unwrap();
+        fs::remove_dir_all(target.join("v3")).unwrap();
+
+        assert_eq!(get_deployed_versions(target_str, DEFAULT_CURRENT_LINK).unwrap().len(), 2);
+        assert_eq!(current_link_state(target_str, DEFAULT_CURRENT_LINK), CurrentLink::Broken("v3".to_string()));
+        assert_eq!(
+            current_link_state(target_str, DEFAULT_CURRENT_LINK).to_string(),
+            "current symlink is broken (points to missing v3)"
+        );
+
+        let repaired = repair_current(target_str, DEFAULT_CURRENT_LINK).unwrap().unwrap();
+        assert_eq!(current_link_state(target_str, DEFAULT_CURRENT_LINK), CurrentLink::Valid(repaired));
+        assert_eq!(repair_current(target_str, DEFAULT_CURRENT_LINK).unwrap(), None);
+
+        // Rolling back over a dangling link replaces it instead of failing
+        fs::remove_dir_all(target.join("v2")).unwrap();
+        fs::remove_dir_all(target.join("v1")).unwrap();
+        fs::create_dir_all(target.join("v4")).unwrap();
+        rollback_to_version(target_str, DEFAULT_CURRENT_LINK, "v4").unwrap();
+        assert_eq!(current_version(target_str, DEFAULT_CURRENT_LINK).as_deref(), Some("v4"));
+        fs::remove_dir_all(&target).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_prune_versions_keeps_current() {
+        let target = setup_target(&["v1", "v2", "v3"], "v2");
+        let target_str = target.to_str().unwrap();
+
+        let dry = prune_versions(target_str, DEFAULT_CURRENT_LINK, 0, true).unwrap();
+        assert_eq!(dry.removed.len(), 2);
+        assert_eq!(dry.freed_bytes, 20);
+        assert_eq!(get_deployed_versions(target_str, DEFAULT_CURRENT_LINK).unwrap().len(), 3);
+
+        let report = prune_versions(target_str, DEFAULT_CURRENT_LINK, 0, false).unwrap();
+        assert!(!report.removed.contains(&"v2".to_string()));
+        assert_eq!(get_deployed_versions(target_str, DEFAULT_CURRENT_LINK).unwrap(), vec!["v2"]);
+        assert_eq!(current_version(target_str, DEFAULT_CURRENT_LINK).as_deref(), Some("v2"));
+        fs::remove_dir_all(&target).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_archive_old_versions_and_restore() {
+        let target = std::env::temp_dir().join(format!("postloop-archive-{}", uuid::Uuid::new_v4()));
+        for version in ["v1", "v2", "v3"] {
+            fs::create_dir_all(target.join(version).join("static")).unwrap();
+            fs::write(target.join(version).join("static/app.js"), version).unwrap();
+            std::thread::sleep(std::time::Duration::from_millis(20));
+        }
+        std::os::unix::fs::symlink(target.join("v3"), target.join("current")).unwrap();
+        let target_str = target.to_str().unwrap();
+
+        cleanup_old_versions(target_str, DEFAULT_CURRENT_LINK, 1, true).unwrap();
+        assert_eq!(get_deployed_versions(target_str, DEFAULT_CURRENT_LINK).unwrap(), vec!["v3"]);
+        assert!(archive_path(target_str, "v1").is_file());
+        assert!(archive_path(target_str, "v2").is_file());
+
+        let result = rollback_to_version(target_str, DEFAULT_CURRENT_LINK, "v1").unwrap();
+        assert_eq!(result.from.as_deref(), Some("v3"));
+        assert_eq!(fs::read_to_string(target.join("current/static/app.js")).unwrap(), "v1");
+        assert!(!archive_path(target_str, "v1").exists());
+        assert!(rollback_to_version(target_str, DEFAULT_CURRENT_LINK, "v9").is_err());
+
+        // A truncated archive leaves neither a partial version nor staging behind
+        let archive = fs::read(archive_path(target_str, "v2")).unwrap();
+        fs::write(archive_path(target_str, "v2"), &archive[..archive.len() / 2]).unwrap();
+        assert!(rollback_to_version(target_str, DEFAULT_CURRENT_LINK, "v2").is_err());
+        assert!(!target.join("v2").exists());
+        assert!(!target.join(".v2.staging").exists());
+        assert!(archive_path(target_str, "v2").is_file());
+        assert_eq!(fs::read_to_string(target.join("current/static/app.js")).unwrap(), "v1");
+        fs::remove_dir_all(&target).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_rollback_replaces_plain_current_directory_and_dangling_link() {
+        let target = std::env::temp_dir().join(format!("postloop-current-{}", uuid::Uuid::new_v4()));
+        let target_str = target.to_str().unwrap();
+        for version in ["v1", "v2"] {
+            fs::create_dir_all(target.join(version)).unwrap();
+            std::thread::sleep(std::time::Duration::from_millis(20));
+        }
+
+        // A manual deploy left a copied directory as 'current'
+        fs::create_dir_all(target.join("current/assets")).unwrap();
+        fs::write(target.join("current/assets/app.js"), "manual").unwrap();
+        let result = rollback_to_previous(target_str, DEFAULT_CURRENT_LINK).unwrap();
+        assert_eq!(result.to, "v1");
+        assert_eq!(fs::read_link(target.join("current")).unwrap(), target.join("v1"));
+        assert!(!uses_pointer_file(target_str));
+
+        // ... or a plain file
+        fs::remove_file(target.join("current")).unwrap();
+        fs::write(target.join("current"), "v2").unwrap();
+        rollback_to_version(target_str, DEFAULT_CURRENT_LINK, "v2").unwrap();
+        assert_eq!(current_version(target_str, DEFAULT_CURRENT_LINK).as_deref(), Some("v2"));
+
+        // 'current' points at a version that was deleted
+        fs::create_dir_all(target.join("v3")).unwrap();
+        switch_current(target_str, DEFAULT_CURRENT_LINK, &format!("{}/v3", target_str)).unwrap();
+        fs::remove_dir_all(target.join("v3")).unwrap();
+        rollback_to_version(target_str, DEFAULT_CURRENT_LINK, "v1").unwrap();
+        assert_eq!(current_version(target_str, DEFAULT_CURRENT_LINK).as_deref(), Some("v1"));
+        assert!(!uses_pointer_file(target_str));
+        fs::remove_dir_all(&target).unwrap();
+    }
+
+    #[test]
+    fn test_pointer_file_mode() {
+        let target = std::env::temp_dir().join(format!("postloop-pointer-{}", uuid::Uuid::new_v4()));
+        for version in ["v1", "v2", "v3"] {
+            fs::create_dir_all(target.join(version)).unwrap();
+            std::thread::sleep(std::time::Duration::from_millis(20));
+        }
+        let target_str = target.to_str().unwrap();
+
+        write_current_pointer(target_str, DEFAULT_CURRENT_LINK, &format!("{}/v3", target_str)).unwrap();
+        assert!(uses_pointer_file(target_str));
+        assert!(!target.join("current").exists());
+        assert_eq!(current_version(target_str, DEFAULT_CURRENT_LINK).as_deref(), Some("v3"));
+        assert_eq!(current_link_state(target_str, DEFAULT_CURRENT_LINK), CurrentLink::Valid("v3".to_string()));
+
+        let result = rollback_to_previous(target_str, DEFAULT_CURRENT_LINK).unwrap();
+        assert_eq!(result.from.as_deref(), Some("v3"));
+        assert_eq!(result.to, "v2");
+        assert_eq!(fs::read_to_string(target.join(POINTER_FILE)).unwrap(), "v2\n");
+        assert!(fs::symlink_metadata(target.join("current")).is_err());
+
+        // Pruning never removes the version the pointer names
+        let report = prune_versions(target_str, DEFAULT_CURRENT_LINK, 1, false).unwrap();
+        assert_eq!(report.removed, vec!["v1".to_string()]);
+        assert!(target.join("v2").is_dir());
+
+        fs::remove_dir_all(target.join("v2")).unwrap();
+        assert_eq!(current_link_state(target_str, DEFAULT_CURRENT_LINK), CurrentLink::Broken("v2".to_string()));
+        fs::remove_dir_all(&target).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_size_quota_removes_oldest_but_keeps_current() {
+        let target = std::env::temp_dir().join(format!("postloop-quota-{}", uuid::Uuid::new_v4()));
+        for version in ["v1", "v2", "v3", "v4"] {
+            fs::create_dir_all(target.join(version)).unwrap();
+            fs::write(target.join(version).join("app"), "0123456789").unwrap();
+            std::thread::sleep(std::time::Duration::from_millis(20));
+        }
+        std::os::unix::fs::symlink(target.join("v1"), target.join("current")).unwrap();
+        let target_str = target.to_str().unwrap();
+
+        let report = enforce_size_quota(target_str, DEFAULT_CURRENT_LINK, 25).unwrap();
+        assert_eq!(report.removed, vec!["v2", "v3"]);
+        assert_eq!(report.freed_bytes, 20);
+        assert_eq!(get_deployed_versions(target_str, DEFAULT_CURRENT_LINK).unwrap(), vec!["v4", "v1"]);
+
+        // Within quota: nothing to do
+        assert!(enforce_size_quota(target_str, DEFAULT_CURRENT_LINK, 25).unwrap().removed.is_empty());
+        fs::remove_dir_all(&target).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_size_quota_counts_hardlinked_files_once() {
+        let target = std::env::temp_dir().join(format!("postloop-quota-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(target.join("v1")).unwrap();
+        fs::write(target.join("v1/shared"), "0123456789").unwrap();
+        fs::write(target.join("v1/own"), "0123456789").unwrap();
+        for version in ["v2", "v3"] {
+            std::thread::sleep(std::time::Duration::from_millis(20));
+            fs::create_dir_all(target.join(version)).unwrap();
+            fs::hard_link(target.join("v1/shared"), target.join(version).join("shared")).unwrap();
+        }
+        std::os::unix::fs::symlink(target.join("v3"), target.join("current")).unwrap();
+        let target_str = target.to_str().unwrap();
+
+        // 20 bytes on disk, although the versions list 40
+        assert!(enforce_size_quota(target_str, DEFAULT_CURRENT_LINK, 20).unwrap().removed.is_empty());
+
+        // Only v1's own file is freed; the shared one lives on in v2 and v3
+        let report = enforce_size_quota(target_str, DEFAULT_CURRENT_LINK, 15).unwrap();
+        assert_eq!(report.removed, vec!["v1"]);
+        assert_eq!(report.freed_bytes, 10);
+        fs::remove_dir_all(&target).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_plan_rollback_leaves_current_alone() {
+        let target = setup_target(&["v1", "v2", "v3"], "v3");
+        let target_str = target.to_str().unwrap();
+        let link_before = fs::read_link(target.join("current")).unwrap();
+
+        let plan = plan_rollback(target_str, DEFAULT_CURRENT_LINK, None).unwrap();
+        assert_eq!(plan.from.as_deref(), Some("v3"));
+        assert_eq!(plan.to, get_deployed_versions(target_str, DEFAULT_CURRENT_LINK).unwrap()[1]);
+        assert_eq!(plan_rollback(target_str, DEFAULT_CURRENT_LINK, Some("v1")).unwrap().to, "v1");
+        assert!(plan_rollback(target_str, DEFAULT_CURRENT_LINK, Some("v9")).unwrap_err().to_string().contains("Version not found"));
+
+        assert_eq!(fs::read_link(target.join("current")).unwrap(), link_before);
+        assert!(read_redo_stack(target_str).is_empty());
+        fs::remove_dir_all(&target).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_rollback_then_redo() {
+        let target = setup_target(&["v1", "v2", "v3"], "v3");
+        let target_str = target.to_str().unwrap();
+
+        let first = rollback_to_version(target_str, DEFAULT_CURRENT_LINK, "v2").unwrap();
+        assert_eq!(
+            first,
+            RollbackResult {
+                from: Some("v3".to_string()),
+                to: "v2".to_string(),
+                target_dir: target_str.to_string(),
+            }
+        );
+        let second = rollback_to_version(target_str, DEFAULT_CURRENT_LINK, "v1").unwrap();
+        assert_eq!(second.from.as_deref(), Some("v2"));
+        assert_eq!(current_version(target_str, DEFAULT_CURRENT_LINK).as_deref(), Some("v1"));
+
+        assert_eq!(redo_rollback(target_str, DEFAULT_CURRENT_LINK).unwrap(), "v2");
+        assert_eq!(redo_rollback(target_str, DEFAULT_CURRENT_LINK).unwrap(), "v3");
+        assert_eq!(current_version(target_str, DEFAULT_CURRENT_LINK).as_deref(), Some("v3"));
+        assert!(redo_rollback(target_str, DEFAULT_CURRENT_LINK).is_err());
+        fs::remove_dir_all(&target).unwrap();
+    }
 }
@@ -0,0 +1,381 @@
+//! Child process tracking for graceful aborts
+//!
+//! Build and deploy commands are spawned through [`run_tracked`], which keeps
+//! running children in a shared list. The handler installed by
+//! [`install_signal_handlers`] kills them (with everything they started) on
+//! the first SIGINT/SIGTERM so the caller unwinds with an "aborted by
+//! signal" error; a second signal exits immediately.
+
+use std::cell::{Cell, RefCell};
+use std::fs::{self, OpenOptions};
+use std::io::{Read, Write};
+use std::path::PathBuf;
+use std::process::{Child, Command, Output, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+pub const ABORTED: &str = "Aborted by signal";
+pub const TIMED_OUT: &str = "Pipeline timed out";
+
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+static ACTIVE_CHILDREN: Mutex<Vec<Child>> = Mutex::new(Vec::new());
+static SIGNAL_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    /// Wall-clock budget of the run on this thread, see [`set_deadline`]
+    static DEADLINE: Cell<Option<Instant>> = const { Cell::new(None) };
+    /// Variables for configured commands on this thread, see [`set_command_env`]
+    static COMMAND_ENV: RefCell<Vec<(String, String)>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Install SIGINT/SIGTERM handlers that abort the active child process
+pub fn install_signal_handlers() -> Result<(), Box<dyn std::error::Error>> {
+    ctrlc::set_handler(|| {
+        if SIGNAL_COUNT.fetch_add(1, Ordering::SeqCst) > 0 {
+            eprintln!("Received second signal, exiting immediately");
+            crate::logger::flush_run();
+            std::process::exit(130);
+        }
+
+        log::warn!("{}, stopping active command", ABORTED);
+        eprintln!("{}, stopping active command (signal again to force exit)", ABORTED);
+        for child in active_children().iter_mut() {
+            kill_tree(child);
+        }
+    })?;
+    Ok(())
+}
+
+/// Turn a configured command line into a [`Command`]
+///
+/// By default the line is split on whitespace with no quoting rules. With
+/// `use_shell` it is passed whole to `sh -c` (`cmd /C` on Windows), so pipes,
+/// `&&` and quotes work. Returns `None` for an empty line.
+pub fn shell_command(command_line: &str, use_shell: bool) -> Option<Command> {
+    if command_line.trim().is_empty() {
+        return None;
+    }
+
+    if use_shell {
+        #[cfg(windows)]
+        let (shell, flag) = ("cmd", "/C");
+        #[cfg(not(windows))]
+        let (shell, flag) = ("sh", "-c");
+
+        let mut command = Command::new(shell);
+        command.args([flag, command_line]);
+        apply_command_env(&mut command);
+        return Some(command);
+    }
+
+    let mut parts = command_line.split_whitespace();
+    let mut command = Command::new(parts.next()?);
+    command.args(parts);
+    apply_command_env(&mut command);
+    Some(command)
+}
+
+/// Run a configured command line in `working_dir` with extra environment
+/// variables, capturing its output (see [`shell_command`] and [`run_tracked`])
+///
+/// A non-zero exit is not an error here; callers inspect the status.
+pub fn run_command_line(
+    command_line: &str,
+    use_shell: bool,
+    working_dir: &str,
+    env: &[(&str, &str)],
+) -> Result<Output, Box<dyn std::error::Error>> {
+    let mut process = shell_command(command_line, use_shell).ok_or("Command is empty")?;
+    process.current_dir(working_dir).envs(env.iter().copied());
+    run_tracked(&mut process)
+}
+
+/// Run a multi-line script from the config (e.g. `deploy.pre_deploy`) in
+/// `working_dir`, capturing its output
+///
+/// The script is written to a temporary file only the current user can read
+/// and run, executed with `sh` (`cmd /C` on Windows), and removed afterwards
+/// whether or not it succeeded. A non-zero exit is not an error here.
+pub fn run_inline_script(
+    script: &str,
+    working_dir: &str,
+    env: &[(&str, &str)],
+) -> Result<Output, Box<dyn std::error::Error>> {
+    #[cfg(windows)]
+    let (shell, flag, extension) = ("cmd", Some("/C"), "cmd");
+    #[cfg(not(windows))]
+    let (shell, flag, extension) = ("sh", None::<&str>, "sh");
+
+    let script_file = TempScript::create(script, extension)?;
+    let mut process = Command::new(shell);
+    apply_command_env(&mut process);
+    process
+        .args(flag)
+        .arg(&script_file.0)
+        .current_dir(working_dir)
+        .envs(env.iter().copied());
+    run_tracked(&mut process)
+}
+
+/// A script written to the temp directory, deleted on drop
+struct TempScript(PathBuf);
+
+impl TempScript {
+    fn create(script: &str, extension: &str) -> std::io::Result<Self> {
+        let path = std::env::temp_dir().join(format!("ploop-script-{}.{}", uuid::Uuid::new_v4(), extension));
+        let mut options = OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o700);
+
+        let mut file = options.open(&path)?;
+        let script_file = TempScript(path);
+        file.write_all(script.as_bytes())?;
+        Ok(script_file)
+    }
+}
+
+impl Drop for TempScript {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+/// Give work on the current thread a wall-clock deadline, or clear it with `None`
+///
+/// Once the deadline passes, the active child is killed and everything that
+/// checks [`is_aborted`] unwinds with a [`TIMED_OUT`] error. The deadline is
+/// per thread so concurrent runs (and tests) keep their own budgets.
+pub fn set_deadline(deadline: Option<Instant>) {
+    DEADLINE.with(|current| current.set(deadline));
+}
+
+/// The current thread's deadline, for handing on to worker threads
+pub fn deadline() -> Option<Instant> {
+    DEADLINE.with(|current| current.get())
+}
+
+/// Set `name` for every configured command (see [`shell_command`] and
+/// [`run_inline_script`]) later spawned on this thread, e.g. `PLOOP_COMMIT`
+///
+/// Like the deadline, the variables are per thread; variables passed to a
+/// single command take precedence.
+pub fn set_command_env(name: &str, value: &str) {
+    COMMAND_ENV.with(|env| {
+        let mut env = env.borrow_mut();
+        env.retain(|(existing, _)| existing != name);
+        env.push((name.to_string(), value.to_string()));
+    });
+}
+
+/// Drop every variable set with [`set_command_env`] on this thread
+pub fn clear_command_env() {
+    COMMAND_ENV.with(|env| env.borrow_mut().clear());
+}
+
+fn apply_command_env(process: &mut Command) {
+    COMMAND_ENV.with(|env| {
+        process.envs(env.borrow().iter().map(|(name, value)| (name, value)));
+    });
+}
+
+fn deadline_passed() -> bool {
+    deadline().is_some_and(|deadline| Instant::now() >= deadline)
+}
+
+/// Check if an abort signal has been received or the thread's deadline has passed
+pub fn is_aborted() -> bool {
+    SIGNAL_COUNT.load(Ordering::SeqCst) > 0 || deadline_passed()
+}
+
+/// The error to unwind with once [`is_aborted`] is true
+pub fn abort_error() -> Box<dyn std::error::Error> {
+    if SIGNAL_COUNT.load(Ordering::SeqCst) > 0 {
+        ABORTED.into()
+    } else {
+        TIMED_OUT.into()
+    }
+}
+
+/// Run a command to completion, capturing its output, while tracking it as the active child
+///
+/// The command gets no stdin: it runs in its own process group, where
+/// reading from the terminal would stop it.
+pub fn run_tracked(command: &mut Command) -> Result<Output, Box<dyn std::error::Error>> {
+    if is_aborted() {
+        return Err(abort_error());
+    }
+
+    // Its own process group, so a kill reaches the shell's children too
+    #[cfg(unix)]
+    std::os::unix::process::CommandExt::process_group(command, 0);
+    let mut child = command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    let stdout = read_in_background(child.stdout.take());
+    let stderr = read_in_background(child.stderr.take());
+    let pid = child.id();
+    active_children().push(child);
+
+    // Quick commands (most git calls) finish long before the first full interval
+    let mut interval = Duration::from_millis(1);
+    let status = loop {
+        let mut children = active_children();
+        let index = children
+            .iter()
+            .position(|child| child.id() == pid)
+            .ok_or("Active child process went missing")?;
+        match children[index].try_wait() {
+            Ok(Some(status)) => {
+                // Already reaped by try_wait, so this only returns the cached status
+                let _ = children.swap_remove(index).wait();
+                break status;
+            }
+            Ok(None) if deadline_passed() => {
+                let mut child = children.swap_remove(index);
+                kill_tree(&mut child);
+                let _ = child.wait();
+                return Err(TIMED_OUT.into());
+            }
+            Ok(None) => {}
+            Err(e) => {
+                let mut child = children.swap_remove(index);
+                kill_tree(&mut child);
+                let _ = child.wait();
+                return Err(e.into());
+            }
+        }
+        drop(children);
+        thread::sleep(interval);
+        interval = (interval * 2).min(POLL_INTERVAL);
+    };
+
+    let output = Output {
+        status,
+        stdout: stdout.join().unwrap_or_default(),
+        stderr: stderr.join().unwrap_or_default(),
+    };
+
+    if is_aborted() {
+        return Err(abort_error());
+    }
+
+    Ok(output)
+}
+
+/// Kill a tracked child along with the processes it started
+///
+/// Tracked children lead their own process group on Unix, so the whole group
+/// is killed; otherwise a shell's grandchildren would keep running and hold
+/// the output pipes open.
+fn kill_tree(child: &mut Child) {
+    #[cfg(unix)]
+    if let Ok(group) = libc::pid_t::try_from(child.id()) {
+        // SAFETY: killpg only sends a signal; the group was created by run_tracked
+        unsafe {
+            libc::killpg(group, libc::SIGKILL);
+        }
+    }
+    let _ = child.kill();
+}
+
+fn active_children() -> MutexGuard<'static, Vec<Child>> {
+    ACTIVE_CHILDREN.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+fn read_in_background<R: Read + Send + 'static>(source: Option<R>) -> JoinHandle<Vec<u8>> {
+    thread::spawn(move || {
+        let mut buffer = Vec::new();
+        if let Some(mut source) = source {
+            let _ = source.read_to_end(&mut buffer);
+        }
+        buffer
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn test_inline_script_runs_and_is_removed() {
+        let dir = std::env::temp_dir();
+        let script = "set -e\nGREETING=hello\necho \"$GREETING $PLOOP_COMMIT\"\nbasename \"$0\"\n";
+        let output = run_inline_script(script, dir.to_str().unwrap(), &[("PLOOP_COMMIT", "abc1234")]).unwrap();
+        assert!(output.status.success());
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let mut lines = stdout.lines();
+        assert_eq!(lines.next(), Some("hello abc1234"));
+        let script_name = lines.next().unwrap();
+        assert!(!dir.join(script_name).exists());
+
+        let output = run_inline_script("echo partial\nexit 3\n", dir.to_str().unwrap(), &[]).unwrap();
+        assert_eq!(output.status.code(), Some(3));
+    }
+
+    #[test]
+    fn test_run_tracked_captures_output() {
+        let output = run_tracked(Command::new("echo").arg("tracked")).unwrap();
+        assert!(output.status.success());
+        assert_eq!(String::from_utf8_lossy(&output.stdout).trim(), "tracked");
+    }
+
+    /// A shell that starts a grandchild which would write `marker` a second later
+    #[cfg(unix)]
+    fn shell_with_grandchild(dir: &std::path::Path) -> Command {
+        let mut command = Command::new("sh");
+        command
+            .args(["-c", "echo $$ > pid; (sleep 1; echo late > marker) & wait"])
+            .current_dir(dir);
+        command
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_abort_kills_grandchildren() {
+        let dir = crate::test_support::temp_dir("abort-group");
+        let worker_dir = dir.clone();
+        let started = Instant::now();
+        let worker = thread::spawn(move || run_tracked(&mut shell_with_grandchild(&worker_dir)).map_err(|e| e.to_string()));
+
+        let pid: u32 = loop {
+            if let Some(pid) = fs::read_to_string(dir.join("pid")).ok().and_then(|pid| pid.trim().parse().ok()) {
+                break pid;
+            }
+            thread::sleep(Duration::from_millis(10));
+        };
+        // What the signal handler does, limited to this test's child
+        if let Some(child) = active_children().iter_mut().find(|child| child.id() == pid) {
+            kill_tree(child);
+        }
+
+        let output = worker.join().unwrap().unwrap();
+        assert!(!output.status.success());
+        assert!(started.elapsed() < Duration::from_secs(1), "{:?}", started.elapsed());
+        thread::sleep(Duration::from_millis(1500));
+        assert!(!dir.join("marker").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_timeout_kills_grandchildren() {
+        let dir = crate::test_support::temp_dir("timeout-group");
+        let started = Instant::now();
+        set_deadline(Some(Instant::now() + Duration::from_millis(200)));
+        let err = run_tracked(&mut shell_with_grandchild(&dir)).unwrap_err();
+        set_deadline(None);
+
+        assert_eq!(err.to_string(), TIMED_OUT);
+        assert!(started.elapsed() < Duration::from_secs(1), "{:?}", started.elapsed());
+        thread::sleep(Duration::from_millis(1500));
+        assert!(!dir.join("marker").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
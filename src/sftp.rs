@@ -0,0 +1,438 @@
+use crate::builder;
+use crate::config::{ArtifactSpec, SftpConfig};
+use crate::rollback::{VersionMeta, META_FILE, POINTER_FILE};
+use ssh2::{Session, Sftp};
+use std::fs;
+use std::io::Read;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// The remote file operations deploys and rollbacks need, so they can run
+/// against something other than a live SFTP session in tests
+trait RemoteFs {
+    /// Names of the directories in `dir`, with their modification times
+    fn list_dirs(&self, dir: &Path) -> Result<Vec<(String, u64)>, Box<dyn std::error::Error>>;
+    /// Whether anything (a dangling symlink included) exists at `path`
+    fn exists(&self, path: &Path) -> bool;
+    fn read_link(&self, path: &Path) -> Result<PathBuf, Box<dyn std::error::Error>>;
+    fn read_file(&self, path: &Path) -> Result<Vec<u8>, Box<dyn std::error::Error>>;
+    /// Create or truncate `path` and fill it from `content`
+    fn write_file(&self, path: &Path, content: &mut dyn Read) -> Result<(), Box<dyn std::error::Error>>;
+    fn mkdir(&self, path: &Path) -> Result<(), Box<dyn std::error::Error>>;
+    fn remove_file(&self, path: &Path) -> Result<(), Box<dyn std::error::Error>>;
+    fn symlink(&self, target: &Path, link: &Path) -> Result<(), Box<dyn std::error::Error>>;
+    fn rename(&self, from: &Path, to: &Path) -> Result<(), Box<dyn std::error::Error>>;
+}
+
+impl RemoteFs for Sftp {
+    fn list_dirs(&self, dir: &Path) -> Result<Vec<(String, u64)>, Box<dyn std::error::Error>> {
+        Ok(self
+            .readdir(dir)?
+            .into_iter()
+            .filter(|(_, stat)| stat.is_dir())
+            .filter_map(|(path, stat)| Some((path.file_name()?.to_str()?.to_string(), stat.mtime.unwrap_or(0))))
+            .collect())
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        self.lstat(path).is_ok()
+    }
+
+    fn read_link(&self, path: &Path) -> Result<PathBuf, Box<dyn std::error::Error>> {
+        Ok(self.readlink(path)?)
+    }
+
+    fn read_file(&self, path: &Path) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let mut content = Vec::new();
+        self.open(path)?.read_to_end(&mut content)?;
+        Ok(content)
+    }
+
+    fn write_file(&self, path: &Path, content: &mut dyn Read) -> Result<(), Box<dyn std::error::Error>> {
+        std::io::copy(content, &mut self.create(path)?)?;
+        Ok(())
+    }
+
+    fn mkdir(&self, path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+        Ok(Sftp::mkdir(self, path, 0o755)?)
+    }
+
+    fn remove_file(&self, path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+        Ok(self.unlink(path)?)
+    }
+
+    fn symlink(&self, target: &Path, link: &Path) -> Result<(), Box<dyn std::error::Error>> {
+        Ok(Sftp::symlink(self, target, link)?)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> Result<(), Box<dyn std::error::Error>> {
+        Ok(Sftp::rename(self, from, to, None)?)
+    }
+}
+
+/// A remote version directory's name and its metadata, if it has any
+type RemoteVersion = (String, Option<VersionMeta>);
+
+/// Open an authenticated SFTP session to the configured host
+///
+/// Key auth is tried first (when `key_path` is set), then the password (when
+/// set), then the SSH agent; the first to succeed is used.
+pub fn connect(config: &SftpConfig) -> Result<Sftp, Box<dyn std::error::Error>> {
+    let endpoint = format!("{}:{}", config.host, config.port);
+    let timeout = Duration::from_secs(config.timeout_secs);
+    let connect_error = |e: &dyn std::fmt::Display| format!("Cannot connect to SFTP host {}: {}", endpoint, e);
+
+    let address = endpoint
+        .to_socket_addrs()
+        .map_err(|e| connect_error(&e))?
+        .next()
+        .ok_or_else(|| connect_error(&"no address found"))?;
+    let tcp = TcpStream::connect_timeout(&address, timeout).map_err(|e| connect_error(&e))?;
+    tcp.set_read_timeout(Some(timeout))?;
+    tcp.set_write_timeout(Some(timeout))?;
+
+    let mut session = Session::new()?;
+    session.set_tcp_stream(tcp);
+    session.set_timeout(timeout.as_millis().try_into().unwrap_or(u32::MAX));
+    session.handshake().map_err(|e| connect_error(&e))?;
+
+    let mut failures = Vec::new();
+    if let Some(key_path) = &config.key_path {
+        if let Err(e) = session.userauth_pubkey_file(&config.user, None, Path::new(key_path), None) {
+            failures.push(format!("key {}: {}", key_path, e));
+        }
+    }
+    if !session.authenticated() {
+        if let Some(password) = &config.password {
+            if let Err(e) = session.userauth_password(&config.user, password) {
+                failures.push(format!("password: {}", e));
+            }
+        }
+    }
+    if !session.authenticated() {
+        if let Err(e) = session.userauth_agent(&config.user) {
+            failures.push(format!("agent: {}", e));
+        }
+    }
+    if !session.authenticated() {
+        return Err(format!("SFTP authentication as {} on {} failed ({})", config.user, endpoint, failures.join("; ")).into());
+    }
+
+    Ok(session.sftp()?)
+}
+
+/// Upload artifacts into `{remote_dir}/{version}`, record its metadata and
+/// point the remote `link_name` symlink at it
+pub fn deploy_with_sftp(
+    artifacts: &[ArtifactSpec],
+    config: &SftpConfig,
+    artifact_base: &str,
+    version: &str,
+    link_name: &str,
+    commit_hash: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    log::info!("Starting SFTP deployment to {}:{}", config.host, config.remote_dir);
+
+    let resolved = builder::resolve_artifacts(artifacts, artifact_base)?;
+    let sftp = connect(config)?;
+    deploy_to(&sftp, &resolved, config, version, link_name, commit_hash)
+}
+
+fn deploy_to(
+    remote: &dyn RemoteFs,
+    resolved: &[builder::ResolvedArtifact],
+    config: &SftpConfig,
+    version: &str,
+    link_name: &str,
+    commit_hash: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let remote_dir = Path::new(&config.remote_dir);
+    let version_dir = remote_dir.join(version);
+    mkdir_all(remote, &version_dir)?;
+
+    for artifact in resolved {
+        upload(remote, &artifact.path, &version_dir.join(artifact.dest_name()?))?;
+        log::info!("Uploaded artifact: {:?}", artifact.path);
+    }
+
+    // Rollbacks order versions by this sequence, not by the server's mtimes
+    let sequence = remote_versions(remote, remote_dir, link_name)?
+        .iter()
+        .filter(|(name, _)| name != version)
+        .filter_map(|(_, meta)| meta.as_ref()?.sequence)
+        .max()
+        .unwrap_or(0)
+        + 1;
+    let meta = VersionMeta {
+        commit: Some(commit_hash.to_string()),
+        deployed_at: Some(chrono::Local::now().to_rfc3339()),
+        sequence: Some(sequence),
+        user: Some(crate::history::current_user()),
+        host: Some(crate::history::current_host()),
+        run_id: crate::logger::current_run_id(),
+        ..VersionMeta::default()
+    };
+    remote.write_file(&version_dir.join(META_FILE), &mut serde_json::to_string_pretty(&meta)?.as_bytes())?;
+
+    switch_remote_current(remote, config, link_name, version)?;
+    log::info!("Remote current now points to: {}", version);
+
+    Ok(())
+}
+
+/// Re-point the remote 'current' at the version deployed before the current one
+pub fn rollback_to_previous(config: &SftpConfig, link_name: &str) -> Result<String, Box<dyn std::error::Error>> {
+    let sftp = connect(config)?;
+    rollback_on(&sftp, config, link_name)
+}
+
+fn rollback_on(remote: &dyn RemoteFs, config: &SftpConfig, link_name: &str) -> Result<String, Box<dyn std::error::Error>> {
+    let versions = remote_versions(remote, Path::new(&config.remote_dir), link_name)?;
+    let current = remote_current(remote, config, link_name);
+
+    let previous = versions
+        .into_iter()
+        .map(|(name, _)| name)
+        .find(|version| Some(version) != current.as_ref())
+        .ok_or("No previous remote version available for rollback")?;
+
+    switch_remote_current(remote, config, link_name, &previous)?;
+    log::info!("Rolled back remote to version: {}", previous);
+
+    Ok(previous)
+}
+
+/// Version directories under `remote_dir` with their metadata, newest first
+///
+/// Versions are ordered by the sequence in their metadata; ones deployed
+/// before metadata was written come after those, by modification time.
+fn remote_versions(
+    remote: &dyn RemoteFs,
+    remote_dir: &Path,
+    link_name: &str,
+) -> Result<Vec<RemoteVersion>, Box<dyn std::error::Error>> {
+    let mut versions: Vec<(String, Option<VersionMeta>, u64)> = remote
+        .list_dirs(remote_dir)?
+        .into_iter()
+        .filter(|(name, _)| name != link_name && !name.starts_with('.'))
+        .map(|(name, mtime)| {
+            let meta = remote
+                .read_file(&remote_dir.join(&name).join(META_FILE))
+                .ok()
+                .and_then(|content| serde_json::from_slice::<VersionMeta>(&content).ok());
+            (name, meta, mtime)
+        })
+        .collect();
+
+    versions.sort_by_key(|(_, meta, mtime)| std::cmp::Reverse((meta.as_ref().and_then(|meta| meta.sequence), *mtime)));
+    Ok(versions.into_iter().map(|(name, meta, _)| (name, meta)).collect())
+}
+
+/// Version the remote 'current' symlink (or pointer file) names
+fn remote_current(remote: &dyn RemoteFs, config: &SftpConfig, link_name: &str) -> Option<String> {
+    let remote_dir = Path::new(&config.remote_dir);
+    if let Ok(target) = remote.read_link(&remote_dir.join(link_name)) {
+        return target.file_name()?.to_str().map(|name| name.to_string());
+    }
+
+    let pointer = String::from_utf8(remote.read_file(&remote_dir.join(POINTER_FILE)).ok()?).ok()?;
+    let version = pointer.trim();
+    (!version.is_empty()).then(|| version.to_string())
+}
+
+/// Point the remote 'current' at `version`, falling back to the pointer file
+/// when the server refuses to create symlinks
+fn switch_remote_current(
+    remote: &dyn RemoteFs,
+    config: &SftpConfig,
+    link_name: &str,
+    version: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let remote_dir = Path::new(&config.remote_dir);
+
+    if !config.pointer_file {
+        let current = remote_dir.join(link_name);
+        if remote.exists(&current) {
+            remote.remove_file(&current)?;
+        }
+        // Relative target, so the link stays valid if remote_dir is moved
+        match remote.symlink(Path::new(version), &current) {
+            Ok(()) => return Ok(()),
+            Err(e) => log::warn!("Remote symlink failed ({}), using {} instead", e, POINTER_FILE),
+        }
+    }
+
+    let pointer = remote_dir.join(POINTER_FILE);
+    let temp = remote_dir.join(format!("{}.tmp", POINTER_FILE));
+    remote.write_file(&temp, &mut format!("{}\n", version).as_bytes())?;
+    if remote.exists(&pointer) {
+        remote.remove_file(&pointer)?;
+    }
+    remote.rename(&temp, &pointer)?;
+    Ok(())
+}
+
+fn mkdir_all(remote: &dyn RemoteFs, dir: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    let mut path = PathBuf::new();
+    for component in dir.components() {
+        path.push(component);
+        if !remote.exists(&path) {
+            remote.mkdir(&path)?;
+        }
+    }
+    Ok(())
+}
+
+/// Upload a file or directory tree
+fn upload(remote: &dyn RemoteFs, local: &Path, remote_path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    if local.is_dir() {
+        mkdir_all(remote, remote_path)?;
+        for entry in fs::read_dir(local)? {
+            let entry = entry?;
+            upload(remote, &entry.path(), &remote_path.join(entry.file_name()))?;
+        }
+        return Ok(());
+    }
+
+    remote.write_file(remote_path, &mut fs::File::open(local)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A "remote" kept in a local directory, with every remote path taken
+    /// relative to `root`
+    struct LocalRemote {
+        root: PathBuf,
+        /// Refuse symlinks, as some SFTP servers do
+        no_symlinks: bool,
+    }
+
+    impl LocalRemote {
+        fn local(&self, path: &Path) -> PathBuf {
+            self.root.join(path.strip_prefix("/").unwrap_or(path))
+        }
+    }
+
+    impl RemoteFs for LocalRemote {
+        fn list_dirs(&self, dir: &Path) -> Result<Vec<(String, u64)>, Box<dyn std::error::Error>> {
+            let mut dirs = Vec::new();
+            for entry in fs::read_dir(self.local(dir))? {
+                let entry = entry?;
+                if entry.file_type()?.is_dir() {
+                    dirs.push((entry.file_name().to_string_lossy().into_owned(), 0));
+                }
+            }
+            Ok(dirs)
+        }
+
+        fn exists(&self, path: &Path) -> bool {
+            fs::symlink_metadata(self.local(path)).is_ok()
+        }
+
+        fn read_link(&self, path: &Path) -> Result<PathBuf, Box<dyn std::error::Error>> {
+            Ok(fs::read_link(self.local(path))?)
+        }
+
+        fn read_file(&self, path: &Path) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+            Ok(fs::read(self.local(path))?)
+        }
+
+        fn write_file(&self, path: &Path, content: &mut dyn Read) -> Result<(), Box<dyn std::error::Error>> {
+            std::io::copy(content, &mut fs::File::create(self.local(path))?)?;
+            Ok(())
+        }
+
+        fn mkdir(&self, path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+            Ok(fs::create_dir(self.local(path))?)
+        }
+
+        fn remove_file(&self, path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+            Ok(fs::remove_file(self.local(path))?)
+        }
+
+        fn symlink(&self, target: &Path, link: &Path) -> Result<(), Box<dyn std::error::Error>> {
+            if self.no_symlinks {
+                return Err("permission denied".into());
+            }
+            Ok(std::os::unix::fs::symlink(target, self.local(link))?)
+        }
+
+        fn rename(&self, from: &Path, to: &Path) -> Result<(), Box<dyn std::error::Error>> {
+            Ok(fs::rename(self.local(from), self.local(to))?)
+        }
+    }
+
+    fn sftp_config(remote_dir: &str) -> SftpConfig {
+        SftpConfig {
+            host: "127.0.0.1".to_string(),
+            port: 22,
+            user: "deploy".to_string(),
+            remote_dir: remote_dir.to_string(),
+            key_path: None,
+            password: None,
+            timeout_secs: 2,
+            pointer_file: false,
+        }
+    }
+
+    #[test]
+    fn test_connection_failure_names_host() {
+        // Bind then drop a listener to get a local port nothing listens on
+        let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let config = SftpConfig {
+            port,
+            ..sftp_config("/srv/app")
+        };
+
+        let err = connect(&config).err().unwrap();
+        assert!(err.to_string().contains(&format!("127.0.0.1:{}", port)), "{}", err);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_remote_rollback_follows_deploy_order() {
+        let local = crate::test_support::temp_dir("sftp-local");
+        fs::write(local.join("app"), "build").unwrap();
+        let spec = ArtifactSpec::from("app");
+        let resolved = vec![builder::ResolvedArtifact {
+            path: local.join("app"),
+            spec: &spec,
+        }];
+        let remote = LocalRemote {
+            root: crate::test_support::temp_dir("sftp-remote"),
+            no_symlinks: false,
+        };
+        let config = sftp_config("/srv/app");
+        let link = crate::rollback::DEFAULT_CURRENT_LINK;
+
+        // Names sort (and, on a busy server, mtimes may order) differently
+        // from the order they were deployed in
+        for version in ["ccc", "aaa", "bbb"] {
+            deploy_to(&remote, &resolved, &config, version, link, "0123456789").unwrap();
+        }
+        assert_eq!(remote_current(&remote, &config, link).as_deref(), Some("bbb"));
+        assert_eq!(fs::read_to_string(remote.local(Path::new("/srv/app/bbb/app"))).unwrap(), "build");
+        let order: Vec<String> = remote_versions(&remote, Path::new("/srv/app"), link)
+            .unwrap()
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect();
+        assert_eq!(order, vec!["bbb", "aaa", "ccc"]);
+
+        assert_eq!(rollback_on(&remote, &config, link).unwrap(), "aaa");
+        assert_eq!(remote_current(&remote, &config, link).as_deref(), Some("aaa"));
+
+        // Servers refusing symlinks get the pointer file
+        let refusing = LocalRemote {
+            root: remote.root.clone(),
+            no_symlinks: true,
+        };
+        deploy_to(&refusing, &resolved, &config, "ddd", link, "0123456789").unwrap();
+        assert_eq!(fs::read_to_string(remote.local(Path::new("/srv/app/current.txt"))).unwrap(), "ddd\n");
+        fs::remove_dir_all(&local).unwrap();
+        fs::remove_dir_all(&remote.root).unwrap();
+    }
+}
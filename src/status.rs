@@ -0,0 +1,200 @@
+use crate::config::Config;
+use crate::history::{self, HistoryRecord};
+use crate::pipeline;
+use crate::rollback::{self, DeployedVersion};
+use crate::runner;
+use crate::syncer;
+use serde::Serialize;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// ANSI sequence that clears the terminal and homes the cursor, printed before
+/// each refresh of a watched status
+pub const CLEAR_SCREEN: &str = "\x1b[2J\x1b[H";
+
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// What one deploy target looks like right now
+#[derive(Debug, Clone, Serialize)]
+pub struct TargetStatus {
+    pub name: String,
+    pub target_dir: String,
+    /// Deployed versions, newest first (empty when the directory does not exist)
+    pub versions: Vec<DeployedVersion>,
+    pub last_run: Option<HistoryRecord>,
+    /// Why the target could not be read, e.g. it vanished mid-listing
+    pub error: Option<String>,
+}
+
+/// Snapshot of every deploy target plus the sync state (`ploop status --json`
+/// serializes it as is)
+#[derive(Debug, Clone, Serialize)]
+pub struct Status {
+    pub targets: Vec<TargetStatus>,
+    /// Ahead/behind description, or `None` when sync is disabled
+    pub sync: Option<String>,
+}
+
+/// Gather the status of every configured target
+///
+/// `{branch}`/`{commit}` placeholders are resolved as a run would. Never
+/// fails: targets that are missing or unreadable (or whose directory cannot
+/// be resolved) are reported as such, so a watch loop survives directories
+/// appearing and disappearing.
+pub fn gather(config: &Config) -> Status {
+    let resolved = pipeline::resolve_templates(config);
+    let unresolved = resolved.as_ref().err().map(|e| e.to_string());
+    let config = resolved.as_ref().unwrap_or(config);
+
+    let targets = config
+        .deploy
+        .file_targets()
+        .into_iter()
+        .map(|(name, target_dir)| {
+            let listed = match &unresolved {
+                Some(error) => Err(error.clone()),
+                None => rollback::get_deployed_versions_detailed(target_dir, &config.deploy.current_link_name).map_err(|e| e.to_string()),
+            };
+            let (versions, error) = match listed {
+                Ok(versions) => (versions, None),
+                Err(e) => (Vec::new(), Some(e)),
+            };
+            let last_run = history::recent_records(&config.deploy.state_dir(target_dir), 1)
+                .ok()
+                .and_then(|records| records.into_iter().next());
+            TargetStatus {
+                name: name.to_string(),
+                target_dir: target_dir.to_string(),
+                versions,
+                last_run,
+                error,
+            }
+        })
+        .collect();
+
+    let sync = config.sync.enabled.then(|| {
+        match syncer::ahead_behind(&config.sync.remote, &config.sync.branch, &config.watch.repo_path, &config.watch.git) {
+            Ok(counts) => syncer::describe_ahead_behind(counts),
+            Err(e) => format!("unknown ({})", e),
+        }
+    });
+
+    Status { targets, sync }
+}
+
+/// Format a status snapshot for the terminal
+pub fn format_status(status: &Status) -> Vec<String> {
+    let mut lines = Vec::new();
+    for target in &status.targets {
+        lines.push(format!("{} ({})", target.name, target.target_dir));
+        if let Some(error) = &target.error {
+            lines.push(format!("  unreadable: {}", error));
+        } else if target.versions.is_empty() {
+            lines.push("  no versions deployed".to_string());
+        }
+        lines.extend(
+            rollback::format_version_list(&target.versions, |_| None)
+                .into_iter()
+                .map(|line| format!("  {}", line)),
+        );
+        if let Some(run) = &target.last_run {
+            let by = match (&run.user, &run.host) {
+                (Some(user), Some(host)) => format!(" by {}@{}", user, host),
+                (Some(user), None) => format!(" by {}", user),
+                _ => String::new(),
+            };
+            lines.push(format!("  last run: {} {} at {}{}", run.outcome, run.commit, run.timestamp, by));
+        }
+    }
+    if let Some(sync) = &status.sync {
+        lines.push(format!("sync: {}", sync));
+    }
+    lines
+}
+
+/// Re-gather the status every `interval` and hand it to `render` until
+/// `render` returns false or an abort signal (Ctrl-C) arrives
+///
+/// Install [`runner::install_signal_handlers`] first for Ctrl-C to stop the loop.
+pub fn watch(config: &Config, interval: Duration, mut render: impl FnMut(&Status) -> bool) {
+    while !runner::is_aborted() {
+        if !render(&gather(config)) {
+            return;
+        }
+
+        let next = Instant::now() + interval;
+        while Instant::now() < next {
+            if runner::is_aborted() {
+                return;
+            }
+            thread::sleep(POLL_INTERVAL.min(next.saturating_duration_since(Instant::now())));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_watch_survives_target_appearing_and_disappearing() {
+        let target = std::env::temp_dir().join(format!("postloop-status-{}", uuid::Uuid::new_v4()));
+        let mut config = Config::default();
+        config.deploy.target_dir = Some(target.to_str().unwrap().to_string());
+        config.sync.enabled = false;
+
+        let mut snapshots = Vec::new();
+        watch(&config, Duration::from_millis(10), |status| {
+            snapshots.push(format_status(status));
+            match snapshots.len() {
+                1 => fs::create_dir_all(target.join("abc1234")).unwrap(),
+                2 => fs::remove_dir_all(&target).unwrap(),
+                _ => {}
+            }
+            snapshots.len() < 3
+        });
+
+        assert_eq!(snapshots.len(), 3);
+        assert!(snapshots[0].contains(&"  no versions deployed".to_string()));
+        assert!(snapshots[1].iter().any(|line| line.contains("abc1234")));
+        assert_eq!(snapshots[2], snapshots[0]);
+    }
+
+    #[test]
+    fn test_status_json_includes_deployer() {
+        let target = std::env::temp_dir().join(format!("postloop-status-{}", uuid::Uuid::new_v4()));
+        let target_str = target.to_str().unwrap();
+        let mut config = Config::default();
+        config.deploy.target_dir = Some(target_str.to_string());
+        config.sync.enabled = false;
+        let record = HistoryRecord::new("abc1234", history::Outcome::Success, Duration::from_secs(1));
+        history::append_record(target_str, &record).unwrap();
+
+        let json = serde_json::to_value(gather(&config)).unwrap();
+        let last_run = &json["targets"][0]["last_run"];
+        assert_eq!(last_run["user"], history::current_user());
+        assert_eq!(last_run["host"], history::current_host());
+        fs::remove_dir_all(&target).unwrap();
+    }
+
+    #[test]
+    fn test_status_resolves_branch_target() {
+        let repo = crate::test_support::init_repo();
+        crate::test_support::commit_file(&repo, "a.txt");
+        let mut config = Config::default();
+        config.watch.repo_path = repo.to_str().unwrap().to_string();
+        config.deploy.target_dir = Some(format!("{}/deploy/{{branch}}", repo.display()));
+        config.sync.enabled = false;
+        fs::create_dir_all(repo.join("deploy/main/abc1234")).unwrap();
+
+        let status = gather(&config);
+        assert_eq!(status.targets[0].target_dir, format!("{}/deploy/main", repo.display()));
+        assert_eq!(status.targets[0].versions[0].name, "abc1234");
+
+        crate::test_support::git(&repo, &["checkout", "-q", "--detach"]);
+        let status = gather(&config);
+        assert!(status.targets[0].error.as_deref().unwrap().contains("Cannot resolve target_dir"), "{:?}", status.targets[0]);
+        fs::remove_dir_all(&repo).unwrap();
+    }
+}
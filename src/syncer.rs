@@ -1,64 +1,275 @@
-use std::process::Command;
+use crate::config::{GitConfig, SyncConfig, SyncProvider};
+use crate::hook;
+use crate::runner;
+use std::time::Duration;
 
-/// Sync code to remote GitHub repository
-pub fn sync_to_github(
+const RELEASE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Push `branch` to `remote` (any git host; this is a plain `git push`)
+///
+/// With `push_submodules`, submodule commits the pushed revisions reference
+/// are pushed to the submodules' own remotes first (a no-op without submodules).
+pub fn sync_to_remote(
     remote: &str,
     branch: &str,
     repo_path: &str,
+    git: &GitConfig,
+    push_submodules: bool,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    log::info!("Syncing to GitHub: {} {}", remote, branch);
+    log::info!("Syncing to {}: {}", remote, branch);
+
+    let mut args = vec!["push"];
+    if push_submodules && hook::has_submodules(repo_path) {
+        args.push("--recurse-submodules=on-demand");
+    }
+    args.extend([remote, branch]);
 
     // Execute git push
-    let output = Command::new("git")
-        .args(&["push", remote, branch])
-        .current_dir(repo_path)
-        .output()?;
+    let output = runner::run_tracked(hook::git_command(repo_path, git).args(&args))?;
 
     // Check if push succeeded
     if !output.status.success() {
         let stderr = String::from_utf8_lossy(&output.stderr);
-        log::warn!("GitHub sync failed: {}", stderr);
+        log::warn!("Sync to {} failed: {}", remote, stderr);
         return Err(format!("Git push failed: {}", stderr).into());
     }
 
     let stdout = String::from_utf8_lossy(&output.stdout);
     let stderr = String::from_utf8_lossy(&output.stderr);
-    log::info!("GitHub sync succeeded: {} {}", stdout, stderr);
+    log::info!("Sync to {} succeeded: {} {}", remote, stdout, stderr);
 
     Ok(())
 }
 
+/// Name of the tag (and release) created for a deployed commit
+pub fn release_tag(commit: &str) -> String {
+    format!("deploy-{}", &commit[..commit.len().min(7)])
+}
+
+/// Create a release (GitHub) or tag (GitLab) for `commit` through the
+/// provider's REST API
+///
+/// Does nothing and returns `None` unless both `provider` and `token` are
+/// configured; otherwise returns the tag name created.
+pub fn sync_release(config: &SyncConfig, commit: &str) -> Result<Option<String>, Box<dyn std::error::Error>> {
+    let (Some(provider), Some(token)) = (config.provider, config.token.as_deref()) else {
+        return Ok(None);
+    };
+    let repository = config
+        .repository
+        .as_deref()
+        .ok_or("sync.repository must be set to create releases")?;
+    let api_url = config
+        .api_url
+        .as_deref()
+        .unwrap_or(provider.default_api_url())
+        .trim_end_matches('/');
+    let tag = release_tag(commit);
+
+    let agent = ureq::AgentBuilder::new().timeout(RELEASE_TIMEOUT).build();
+    let request = match provider {
+        SyncProvider::GitHub => agent
+            .post(&format!("{}/repos/{}/releases", api_url, repository))
+            .set("Authorization", &format!("Bearer {}", token))
+            .set("Accept", "application/vnd.github+json"),
+        SyncProvider::GitLab => agent
+            .post(&format!("{}/projects/{}/repository/tags", api_url, repository.replace('/', "%2F")))
+            .set("PRIVATE-TOKEN", token),
+    };
+    let body = match provider {
+        SyncProvider::GitHub => serde_json::json!({ "tag_name": tag, "target_commitish": commit, "name": tag }),
+        SyncProvider::GitLab => serde_json::json!({ "tag_name": tag, "ref": commit }),
+    };
+
+    match request.set("Content-Type", "application/json").send_string(&body.to_string()) {
+        Ok(_) => {
+            log::info!("Created {:?} release {} for {}", provider, tag, repository);
+            Ok(Some(tag))
+        }
+        Err(ureq::Error::Status(code, response)) => {
+            let detail = response.into_string().unwrap_or_default();
+            Err(format!("Creating release {} failed with HTTP {}: {}", tag, code, detail.trim()).into())
+        }
+        Err(e) => Err(format!("Creating release {} failed: {}", tag, e).into()),
+    }
+}
+
 /// Check if there are unpushed commits
+///
+/// A branch without a remote counterpart counts as unpushed.
 pub fn has_unpushed_commits(
     remote: &str,
     branch: &str,
     repo_path: &str,
+    git: &GitConfig,
 ) -> Result<bool, Box<dyn std::error::Error>> {
-    // Get local commit
-    let local_output = Command::new("git")
-        .args(&["rev-parse", branch])
-        .current_dir(repo_path)
-        .output()?;
-
-    if !local_output.status.success() {
-        return Err("Failed to get local commit".into());
+    Ok(match ahead_behind(remote, branch, repo_path, git)? {
+        Some((ahead, _)) => ahead > 0,
+        None => true,
+    })
+}
+
+/// Count commits the local branch is ahead of and behind `remote/branch`
+///
+/// Returns `None` when the remote branch is unknown (e.g. never pushed).
+pub fn ahead_behind(
+    remote: &str,
+    branch: &str,
+    repo_path: &str,
+    git: &GitConfig,
+) -> Result<Option<(usize, usize)>, Box<dyn std::error::Error>> {
+    let remote_ref = format!("{}/{}", remote, branch);
+    let remote_exists = runner::run_tracked(
+        hook::git_command(repo_path, git)
+            .args(["rev-parse", "--verify", "--quiet", &remote_ref]),
+    )?
+        .status
+        .success();
+    if !remote_exists {
+        return Ok(None);
     }
 
-    let local_commit = String::from_utf8(local_output.stdout)?.trim().to_string();
+    let range = format!("{}...{}", branch, remote_ref);
+    let output = runner::run_tracked(
+        hook::git_command(repo_path, git)
+            .args(["rev-list", "--left-right", "--count", &range]),
+    )?;
 
-    // Get remote commit
-    let remote_ref = format!("{}/{}", remote, branch);
-    let remote_output = Command::new("git")
-        .args(&["rev-parse", &remote_ref])
-        .current_dir(repo_path)
-        .output()?;
-
-    if !remote_output.status.success() {
-        // Remote branch might not exist yet
-        return Ok(true);
+    if !output.status.success() {
+        return Err("Failed to compare local and remote commits".into());
+    }
+
+    let counts = String::from_utf8(output.stdout)?;
+    let mut counts = counts.split_whitespace().map(|count| count.parse::<usize>());
+    match (counts.next(), counts.next()) {
+        (Some(Ok(ahead)), Some(Ok(behind))) => Ok(Some((ahead, behind))),
+        _ => Err("Unexpected git rev-list output".into()),
+    }
+}
+
+/// Check that `remote` answers `git ls-remote` without prompting for credentials
+pub fn remote_reachable(remote: &str, repo_path: &str, git: &GitConfig) -> Result<(), Box<dyn std::error::Error>> {
+    let output = runner::run_tracked(hook::git_command(repo_path, git).args(["ls-remote", "--heads", remote]))?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(format!("Remote {} is not reachable: {}", remote, stderr.trim()).into());
     }
 
-    let remote_commit = String::from_utf8(remote_output.stdout)?.trim().to_string();
+    Ok(())
+}
+
+/// Describe an ahead/behind count for status output
+pub fn describe_ahead_behind(counts: Option<(usize, usize)>) -> String {
+    match counts {
+        Some((0, 0)) => "up to date".to_string(),
+        Some((ahead, behind)) => format!("{} ahead, {} behind", ahead, behind),
+        None => "unknown (no remote branch)".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{commit_file, git, init_bare_repo, init_repo, init_repo_with_submodule, serve_http_once};
+    use std::net::TcpListener;
+
+    fn release_config(provider: SyncProvider, api_url: String) -> SyncConfig {
+        SyncConfig {
+            provider: Some(provider),
+            token: Some("secret-token".to_string()),
+            repository: Some("acme/app".to_string()),
+            api_url: Some(api_url),
+            ..SyncConfig::default()
+        }
+    }
+
+    #[test]
+    fn test_sync_release_calls_provider_api() {
+        let commit = "abc1234def5678abc1234def5678abc1234def56";
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let api_url = format!("http://{}", listener.local_addr().unwrap());
+        let server = serve_http_once(listener, "201 Created");
+        let tag = sync_release(&release_config(SyncProvider::GitHub, api_url), commit).unwrap();
+        assert_eq!(tag.as_deref(), Some("deploy-abc1234"));
+        let request = server.join().unwrap();
+        assert!(request.starts_with("POST /repos/acme/app/releases "), "{}", request);
+        assert!(request.to_lowercase().contains("authorization: bearer secret-token"));
+        let body: serde_json::Value = serde_json::from_str(request.split_once("\r\n\r\n").unwrap().1).unwrap();
+        assert_eq!(body["tag_name"], "deploy-abc1234");
+        assert_eq!(body["target_commitish"], commit);
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let api_url = format!("http://{}", listener.local_addr().unwrap());
+        let server = serve_http_once(listener, "201 Created");
+        sync_release(&release_config(SyncProvider::GitLab, api_url), commit).unwrap();
+        let request = server.join().unwrap();
+        assert!(request.starts_with("POST /projects/acme%2Fapp/repository/tags "), "{}", request);
+        assert!(request.to_lowercase().contains("private-token: secret-token"));
+
+        // A rejected call is an error naming the status
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let api_url = format!("http://{}", listener.local_addr().unwrap());
+        let server = serve_http_once(listener, "401 Unauthorized");
+        let err = sync_release(&release_config(SyncProvider::GitHub, api_url), commit).unwrap_err();
+        assert!(err.to_string().contains("HTTP 401"), "{}", err);
+        server.join().unwrap();
 
-    Ok(local_commit != remote_commit)
+        // No token: plain push only, no request made
+        let config = SyncConfig {
+            token: None,
+            ..release_config(SyncProvider::GitHub, "http://127.0.0.1:9".to_string())
+        };
+        assert_eq!(sync_release(&config, commit).unwrap(), None);
+    }
+
+    #[test]
+    fn test_ahead_behind_counts() {
+        let repo = init_repo();
+        let repo_str = repo.to_str().unwrap();
+        commit_file(&repo, "base.txt");
+
+        assert_eq!(ahead_behind("origin", "main", repo_str, &GitConfig::default()).unwrap(), None);
+        assert!(has_unpushed_commits("origin", "main", repo_str, &GitConfig::default()).unwrap());
+
+        // Fake a remote that has one commit the local branch lacks
+        git(&repo, &["checkout", "-q", "-b", "other"]);
+        let remote_commit = commit_file(&repo, "remote.txt");
+        git(&repo, &["checkout", "-q", "main"]);
+        commit_file(&repo, "local1.txt");
+        commit_file(&repo, "local2.txt");
+        git(&repo, &["update-ref", "refs/remotes/origin/main", &remote_commit]);
+
+        let counts = ahead_behind("origin", "main", repo_str, &GitConfig::default()).unwrap();
+        assert_eq!(counts, Some((2, 1)));
+        assert_eq!(describe_ahead_behind(counts), "2 ahead, 1 behind");
+        assert!(has_unpushed_commits("origin", "main", repo_str, &GitConfig::default()).unwrap());
+        std::fs::remove_dir_all(&repo).unwrap();
+    }
+
+    #[test]
+    fn test_sync_pushes_submodule_commits() {
+        let (superproject, sub_remote) = init_repo_with_submodule();
+        let super_remote = init_bare_repo();
+        git(&superproject, &["remote", "add", "origin", super_remote.to_str().unwrap()]);
+
+        // A submodule commit that only exists locally
+        let sub_commit = commit_file(&superproject.join("sub"), "feature.txt");
+        git(&superproject, &["commit", "-qam", "bump sub"]);
+
+        let super_str = superproject.to_str().unwrap();
+        sync_to_remote("origin", "main", super_str, &GitConfig::default(), true).unwrap();
+        git(&sub_remote, &["cat-file", "-e", &sub_commit]);
+
+        // Updating re-populates a deinitialized submodule at the referenced commit
+        git(&superproject, &["submodule", "--quiet", "deinit", "-f", "sub"]);
+        assert!(!superproject.join("sub/feature.txt").exists());
+        hook::update_submodules(super_str, &GitConfig::default()).unwrap();
+        assert!(superproject.join("sub/feature.txt").exists());
+
+        for dir in [superproject, sub_remote, super_remote] {
+            std::fs::remove_dir_all(dir).unwrap();
+        }
+    }
 }
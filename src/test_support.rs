@@ -0,0 +1,96 @@
+//! Shared helpers for unit tests
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// Create a unique, empty scratch directory
+pub fn temp_dir(prefix: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("postloop-{}-{}", prefix, uuid::Uuid::new_v4()));
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Run git in `repo` with a fixed identity, asserting success and returning stdout
+pub fn git(repo: &Path, args: &[&str]) -> String {
+    let output = Command::new("git")
+        .current_dir(repo)
+        .args(["-c", "user.name=postloop", "-c", "user.email=postloop@example.com"])
+        .args(["-c", "protocol.file.allow=always"])
+        .args(args)
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "git {:?} failed: {}", args, String::from_utf8_lossy(&output.stderr));
+    String::from_utf8(output.stdout).unwrap().trim().to_string()
+}
+
+/// Initialize a git repository on branch `main`
+pub fn init_repo() -> PathBuf {
+    let repo = temp_dir("repo");
+    git(&repo, &["init", "-q"]);
+    git(&repo, &["symbolic-ref", "HEAD", "refs/heads/main"]);
+    repo
+}
+
+/// Initialize a bare repository whose HEAD is `main`
+pub fn init_bare_repo() -> PathBuf {
+    let repo = temp_dir("bare");
+    git(&repo, &["init", "-q", "--bare"]);
+    git(&repo, &["symbolic-ref", "HEAD", "refs/heads/main"]);
+    repo
+}
+
+/// Initialize a repository with one commit and a submodule at `sub`
+///
+/// Returns the superproject and the bare repository the submodule tracks.
+pub fn init_repo_with_submodule() -> (PathBuf, PathBuf) {
+    let sub_remote = init_bare_repo();
+    let sub = init_repo();
+    commit_file(&sub, "lib.txt");
+    git(&sub, &["push", "-q", sub_remote.to_str().unwrap(), "main"]);
+    fs::remove_dir_all(&sub).unwrap();
+
+    let repo = init_repo();
+    commit_file(&repo, "README");
+    git(&repo, &["submodule", "add", "-q", sub_remote.to_str().unwrap(), "sub"]);
+    git(&repo, &["commit", "-qm", "add submodule"]);
+    (repo, sub_remote)
+}
+
+/// Write `file` (content = its path) and commit it, returning the new commit hash
+pub fn commit_file(repo: &Path, file: &str) -> String {
+    let path = repo.join(file);
+    fs::create_dir_all(path.parent().unwrap()).unwrap();
+    fs::write(&path, file).unwrap();
+    git(repo, &["add", "."]);
+    git(repo, &["commit", "-q", "-m", file]);
+    git(repo, &["rev-parse", "HEAD"])
+}
+
+/// Accept one HTTP request on `listener`, answer it with `status` and return
+/// the raw request (head and body)
+pub fn serve_http_once(listener: std::net::TcpListener, status: &'static str) -> std::thread::JoinHandle<String> {
+    use std::io::{Read, Write};
+    std::thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut request = Vec::new();
+        let mut buffer = [0_u8; 1024];
+        loop {
+            let read = stream.read(&mut buffer).unwrap();
+            request.extend_from_slice(&buffer[..read]);
+            let text = String::from_utf8_lossy(&request).to_string();
+            if let Some((headers, body)) = text.split_once("\r\n\r\n") {
+                let length = headers
+                    .lines()
+                    .find_map(|line| line.to_lowercase().strip_prefix("content-length:").map(|v| v.trim().to_string()))
+                    .and_then(|v| v.parse::<usize>().ok())
+                    .unwrap_or(0);
+                if body.len() >= length || read == 0 {
+                    let response = format!("HTTP/1.1 {}\r\nContent-Length: 0\r\n\r\n", status);
+                    stream.write_all(response.as_bytes()).unwrap();
+                    return text;
+                }
+            }
+        }
+    })
+}